@@ -0,0 +1,53 @@
+// Finding each vehicle's nearest stop: one closest_stop call per vehicle against
+// nearest_stop_batch, which works out each stop's trig once for the whole batch.
+//
+//     cargo bench --bench nearest_stop
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use rand::rngs::StdRng;
+use rand::{RngExt, SeedableRng};
+use std::hint::black_box;
+
+#[allow(dead_code, unused_imports)] // Only part of it is benched; its tests are left out of a bench build
+#[path = "../src/geo.rs"]
+mod geo;
+
+use geo::{closest_stop, nearest_stop_batch, Located};
+
+struct Stop(f64, f64);
+
+impl Located for Stop {
+    fn lat(&self) -> f64 {
+        self.0
+    }
+    fn lng(&self) -> f64 {
+        self.1
+    }
+    fn radius(&self) -> f64 {
+        200.0
+    }
+}
+
+/// `n` positions within about 20 km of 53.0,-1.5
+fn positions(rng: &mut StdRng, n: usize) -> Vec<(f64, f64)> {
+    (0..n).map(|_| (53.0 + rng.random_range(-0.2..0.2), -1.5 + rng.random_range(-0.3..0.3))).collect()
+}
+
+fn nearest_stop(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(141);
+    let buses = positions(&mut rng, 100);
+    let mut group = c.benchmark_group("nearest_stop_100_vehicles");
+    for stop_count in [10, 100, 1000] {
+        let stops: Vec<Stop> = positions(&mut rng, stop_count).into_iter().map(|(lat, lng)| Stop(lat, lng)).collect();
+        group.bench_with_input(BenchmarkId::new("closest_stop_each", stop_count), &stops, |b, stops| {
+            b.iter(|| buses.iter().map(|&(lat, lng)| closest_stop(lat, lng, black_box(stops)).map(|(_, d)| d)).collect::<Vec<_>>())
+        });
+        group.bench_with_input(BenchmarkId::new("nearest_stop_batch", stop_count), &stops, |b, stops| {
+            b.iter(|| nearest_stop_batch(black_box(&buses), black_box(stops)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, nearest_stop);
+criterion_main!(benches);
@@ -0,0 +1,89 @@
+// Serial against rayon for the distance passes that switch at PARALLEL_MIN_STOPS. Compare each
+// pair at the stop counts around the threshold on the machine it's meant for.
+//
+//     cargo bench --features parallel --bench parallel
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use rand::rngs::StdRng;
+use rand::{RngExt, SeedableRng};
+use std::hint::black_box;
+
+#[allow(dead_code, unused_imports)] // Only part of it is benched; its tests are left out of a bench build
+#[path = "../src/geo.rs"]
+mod geo;
+
+use geo::Located;
+
+struct Stop(f64, f64);
+
+impl Located for Stop {
+    fn lat(&self) -> f64 {
+        self.0
+    }
+    fn lng(&self) -> f64 {
+        self.1
+    }
+    fn radius(&self) -> f64 {
+        200.0
+    }
+}
+
+/// `n` positions within about 20 km of 53.0,-1.5
+fn positions(rng: &mut StdRng, n: usize) -> Vec<(f64, f64)> {
+    (0..n).map(|_| (53.0 + rng.random_range(-0.2..0.2), -1.5 + rng.random_range(-0.3..0.3))).collect()
+}
+
+const STOP_COUNTS: [usize; 5] = [100, 300, 1000, 3000, 10000];
+
+/// nearest_stop_batch for 100 vehicles, as when trimming to MAX_SERVICES_PER_CYCLE
+fn batch(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(123);
+    let buses = positions(&mut rng, 100);
+    let mut group = c.benchmark_group("nearest_stop_batch_100_vehicles");
+    for stop_count in STOP_COUNTS {
+        let stops: Vec<Stop> = positions(&mut rng, stop_count).into_iter().map(|(lat, lng)| Stop(lat, lng)).collect();
+        group.bench_with_input(BenchmarkId::new("serial", stop_count), &stops, |b, stops| {
+            b.iter(|| geo::nearest_stop_batch_serial(black_box(&buses), black_box(stops)))
+        });
+        group.bench_with_input(BenchmarkId::new("parallel", stop_count), &stops, |b, stops| {
+            b.iter(|| geo::nearest_stop_batch_parallel(black_box(&buses), black_box(stops)))
+        });
+    }
+    group.finish();
+}
+
+/// closest_stop for one vehicle, as for early warnings
+fn closest(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(1230);
+    let (lat, lng) = positions(&mut rng, 1)[0];
+    let mut group = c.benchmark_group("closest_stop_1_vehicle");
+    for stop_count in STOP_COUNTS {
+        let stops: Vec<Stop> = positions(&mut rng, stop_count).into_iter().map(|(lat, lng)| Stop(lat, lng)).collect();
+        group.bench_with_input(BenchmarkId::new("serial", stop_count), &stops, |b, stops| {
+            b.iter(|| geo::closest_stop_serial(black_box(lat), black_box(lng), black_box(stops)))
+        });
+        group.bench_with_input(BenchmarkId::new("parallel", stop_count), &stops, |b, stops| {
+            b.iter(|| geo::closest_stop_parallel(black_box(lat), black_box(lng), black_box(stops)))
+        });
+    }
+    group.finish();
+}
+
+/// first_stop_within for one vehicle that isn't at any stop, so every stop is measured
+fn within(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(123);
+    let mut group = c.benchmark_group("first_stop_within_1_vehicle");
+    for stop_count in STOP_COUNTS {
+        let stops: Vec<Stop> = positions(&mut rng, stop_count).into_iter().map(|(lat, lng)| Stop(lat, lng)).collect();
+        group.bench_with_input(BenchmarkId::new("serial", stop_count), &stops, |b, stops| {
+            b.iter(|| geo::first_stop_within_serial(black_box(54.0), black_box(-1.5), black_box(stops)))
+        });
+        group.bench_with_input(BenchmarkId::new("parallel", stop_count), &stops, |b, stops| {
+            b.iter(|| geo::first_stop_within_parallel(black_box(54.0), black_box(-1.5), black_box(stops)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, batch, closest, within);
+criterion_main!(benches);
@@ -0,0 +1,66 @@
+// Finding the stop a bus is at, with the StopIndex grid against checking every stop in turn.
+//
+//     cargo bench --bench stop_index
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use rand::rngs::StdRng;
+use rand::{RngExt, SeedableRng};
+use std::hint::black_box;
+
+#[allow(dead_code, unused_imports)] // Only part of it is benched; its tests are left out of a bench build
+#[path = "../src/geo.rs"]
+mod geo;
+#[allow(dead_code, unused_imports)]
+#[path = "../src/stop_index.rs"]
+mod stop_index;
+
+use geo::{haversine_distance, Located};
+use stop_index::StopIndex;
+
+struct Stop(f64, f64);
+
+impl Located for Stop {
+    fn lat(&self) -> f64 {
+        self.0
+    }
+    fn lng(&self) -> f64 {
+        self.1
+    }
+    fn radius(&self) -> f64 {
+        200.0
+    }
+}
+
+/// `n` positions within about 20 km of 53.0,-1.5
+fn positions(rng: &mut StdRng, n: usize) -> Vec<(f64, f64)> {
+    (0..n).map(|_| (53.0 + rng.random_range(-0.2..0.2), -1.5 + rng.random_range(-0.3..0.3))).collect()
+}
+
+fn at_stop(lat: f64, lng: f64, stops: &[Stop], candidates: impl IntoIterator<Item = usize>) -> Option<usize> {
+    candidates.into_iter().find(|&i| haversine_distance(lat, lng, stops[i].0, stops[i].1) <= stops[i].radius())
+}
+
+fn stop_index(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(142);
+    let buses = positions(&mut rng, 100);
+    let mut group = c.benchmark_group("stop_at_100_vehicles");
+    for stop_count in [32, 500, 5000] {
+        let stops: Vec<Stop> = positions(&mut rng, stop_count).into_iter().map(|(lat, lng)| Stop(lat, lng)).collect();
+        let index = StopIndex::new(&stops);
+        group.bench_with_input(BenchmarkId::new("scan", stop_count), &stops, |b, stops| {
+            b.iter(|| buses.iter().map(|&(lat, lng)| at_stop(lat, lng, black_box(stops), 0..stops.len())).collect::<Vec<_>>())
+        });
+        group.bench_with_input(BenchmarkId::new("indexed", stop_count), &stops, |b, stops| {
+            b.iter(|| {
+                buses
+                    .iter()
+                    .map(|&(lat, lng)| at_stop(lat, lng, black_box(stops), index.candidates(lat, lng, stops.len()).unwrap()))
+                    .collect::<Vec<_>>()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, stop_index);
+criterion_main!(benches);
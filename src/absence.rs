@@ -0,0 +1,108 @@
+// Notices when a service in WATCH_SERVICES has been missing from the feed for ABSENCE_ALERT_SECS,
+// which can mean a cancellation. Each absence is reported once; the service reappearing resets it.
+
+use chrono::{DateTime, Utc};
+
+use crate::{env_or_file, env_parse};
+
+#[derive(Debug, Clone, Copy)]
+struct Seen {
+    at: DateTime<Utc>, // Last time it was in the feed (or when watching started)
+    reported: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct AbsenceWatch {
+    threshold_secs: i64,
+    services: Vec<(String, Option<Seen>)>, // Service and its last sighting; None until the first cycle
+}
+
+impl AbsenceWatch {
+    /// Reads `WATCH_SERVICES` (comma-separated) and `ABSENCE_ALERT_SECS` (default 1200)
+    pub fn from_env() -> Result<AbsenceWatch, String> {
+        let services = env_or_file("WATCH_SERVICES")?
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|service| !service.is_empty())
+            .map(|service| (service.to_string(), None))
+            .collect();
+        Ok(AbsenceWatch { threshold_secs: env_parse("ABSENCE_ALERT_SECS", 1200)?, services })
+    }
+
+    /// Records which services the latest response contained and returns each watched service
+    /// that has just passed the threshold, with how long it's been missing
+    pub fn observe<'a>(&mut self, seen: impl IntoIterator<Item = &'a str>, now: DateTime<Utc>) -> Vec<(String, i64)> {
+        if self.services.is_empty() {
+            return Vec::new();
+        }
+        let seen: Vec<&str> = seen.into_iter().map(str::trim).collect();
+
+        let mut absent = Vec::new();
+        for (service, last) in self.services.iter_mut() {
+            let entry = last.get_or_insert(Seen { at: now, reported: false });
+            if seen.iter().any(|seen| seen.eq_ignore_ascii_case(service)) {
+                *entry = Seen { at: now, reported: false };
+                continue;
+            }
+            let missing_secs = (now - entry.at).num_seconds();
+            if missing_secs >= self.threshold_secs && !entry.reported {
+                entry.reported = true;
+                absent.push((service.clone(), missing_secs));
+            }
+        }
+        absent
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{fixed_now, with_env};
+    use chrono::Duration;
+
+    /// What each cycle a minute apart reports, given the services in each response
+    fn absences(watch: &mut AbsenceWatch, cycles: &[&[&str]]) -> Vec<Vec<(String, i64)>> {
+        cycles
+            .iter()
+            .enumerate()
+            .map(|(minute, seen)| watch.observe(seen.iter().copied(), fixed_now() + Duration::minutes(minute as i64)))
+            .collect()
+    }
+
+    fn watch(services: &str, threshold_secs: &str) -> AbsenceWatch {
+        with_env(&[("WATCH_SERVICES", services), ("ABSENCE_ALERT_SECS", threshold_secs)], AbsenceWatch::from_env).unwrap()
+    }
+
+    #[test]
+    fn an_absence_is_reported_once_until_the_service_comes_back() {
+        let mut watching = watch("36, X1", "180");
+        let cycles: [&[&str]; 10] = [
+            &["36", "7"],
+            &["7"],
+            &["7"],
+            &["7"], // 36 missing for 3 min
+            &["7"],
+            &["7"],
+            &[" 36 "], // Back, then gone again
+            &[],
+            &[],
+            &[],
+        ];
+        let absent = |service: &str| (service.to_string(), 180);
+        // X1 never shows, so counts from the first cycle
+        assert_eq!(
+            absences(&mut watching, &cycles),
+            [vec![], vec![], vec![], vec![absent("36"), absent("X1")], vec![], vec![], vec![], vec![], vec![], vec![absent("36")]]
+        );
+    }
+
+    #[test]
+    fn matching_ignores_case_and_nothing_is_watched_by_default() {
+        let mut x1 = watch("x1", "60");
+        assert!(absences(&mut x1, &[&["X1"], &["X1"], &["X1"]]).iter().all(Vec::is_empty));
+
+        let mut unwatched = watch(" , ", "0");
+        assert!(absences(&mut unwatched, &[&[], &[], &[]]).iter().all(Vec::is_empty));
+    }
+}
@@ -0,0 +1,149 @@
+// Cooldown bookkeeping so a bus sitting near a stop doesn't alert every cycle
+
+use chrono::{DateTime, Duration, Utc};
+use std::collections::HashMap;
+
+use crate::stop_names;
+
+/// How long alerts for the same key are held back after one is sent
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cooldown {
+    Time(Duration), // ALERT_COOLDOWN_SECS
+    Cycles(u64),    // ALERT_COOLDOWN_CYCLES: this many following poll cycles stay quiet
+}
+
+#[derive(Debug)]
+pub struct AlertTracker {
+    cooldown: Cooldown,
+    cycle: u64,                                       // Poll cycles started so far
+    last_sent: HashMap<String, (DateTime<Utc>, u64)>, // Keyed by vehicle + stop (or group): when and in which cycle
+}
+
+impl AlertTracker {
+    pub fn new(cooldown: Cooldown) -> AlertTracker {
+        AlertTracker {
+            cooldown,
+            cycle: 0,
+            last_sent: HashMap::new(),
+        }
+    }
+
+    /// Marks the start of a poll cycle, for cycle-based cooldowns
+    pub fn next_cycle(&mut self) {
+        self.cycle += 1;
+    }
+
+    /// Records an alert for `key` and returns true, unless one was already sent within the cooldown
+    pub fn should_alert(&mut self, key: &str, now: DateTime<Utc>) -> bool {
+        if let Some((last_at, last_cycle)) = self.last_sent.get(key) {
+            let cooling = match self.cooldown {
+                Cooldown::Time(cooldown) => now - *last_at < cooldown,
+                Cooldown::Cycles(cycles) => self.cycle - last_cycle <= cycles,
+            };
+            if cooling {
+                return false;
+            }
+        }
+
+        self.record(key, now);
+        true
+    }
+
+    /// Records an alert for `key` sent regardless of the cooldown, which starts over from now
+    pub fn record(&mut self, key: &str, now: DateTime<Utc>) {
+        self.last_sent.insert(key.to_string(), (now, self.cycle));
+    }
+}
+
+/// Identifies what an alert is about: a vehicle and either a stop group or a single stop.
+/// Stops sharing a group share one key, so a bus passing through a cluster alerts once,
+/// and stop names are canonicalised so spelling variants of one stop share a key too.
+pub fn alert_key(vehicle_key: &str, stop_name: &str, group: Option<&str>) -> String {
+    match group {
+        Some(group) => format!("{}@group:{}", vehicle_key, group),
+        None => format!("{}@stop:{}", vehicle_key, stop_names::canonical(stop_name)),
+    }
+}
+
+/// Key for the early "approaching" heads-up, kept separate so it doesn't use up the arrival alert
+pub fn early_alert_key(vehicle_key: &str, stop_name: &str, group: Option<&str>) -> String {
+    format!("{}#early", alert_key(vehicle_key, stop_name, group))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::fixed_now;
+
+    /// Whether `key` alerts in each of `cycles` poll cycles, a minute apart
+    fn alerts_by_cycle(tracker: &mut AlertTracker, key: &str, cycles: i64) -> Vec<bool> {
+        (0..cycles)
+            .map(|minute| {
+                tracker.next_cycle();
+                tracker.should_alert(key, fixed_now() + Duration::minutes(minute))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn cycle_cooldown_keeps_exactly_that_many_cycles_quiet() {
+        let mut tracker = AlertTracker::new(Cooldown::Cycles(2));
+        assert_eq!(alerts_by_cycle(&mut tracker, "fleet:1@stop:home", 7), [true, false, false, true, false, false, true]);
+
+        let mut tracker = AlertTracker::new(Cooldown::Cycles(0));
+        assert_eq!(alerts_by_cycle(&mut tracker, "fleet:1@stop:home", 3), [true, true, true]);
+    }
+
+    #[test]
+    fn time_cooldown_ends_once_the_full_duration_has_passed() {
+        let mut tracker = AlertTracker::new(Cooldown::Time(Duration::seconds(300)));
+        let key = "fleet:1@stop:home";
+        assert!(tracker.should_alert(key, fixed_now()));
+        assert!(!tracker.should_alert(key, fixed_now() + Duration::seconds(299)));
+        assert!(tracker.should_alert(key, fixed_now() + Duration::seconds(300)));
+        // Held back alerts don't restart the cooldown; sent ones do
+        assert!(!tracker.should_alert(key, fixed_now() + Duration::seconds(599)));
+        assert!(tracker.should_alert(key, fixed_now() + Duration::seconds(600)));
+    }
+
+    #[test]
+    fn a_recorded_alert_restarts_the_cooldown() {
+        let mut tracker = AlertTracker::new(Cooldown::Time(Duration::seconds(300)));
+        let key = "fleet:1@stop:home";
+        assert!(tracker.should_alert(key, fixed_now()));
+        tracker.record(key, fixed_now() + Duration::seconds(200));
+        assert!(!tracker.should_alert(key, fixed_now() + Duration::seconds(400)));
+        assert!(tracker.should_alert(key, fixed_now() + Duration::seconds(500)));
+    }
+
+    #[test]
+    fn keys_cool_down_independently() {
+        let mut tracker = AlertTracker::new(Cooldown::Cycles(5));
+        tracker.next_cycle();
+        assert!(tracker.should_alert("fleet:1@stop:home", fixed_now()));
+        assert!(tracker.should_alert("fleet:2@stop:home", fixed_now()));
+        assert!(tracker.should_alert("fleet:1@stop:work", fixed_now()));
+        assert!(!tracker.should_alert("fleet:1@stop:home", fixed_now()));
+    }
+
+    #[test]
+    fn grouped_stops_share_a_key_and_early_warnings_have_their_own() {
+        assert_eq!(alert_key("fleet:1", "Market Square", Some("town")), alert_key("fleet:1", "Station Road", Some("town")));
+        assert_ne!(alert_key("fleet:1", "Market Square", None), alert_key("fleet:1", "Station Road", None));
+        assert_eq!(alert_key("fleet:1", "Market Sq.", None), alert_key("fleet:1", "market square", None));
+        assert_ne!(early_alert_key("fleet:1", "Market Square", None), alert_key("fleet:1", "Market Square", None));
+    }
+
+    #[test]
+    fn stops_in_a_group_share_one_cooldown() {
+        let mut tracker = AlertTracker::new(Cooldown::Time(Duration::seconds(300)));
+        let now = Utc::now();
+        // The bus stops at one, then moves on to the other
+        assert!(tracker.should_alert(&alert_key("1", "Market Square", Some("Town")), now));
+        assert!(!tracker.should_alert(&alert_key("1", "Market Street", Some("Town")), now + Duration::minutes(1)));
+
+        // Ungrouped, each stop alerts on its own
+        assert!(tracker.should_alert(&alert_key("1", "Market Square", None), now));
+        assert!(tracker.should_alert(&alert_key("1", "Market Street", None), now + Duration::minutes(1)));
+    }
+}
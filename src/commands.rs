@@ -0,0 +1,84 @@
+// Text commands sent to the bot in Telegram, e.g. "/status home" or "/now"
+
+use crate::format::Style;
+use crate::history::Arrival;
+use crate::session::{self, SharedStatus};
+
+const HISTORY_PAGE_CHARS: usize = 3500; // Well under Telegram's 4096-character message cap
+
+/// What to do about a command
+pub enum Response {
+    Text(String),
+    NearestBuses, // /now: needs a fresh look at the API, which the main loop does
+    History { service: Option<String> }, // /history: the main loop owns the history database
+    Extend { minutes: Option<u64> },     // /extend: the main loop owns the run's deadline; None for the default
+    Follow { fleet: Option<String> },    // /follow: the main loop follows the bus; None stops following
+}
+
+/// Works out the response to a command message, or None if it isn't a command we handle
+pub fn reply_to(text: &str, status: &SharedStatus, style: &Style) -> Option<Response> {
+    let mut words = text.split_whitespace();
+    // Commands may be addressed to the bot explicitly in groups: "/status@my_bot"
+    let command = words.next()?.split('@').next()?.to_ascii_lowercase();
+    let argument = words.next();
+
+    match command.as_str() {
+        "/status" => Some(Response::Text(session::render_status(&status.lock().unwrap(), argument, style))),
+        "/now" => Some(Response::NearestBuses),
+        "/history" => Some(Response::History { service: argument.map(str::to_string) }),
+        "/extend" => match argument.map(str::parse::<u64>) {
+            None => Some(Response::Extend { minutes: None }),
+            Some(Ok(minutes)) if minutes > 0 => Some(Response::Extend { minutes: Some(minutes) }),
+            Some(_) => Some(Response::Text("Usage: /extend 15 (minutes to add to this run)".to_string())),
+        },
+        "/follow" => match argument {
+            Some(argument) if argument.eq_ignore_ascii_case("off") => Some(Response::Follow { fleet: None }),
+            Some(fleet) => Some(Response::Follow { fleet: Some(fleet.to_string()) }),
+            None => Some(Response::Text("Usage: /follow 47201 (the bus's fleet number), or /follow off".to_string())),
+        },
+        _ => None,
+    }
+}
+
+/// Renders one page of the /history reply, one line per service and stop:
+/// "7 → Main St: 07:48, 08:03, 08:21". Returns the text and whether more pages follow.
+pub fn render_history(arrivals: &[Arrival], service: Option<&str>, page: usize, style: &Style) -> (String, bool) {
+    if arrivals.is_empty() {
+        return match service {
+            Some(service) => (format!("No arrivals recorded today for service {}.", service), false),
+            None => ("No arrivals recorded today.".to_string(), false),
+        };
+    }
+
+    let mut lines: Vec<String> = Vec::new();
+    let mut previous: Option<(&str, &str)> = None; // Arrivals come sorted by service, then stop
+    for arrival in arrivals {
+        let time = style.time(arrival.arrived_at);
+        let key = (arrival.service.as_str(), arrival.stop.as_str());
+        match lines.last_mut() {
+            Some(line) if previous == Some(key) => line.push_str(&format!(", {}", time)),
+            _ => lines.push(format!("{} → {}: {}", arrival.service, arrival.stop, time)),
+        }
+        previous = Some(key);
+    }
+
+    // Pack whole lines into pages; a single overlong line still gets a page of its own
+    let mut pages: Vec<String> = Vec::new();
+    for line in lines {
+        match pages.last_mut() {
+            Some(current) if current.len() + line.len() < HISTORY_PAGE_CHARS => {
+                current.push('\n');
+                current.push_str(&line);
+            }
+            _ => pages.push(line),
+        }
+    }
+
+    let total = pages.len();
+    let page = page.min(total - 1);
+    let mut text = format!("Today's arrivals:\n{}", pages[page]);
+    if total > 1 {
+        text.push_str(&format!("\n(page {} of {})", page + 1, total));
+    }
+    (text, page + 1 < total)
+}
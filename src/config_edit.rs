@@ -0,0 +1,162 @@
+// `add-stop` / `remove-stop`: edit the config file's [[stops]] in place, keeping comments and layout
+
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use toml_edit::{value, Array, ArrayOfTables, DocumentMut, Item, Table};
+
+use crate::stop_names;
+
+/// A stop to append, as given on the command line
+#[derive(Debug, Clone)]
+pub struct NewStop {
+    pub name: String,
+    pub lat: f64,
+    pub lng: f64,
+    pub radius: Option<f64>,
+    pub services: Vec<String>,
+}
+
+impl NewStop {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.trim().is_empty() {
+            return Err("The stop needs a name.".to_string());
+        }
+        check_coordinates(self.lat, self.lng)?;
+        if self.radius.is_some_and(|radius| radius <= 0.0 || !radius.is_finite()) {
+            return Err("The radius must be a positive number of meters.".to_string());
+        }
+        Ok(())
+    }
+}
+
+pub fn check_coordinates(lat: f64, lng: f64) -> Result<(), String> {
+    if (-90.0..=90.0).contains(&lat) && (-180.0..=180.0).contains(&lng) {
+        Ok(())
+    } else {
+        Err(format!("Invalid coordinates {}, {}.", lat, lng))
+    }
+}
+
+/// Appends a `[[stops]]` entry and returns the TOML that was added
+pub fn add_stop(path: &Path, stop: &NewStop) -> Result<String, String> {
+    stop.validate()?;
+
+    let mut doc = read_document(path)?;
+    let stops = stops_array(&mut doc)?;
+    if find_stop(stops, &stop.name).is_some() {
+        return Err(format!("A stop named '{}' already exists in {}.", stop.name, path.display()));
+    }
+
+    let mut table = Table::new();
+    table["name"] = value(stop.name.trim());
+    table["lat"] = value(stop.lat);
+    table["lng"] = value(stop.lng);
+    if let Some(radius) = stop.radius {
+        table["radius"] = value(radius);
+    }
+    if !stop.services.is_empty() {
+        table["services"] = value(stop.services.iter().collect::<Array>());
+    }
+    stops.push(table.clone());
+
+    write_document(path, &doc)?;
+
+    let mut entry = DocumentMut::new();
+    entry["stops"] = Item::ArrayOfTables(ArrayOfTables::from_iter([table]));
+    Ok(entry.to_string())
+}
+
+/// Removes the `[[stops]]` entry with this name (case-insensitive)
+pub fn remove_stop(path: &Path, name: &str) -> Result<(), String> {
+    let mut doc = read_document(path)?;
+    let stops = stops_array(&mut doc)?;
+    let index = find_stop(stops, name).ok_or_else(|| format!("No stop named '{}' in {}.", name, path.display()))?;
+    stops.remove(index);
+
+    write_document(path, &doc)
+}
+
+fn read_document(path: &Path) -> Result<DocumentMut, String> {
+    match fs::read_to_string(path) {
+        Ok(contents) => contents
+            .parse::<DocumentMut>()
+            .map_err(|e| format!("Invalid config file {}: {}", path.display(), e)),
+        // Adding the first stop creates the file
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(DocumentMut::new()),
+        Err(e) => Err(format!("Could not read config file {}: {}", path.display(), e)),
+    }
+}
+
+pub fn write_document(path: &Path, doc: &DocumentMut) -> Result<(), String> {
+    // The file may hold the bot token, so keep it private to its owner
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut file| file.write_all(doc.to_string().as_bytes()))
+        .map_err(|e| format!("Could not write config file {}: {}", path.display(), e))
+}
+
+fn stops_array(doc: &mut DocumentMut) -> Result<&mut ArrayOfTables, String> {
+    doc.entry("stops")
+        .or_insert_with(|| Item::ArrayOfTables(ArrayOfTables::new()))
+        .as_array_of_tables_mut()
+        .ok_or_else(|| "'stops' in the config file must be written as [[stops]] tables.".to_string())
+}
+
+fn find_stop(stops: &ArrayOfTables, name: &str) -> Option<usize> {
+    stops.iter().position(|table| {
+        table
+            .get("name")
+            .and_then(|n| n.as_str())
+            .is_some_and(|n| stop_names::same_stop(n, name))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn new_stop(name: &str) -> NewStop {
+        NewStop { name: name.to_string(), lat: 53.0, lng: -1.5, radius: None, services: vec!["7".to_string()] }
+    }
+
+    #[test]
+    fn stops_are_added_and_removed_keeping_the_rest_of_the_file() {
+        let path = env::temp_dir().join(format!("config-edit-test-{}.toml", process::id()));
+        fs::write(&path, "# My stops\nalert_template = \"{stop}\"\n").unwrap();
+
+        let added = add_stop(&path, &new_stop("Market Square")).unwrap();
+        assert_eq!(added, "[[stops]]\nname = \"Market Square\"\nlat = 53.0\nlng = -1.5\nservices = [\"7\"]\n");
+        assert_eq!(
+            add_stop(&path, &new_stop("market square ")),
+            Err(format!("A stop named 'market square ' already exists in {}.", path.display()))
+        );
+        add_stop(&path, &new_stop("Station Road")).unwrap();
+
+        remove_stop(&path, "MARKET SQUARE").unwrap();
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(contents.starts_with("# My stops\nalert_template = \"{stop}\"\n"), "{}", contents);
+        assert!(contents.contains("name = \"Station Road\"") && !contents.contains("Market Square"), "{}", contents);
+        assert_eq!(remove_stop(&path, "Market Square"), Err(format!("No stop named 'Market Square' in {}.", path.display())));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn invalid_stops_are_refused_before_touching_the_file() {
+        let path = env::temp_dir().join(format!("config-edit-invalid-{}.toml", process::id()));
+        let mut stop = new_stop("Market Square");
+        stop.lat = 91.0;
+        assert_eq!(add_stop(&path, &stop), Err("Invalid coordinates 91, -1.5.".to_string()));
+        stop.lat = 53.0;
+        stop.radius = Some(0.0);
+        assert_eq!(add_stop(&path, &stop), Err("The radius must be a positive number of meters.".to_string()));
+        assert!(!path.exists());
+    }
+}
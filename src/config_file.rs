@@ -0,0 +1,403 @@
+// Optional TOML config file (--config / CONFIG_FILE) for stops, named groups and templates.
+// The top-level settings are fallbacks for the environment variable of the same name.
+//
+//     lat = 53.4
+//     lng = -2.98
+//     radius = 1000
+//     poll_interval_secs = 10
+//     telegram_bot_token = "123:abc"
+//     telegram_chat_id = "123456"
+//     alert_template = "{group}: bus {label} is near {stop}"  # {label}: the service's label, see below
+//
+//     [[stops]]
+//     name = "Main Street"
+//     lat = 53.4
+//     lng = -2.98
+//     radius = 250     # optional, meters
+//     group = "home"   # optional
+//     services = ["7", "X24:towards City Centre"]  # optional, only these services (one way, see direction.rs) alert here
+//     days = ["tue", "thu"]    # optional, only watched on these days (in TIMEZONE)
+//     quiet_days = ["sat", "sun"]    # optional, alerts here are silent on these days...
+//     quiet_hours = "09:00-17:30"    # ...and/or in these hours, instead of SILENT_HOURS ("never": always loud)
+//
+//     [groups.home]
+//     telegram_chat_ids = ["123456", "-100987654"]  # where this group's alerts go
+//     ntfy_topic = "home-buses"         # optional, likewise for the other channels when they're set up:
+//     gotify_token = "AbC1dE2"          # a topic on NTFY_URL, a Gotify application token,
+//     matrix_room_id = "!home:example.org"  # a Matrix room, SMS numbers and a webhook URL
+//     sms_to = ["+447700900123"]
+//     webhook_url = "https://example.org/home-buses"
+//
+//     [service_labels]          # optional, how services are shown in messages (see service_labels.rs)
+//     7 = "🟦 7 (City)"
+//     X24 = "🟥 X24"
+//
+//     [field_map]
+//     serviceNumber = "route"  # if the API renames a key (see fields.rs)
+//
+//     [[timetable]]             # optional, scheduled times for `report punctuality`
+//     service = "7"
+//     stop = "Main Street"
+//     times = ["07:32", "07:52", "08:12"]
+//     days = ["mon", "tue", "wed", "thu", "fri"]  # optional, default every day
+//
+//     [[routes]]                # optional, a service's route for distances along the road (see routes.rs)
+//     service = "7"
+//     points = [[53.40, -2.98], [53.41, -2.97]]  # or polyline = "...", or geojson = "path"
+//
+//     [schedule]                # optional, when to poll (see schedule.rs); always without it
+//     cron = ["30-59 7 * * Mon-Fri", "0-30 8 * * Mon-Fri"]
+//     except = ["2024-05-31"]
+//
+//     [[profiles]]              # optional; each profile is tracked concurrently in one process
+//     name = "town"
+//     lat = 53.41               # lat, lng, radius, poll_interval_secs, telegram_chat_id and
+//     lng = -2.99               # alert_template override the top-level values for this profile
+//     telegram_chat_id = "-100555"
+//     days = ["sat", "sun"]     # optional: without --profile, only run on these days...
+//     hours = "09:00-18:00"     # ...and/or when started within these hours
+//     services = ["3", "9"]     # optional, for stops that don't list their own
+//     [[profiles.stops]]        # a profile with stops uses only its own; [profiles.groups] too
+//     name = "Market Square"
+//     lat = 53.41
+//     lng = -2.99
+
+use chrono::{Datelike, NaiveDateTime, NaiveTime, Weekday};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+
+use crate::routes::RouteEntry;
+use crate::schedule::{PollSchedule, ScheduleEntry};
+use crate::timezone::TimeWindow;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    pub lat: Option<f64>,
+    pub lng: Option<f64>,
+    pub radius: Option<u32>,
+    pub poll_interval_secs: Option<u64>,
+    pub telegram_bot_token: Option<String>,
+    pub telegram_chat_id: Option<String>,
+    #[serde(default)]
+    pub stops: Vec<StopEntry>,
+    #[serde(default)]
+    pub groups: BTreeMap<String, GroupEntry>,
+    #[serde(default)]
+    pub field_map: BTreeMap<String, String>, // See fields.rs
+    #[serde(default)]
+    pub service_labels: BTreeMap<String, String>, // Service number -> label
+    pub alert_template: Option<String>,
+    #[serde(default)]
+    pub profiles: Vec<ProfileEntry>,
+    #[serde(default)]
+    pub timetable: Vec<TimetableEntry>,
+    #[serde(default)]
+    pub schedule: ScheduleEntry,
+    #[serde(default)]
+    pub routes: Vec<RouteEntry>,
+}
+
+/// When a service is scheduled at a stop
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimetableEntry {
+    pub service: String,
+    pub stop: String,
+    pub times: Vec<String>, // "HH:MM", local time
+    #[serde(default)]
+    pub days: Vec<String>, // Weekdays it runs; empty means every day
+}
+
+impl TimetableEntry {
+    pub fn runs_on(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.iter().any(|d| d.parse::<Weekday>().ok() == Some(day))
+    }
+
+    /// The scheduled times; `load` has already checked they parse
+    pub fn times(&self) -> Vec<NaiveTime> {
+        self.times.iter().filter_map(|time| NaiveTime::parse_from_str(time.trim(), "%H:%M").ok()).collect()
+    }
+}
+
+/// A named set of stops and settings, tracked alongside the others
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProfileEntry {
+    pub name: String,
+    pub lat: Option<f64>,
+    pub lng: Option<f64>,
+    pub radius: Option<u32>,
+    pub poll_interval_secs: Option<u64>,
+    pub telegram_chat_id: Option<String>,
+    #[serde(default)]
+    pub stops: Vec<StopEntry>,
+    #[serde(default)]
+    pub groups: BTreeMap<String, GroupEntry>,
+    pub alert_template: Option<String>,
+    #[serde(default)]
+    pub days: Vec<String>, // Weekdays ("mon", "Tuesday", ...) the profile is picked automatically on
+    pub hours: Option<String>, // "07:00-09:30": picked automatically when started inside this window
+    #[serde(default)]
+    pub services: Vec<String>, // Services that alert at stops without their own list
+}
+
+impl ProfileEntry {
+    /// Whether the day and time rules pick this profile at `now` (local time); no rules always match
+    fn scheduled_at(&self, now: NaiveDateTime) -> bool {
+        let day_matches = self.days.is_empty() || self.days.iter().any(|day| day.parse::<Weekday>().ok() == Some(now.weekday()));
+        let hour_matches = self.hours.as_deref().and_then(TimeWindow::parse).is_none_or(|window| window.contains(now.time()));
+        day_matches && hour_matches
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StopEntry {
+    pub name: String,
+    pub lat: f64,
+    pub lng: f64,
+    pub radius: Option<f64>,
+    pub group: Option<String>,
+    #[serde(default)]
+    pub services: Vec<String>,
+    #[serde(default)]
+    pub days: Vec<String>,
+    #[serde(default)]
+    pub quiet_days: Vec<String>,
+    pub quiet_hours: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GroupEntry {
+    /// Telegram chats that receive this group's alerts instead of TELEGRAM_CHAT_ID
+    #[serde(default)]
+    pub telegram_chat_ids: Vec<String>,
+    pub ntfy_topic: Option<String>,     // Instead of NTFY_TOPIC, on the same server
+    pub gotify_token: Option<String>,   // Instead of GOTIFY_TOKEN, so the alerts land in another application
+    pub matrix_room_id: Option<String>, // Instead of MATRIX_ROOM_ID
+    #[serde(default)]
+    pub sms_to: Vec<String>, // Instead of TWILIO_TO
+    pub webhook_url: Option<String>, // Instead of WEBHOOK_URL, signed with the same WEBHOOK_SECRET
+}
+
+/// Each group's non-empty value of one GroupEntry field
+pub fn group_destinations<T>(groups: &BTreeMap<String, GroupEntry>, field: impl Fn(&GroupEntry) -> Option<T>) -> HashMap<String, T> {
+    groups.iter().filter_map(|(name, group)| Some((name.clone(), field(group)?))).collect()
+}
+
+impl ConfigFile {
+    /// Loads the file if a path was given, otherwise returns an empty config
+    pub fn load_opt(path: Option<&Path>) -> Result<ConfigFile, String> {
+        match path {
+            Some(path) => ConfigFile::load(path),
+            None => Ok(ConfigFile::default()),
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<ConfigFile, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Could not read config file {}: {}", path.display(), e))?;
+        let file: ConfigFile = toml::from_str(&contents)
+            .map_err(|e| format!("Invalid config file {}: {}", path.display(), e))?;
+
+        for (index, profile) in file.profiles.iter().enumerate() {
+            if file.profiles[..index].iter().any(|other| other.name.eq_ignore_ascii_case(&profile.name)) {
+                return Err(format!("Config file {} has two profiles named '{}'.", path.display(), profile.name));
+            }
+            if let Some(day) = profile.days.iter().find(|day| day.parse::<Weekday>().is_err()) {
+                return Err(format!("Profile '{}' in {} has an unknown day '{}'.", profile.name, path.display(), day));
+            }
+            if profile.hours.as_deref().is_some_and(|hours| TimeWindow::parse(hours).is_none()) {
+                return Err(format!("Profile '{}' in {}: hours must look like 07:00-09:30.", profile.name, path.display()));
+            }
+        }
+
+        for entry in &file.timetable {
+            if let Some(time) = entry.times.iter().find(|time| NaiveTime::parse_from_str(time.trim(), "%H:%M").is_err()) {
+                return Err(format!("Timetable for {} at {} in {} has an invalid time '{}'.", entry.service, entry.stop, path.display(), time));
+            }
+            if let Some(day) = entry.days.iter().find(|day| day.parse::<Weekday>().is_err()) {
+                return Err(format!("Timetable for {} at {} in {} has an unknown day '{}'.", entry.service, entry.stop, path.display(), day));
+            }
+        }
+
+        PollSchedule::parse(&file.schedule).map_err(|e| format!("Config file {}: {}", path.display(), e))?;
+
+        // Routing for a group nobody uses is almost certainly a typo
+        let all_stops = || file.stops.iter().chain(file.profiles.iter().flat_map(|profile| &profile.stops));
+        let all_groups = file.groups.keys().chain(file.profiles.iter().flat_map(|profile| profile.groups.keys()));
+        for name in all_groups {
+            if !all_stops().any(|stop| stop.group.as_deref() == Some(name.as_str())) {
+                return Err(format!("Config file {} configures group '{}' but no stop uses it.", path.display(), name));
+            }
+        }
+
+        Ok(file)
+    }
+
+    /// The settings each tracker runs with: just this file when there are no profiles, otherwise the
+    /// profile named by --profile or every profile whose days/hours match `now` (local time).
+    /// A profile's values take the place of the top-level ones; credentials and the rest are shared.
+    pub fn profiles(&self, requested: Option<&str>, now: NaiveDateTime) -> Result<Vec<(Option<String>, ConfigFile)>, String> {
+        let names = || self.profiles.iter().map(|profile| profile.name.as_str()).collect::<Vec<_>>().join(", ");
+        let selected: Vec<&ProfileEntry> = match requested {
+            None if self.profiles.is_empty() => return Ok(vec![(None, self.clone())]),
+            Some(name) if self.profiles.is_empty() => return Err(format!("Profile '{}' requested, but the config file has no profiles.", name)),
+            Some(name) => match self.profiles.iter().find(|profile| profile.name.eq_ignore_ascii_case(name)) {
+                Some(profile) => vec![profile],
+                None => return Err(format!("Unknown profile '{}'. Profiles: {}.", name, names())),
+            },
+            None => self.profiles.iter().filter(|profile| profile.scheduled_at(now)).collect(),
+        };
+        if selected.is_empty() {
+            return Err(format!(
+                "No profile is scheduled for {} {}; pick one of {} with --profile.",
+                now.weekday(),
+                now.format("%H:%M"),
+                names()
+            ));
+        }
+
+        Ok(selected
+            .into_iter()
+            .map(|profile| {
+                let mut file = ConfigFile { profiles: Vec::new(), ..self.clone() };
+                file.lat = profile.lat.or(self.lat);
+                file.lng = profile.lng.or(self.lng);
+                file.radius = profile.radius.or(self.radius);
+                file.poll_interval_secs = profile.poll_interval_secs.or(self.poll_interval_secs);
+                file.telegram_chat_id = profile.telegram_chat_id.clone().or_else(|| self.telegram_chat_id.clone());
+                file.alert_template = profile.alert_template.clone().or_else(|| self.alert_template.clone());
+                if !profile.stops.is_empty() {
+                    file.stops = profile.stops.clone();
+                }
+                for stop in file.stops.iter_mut().filter(|stop| stop.services.is_empty()) {
+                    stop.services = profile.services.clone();
+                }
+                file.groups.extend(profile.groups.clone());
+                (Some(profile.name.clone()), file)
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveDate, NaiveTime};
+    use std::{env, process};
+
+    const ROUTINES: &str = r#"
+        lat = 53.0
+        lng = -1.5
+        radius = 800
+        telegram_bot_token = "123:abc"
+        telegram_chat_id = "100"
+
+        [[stops]]
+        name = "Home"
+        lat = 53.0
+        lng = -1.5
+
+        [[profiles]]
+        name = "weekday"
+        days = ["mon", "tue", "wed", "thu", "fri"]
+        hours = "07:00-09:30"
+        services = ["7"]
+        poll_interval_secs = 20
+        [[profiles.stops]]
+        name = "Work"
+        lat = 53.1
+        lng = -1.4
+        [[profiles.stops]]
+        name = "Depot"
+        lat = 53.2
+        lng = -1.3
+        services = ["X1"]
+
+        [[profiles]]
+        name = "Weekend"
+        days = ["sat", "sun"]
+        lat = 53.41
+        telegram_chat_id = "-100555"
+        services = ["3", "9"]
+    "#;
+
+    fn routines() -> ConfigFile {
+        toml::from_str(ROUTINES).unwrap()
+    }
+
+    /// 2024-03-04 is a Monday
+    fn at(day: u32, time: &str) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 3, day).unwrap().and_time(NaiveTime::parse_from_str(time, "%H:%M").unwrap())
+    }
+
+    fn names(profiles: &[(Option<String>, ConfigFile)]) -> Vec<Option<&str>> {
+        profiles.iter().map(|(name, _)| name.as_deref()).collect()
+    }
+
+    #[test]
+    fn profiles_are_picked_by_day_and_hours_or_by_name() {
+        let file = routines();
+        assert_eq!(names(&file.profiles(None, at(4, "08:00")).unwrap()), [Some("weekday")]);
+        assert_eq!(names(&file.profiles(None, at(9, "08:00")).unwrap()), [Some("Weekend")]);
+        assert_eq!(names(&file.profiles(None, at(10, "23:00")).unwrap()), [Some("Weekend")]);
+
+        // Outside every profile's rules, --profile is the way in
+        let err = file.profiles(None, at(4, "12:00")).unwrap_err();
+        assert_eq!(err, "No profile is scheduled for Mon 12:00; pick one of weekday, Weekend with --profile.");
+        assert_eq!(names(&file.profiles(Some("weekend"), at(4, "12:00")).unwrap()), [Some("Weekend")]);
+        assert_eq!(file.profiles(Some("holiday"), at(4, "08:00")).unwrap_err(), "Unknown profile 'holiday'. Profiles: weekday, Weekend.");
+    }
+
+    #[test]
+    fn without_profiles_the_file_runs_as_it_is() {
+        let file = ConfigFile { profiles: Vec::new(), ..routines() };
+        let profiles = file.profiles(None, at(4, "12:00")).unwrap();
+        assert_eq!(names(&profiles), [None]);
+        assert_eq!(profiles[0].1.stops[0].name, "Home");
+        assert_eq!(file.profiles(Some("weekday"), at(4, "08:00")).unwrap_err(), "Profile 'weekday' requested, but the config file has no profiles.");
+    }
+
+    #[test]
+    fn profile_values_replace_the_top_level_ones_and_the_rest_is_inherited() {
+        let file = routines();
+        let (_, weekday) = file.profiles(Some("weekday"), at(4, "08:00")).unwrap().remove(0);
+        assert_eq!((weekday.lat, weekday.lng, weekday.radius, weekday.poll_interval_secs), (Some(53.0), Some(-1.5), Some(800), Some(20)));
+        assert_eq!(weekday.telegram_bot_token.as_deref(), Some("123:abc"));
+        assert_eq!(weekday.telegram_chat_id.as_deref(), Some("100"));
+        assert!(weekday.profiles.is_empty());
+        // Its own stops replace the top-level ones; its services apply where a stop has none
+        let stops: Vec<(&str, &[String])> = weekday.stops.iter().map(|stop| (stop.name.as_str(), stop.services.as_slice())).collect();
+        assert_eq!(stops, [("Work", &["7".to_string()][..]), ("Depot", &["X1".to_string()][..])]);
+
+        let (_, weekend) = file.profiles(Some("Weekend"), at(9, "10:00")).unwrap().remove(0);
+        assert_eq!((weekend.lat, weekend.lng), (Some(53.41), Some(-1.5)));
+        assert_eq!(weekend.telegram_chat_id.as_deref(), Some("-100555"));
+        assert_eq!(weekend.telegram_bot_token.as_deref(), Some("123:abc"));
+        assert_eq!(weekend.stops.len(), 1);
+        assert_eq!((weekend.stops[0].name.as_str(), weekend.stops[0].services.as_slice()), ("Home", &["3".to_string(), "9".to_string()][..]));
+    }
+
+    #[test]
+    fn loading_rejects_ambiguous_or_malformed_profiles() {
+        let load = |text: &str| {
+            let path = env::temp_dir().join(format!("config-file-test-{}.toml", process::id()));
+            fs::write(&path, text).unwrap();
+            let loaded = ConfigFile::load(&path).map(|_| ()).map_err(|e| e.replace(&path.display().to_string(), "FILE"));
+            let _ = fs::remove_file(&path);
+            loaded
+        };
+        assert_eq!(load(ROUTINES), Ok(()));
+        assert_eq!(
+            load("[[profiles]]\nname = \"Work\"\n[[profiles]]\nname = \"work\""),
+            Err("Config file FILE has two profiles named 'work'.".to_string())
+        );
+        assert_eq!(load("[[profiles]]\nname = \"a\"\ndays = [\"funday\"]"), Err("Profile 'a' in FILE has an unknown day 'funday'.".to_string()));
+        assert_eq!(load("[[profiles]]\nname = \"a\"\nhours = \"morning\""), Err("Profile 'a' in FILE: hours must look like 07:00-09:30.".to_string()));
+    }
+}
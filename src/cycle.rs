@@ -0,0 +1,718 @@
+// What one poll's vehicle response means: arrivals, early warnings, departures, bunching and
+// absences, worked out from the stops and the session's state without sending anything.
+// check_buses sends what comes out, so the decisions can be checked against recorded
+// responses with a fixed clock.
+
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use tracing::{debug, error, info};
+
+use crate::notify::{Alert, AlertKind};
+use crate::routes::Along;
+use crate::session::{AlertEvent, InRange, Position, Session};
+use crate::telegram::AlertedBus;
+use crate::{
+    alerts, bucket_distance, bus_line, closest_stop, dedup_vehicles, describe_service, eta_secs, find_nearest_stop, format, is_approaching, nearest_line_first,
+    operator_link, render_alert_template, route_serves_stop, route_to_stop, vehicle_direction, vehicles_to_check, BusStop, Config, Vehicle,
+};
+
+/// Something a cycle wants sent
+#[derive(Debug, Clone, PartialEq)]
+pub enum CycleEvent {
+    /// A service gone missing for ABSENCE_ALERT_SECS
+    Absent(String),
+    /// An arrival or early warning. For arrivals `road_at` is where to look up the road
+    /// (REVERSE_GEOCODE); it and then `link` are added to the text on the way out.
+    Alert { alert: Box<Alert>, road_at: Option<(f64, f64)>, link: Option<String> },
+    /// A bus the user was told about has left its stop
+    Departed(String),
+    /// Two or more buses newly in one stop's radius (DETECT_BUNCHING)
+    Bunched(String),
+}
+
+/// The outcome of one cycle
+#[derive(Debug, Default)]
+pub struct Cycle {
+    pub events: Vec<CycleEvent>,
+    pub live_lines: Vec<(f64, String)>, // (distance, line) for the live message, nearest first
+}
+
+/// Works through one response, updating `session` and returning what to send.
+/// None when the response has no `services` array.
+pub fn decide(response: &Value, config: &Config, bus_stops: &[BusStop], session: &mut Session, now: DateTime<Utc>) -> Option<Cycle> {
+    session.alert_tracker.next_cycle();
+    let Some(services) = response["services"].as_array() else {
+        info!("No services found in the response.");
+        return None;
+    };
+
+    // Right after startup, buses already at stops are only noted, so a restart doesn't alert for all of them
+    let warming_up = (now - session.started_at).num_seconds() < config.warmup_secs;
+    session.in_range.clear();
+    session.positions.clear();
+    let mut cycle = Cycle::default();
+    let mut at_stops: BTreeMap<String, Vec<String>> = BTreeMap::new(); // Stop -> buses of interest in its radius, for DETECT_BUNCHING
+
+    let mut vehicles: Vec<Vehicle> = services.iter().filter_map(|service| Vehicle::from_json(service, &config.fields)).collect();
+    dedup_vehicles(&mut vehicles);
+    for (service, missing_secs) in session.absence.observe(vehicles.iter().map(|vehicle| vehicle.service_number.as_str()), now) {
+        let missing_secs = if missing_secs >= 60 { missing_secs / 60 * 60 } else { missing_secs };
+        let message = format!("Bus {} hasn't been seen for {}", config.service_labels.label(&service), format::format_duration(missing_secs));
+        info!("{}", message);
+        cycle.events.push(CycleEvent::Absent(message));
+    }
+
+    let before = vehicles.len();
+    vehicles.retain(|vehicle| !vehicle.is_ignored(&config.ignore_vehicles));
+    session.ignored_observations += (before - vehicles.len()) as u64;
+    if !config.service_labels.is_empty() {
+        let unlabelled = vehicles.iter().filter(|vehicle| !config.service_labels.has_label(&vehicle.service_number));
+        session.unlabelled_services.extend(unlabelled.map(|vehicle| vehicle.service_number.clone()));
+    }
+    if let Some(history) = session.history.as_ref().filter(|_| config.history_positions) {
+        let positions: Vec<_> = vehicles
+            .iter()
+            .filter(|vehicle| vehicle.in_service())
+            .map(|vehicle| (vehicle.service_number.as_str(), vehicle.identifier(), vehicle.lat, vehicle.lng, vehicle.updated_at.unwrap_or(now)))
+            .collect();
+        if let Err(e) = history.record_positions(&positions) {
+            error!("Error recording positions in history: {}", e);
+        }
+    }
+
+    for mut vehicle in vehicles_to_check(vehicles, config, bus_stops, now) {
+        let fix_age = vehicle.fix_age_secs(now);
+        (vehicle.lat, vehicle.lng) = session.tracks.check(&vehicle.key(), vehicle.lat, vehicle.lng, vehicle.updated_at.unwrap_or(now), now);
+        if let Some(parked_after) = config.parked_after {
+            let still_for = session.tracks.stationary_for(&vehicle.key(), vehicle.speed, config.parked_speed_kmh, now);
+            if still_for >= parked_after {
+                debug!("Skipping bus {} ({}): parked for {} min", vehicle.service_number, vehicle.identifier(), still_for.num_minutes());
+                continue;
+            }
+        }
+
+        session.positions.push(Position {
+            service_number: vehicle.service_number.clone(),
+            vehicle: vehicle.identifier(),
+            lat: vehicle.lat,
+            lng: vehicle.lng,
+        });
+        cycle.live_lines.extend(bus_line(&vehicle, config, bus_stops));
+        if config.report_closest_approach {
+            if let Some((stop, distance)) = closest_stop(vehicle.lat, vehicle.lng, bus_stops) {
+                session.record_approach(&vehicle.service_number, &stop.name, distance);
+            }
+        }
+
+        if let Some((nearby_stop, distance)) = find_nearest_stop(vehicle.lat, vehicle.lng, bus_stops, &session.stop_index) {
+            session.in_range.push(InRange {
+                service_number: vehicle.service_number.clone(),
+                vehicle: vehicle.identifier(),
+                stop: nearby_stop.name.clone(),
+                group: nearby_stop.group.clone(),
+                distance,
+            });
+
+            let (visit, arrived) =
+                session.presence.observe(&vehicle.key(), &vehicle.service_number, &vehicle.identifier(), &nearby_stop.name, now);
+            if arrived {
+                visit.during_warmup = warming_up;
+                if let Some(history) = &session.history {
+                    match history.record_arrival(&vehicle.service_number, &vehicle.identifier(), &nearby_stop.name, now) {
+                        Ok(id) => visit.history_id = Some(id),
+                        Err(e) => error!("Error recording arrival in history: {}", e),
+                    }
+                }
+            }
+
+            let direction = vehicle_direction(&vehicle, config);
+            if !nearby_stop.serves(&vehicle.service_number, &direction) {
+                debug!("Not alerting for bus {} ({}) at {}: not one of the stop's services", vehicle.service_number, direction, nearby_stop.name);
+                continue;
+            }
+            if config.detect_bunching {
+                at_stops.entry(nearby_stop.name.clone()).or_default().push(config.service_labels.label(&vehicle.service_number).to_string());
+            }
+
+            if config.require_route_match && !route_serves_stop(vehicle.upcoming_stops.as_deref(), &nearby_stop.name) {
+                debug!("Not alerting for bus {} ({}): its route doesn't serve {}", vehicle.service_number, vehicle.identifier(), nearby_stop.name);
+                continue;
+            }
+
+            if config.require_approaching_heading && !is_approaching(&vehicle, nearby_stop, config) {
+                debug!("Not alerting for bus {} ({}): {} is behind it", vehicle.service_number, vehicle.identifier(), nearby_stop.name);
+                continue;
+            }
+
+            if session.resumed && fix_age.is_some_and(|age| age > config.fix_age_warn_secs) {
+                debug!("Not alerting for bus {} ({}) near {}: stale position right after a resume", vehicle.service_number, vehicle.identifier(), nearby_stop.name);
+                continue;
+            }
+
+            if visit.during_warmup {
+                debug!("Not alerting for bus {} ({}) near {}: already there during warm-up", vehicle.service_number, vehicle.identifier(), nearby_stop.name);
+                continue;
+            }
+
+            // Wait for the bus to stay in range a few polls, so a single jittery fix doesn't alert
+            if visit.cycles < config.confirmation_cycles {
+                debug!("Bus {} ({}) near {}: {} of {} confirming polls", vehicle.service_number, vehicle.identifier(), nearby_stop.name, visit.cycles, config.confirmation_cycles);
+                continue;
+            }
+
+            let key = alerts::alert_key(&vehicle.key(), &nearby_stop.name, nearby_stop.group.as_deref());
+            let priority = config.priority_services.iter().any(|service| service.eq_ignore_ascii_case(&vehicle.service_number));
+            if priority {
+                // Sent through any cooldown, but it still starts one like any other alert
+                session.alert_tracker.record(&key, now);
+            } else if !session.alert_tracker.should_alert(&key, now) {
+                continue;
+            }
+
+            let mut message = match (&config.alert_template, &nearby_stop.group) {
+                (Some(template), _) => render_alert_template(template, &vehicle, &direction, nearby_stop, &config.service_labels),
+                (None, Some(group)) => format!(
+                    "Bus ({}) {} [{}] is in **{}** (near {})!",
+                    config.service_labels.label(&vehicle.service_number), describe_service(&vehicle, &direction), vehicle.identifier(), group, nearby_stop.name
+                ),
+                (None, None) => format!(
+                    "Bus ({}) {} [{}] is near **{}**!",
+                    config.service_labels.label(&vehicle.service_number), describe_service(&vehicle, &direction), vehicle.identifier(), nearby_stop.name
+                ),
+            };
+            // Dedup on the alert itself, not the suffixes below that change from poll to poll
+            let dedup_key = message.clone();
+            if config.confirmation_cycles > 1 {
+                let in_range = (now - visit.arrived_at).num_seconds();
+                message.push_str(&format!(" (in range for {})", format::format_duration(in_range)));
+            }
+            if let Some(age) = fix_age.filter(|age| *age > config.fix_age_warn_secs) {
+                message.push_str(&format!(" (position {} s old)", age));
+            }
+
+            info!("Bus {} ({}) found near: {}", vehicle.service_number, vehicle.identifier(), nearby_stop.name);
+            visit.alerted = true;
+            session.record_alert(AlertEvent {
+                at: now,
+                service_number: vehicle.service_number.clone(),
+                vehicle: vehicle.identifier(),
+                stop: nearby_stop.name.clone(),
+                group: nearby_stop.group.clone(),
+                lat: vehicle.lat,
+                lng: vehicle.lng,
+                direction: direction.clone(),
+            });
+
+            cycle.events.push(CycleEvent::Alert {
+                alert: Box::new(Alert {
+                    text: message,
+                    dedup_key,
+                    bus: alerted_bus(&vehicle, nearby_stop),
+                    direction,
+                    group: nearby_stop.group.clone(),
+                    kind: AlertKind::Arrival,
+                    quiet: nearby_stop.quiet.as_ref().map(|quiet| quiet.contains(config.style.zone.local_time(now))),
+                    priority,
+                }),
+                road_at: Some((vehicle.lat, vehicle.lng)),
+                link: config.include_operator_link.then(|| operator_link(&vehicle.service_number, vehicle.lat, vehicle.lng)),
+            });
+        } else if let Some((stop, distance)) = closest_stop(vehicle.lat, vehicle.lng, bus_stops)
+            .filter(|(_, distance)| config.early_warning_radius.is_some_and(|radius| *distance <= radius))
+        {
+            // Not at a stop yet but within EARLY_WARNING_RADIUS: a one-off heads-up
+            let direction = vehicle_direction(&vehicle, config);
+            if !stop.serves(&vehicle.service_number, &direction)
+                || (config.require_route_match && !route_serves_stop(vehicle.upcoming_stops.as_deref(), &stop.name))
+                || (config.require_approaching_heading && !is_approaching(&vehicle, stop, config))
+            {
+                continue;
+            }
+            // Along the route the bus may have further to go than EARLY_WARNING_RADIUS, or be past the stop
+            let distance = match route_to_stop(&vehicle, stop, config) {
+                Along::Ahead(along) if config.early_warning_radius.is_some_and(|radius| along <= radius) => along,
+                Along::Ahead(_) | Along::Behind => continue,
+                Along::OffRoute => distance,
+            };
+            let key = alerts::early_alert_key(&vehicle.key(), &stop.name, stop.group.as_deref());
+            // Checked first so a bus seen while warming up isn't put on cooldown before it could alert
+            if warming_up || !session.alert_tracker.should_alert(&key, now) {
+                continue;
+            }
+
+            let eta = match eta_secs(distance, vehicle.speed) {
+                Some(secs) => format!(", ~{}", config.style.eta(secs)),
+                None => String::new(),
+            };
+            let describe = |distance: f64, eta: &str| {
+                format!(
+                    "Bus ({}) {} [{}] is approaching **{}** ({} away{})",
+                    config.service_labels.label(&vehicle.service_number),
+                    describe_service(&vehicle, &direction),
+                    vehicle.identifier(),
+                    stop.name,
+                    config.style.distance(distance),
+                    eta
+                )
+            };
+            let message = describe(distance, &eta);
+            // GPS wiggle shouldn't make the same heads-up look new to the send dedup
+            let dedup_key = describe(bucket_distance(distance, config.dedup_distance_bucket_m), "");
+            info!("Bus {} ({}) approaching: {}", vehicle.service_number, vehicle.identifier(), stop.name);
+
+            cycle.events.push(CycleEvent::Alert {
+                alert: Box::new(Alert {
+                    text: message,
+                    dedup_key,
+                    bus: alerted_bus(&vehicle, stop),
+                    direction,
+                    group: stop.group.clone(),
+                    kind: AlertKind::EarlyWarning,
+                    quiet: stop.quiet.as_ref().map(|quiet| quiet.contains(config.style.zone.local_time(now))),
+                    priority: false,
+                }),
+                road_at: None,
+                link: None,
+            });
+        }
+    }
+
+    for departure in session.presence.take_departures(now) {
+        let visit = &departure.visit;
+        let message = format!(
+            "Bus {} [{}] left {} after {}",
+            visit.service_number,
+            visit.vehicle,
+            visit.stop,
+            format::format_duration(departure.dwell_secs)
+        );
+        info!("{}", message);
+
+        if let (Some(history), Some(id)) = (&session.history, visit.history_id) {
+            if let Err(e) = history.record_departure(id, departure.departed_at, departure.dwell_secs) {
+                error!("Error recording departure in history: {}", e);
+            }
+        }
+
+        // Only follow up on buses the user was actually told about
+        if visit.alerted {
+            cycle.events.push(CycleEvent::Departed(message));
+        }
+    }
+
+    if config.detect_bunching {
+        for (stop, buses) in session.newly_bunched(at_stops) {
+            let message = format!("{} buses bunched at **{}** ({})", buses.len(), stop, buses.join(", "));
+            info!("{}", message);
+            if !warming_up {
+                cycle.events.push(CycleEvent::Bunched(message));
+            }
+        }
+    }
+
+    session.in_range.sort_by(InRange::nearest_first);
+    cycle.live_lines.sort_by(nearest_line_first);
+    Some(cycle)
+}
+
+/// What an alert's buttons need to find `vehicle` again at `stop`
+fn alerted_bus(vehicle: &Vehicle, stop: &BusStop) -> AlertedBus {
+    AlertedBus { key: vehicle.key(), service: vehicle.service_number.clone(), vehicle: vehicle.identifier(), stop: stop.name.clone() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::absence::AbsenceWatch;
+    use crate::config_file::ConfigFile;
+    use crate::test_support::{self, fixed_now, stop};
+    use chrono::Duration;
+
+    const NORMAL: &str = include_str!("../tests/fixtures/normal.json");
+    const EMPTY: &str = include_str!("../tests/fixtures/empty.json");
+    const NUMERIC_COORDINATES: &str = include_str!("../tests/fixtures/numeric_coordinates.json");
+    const MISSING_FIELDS: &str = include_str!("../tests/fixtures/missing_fields.json");
+    const DUPLICATED_VEHICLES: &str = include_str!("../tests/fixtures/duplicated_vehicles.json");
+    const PARTIAL_IDENTIFIERS: &str = include_str!("../tests/fixtures/partial_identifiers.json");
+
+    fn stops() -> Vec<BusStop> {
+        vec![stop("Market Square", 53.0, -1.5), stop("Station Road", 53.01, -1.5)]
+    }
+
+    /// Runs each response in turn a minute apart, starting at `fixed_now`, in a session an hour old.
+    /// Returns each cycle's events.
+    fn run(config: &Config, responses: &[&str]) -> Vec<Vec<CycleEvent>> {
+        run_from(config, fixed_now() - Duration::hours(1), responses)
+    }
+
+    fn run_from(config: &Config, started_at: DateTime<Utc>, responses: &[&str]) -> Vec<Vec<CycleEvent>> {
+        let stops = stops();
+        let mut session = test_support::session(config, started_at);
+        responses
+            .iter()
+            .enumerate()
+            .map(|(minute, response)| {
+                let response: Value = serde_json::from_str(response).unwrap();
+                let now = fixed_now() + Duration::minutes(minute as i64);
+                decide(&response, config, &stops, &mut session, now).expect("fixture has a services array").events
+            })
+            .collect()
+    }
+
+    /// The alerts among a cycle's events
+    fn alerts(events: &[CycleEvent]) -> Vec<&Alert> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                CycleEvent::Alert { alert, .. } => Some(&**alert),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// A response for the cycle `minute` minutes after `fixed_now`, with a bus 7 at each position
+    /// (fleet 1, 2, ...) fixed 30 s earlier
+    fn bus_at(minute: i64, positions: &[(f64, f64)]) -> String {
+        let updated = fixed_now() + Duration::minutes(minute) - Duration::seconds(30);
+        let services: Vec<Value> = positions
+            .iter()
+            .enumerate()
+            .map(|(i, (lat, lng))| {
+                serde_json::json!({
+                    "serviceNumber": "7", "serviceDescription": "Bus Station - Hospital", "fleetNumber": (i + 1).to_string(), "latitude": lat.to_string(), "longitude": lng.to_string(),
+                    "speed": "20", "updateTime": updated.timestamp_millis().to_string()
+                })
+            })
+            .collect();
+        serde_json::json!({ "services": services }).to_string()
+    }
+
+    #[test]
+    fn normal_response_alerts_buses_in_a_stop_radius() {
+        let events = run(&test_support::config(&[]), &[NORMAL]).remove(0);
+        insta::assert_debug_snapshot!(events);
+    }
+
+    #[test]
+    fn numeric_coordinates_read_like_strings() {
+        let config = test_support::config(&[]);
+        let events = run(&config, &[NUMERIC_COORDINATES]).remove(0);
+        assert_eq!(events, run(&config, &[NORMAL]).remove(0));
+        insta::assert_debug_snapshot!(events);
+    }
+
+    #[test]
+    fn empty_response_sends_nothing() {
+        let events = run(&test_support::config(&[]), &[EMPTY]).remove(0);
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn missing_descriptive_fields_fall_back_to_placeholders() {
+        let events = run(&test_support::config(&[]), &[MISSING_FIELDS]).remove(0);
+        insta::assert_debug_snapshot!(events);
+    }
+
+    #[test]
+    fn duplicated_vehicles_alert_once_from_the_freshest_fix() {
+        let events = run(&test_support::config(&[]), &[DUPLICATED_VEHICLES]).remove(0);
+        insta::assert_debug_snapshot!(events);
+    }
+
+    #[test]
+    fn entries_sharing_either_identifier_are_one_vehicle() {
+        // Bus 7 is listed by fleet number, by registration, and by both; bus 9's two entries share neither
+        let events = run(&test_support::config(&[]), &[PARTIAL_IDENTIFIERS]).remove(0);
+        let vehicles: Vec<(&str, &str)> = alerts(&events).iter().map(|alert| (alert.bus.vehicle.as_str(), alert.bus.stop.as_str())).collect();
+        assert_eq!(vehicles, [("fleet 10812 / SN19 ABC", "Market Square"), ("fleet 10900", "Market Square"), ("YX20 DEF", "Station Road")]);
+        insta::assert_debug_snapshot!(events);
+    }
+
+    #[test]
+    fn early_warning_for_a_bus_inside_the_wider_radius() {
+        let events = run(&test_support::config(&[("EARLY_WARNING_RADIUS", "500")]), &[NORMAL]).remove(0);
+        insta::assert_debug_snapshot!(events);
+    }
+
+    #[test]
+    fn early_warnings_a_little_apart_share_a_dedup_key() {
+        // A bus 290, 310 or 320 m short of Market Square, each seen by a fresh session
+        let key = |config: &Config, meters: f64| {
+            let response = bus_at(0, &[(53.0 - meters / 111_195.0, -1.5)]);
+            let events = run(config, &[&response]).remove(0);
+            let alert = alerts(&events).into_iter().find(|alert| alert.kind == AlertKind::EarlyWarning).unwrap().clone();
+            (alert.text, alert.dedup_key)
+        };
+
+        let config = test_support::config(&[("EARLY_WARNING_RADIUS", "500")]);
+        let (text_290, key_290) = key(&config, 290.0);
+        let (text_310, key_310) = key(&config, 310.0);
+        let (_, key_320) = key(&config, 320.0);
+        assert_ne!(text_290, text_310);
+        assert_eq!(key_290, key_310); // Both round to 300 m
+        assert!(key_290.contains("(300 m away)"), "{}", key_290);
+        assert_ne!(key_310, key_320); // 325 m
+
+        // Other bucket sizes, or none
+        let config = test_support::config(&[("EARLY_WARNING_RADIUS", "500"), ("DEDUP_DISTANCE_BUCKET_M", "50")]);
+        assert_eq!(key(&config, 310.0).1, key(&config, 320.0).1);
+        let config = test_support::config(&[("EARLY_WARNING_RADIUS", "500"), ("DEDUP_DISTANCE_BUCKET_M", "0")]);
+        assert_ne!(key(&config, 290.0).1, key(&config, 310.0).1);
+
+        assert_eq!(bucket_distance(312.4, 25.0), 300.0);
+        assert_eq!(bucket_distance(312.5, 25.0), 325.0);
+        assert_eq!(bucket_distance(312.5, 0.0), 312.5);
+    }
+
+    #[test]
+    fn vehicles_without_a_real_service_are_skipped_unless_told_otherwise() {
+        let updated = (fixed_now() - Duration::seconds(30)).timestamp_millis().to_string();
+        let bus = |fleet: usize, service: Option<&str>| {
+            let mut bus = serde_json::json!({ "fleetNumber": fleet.to_string(), "latitude": "53.0001", "longitude": "-1.5", "speed": "20", "updateTime": updated });
+            if let Some(service) = service {
+                bus["serviceNumber"] = service.into();
+            }
+            bus
+        };
+        let response = serde_json::json!({ "services": [bus(1, Some("7")), bus(2, Some("")), bus(3, Some("Unknown")), bus(4, Some(" unknown ")), bus(5, None)] });
+        let alerted = |vars: &[(&str, &str)]| {
+            let events = run(&test_support::config(vars), &[&response.to_string()]).remove(0);
+            let mut vehicles: Vec<String> = alerts(&events).iter().map(|alert| alert.bus.vehicle.clone()).collect();
+            vehicles.sort();
+            vehicles
+        };
+
+        assert_eq!(alerted(&[]), ["fleet 1"]);
+        assert_eq!(alerted(&[("SKIP_UNKNOWN_SERVICES", "false")]), ["fleet 1", "fleet 2", "fleet 3", "fleet 4", "fleet 5"]);
+    }
+
+    #[test]
+    fn a_missing_watched_service_is_reported_once() {
+        let config = test_support::config(&[]);
+        let mut session = test_support::session(&config, fixed_now() - Duration::hours(1));
+        session.absence = test_support::with_env(&[("WATCH_SERVICES", "36"), ("ABSENCE_ALERT_SECS", "120")], AbsenceWatch::from_env).unwrap();
+        let response: Value = serde_json::from_str(NORMAL).unwrap();
+        let absent: Vec<Vec<CycleEvent>> = (0..5)
+            .map(|minute| {
+                let events = decide(&response, &config, &stops(), &mut session, fixed_now() + Duration::minutes(minute)).unwrap().events;
+                events.into_iter().filter(|event| matches!(event, CycleEvent::Absent(_))).collect()
+            })
+            .collect();
+        assert_eq!(absent, [vec![], vec![], vec![CycleEvent::Absent("Bus 36 hasn't been seen for 2 min".to_string())], vec![], vec![]]);
+    }
+
+    #[test]
+    fn two_buses_at_one_stop_are_bunched_and_one_is_not() {
+        let bunched = |config: &Config, positions: &[&[(f64, f64)]]| -> Vec<Vec<CycleEvent>> {
+            let responses: Vec<String> = positions.iter().enumerate().map(|(minute, positions)| bus_at(minute as i64, positions)).collect();
+            let responses: Vec<&str> = responses.iter().map(String::as_str).collect();
+            run(config, &responses)
+                .into_iter()
+                .map(|events| events.into_iter().filter(|event| matches!(event, CycleEvent::Bunched(_))).collect())
+                .collect()
+        };
+        let (one, two) = (&[(53.0005, -1.5)][..], &[(53.0005, -1.5), (52.9995, -1.5)][..]);
+        // One at each stop isn't a bunch either
+        let apart = &[(53.0005, -1.5), (53.0095, -1.5)][..];
+        let positions = [one, two, two, apart, two];
+
+        let config = test_support::config(&[("DETECT_BUNCHING", "true")]);
+        let message = CycleEvent::Bunched("2 buses bunched at **Market Square** (7, 7)".to_string());
+        // Reported when the bunch forms, not again while it lasts, and again if it forms anew
+        assert_eq!(bunched(&config, &positions), [vec![], vec![message.clone()], vec![], vec![], vec![message]]);
+
+        let config = test_support::config(&[]);
+        assert_eq!(bunched(&config, &positions), vec![Vec::<CycleEvent>::new(); 5]);
+    }
+
+    #[test]
+    fn early_warning_seen_during_warm_up_still_alerts_after_it() {
+        let config = test_support::config(&[("EARLY_WARNING_RADIUS", "500"), ("WARMUP_SECS", "120")]);
+        let cycles = run_from(&config, fixed_now(), &[NORMAL, NORMAL, NORMAL]);
+        assert!(cycles[0].is_empty() && cycles[1].is_empty(), "alerted while warming up: {:?}", cycles);
+        let early: Vec<&Alert> = alerts(&cycles[2]).into_iter().filter(|alert| alert.kind == AlertKind::EarlyWarning).collect();
+        assert_eq!(early.len(), 1, "{:?}", cycles[2]);
+        assert!(early[0].text.contains("[fleet 10813 / SN19 ABD] is approaching **Station Road**"));
+    }
+
+    #[test]
+    fn later_cycles_keep_the_cooldown_and_report_departures() {
+        let cycles = run(&test_support::config(&[]), &[NORMAL, NORMAL, EMPTY]);
+        assert_eq!(cycles[0].len(), 2);
+        assert!(cycles[1].is_empty(), "still cooling down: {:?}", cycles[1]);
+        insta::assert_debug_snapshot!(cycles[2]);
+    }
+
+    #[test]
+    fn priority_services_alert_through_the_cooldown() {
+        let cycles = run(&test_support::config(&[("PRIORITY_SERVICES", "7")]), &[NORMAL, NORMAL, NORMAL]);
+        let alerted: Vec<Vec<&str>> = cycles
+            .iter()
+            .map(|events| {
+                events
+                    .iter()
+                    .filter_map(|event| match event {
+                        CycleEvent::Alert { alert, .. } => Some(alert.bus.service.as_str()),
+                        _ => None,
+                    })
+                    .collect()
+            })
+            .collect();
+        assert_eq!(alerted, [vec!["7", "9"], vec!["7"], vec!["7"]]);
+    }
+
+    #[test]
+    fn priority_alerts_still_start_a_cooldown() {
+        let config = test_support::config(&[("PRIORITY_SERVICES", "7")]);
+        let mut session = test_support::session(&config, fixed_now() - Duration::hours(1));
+        let response: Value = serde_json::from_str(NORMAL).unwrap();
+        for minute in 0..2 {
+            decide(&response, &config, &stops(), &mut session, fixed_now() + Duration::minutes(minute)).unwrap();
+        }
+        // The default 5 minute cooldown counts from the second alert, not the first
+        let key = alerts::alert_key("fleet:10812", "Market Square", None);
+        assert!(!session.alert_tracker.should_alert(&key, fixed_now() + Duration::minutes(5)));
+        assert!(session.alert_tracker.should_alert(&key, fixed_now() + Duration::minutes(6)));
+    }
+
+    #[test]
+    fn service_limit_keeps_the_nearest_usable_vehicles() {
+        // Bus 3 is nearest but its fix is ten minutes old; bus 9 is farthest
+        let response = r#"{ "services": [
+            { "serviceNumber": "3", "fleetNumber": "1", "latitude": "53.0000", "longitude": "-1.5000", "updateTime": "1709538600000" },
+            { "serviceNumber": "7", "fleetNumber": "2", "latitude": "53.0003", "longitude": "-1.5002", "updateTime": "1709539170000" },
+            { "serviceNumber": "8", "fleetNumber": "3", "latitude": "53.0101", "longitude": "-1.5001", "updateTime": "1709539170000" },
+            { "serviceNumber": "9", "fleetNumber": "4", "latitude": "53.0108", "longitude": "-1.5003", "updateTime": "1709539170000" }
+        ] }"#;
+        let events = run(&test_support::config(&[("MAX_SERVICES_PER_CYCLE", "2")]), &[response]).remove(0);
+        let alerted: Vec<&str> = alerts(&events).iter().map(|alert| alert.bus.service.as_str()).collect();
+        assert_eq!(alerted, ["8", "7"]); // Nearest first
+    }
+
+    #[test]
+    fn profiles_alert_independently_from_one_response() {
+        let file: ConfigFile = toml::from_str(
+            r#"
+            [[profiles]]
+            name = "work"
+            [[profiles.stops]]
+            name = "Market Square"
+            lat = 53.0
+            lng = -1.5
+            services = ["7"]
+
+            [[profiles]]
+            name = "town"
+            [[profiles.stops]]
+            name = "Market Square"
+            lat = 53.0
+            lng = -1.5
+            [[profiles.stops]]
+            name = "Station Road"
+            lat = 53.01
+            lng = -1.5
+            "#,
+        )
+        .unwrap();
+        let mut profiles: Vec<(Config, Vec<BusStop>, Session)> = file
+            .profiles(None, fixed_now().naive_utc())
+            .unwrap()
+            .into_iter()
+            .map(|(_, file)| {
+                let config = test_support::with_env(&[("LAT", "53.0"), ("LNG", "-1.5"), ("RADIUS", "1000")], || Config::from_env(&file).unwrap());
+                let stops = crate::load_bus_stops(&file.stops, None).unwrap();
+                let session = test_support::session(&config, fixed_now() - Duration::hours(1));
+                (config, stops, session)
+            })
+            .collect();
+
+        // One fetch, handed to each profile in turn: 7 and 9 at Market Square, 12 at Station Road
+        let updated = (fixed_now() - Duration::seconds(30)).timestamp_millis().to_string();
+        let response = serde_json::json!({ "services": [
+            { "serviceNumber": "7", "fleetNumber": "1", "latitude": "53.0001", "longitude": "-1.5", "speed": "20", "updateTime": updated },
+            { "serviceNumber": "9", "fleetNumber": "2", "latitude": "53.0002", "longitude": "-1.5", "speed": "20", "updateTime": updated },
+            { "serviceNumber": "12", "fleetNumber": "3", "latitude": "53.0101", "longitude": "-1.5", "speed": "20", "updateTime": updated },
+        ]});
+        let mut streams = Vec::new();
+        for now in [fixed_now(), fixed_now() + Duration::minutes(1)] {
+            let cycle: Vec<Vec<(String, String)>> = profiles
+                .iter_mut()
+                .map(|(config, stops, session)| {
+                    let events = decide(&response, config, stops, session, now).unwrap().events;
+                    alerts(&events).into_iter().map(|alert| (alert.bus.service.clone(), alert.bus.stop.clone())).collect()
+                })
+                .collect();
+            streams.push(cycle);
+        }
+
+        let pair = |service: &str, stop: &str| (service.to_string(), stop.to_string());
+        // Work only hears about service 7; town about everything at its stops, 7 included,
+        // though work has just been told about that bus
+        assert_eq!(streams[0][0], [pair("7", "Market Square")]);
+        let mut town = streams[0][1].clone();
+        town.sort();
+        assert_eq!(town, [pair("12", "Station Road"), pair("7", "Market Square"), pair("9", "Market Square")]);
+        // Each profile's cooldowns are its own, and hold on the next fetch
+        assert_eq!(streams[1], [Vec::new(), Vec::new()]);
+    }
+
+    #[test]
+    fn arrivals_carry_an_operator_link_when_asked() {
+        let links = |vars: &[(&str, &str)]| -> Vec<Option<String>> {
+            run(&test_support::config(vars), &[NORMAL])
+                .remove(0)
+                .into_iter()
+                .filter_map(|event| match event {
+                    CycleEvent::Alert { link, .. } => Some(link),
+                    _ => None,
+                })
+                .collect()
+        };
+        assert_eq!(links(&[]), [None, None]);
+        assert_eq!(
+            links(&[("INCLUDE_OPERATOR_LINK", "true")]),
+            [Some(operator_link("7", 53.0003, -1.5002)), Some(operator_link("9", 53.0101, -1.5001))]
+        );
+    }
+
+    /// Each cycle's alerts as (kind, text)
+    fn alert_texts(cycles: &[Vec<CycleEvent>]) -> Vec<Vec<(AlertKind, String)>> {
+        cycles.iter().map(|events| alerts(events).iter().map(|alert| (alert.kind, alert.text.clone())).collect()).collect()
+    }
+
+    #[test]
+    fn arrivals_wait_for_confirming_polls_after_the_early_warning() {
+        let config = test_support::config(&[("CONFIRMATION_CYCLES", "2"), ("EARLY_WARNING_RADIUS", "500")]);
+        // 400 m out, then in Market Square's radius for two polls
+        let responses = [bus_at(0, &[(53.0036, -1.5)]), bus_at(1, &[(53.001, -1.5)]), bus_at(2, &[(53.0008, -1.5)])];
+        let responses: Vec<&str> = responses.iter().map(String::as_str).collect();
+        let cycles = alert_texts(&run(&config, &responses));
+        assert_eq!(
+            cycles,
+            [
+                vec![(AlertKind::EarlyWarning, "Bus (7) Bus Station - Hospital [fleet 1] is approaching **Market Square** (400 m away, ~2 min)".to_string())],
+                vec![],
+                vec![(AlertKind::Arrival, "Bus (7) Bus Station - Hospital [fleet 1] is near **Market Square**! (in range for 1 min)".to_string())],
+            ]
+        );
+    }
+
+    #[test]
+    fn a_single_fix_in_range_is_not_confirmed() {
+        let config = test_support::config(&[("CONFIRMATION_CYCLES", "2"), ("EARLY_WARNING_RADIUS", "500")]);
+        // One jittery fix inside the radius, then back out and on its way
+        let responses = [bus_at(0, &[(53.001, -1.5)]), bus_at(1, &[(53.0036, -1.5)]), bus_at(2, &[(53.0045, -1.5)])];
+        let responses: Vec<&str> = responses.iter().map(String::as_str).collect();
+        let cycles = alert_texts(&run(&config, &responses));
+        assert!(cycles.iter().flatten().all(|(kind, _)| *kind == AlertKind::EarlyWarning), "{:?}", cycles);
+        assert_eq!(cycles[0], []);
+    }
+
+    #[test]
+    fn no_services_array_is_not_a_cycle() {
+        let config = test_support::config(&[]);
+        let mut session = test_support::session(&config, fixed_now());
+        assert!(decide(&serde_json::json!({ "error": "busy" }), &config, &stops(), &mut session, fixed_now()).is_none());
+    }
+}
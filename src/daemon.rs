@@ -0,0 +1,92 @@
+// --daemon: instead of one run per invocation (from cron, say), stay up and start a session at each
+// start time of the config file's [schedule]. Every session re-reads the settings and starts with
+// fresh state, lasts RUN_MINUTES, and ends with a summary. Starting inside a window doesn't start a
+// session; the next start does.
+
+use chrono::{Duration as ChronoDuration, Utc};
+use std::path::PathBuf;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time;
+use tracing::{error, info, warn};
+
+use crate::config_file::ConfigFile;
+use crate::schedule::PollSchedule;
+use crate::timezone::Zone;
+use crate::{ExitReason, RunOptions, ACTIVATION_FORMAT};
+
+/// Runs sessions until SIGTERM or SIGINT: straight away between sessions, otherwise once the session has wound down
+pub async fn run(config_path: Option<PathBuf>, profile: Option<String>, stops_path: Option<PathBuf>, options: RunOptions) -> ExitReason {
+    let options = RunOptions { summary: true, ..options };
+    let (mut terminate, mut interrupt) = match (signal(SignalKind::terminate()), signal(SignalKind::interrupt())) {
+        (Ok(terminate), Ok(interrupt)) => (terminate, interrupt),
+        (Err(e), _) | (_, Err(e)) => return ExitReason::ConfigError(format!("Could not listen for SIGTERM/SIGINT: {}", e)),
+    };
+
+    loop {
+        // Re-read every time, so edits to the schedule apply from the next session
+        let (schedule, zone) = match (ConfigFile::load_opt(config_path.as_deref()), Zone::from_env()) {
+            (Ok(file), Ok(zone)) => match PollSchedule::parse(&file.schedule) {
+                Ok(Some(schedule)) => (schedule, zone),
+                Ok(None) => return ExitReason::ConfigError("--daemon needs a [schedule] in the config file to know when to start sessions.".to_string()),
+                Err(e) => return ExitReason::ConfigError(e),
+            },
+            (Err(e), _) | (_, Err(e)) => return ExitReason::ConfigError(e),
+        };
+
+        let Some(start) = schedule.next_activations(zone.local_time(Utc::now()), 1).into_iter().next() else {
+            info!("The schedule has no more start times; exiting.");
+            return ExitReason::Completed;
+        };
+        // A start time the clocks skip over (spring forward) begins on the first minute after the gap
+        let start_at = (0..=120).find_map(|mins| zone.instant_at(start + ChronoDuration::minutes(mins))).unwrap_or_else(Utc::now);
+        info!("Next session at {}", start.format(ACTIVATION_FORMAT));
+
+        let wait = (start_at - Utc::now()).to_std().unwrap_or_default();
+        tokio::select! {
+            _ = time::sleep(wait) => {}
+            _ = terminate.recv() => return stopping("SIGTERM"),
+            _ = interrupt.recv() => return stopping("SIGINT"),
+        }
+
+        info!("Session starting");
+        // The session's trackers see SIGTERM/SIGINT too and wind down (summary, "stopped" notice),
+        // so the first one means exiting once the session has finished. A second doesn't wait, which
+        // also covers a signal sent while the session was still starting, before its trackers listened.
+        let session = crate::run(config_path.clone(), profile.clone(), stops_path.clone(), options.clone());
+        tokio::pin!(session);
+        let mut signalled = false;
+        let reason = loop {
+            let signal = tokio::select! {
+                reason = &mut session => break reason,
+                _ = terminate.recv() => "SIGTERM",
+                _ = interrupt.recv() => "SIGINT",
+            };
+            if signalled {
+                return stopping(signal);
+            }
+            info!("{} received; exiting when the session has stopped.", signal);
+            signalled = true;
+        };
+        if signalled {
+            return match reason {
+                ExitReason::ConfigError(_) | ExitReason::NotifierStartup(_) => reason,
+                _ => ExitReason::Completed,
+            };
+        }
+        match reason {
+            ExitReason::Completed => info!("Session finished"),
+            ExitReason::NoMatch => info!("Session finished without a matching arrival"),
+            // The API may well be back by the next session
+            ExitReason::ApiFailure => warn!("Session ended early: the Stagecoach API is unreachable."),
+            ExitReason::ConfigError(_) | ExitReason::NotifierStartup(_) => {
+                error!("Session could not run; stopping the daemon.");
+                return reason;
+            }
+        }
+    }
+}
+
+fn stopping(signal: &str) -> ExitReason {
+    info!("{} received; exiting.", signal);
+    ExitReason::Completed
+}
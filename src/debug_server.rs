@@ -0,0 +1,357 @@
+// Read-only HTTP endpoint for inspecting a running tracker (DEBUG_HTTP_ADDR, e.g. 127.0.0.1:8080).
+// GET /debug returns the effective settings, the loaded stops and the buses in range as JSON.
+// Tokens and chat ids never appear in it; only which notification channels are on.
+// GET /map is a live map of the stops and buses (Leaflet, loaded from a CDN), kept up to date
+// from GET /events, a server-sent event stream with every bus's position after each cycle.
+
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{self, Duration, Instant};
+use tracing::{debug, info};
+
+use crate::alerts::Cooldown;
+use crate::session::{SharedStatus, Status};
+use crate::timezone::QuietHours;
+use crate::{BusStop, Config, ExclusionZone};
+
+const MAX_REQUEST_BYTES: usize = 8 * 1024;
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+const REDACTED: &str = "[redacted]";
+const EVENT_CHECK: Duration = Duration::from_secs(1); // How often an event stream looks for a new cycle
+const KEEP_ALIVE: Duration = Duration::from_secs(15); // Comment lines so proxies don't close a quiet stream
+const MAP_PAGE: &str = include_str!("map.html");
+
+/// Answers requests until the process exits
+pub async fn serve(listener: TcpListener, settings: Value, status: SharedStatus) {
+    if let Ok(addr) = listener.local_addr() {
+        info!("Debug endpoint at http://{}/debug, live map at http://{}/map", addr, addr);
+    }
+    loop {
+        let Ok((stream, peer)) = listener.accept().await else {
+            continue;
+        };
+        let (settings, status) = (settings.clone(), status.clone());
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, &settings, &status).await {
+                debug!("Debug request from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream, settings: &Value, status: &SharedStatus) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST_BYTES {
+        match time::timeout(READ_TIMEOUT, stream.read(&mut buf)).await {
+            Ok(Ok(0)) | Err(_) => break,
+            Ok(Ok(n)) => request.extend_from_slice(&buf[..n]),
+            Ok(Err(e)) => return Err(e),
+        }
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or_default().split_whitespace();
+    let (code, content_type, body) = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/debug")) => {
+            let body = json!({
+                "settings": settings,
+                "stops": status.lock().unwrap().stops,
+                "in_range": in_range_json(status),
+            });
+            ("200 OK", "application/json", format!("{:#}\n", body))
+        }
+        (Some("GET"), Some("/map")) => ("200 OK", "text/html; charset=utf-8", map_page(status)),
+        (Some("GET"), Some("/events")) => return stream_events(stream, status).await,
+        (Some("GET"), _) => ("404 Not Found", "application/json", "{\"error\": \"not found\"}\n".to_string()),
+        _ => ("405 Method Not Allowed", "application/json", "{\"error\": \"only GET is supported\"}\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        code,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// The map page with the loaded stops written into it. "</" is escaped so a stop name can't end the script.
+fn map_page(status: &SharedStatus) -> String {
+    let stops = Value::Array(status.lock().unwrap().stops.clone()).to_string().replace("</", "<\\/");
+    MAP_PAGE.replace("/*STOPS*/[]", &stops)
+}
+
+/// Sends the bus positions as a server-sent event after each cycle, until the client goes away
+async fn stream_events(mut stream: TcpStream, status: &SharedStatus) -> std::io::Result<()> {
+    stream
+        .write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: keep-alive\r\n\r\n")
+        .await?;
+    let mut sent: Option<(DateTime<Utc>, bool)> = None; // checked_at and staleness of the cycle last sent
+    let mut last_write = Instant::now();
+    loop {
+        let event = {
+            let status = status.lock().unwrap();
+            let current = status.checked_at.map(|at| (at, status.api_down_since.is_some()));
+            (current.is_some() && current != sent).then(|| {
+                sent = current;
+                positions_json(&status)
+            })
+        };
+        match event {
+            Some(event) => stream.write_all(format!("data: {}\n\n", event).as_bytes()).await?,
+            None if last_write.elapsed() >= KEEP_ALIVE => stream.write_all(b": keep-alive\n\n").await?,
+            None => {
+                time::sleep(EVENT_CHECK).await;
+                continue;
+            }
+        }
+        last_write = Instant::now();
+    }
+}
+
+/// Every bus at the latest cycle, as sent on the event stream
+fn positions_json(status: &Status) -> Value {
+    let buses: Vec<Value> = status
+        .positions
+        .iter()
+        .map(|bus| {
+            json!({
+                "service": bus.service_number,
+                "label": status.service_labels.label(&bus.service_number),
+                "vehicle": bus.vehicle,
+                "lat": bus.lat,
+                "lng": bus.lng,
+            })
+        })
+        .collect();
+    json!({
+        "checked_at": status.checked_at.map(|at| at.to_rfc3339()),
+        "stale": status.api_down_since.is_some(),
+        "buses": buses,
+    })
+}
+
+fn in_range_json(status: &SharedStatus) -> Value {
+    let status = status.lock().unwrap();
+    let buses: Vec<Value> = status
+        .in_range
+        .iter()
+        .map(|bus| {
+            json!({
+                "service": bus.service_number,
+                "vehicle": bus.vehicle,
+                "stop": bus.stop,
+                "group": bus.group,
+                "distance_m": bus.distance,
+            })
+        })
+        .collect();
+    json!({
+        "checked_at": status.checked_at.map(|at| at.to_rfc3339()),
+        "stale": status.api_down_since.is_some(),
+        "api_down_since": status.api_down_since.map(|at| at.to_rfc3339()),
+        "buses": buses,
+    })
+}
+
+/// The settings the tracker is running with. `channels` names the notification channels in use.
+pub fn settings_json(config: &Config, channels: &[&str]) -> Value {
+    let cooldown = match config.alert_cooldown {
+        Cooldown::Time(cooldown) => json!({ "secs": cooldown.num_seconds() }),
+        Cooldown::Cycles(cycles) => json!({ "cycles": cycles }),
+    };
+    let radius = if config.derive_radius { json!({ "derived": true, "max": config.radius }) } else { json!(config.radius) };
+    json!({
+        "lat": config.lat,
+        "lng": config.lng,
+        "radius": radius,
+        "poll_interval_secs": config.poll_interval_secs,
+        "stale_fix_secs": config.stale_fix_secs,
+        "fix_age_warn_secs": config.fix_age_warn_secs,
+        "min_speed": config.min_speed,
+        "missing_speed_passes": config.missing_speed_passes,
+        "timezone": config.style.zone.name(),
+        "units": format!("{:?}", config.style.units),
+        "alert_cooldown": cooldown,
+        "gps_file": config.gps_file,
+        "api_retries": config.api_retries,
+        // Fallback URLs sometimes carry an API key in the query
+        "fallback_api_url": config.fallback_api_url.as_deref().map(|url| match url.split_once('?') {
+            Some((base, _)) => format!("{}?{}", base, REDACTED),
+            None => url.to_string(),
+        }),
+        "require_route_match": config.require_route_match,
+        "alert_template": config.alert_template,
+        "include_operator_link": config.include_operator_link,
+        "geofence": config.geofence,
+        "max_services_per_cycle": config.max_services_per_cycle,
+        "max_plausible_speed_kmh": config.max_plausible_speed_kmh,
+        "confirmation_cycles": config.confirmation_cycles,
+        "early_warning_radius": config.early_warning_radius,
+        "warmup_secs": config.warmup_secs,
+        "report_closest_approach": config.report_closest_approach,
+        "wall_clock_budget": config.wall_clock_budget,
+        "align_polls": config.align_polls,
+        "export_geojson": config.export_geojson,
+        "require_approaching_heading": config.require_approaching_heading,
+        "heading_tolerance_deg": config.heading_tolerance_deg,
+        "lifecycle_notifications": config.lifecycle_notifications,
+        "dedup_distance_bucket_m": config.dedup_distance_bucket_m,
+        "skip_unknown_services": config.skip_unknown_services,
+        "exclusion_zone": config.exclusion_zone.as_ref().map(|zone| match zone {
+            ExclusionZone::Circle { lat, lng, radius } => json!({ "lat": lat, "lng": lng, "radius": radius }),
+            ExclusionZone::Polygon(vertices) => json!(vertices),
+        }),
+        "parked_speed_kmh": config.parked_speed_kmh,
+        "parked_after_mins": config.parked_after.map(|after| after.num_minutes()),
+        "ignore_vehicles": config.ignore_vehicles,
+        "client_version": config.client_version,
+        "history_positions": config.history_positions,
+        "notification_channels": channels,
+        "telegram_bot_token": REDACTED,
+        "telegram_chat_id": REDACTED,
+    })
+}
+
+/// The loaded stops, as shown by the debug endpoint
+pub fn stops_json(stops: &[BusStop]) -> Vec<Value> {
+    stops
+        .iter()
+        .map(|stop| {
+            json!({
+                "name": stop.name,
+                "lat": stop.lat,
+                "lng": stop.lng,
+                "radius": stop.radius,
+                "group": stop.group,
+                "services": stop.services,
+                "days": stop.days.iter().map(|day| day.to_string()).collect::<Vec<_>>(),
+                "quiet_hours": stop.quiet.as_ref().map(|quiet| match quiet {
+                    QuietHours::Never => json!("never"),
+                    QuietHours::During { days, window } => json!({
+                        "days": days.iter().map(|day| day.to_string()).collect::<Vec<_>>(),
+                        "hours": window.map(|window| format!("{}-{}", window.start.format("%H:%M"), window.end.format("%H:%M"))),
+                    }),
+                }),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::{InRange, Position};
+    use crate::test_support::{self, fixed_now, stop};
+    use std::sync::{Arc, Mutex};
+
+    fn status_while_down() -> Status {
+        Status {
+            checked_at: Some(fixed_now()),
+            in_range: vec![InRange {
+                service_number: "7".to_string(),
+                vehicle: "fleet 10812".to_string(),
+                stop: "Market Square".to_string(),
+                group: None,
+                distance: 35.0,
+            }],
+            positions: vec![Position { service_number: "7".to_string(), vehicle: "fleet 10812".to_string(), lat: 53.0003, lng: -1.5002 }],
+            api_down_since: Some(fixed_now() + chrono::Duration::minutes(1)),
+            ..Status::default()
+        }
+    }
+
+    #[test]
+    fn buses_from_before_an_outage_are_shown_marked_stale() {
+        let status = SharedStatus::default();
+        *status.lock().unwrap() = status_while_down();
+        assert_eq!(
+            in_range_json(&status),
+            json!({
+                "checked_at": "2024-03-04T08:00:00+00:00",
+                "stale": true,
+                "api_down_since": "2024-03-04T08:01:00+00:00",
+                "buses": [{ "service": "7", "vehicle": "fleet 10812", "stop": "Market Square", "group": null, "distance_m": 35.0 }],
+            })
+        );
+        let positions = positions_json(&status.lock().unwrap());
+        assert_eq!(positions["stale"], true);
+        assert_eq!(positions["buses"][0]["vehicle"], "fleet 10812");
+
+        status.lock().unwrap().api_down_since = None;
+        assert_eq!(in_range_json(&status)["stale"], false);
+    }
+
+    const SECRETS: [&str; 4] = ["123456:SECRET-TOKEN", "-100987654", "s3cret-key", "API-KEY"];
+
+    /// Serves `status` with settings from a config whose Telegram credentials and fallback API key are SECRETS
+    async fn server(status: SharedStatus) -> String {
+        let vars = [
+            ("TELEGRAM_BOT_TOKEN", SECRETS[0]),
+            ("TELEGRAM_CHAT_ID", SECRETS[1]),
+            ("FALLBACK_API_URL", "https://backup.example.org/api?key=s3cret-key&client=API-KEY"),
+        ];
+        let config = test_support::config(&vars);
+        let settings = settings_json(&config, &["telegram", "ntfy"]);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(serve(listener, settings, status));
+        url
+    }
+
+    #[tokio::test]
+    async fn debug_shows_settings_stops_and_buses_without_secrets() {
+        let mut stops = vec![stop("Market Square", 53.0, -1.5), stop("Station Road", 53.01, -1.5)];
+        stops[1].group = Some("work".to_string());
+        let status = Status {
+            checked_at: Some(fixed_now()),
+            in_range: vec![InRange { service_number: "7".to_string(), vehicle: "fleet 10812".to_string(), stop: "Market Square".to_string(), group: None, distance: 34.0 }],
+            stops: stops_json(&stops),
+            ..Status::default()
+        };
+        let url = server(Arc::new(Mutex::new(status))).await;
+
+        let response = reqwest::get(format!("{}/debug", url)).await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["content-type"], "application/json");
+        let body = response.text().await.unwrap();
+        for secret in SECRETS {
+            assert!(!body.contains(secret), "{} in {}", secret, body);
+        }
+
+        let debug: Value = serde_json::from_str(&body).unwrap();
+        let settings = &debug["settings"];
+        assert_eq!(settings["telegram_bot_token"], REDACTED);
+        assert_eq!(settings["telegram_chat_id"], REDACTED);
+        assert_eq!(settings["fallback_api_url"], "https://backup.example.org/api?[redacted]");
+        assert_eq!(settings["notification_channels"], json!(["telegram", "ntfy"]));
+        assert_eq!((settings["lat"].as_f64(), settings["lng"].as_f64(), settings["radius"].as_u64()), (Some(53.0), Some(-1.5), Some(1000)));
+        assert_eq!(
+            debug["stops"],
+            json!([
+                { "name": "Market Square", "lat": 53.0, "lng": -1.5, "radius": 200.0, "group": null, "services": [], "days": [], "quiet_hours": null },
+                { "name": "Station Road", "lat": 53.01, "lng": -1.5, "radius": 200.0, "group": "work", "services": [], "days": [], "quiet_hours": null },
+            ])
+        );
+        assert_eq!(debug["in_range"]["buses"], json!([{ "service": "7", "vehicle": "fleet 10812", "stop": "Market Square", "group": null, "distance_m": 34.0 }]));
+        assert_eq!(debug["in_range"]["stale"], false);
+    }
+
+    #[tokio::test]
+    async fn only_get_on_known_paths_is_answered() {
+        let url = server(SharedStatus::default()).await;
+        let client = reqwest::Client::new();
+        assert_eq!(client.get(format!("{}/secrets", url)).send().await.unwrap().status(), 404);
+        assert_eq!(client.post(format!("{}/debug", url)).send().await.unwrap().status(), 405);
+    }
+
+    #[test]
+    fn fallback_urls_without_a_query_are_shown_as_they_are() {
+        let config = test_support::config(&[("FALLBACK_API_URL", "https://backup.example.org/api")]);
+        assert_eq!(settings_json(&config, &[])["fallback_api_url"], "https://backup.example.org/api");
+    }
+}
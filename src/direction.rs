@@ -0,0 +1,159 @@
+// Which way a bus is going, from a serviceDescription of the form "Origin - Destination": the
+// bus's upcoming stops say which end it's heading for, or else its heading along the service's
+// [[routes]] line, drawn from the first place in the description to the second. A stop's
+// `services` can then ask for one direction, e.g. "7:towards City Centre"; a bus whose direction
+// can't be told always passes, so an odd description never costs an alert.
+
+use std::fmt;
+
+use crate::stop_names;
+
+/// Separators between the two places, tried in order
+const SEPARATORS: &[&str] = &[" - ", " – ", " — ", " to "];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Direction {
+    Towards(String),
+    Unknown,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Direction::Towards(place) => write!(f, "towards {}", place),
+            Direction::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+impl Direction {
+    /// Whether a bus going this way is wanted by a "towards ..." filter
+    fn allows(&self, wanted: &str) -> bool {
+        let wanted = wanted.trim();
+        let wanted = if wanted.to_ascii_lowercase().starts_with("towards ") { &wanted["towards ".len()..] } else { wanted };
+        match self {
+            Direction::Towards(place) => stop_names::same_stop(place, wanted),
+            Direction::Unknown => true,
+        }
+    }
+}
+
+/// The two places in a description, or None if it doesn't look like "Origin - Destination"
+pub fn split_description(description: &str) -> Option<(String, String)> {
+    SEPARATORS.iter().find_map(|separator| {
+        let (from, to) = description.split_once(separator)?;
+        let (from, to) = (from.trim(), to.trim());
+        (!from.is_empty() && !to.is_empty()).then(|| (from.to_string(), to.to_string()))
+    })
+}
+
+/// The direction of a bus on the described service. `forward` says whether it runs the way the
+/// service's route is drawn, when that's known.
+pub fn infer(description: &str, upcoming_stops: Option<&[String]>, forward: Option<bool>) -> Direction {
+    let Some((from, to)) = split_description(description) else {
+        return Direction::Unknown;
+    };
+    if let Some(towards) = upcoming_stops.and_then(|stops| towards_in_stops(&from, &to, stops)) {
+        return Direction::Towards(towards);
+    }
+    match forward {
+        Some(true) => Direction::Towards(to),
+        Some(false) => Direction::Towards(from),
+        None => Direction::Unknown,
+    }
+}
+
+/// Whichever end is named by a later upcoming stop; None if neither is, or both are by the same one
+fn towards_in_stops(from: &str, to: &str, stops: &[String]) -> Option<String> {
+    let last_mention = |place: &str| {
+        let place = stop_names::canonical(place);
+        stops.iter().rposition(|stop| {
+            let stop = stop_names::canonical(stop);
+            stop.contains(&place) || place.contains(&stop)
+        })
+    };
+    match (last_mention(from), last_mention(to)) {
+        (Some(a), Some(b)) if a == b => None,
+        (Some(a), Some(b)) => Some(if b > a { to } else { from }.to_string()),
+        (Some(_), None) => Some(from.to_string()),
+        (None, Some(_)) => Some(to.to_string()),
+        (None, None) => None,
+    }
+}
+
+/// Whether a stop's `services` entry, "7" or "7:towards City Centre", covers this bus
+pub fn service_matches(entry: &str, service_number: &str, direction: &Direction) -> bool {
+    match entry.split_once(':') {
+        Some((service, wanted)) => service.trim().eq_ignore_ascii_case(service_number) && direction.allows(wanted),
+        None => entry.trim().eq_ignore_ascii_case(service_number),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn towards(place: &str) -> Direction {
+        Direction::Towards(place.to_string())
+    }
+
+    fn stops(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn descriptions_split_at_the_first_separator_that_fits() {
+        let split = split_description;
+        assert_eq!(split("Bus Station - Hospital"), Some(("Bus Station".to_string(), "Hospital".to_string())));
+        assert_eq!(split(" Bus Station – Hospital "), Some(("Bus Station".to_string(), "Hospital".to_string())));
+        assert_eq!(split("Bus Station — Hospital"), Some(("Bus Station".to_string(), "Hospital".to_string())));
+        assert_eq!(split("Bus Station to Hospital"), Some(("Bus Station".to_string(), "Hospital".to_string())));
+        // " - " is tried first, so a place with "to" in its name survives
+        assert_eq!(split("Toton - Stapleford"), Some(("Toton".to_string(), "Stapleford".to_string())));
+        assert_eq!(split("Hospital Circular"), None);
+        assert_eq!(split(" - Hospital"), None);
+        assert_eq!(split("Bus-Station"), None);
+    }
+
+    #[test]
+    fn upcoming_stops_say_which_end_the_bus_is_heading_for() {
+        let description = "Bus Station - City Ctr";
+        let upcoming = stops(&["Market Sq", "City Centre (Stand B)"]);
+        assert_eq!(infer(description, Some(&upcoming), None), towards("City Ctr"));
+        // Upcoming stops win over the route's heading
+        assert_eq!(infer(description, Some(&upcoming), Some(false)), towards("City Ctr"));
+        let upcoming = stops(&["Market Sq", "Bus Stn"]);
+        assert_eq!(infer(description, Some(&upcoming), None), towards("Bus Station"));
+        // Both ends coming up: the later one is where it's going
+        let upcoming = stops(&["City Centre", "Market Sq", "Bus Station"]);
+        assert_eq!(infer(description, Some(&upcoming), None), towards("Bus Station"));
+    }
+
+    #[test]
+    fn without_telling_stops_the_route_heading_decides_or_it_stays_unknown() {
+        let description = "Bus Station - Hospital";
+        let upcoming = stops(&["Market Square", "Station Road"]);
+        assert_eq!(infer(description, Some(&upcoming), Some(true)), towards("Hospital"));
+        assert_eq!(infer(description, None, Some(false)), towards("Bus Station"));
+        assert_eq!(infer(description, Some(&upcoming), None), Direction::Unknown);
+        // One stop naming both ends can't tell them apart
+        let upcoming = stops(&["Bus Station for Hospital"]);
+        assert_eq!(infer(description, Some(&upcoming), None), Direction::Unknown);
+        assert_eq!(infer("Hospital Circular", None, Some(true)), Direction::Unknown);
+    }
+
+    #[test]
+    fn service_entries_filter_by_direction_and_unknown_always_passes() {
+        let city = towards("City Centre");
+        assert!(service_matches("7", "7", &city));
+        assert!(service_matches(" x24 ", "X24", &city));
+        assert!(!service_matches("7", "17", &city));
+        assert!(service_matches("7:towards City Ctr", "7", &city));
+        assert!(service_matches("7: city centre", "7", &city));
+        assert!(!service_matches("7:towards Hospital", "7", &city));
+        assert!(!service_matches("9:towards City Centre", "7", &city));
+        assert!(service_matches("7:towards Hospital", "7", &Direction::Unknown));
+        assert_eq!(city.to_string(), "towards City Centre");
+        assert_eq!(Direction::Unknown.to_string(), "unknown");
+    }
+}
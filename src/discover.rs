@@ -0,0 +1,306 @@
+// `discover --source osm`: finds the bus stops around LAT/LNG in OpenStreetMap (highway=bus_stop
+// nodes, through the Overpass API) and adds the chosen ones to the config file's [[stops]].
+// Replies are cached per search, so running it again to pick different stops doesn't query
+// Overpass again; --refresh forces a new query.
+
+use chrono::Utc;
+use clap::{Args, ValueEnum};
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
+use tokio::time::{sleep, Duration};
+use tracing::{info, warn};
+
+use crate::config_edit::{self, NewStop};
+use crate::config_file::ConfigFile;
+use crate::setup::Prompter;
+use crate::{coordinate_setting, env_or_config, haversine_distance, http_client, urlencode};
+
+const DEFAULT_OVERPASS_URL: &str = "https://overpass-api.de/api/interpreter";
+const QUERY_TIMEOUT_SECS: u64 = 25; // Overpass's own limit on the query; the request waits a little longer
+const CACHE_MAX_AGE_SECS: i64 = 7 * 24 * 60 * 60;
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(10); // Doubling after each attempt, without a Retry-After
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(120);
+
+#[derive(Debug, Args)]
+pub struct DiscoverArgs {
+    /// Where to look for stops
+    #[arg(long, value_enum)]
+    source: Source,
+    /// Latitude to search around (default LAT, or `lat` in the config file)
+    #[arg(long, allow_negative_numbers = true, requires = "lng")]
+    lat: Option<f64>,
+    /// Longitude to search around (default LNG, or `lng` in the config file)
+    #[arg(long, allow_negative_numbers = true, requires = "lat")]
+    lng: Option<f64>,
+    /// Search radius in meters (default RADIUS, or `radius` in the config file)
+    #[arg(long)]
+    radius: Option<u32>,
+    /// Overpass API endpoint
+    #[arg(long, env = "OVERPASS_URL", default_value = DEFAULT_OVERPASS_URL)]
+    overpass_url: String,
+    /// Attempts after the first when Overpass is busy or times out
+    #[arg(long, default_value_t = 3)]
+    retries: u32,
+    /// Where replies are cached (default $XDG_CACHE_HOME/stagecoach-tracker or ~/.cache/stagecoach-tracker)
+    #[arg(long, env = "OVERPASS_CACHE_DIR")]
+    cache_dir: Option<PathBuf>,
+    /// Query Overpass even if there's a cached reply for this search
+    #[arg(long)]
+    refresh: bool,
+    /// Add every stop found without asking
+    #[arg(long)]
+    all: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+enum Source {
+    Osm, // OpenStreetMap, through the Overpass API
+}
+
+/// An Overpass reply as cached on disk
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedReply {
+    fetched_at: i64, // Unix seconds
+    elements: Vec<Value>,
+}
+
+/// A stop found in OpenStreetMap
+#[derive(Debug, Clone)]
+struct Found {
+    id: u64,
+    name: Option<String>,
+    reference: Option<String>, // The `ref` tag: the operator's stop code
+    lat: f64,
+    lng: f64,
+    distance: f64, // Meters from the search position
+}
+
+/// Finds the stops, asks which to keep and adds them to the config file. Returns what was done.
+pub async fn run(config_path: Option<PathBuf>, args: &DiscoverArgs) -> Result<String, String> {
+    let Some(path) = config_path else {
+        return Err("Pass --config (or set CONFIG_FILE) to say which config file to add the stops to.".to_string());
+    };
+    let file = ConfigFile::load_opt(Some(&path))?;
+    let (lat, lng) = match (args.lat, args.lng) {
+        (Some(lat), Some(lng)) => (lat, lng),
+        _ => (
+            coordinate_setting("LAT", file.lat, "the latitude to search around")?,
+            coordinate_setting("LNG", file.lng, "the longitude to search around")?,
+        ),
+    };
+    config_edit::check_coordinates(lat, lng)?;
+    let radius = match args.radius {
+        Some(radius) => radius,
+        None => env_or_config("RADIUS", file.radius, "the search radius in meters (a whole number)")?,
+    };
+
+    let found = match args.source {
+        Source::Osm => osm_stops(args, lat, lng, radius).await?,
+    };
+    if found.is_empty() {
+        return Ok(format!("No bus stops found within {} m of {}, {}.", radius, lat, lng));
+    }
+
+    let names = stop_names(&found);
+    for (number, (stop, name)) in found.iter().zip(&names).enumerate() {
+        println!("{:>3}. {} ({:.0} m)", number + 1, name, stop.distance);
+    }
+    let prompter = Prompter { interactive: !args.all && io::stdin().is_terminal() };
+    let chosen = loop {
+        let answer = prompter.ask("Stops to add, e.g. 1,3,5-7 (or all / none)", Some("all"), "--all")?;
+        match parse_selection(&answer, found.len()) {
+            Some(chosen) => break chosen,
+            None => println!("'{}' doesn't look right, please try again.", answer),
+        }
+    };
+
+    let mut added = 0;
+    for index in chosen {
+        let stop = NewStop { name: names[index].clone(), lat: found[index].lat, lng: found[index].lng, radius: None, services: Vec::new() };
+        match config_edit::add_stop(&path, &stop) {
+            Ok(_) => added += 1,
+            Err(e) => println!("Skipped {}: {}", stop.name, e),
+        }
+    }
+    Ok(format!("Added {} stop(s) to {}.", added, path.display()))
+}
+
+/// highway=bus_stop nodes within `radius` of the position, nearest first
+async fn osm_stops(args: &DiscoverArgs, lat: f64, lng: f64, radius: u32) -> Result<Vec<Found>, String> {
+    let cache = cache_dir(args.cache_dir.as_deref())?.map(|dir| dir.join(format!("osm-{:.5}_{:.5}_{}.json", lat, lng, radius)));
+    let cached = match &cache {
+        Some(cache) if !args.refresh => read_cache(cache),
+        _ => None,
+    };
+    let elements = match cached {
+        Some(elements) => {
+            info!("Using the cached Overpass reply for this search (--refresh to query again).");
+            elements
+        }
+        None => {
+            let elements = query_overpass(&http_client()?, args, lat, lng, radius).await?;
+            if let Some(cache) = &cache {
+                if let Err(e) = write_cache(cache, &elements) {
+                    warn!("Could not cache the Overpass reply in {}: {}", cache.display(), e);
+                }
+            }
+            elements
+        }
+    };
+
+    let mut found: Vec<Found> = elements
+        .iter()
+        .filter_map(|element| {
+            let (stop_lat, stop_lng) = (element["lat"].as_f64()?, element["lon"].as_f64()?);
+            let tag = |key: &str| element["tags"][key].as_str().map(str::trim).filter(|value| !value.is_empty()).map(str::to_string);
+            Some(Found {
+                id: element["id"].as_u64()?,
+                name: tag("name"),
+                reference: tag("ref"),
+                lat: stop_lat,
+                lng: stop_lng,
+                distance: haversine_distance(lat, lng, stop_lat, stop_lng),
+            })
+        })
+        .collect();
+    found.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+    Ok(found)
+}
+
+/// Runs the query, waiting and trying again while Overpass is rate limiting (429) or overloaded
+/// (504, or a "timed out" remark in an otherwise successful reply)
+async fn query_overpass(client: &Client, args: &DiscoverArgs, lat: f64, lng: f64, radius: u32) -> Result<Vec<Value>, String> {
+    let query = format!(
+        "[out:json][timeout:{}];node[\"highway\"=\"bus_stop\"](around:{},{},{});out body;",
+        QUERY_TIMEOUT_SECS, radius, lat, lng
+    );
+    let url = format!("{}?data={}", args.overpass_url, urlencode(&query));
+
+    let mut delay = FIRST_RETRY_DELAY;
+    for attempt in 0..=args.retries {
+        if attempt > 0 {
+            println!("Overpass is busy; trying again in {} s...", delay.as_secs());
+            sleep(delay).await;
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+        }
+
+        let response = client
+            .get(&url)
+            .timeout(Duration::from_secs(QUERY_TIMEOUT_SECS + 15))
+            .send()
+            .await;
+        let response = match response {
+            Ok(response) => response,
+            Err(e) if e.is_timeout() => continue,
+            Err(e) => return Err(format!("Could not reach Overpass at {}: {}", args.overpass_url, e)),
+        };
+        match response.status() {
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::GATEWAY_TIMEOUT => {
+                let retry_after = response.headers().get("Retry-After").and_then(|value| value.to_str().ok()?.trim().parse().ok());
+                if let Some(secs) = retry_after {
+                    delay = Duration::from_secs(secs).min(MAX_RETRY_DELAY);
+                }
+                continue;
+            }
+            status if !status.is_success() => return Err(format!("Overpass refused the query ({}).", status)),
+            _ => {}
+        }
+
+        let body: Value = response.json().await.map_err(|e| format!("Unexpected Overpass reply: {}", e))?;
+        if body["remark"].as_str().is_some_and(|remark| remark.contains("timed out")) {
+            continue;
+        }
+        return match body["elements"].as_array() {
+            Some(elements) => Ok(elements.clone()),
+            None => Err("Unexpected Overpass reply: no elements.".to_string()),
+        };
+    }
+    Err(format!("Overpass was still busy after {} attempt(s); try again later or pass another --overpass-url.", args.retries + 1))
+}
+
+/// --cache-dir, else the user's cache directory; None when there's nowhere to put it
+fn cache_dir(dir: Option<&Path>) -> Result<Option<PathBuf>, String> {
+    let dir = match dir {
+        Some(dir) => dir.to_path_buf(),
+        None => match std::env::var_os("XDG_CACHE_HOME").filter(|dir| !dir.is_empty()) {
+            Some(dir) => PathBuf::from(dir).join("stagecoach-tracker"),
+            None => match std::env::var_os("HOME").filter(|dir| !dir.is_empty()) {
+                Some(home) => PathBuf::from(home).join(".cache").join("stagecoach-tracker"),
+                None => return Ok(None),
+            },
+        },
+    };
+    fs::create_dir_all(&dir).map_err(|e| format!("Could not create the cache directory {}: {}", dir.display(), e))?;
+    Ok(Some(dir))
+}
+
+/// The cached elements, unless there aren't any or they're older than CACHE_MAX_AGE_SECS
+fn read_cache(path: &Path) -> Option<Vec<Value>> {
+    let cached: CachedReply = serde_json::from_str(&fs::read_to_string(path).ok()?).ok()?;
+    (Utc::now().timestamp() - cached.fetched_at < CACHE_MAX_AGE_SECS).then_some(cached.elements)
+}
+
+fn write_cache(path: &Path, elements: &[Value]) -> io::Result<()> {
+    let cached = CachedReply { fetched_at: Utc::now().timestamp(), elements: elements.to_vec() };
+    fs::write(path, serde_json::to_string(&cached)?)
+}
+
+/// The name each stop gets in the config: its name tag, else its ref, else "Unnamed stop <id>".
+/// A name shared by several stops (one each side of the road, often) gets the ref or id after it.
+fn stop_names(found: &[Found]) -> Vec<String> {
+    let base = |stop: &Found| match (&stop.name, &stop.reference) {
+        (Some(name), _) => name.clone(),
+        (None, Some(reference)) => reference.clone(),
+        (None, None) => format!("Unnamed stop {}", stop.id),
+    };
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for stop in found {
+        *counts.entry(base(stop).to_lowercase()).or_default() += 1;
+    }
+    found
+        .iter()
+        .map(|stop| {
+            let name = base(stop);
+            if counts[&name.to_lowercase()] == 1 {
+                return name;
+            }
+            match (&stop.name, &stop.reference) {
+                (Some(_), Some(reference)) => format!("{} ({})", name, reference),
+                _ => format!("{} ({})", name, stop.id),
+            }
+        })
+        .collect()
+}
+
+/// Parses "all", "none" or a list like "1,3,5-7" (numbered from 1) into indexes below `count`
+fn parse_selection(text: &str, count: usize) -> Option<Vec<usize>> {
+    match text.trim().to_ascii_lowercase().as_str() {
+        "all" => return Some((0..count).collect()),
+        "none" => return Some(Vec::new()),
+        _ => {}
+    }
+    let mut chosen = Vec::new();
+    for part in text.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+        let (first, last) = match part.split_once('-') {
+            Some((first, last)) => (first.trim().parse::<usize>().ok()?, last.trim().parse::<usize>().ok()?),
+            None => {
+                let number = part.parse::<usize>().ok()?;
+                (number, number)
+            }
+        };
+        if first == 0 || first > last || last > count {
+            return None;
+        }
+        for index in first - 1..last {
+            if !chosen.contains(&index) {
+                chosen.push(index);
+            }
+        }
+    }
+    Some(chosen)
+}
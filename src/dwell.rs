@@ -0,0 +1,120 @@
+// Arrive/depart tracking per (vehicle, stop), used to measure how long buses dwell at stops
+
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+
+/// A bus's current stay within a stop's radius
+#[derive(Debug, Clone)]
+pub struct Visit {
+    pub service_number: String,
+    pub vehicle: String, // Human-readable identifier
+    pub stop: String,
+    pub arrived_at: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub cycles: u32, // Consecutive polls the bus has been seen in range, for CONFIRMATION_CYCLES
+    pub history_id: Option<i64>, // Row in the history database, when enabled
+    pub alerted: bool,           // Whether the arrival produced an alert
+    pub during_warmup: bool,     // Arrived during WARMUP_SECS, so it never alerts
+}
+
+#[derive(Debug, Clone)]
+pub struct Departure {
+    pub visit: Visit,
+    pub departed_at: DateTime<Utc>,
+    pub dwell_secs: i64,
+}
+
+#[derive(Debug, Default)]
+pub struct Presence {
+    visits: HashMap<(String, String), Visit>, // Keyed by (vehicle key, stop name)
+}
+
+impl Presence {
+    /// Notes that a vehicle is within a stop's radius this cycle.
+    /// Returns its visit and whether it has only just arrived.
+    pub fn observe(
+        &mut self,
+        vehicle_key: &str,
+        service_number: &str,
+        vehicle: &str,
+        stop: &str,
+        now: DateTime<Utc>,
+    ) -> (&mut Visit, bool) {
+        let key = (vehicle_key.to_string(), stop.to_string());
+        let arrived = !self.visits.contains_key(&key);
+
+        let visit = self.visits.entry(key).or_insert_with(|| Visit {
+            service_number: service_number.to_string(),
+            vehicle: vehicle.to_string(),
+            stop: stop.to_string(),
+            arrived_at: now,
+            last_seen: now,
+            cycles: 0,
+            history_id: None,
+            alerted: false,
+            during_warmup: false,
+        });
+        visit.last_seen = now;
+        visit.cycles += 1;
+
+        (visit, arrived)
+    }
+
+    /// Ends every visit that wasn't observed at `now`, i.e. buses that have left their stop
+    pub fn take_departures(&mut self, now: DateTime<Utc>) -> Vec<Departure> {
+        let mut left: Vec<(String, String)> = self
+            .visits
+            .iter()
+            .filter(|(_, visit)| visit.last_seen != now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        left.sort(); // The same order every run, not the map's
+
+        left.into_iter()
+            .filter_map(|key| self.visits.remove(&key))
+            .map(|visit| Departure {
+                dwell_secs: (now - visit.arrived_at).num_seconds(),
+                departed_at: now,
+                visit,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::fixed_now;
+    use chrono::Duration;
+
+    #[test]
+    fn a_visit_lasts_from_first_sighting_until_the_first_cycle_without_it() {
+        let mut presence = Presence::default();
+        let (_, arrived) = presence.observe("fleet:1", "7", "fleet 1", "Market Square", fixed_now());
+        assert!(arrived);
+        let (visit, arrived) = presence.observe("fleet:1", "7", "fleet 1", "Market Square", fixed_now() + Duration::seconds(10));
+        assert!(!arrived);
+        visit.alerted = true;
+        assert!(presence.take_departures(fixed_now() + Duration::seconds(10)).is_empty());
+
+        let departures = presence.take_departures(fixed_now() + Duration::seconds(20));
+        assert_eq!(departures.len(), 1);
+        assert_eq!((departures[0].visit.stop.as_str(), departures[0].dwell_secs, departures[0].visit.alerted), ("Market Square", 20, true));
+        assert!(presence.take_departures(fixed_now() + Duration::seconds(30)).is_empty());
+    }
+
+    #[test]
+    fn each_poll_in_range_counts_towards_confirmation_until_the_bus_leaves() {
+        let mut presence = Presence::default();
+        for (secs, cycles) in [(0, 1), (10, 2), (20, 3)] {
+            let (visit, _) = presence.observe("fleet:1", "7", "fleet 1", "Market Square", fixed_now() + Duration::seconds(secs));
+            assert_eq!(visit.cycles, cycles);
+        }
+        presence.take_departures(fixed_now() + Duration::seconds(30));
+
+        // Back after a cycle away: a fresh visit, confirmed from scratch
+        let (visit, arrived) = presence.observe("fleet:1", "7", "fleet 1", "Market Square", fixed_now() + Duration::seconds(40));
+        assert!(arrived);
+        assert_eq!(visit.cycles, 1);
+    }
+}
@@ -0,0 +1,87 @@
+// Errors from the tracker's own operations. Settings helpers still report plain Strings,
+// which become TrackerError::Config at the point a whole component is configured.
+
+use thiserror::Error;
+
+use crate::telegram::SendError;
+
+#[derive(Debug, Error)]
+pub enum TrackerError {
+    /// The request never got a usable HTTP response
+    #[error("HTTP error: {0}")]
+    Http(#[from] reqwest::Error),
+
+    /// A response arrived but wasn't what we expected
+    #[error("Unexpected response: {0}")]
+    Parse(String),
+
+    /// Missing or malformed settings
+    #[error("{0}")]
+    Config(String),
+
+    /// A notification channel refused or failed a send
+    #[error("{0}")]
+    Notify(String),
+}
+
+/// Settings helpers report problems as Strings
+impl From<String> for TrackerError {
+    fn from(e: String) -> TrackerError {
+        TrackerError::Config(e)
+    }
+}
+
+impl From<SendError> for TrackerError {
+    fn from(e: SendError) -> TrackerError {
+        match e {
+            SendError::Http(e) => TrackerError::Http(e),
+            e @ (SendError::Api { .. } | SendError::RateLimited { .. }) => TrackerError::Notify(e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::MockServer;
+    use reqwest::Client;
+    use tokio::time::Instant;
+
+    #[tokio::test]
+    async fn http_failures_and_bad_bodies_are_told_apart() {
+        let server = MockServer::start(vec![(503, "{}".to_string()), (200, "<html>busy</html>".to_string())]);
+        let client = Client::new();
+
+        let error = crate::fetch_json(&client, &server.url, "lat=53").await.unwrap_err();
+        assert!(matches!(&error, TrackerError::Http(e) if e.status() == Some(reqwest::StatusCode::SERVICE_UNAVAILABLE)), "{:?}", error);
+        assert!(error.to_string().starts_with("HTTP error: "), "{}", error);
+
+        let error = crate::fetch_json(&client, &server.url, "lat=53").await.unwrap_err();
+        assert!(matches!(&error, TrackerError::Parse(_)), "{:?}", error);
+        assert!(error.to_string().starts_with("Unexpected response: vehicle API returned invalid JSON: "), "{}", error);
+    }
+
+    #[test]
+    fn settings_errors_are_config_errors() {
+        let error: TrackerError = "Missing LAT.".to_string().into();
+        assert!(matches!(&error, TrackerError::Config(message) if message == "Missing LAT."));
+        assert_eq!(error.to_string(), "Missing LAT.");
+    }
+
+    #[test]
+    fn telegram_refusals_are_notify_errors() {
+        let refused = SendError::Api { code: 403, description: "Forbidden: bot was blocked by the user".to_string(), retry_after: None };
+        let error = TrackerError::from(refused);
+        assert!(matches!(&error, TrackerError::Notify(_)));
+        assert_eq!(error.to_string(), "Forbidden: bot was blocked by the user (error_code 403)");
+
+        let held = TrackerError::from(SendError::RateLimited { until: Instant::now() });
+        assert!(matches!(&held, TrackerError::Notify(message) if message == "held back until Telegram's rate limit passes"));
+    }
+
+    #[tokio::test]
+    async fn telegram_network_errors_stay_http_errors() {
+        let unreachable = Client::new().get("http://127.0.0.1:1/").send().await.unwrap_err();
+        assert!(matches!(TrackerError::from(SendError::Http(unreachable)), TrackerError::Http(_)));
+    }
+}
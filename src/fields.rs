@@ -0,0 +1,140 @@
+// Where each vehicle field lives in the API response (FIELD_MAP), so a renamed key
+// upstream is a config change rather than a silent breakage.
+//
+//     FIELD_MAP="serviceNumber=route,latitude=position.lat,longitude=position.lng"
+//
+// or in the config file:
+//
+//     [field_map]
+//     serviceNumber = "route"
+//     latitude = "position.lat"   # dots reach into nested objects
+
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+use crate::env_or_file;
+
+/// The logical fields that can be remapped; each defaults to the key of the same name
+const FIELDS: [&str; 9] = [
+    "serviceNumber",
+    "serviceDescription",
+    "fleetNumber",
+    "registration",
+    "latitude",
+    "longitude",
+    "speed",
+    "heading",
+    "updateTime",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldMap {
+    pub service_number: String,
+    pub service_description: String,
+    pub fleet_number: String,
+    pub registration: String,
+    pub latitude: String,
+    pub longitude: String,
+    pub speed: String,
+    pub heading: String,
+    pub update_time: String,
+}
+
+impl Default for FieldMap {
+    fn default() -> FieldMap {
+        FieldMap {
+            service_number: "serviceNumber".to_string(),
+            service_description: "serviceDescription".to_string(),
+            fleet_number: "fleetNumber".to_string(),
+            registration: "registration".to_string(),
+            latitude: "latitude".to_string(),
+            longitude: "longitude".to_string(),
+            speed: "speed".to_string(),
+            heading: "heading".to_string(),
+            update_time: "updateTime".to_string(),
+        }
+    }
+}
+
+impl FieldMap {
+    /// Applies the config file's `[field_map]`, then FIELD_MAP on top of it
+    pub fn from_env(file: &BTreeMap<String, String>) -> Result<FieldMap, String> {
+        let mut map = FieldMap::default();
+        for (field, key) in file {
+            map.set(field, key)?;
+        }
+
+        if let Some(value) = env_or_file("FIELD_MAP")? {
+            for entry in value.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+                let (field, key) = entry
+                    .split_once('=')
+                    .ok_or_else(|| format!("FIELD_MAP entries must look like field=key, got '{}'.", entry))?;
+                map.set(field.trim(), key.trim())?;
+            }
+        }
+
+        Ok(map)
+    }
+
+    fn set(&mut self, field: &str, key: &str) -> Result<(), String> {
+        if key.is_empty() {
+            return Err(format!("The field map gives '{}' an empty key.", field));
+        }
+        let slot = match field {
+            "serviceNumber" => &mut self.service_number,
+            "serviceDescription" => &mut self.service_description,
+            "fleetNumber" => &mut self.fleet_number,
+            "registration" => &mut self.registration,
+            "latitude" => &mut self.latitude,
+            "longitude" => &mut self.longitude,
+            "speed" => &mut self.speed,
+            "heading" => &mut self.heading,
+            "updateTime" => &mut self.update_time,
+            _ => return Err(format!("Unknown field '{}' in the field map; expected one of {}.", field, FIELDS.join(", "))),
+        };
+        *slot = key.to_string();
+        Ok(())
+    }
+}
+
+/// Looks up `key` in a vehicle, following dots into nested objects. Missing keys give Null.
+pub fn get<'a>(vehicle: &'a Value, key: &str) -> &'a Value {
+    if let Some(value) = vehicle.get(key) {
+        return value; // Keys that really contain a dot win over nesting
+    }
+    key.split('.').fold(vehicle, |value, part| &value[part])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::with_env;
+    use serde_json::json;
+
+    #[test]
+    fn field_map_applies_the_file_then_the_environment() {
+        let file = BTreeMap::from([("latitude".to_string(), "lat".to_string()), ("speed".to_string(), "kmh".to_string())]);
+        let map = with_env(&[("FIELD_MAP", " latitude = position.lat ,, updateTime=recordedAt ")], || FieldMap::from_env(&file)).unwrap();
+        assert_eq!(map.latitude, "position.lat");
+        assert_eq!(map.speed, "kmh");
+        assert_eq!(map.update_time, "recordedAt");
+        assert_eq!(map.longitude, "longitude");
+    }
+
+    #[test]
+    fn field_map_rejects_unknown_fields_and_malformed_entries() {
+        let error = |value: &str| with_env(&[("FIELD_MAP", value)], || FieldMap::from_env(&BTreeMap::new())).unwrap_err();
+        assert!(error("colour=livery").starts_with("Unknown field 'colour' in the field map; expected one of serviceNumber, "));
+        assert_eq!(error("latitude"), "FIELD_MAP entries must look like field=key, got 'latitude'.");
+        assert_eq!(error("latitude="), "The field map gives 'latitude' an empty key.");
+    }
+
+    #[test]
+    fn keys_reach_into_nested_objects_unless_a_dotted_key_exists() {
+        let vehicle = json!({ "position": { "lat": "53.1" }, "position.lat": "53.2", "vehicle": { "fleet": 7 } });
+        assert_eq!(get(&vehicle, "position.lat"), "53.2");
+        assert_eq!(get(&vehicle, "vehicle.fleet"), 7);
+        assert!(get(&vehicle, "vehicle.reg").is_null());
+        assert!(get(&vehicle, "missing.deeper").is_null());
+    }
+}
@@ -0,0 +1,230 @@
+// Human-facing formatting of distances and times (UNITS, TIME_FORMAT, TIMEZONE).
+// Anything meant for machines should stay in meters and RFC 3339 instead.
+
+use chrono::{DateTime, Utc};
+
+use crate::timezone::Zone;
+
+const METERS_PER_YARD: f64 = 0.9144;
+const YARDS_PER_MILE: f64 = 1760.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Units {
+    Metric,   // meters, then kilometers
+    Imperial, // yards, then miles
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeFormat {
+    H24, // 08:45
+    H12, // 8:45 AM
+}
+
+/// How ETAs are shown (ETA_FORMAT)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EtaFormat {
+    Minutes, // Whole minutes, rounded up: "3 min"
+    Decimal, // Half-minute steps: "2.5 min"
+    Seconds, // Seconds when under two minutes ("45 s"), whole minutes above
+}
+
+/// How times and distances are shown to people
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Style {
+    pub zone: Zone,
+    pub units: Units,
+    pub time_format: TimeFormat,
+    pub eta_format: EtaFormat,
+    pub eta_cap_secs: f64, // Longer ETAs show as "10+ min" (ETA_MAX_MINS)
+}
+
+impl Style {
+    pub fn from_env() -> Result<Style, String> {
+        let units = match crate::env_or_file("UNITS")?.unwrap_or_default().trim().to_ascii_lowercase().as_str() {
+            "" | "metric" => Units::Metric,
+            "imperial" => Units::Imperial,
+            other => return Err(format!("UNITS must be metric or imperial, got '{}'.", other)),
+        };
+        let time_format = match crate::env_or_file("TIME_FORMAT")?.unwrap_or_default().trim().to_ascii_lowercase().as_str() {
+            "" | "24h" => TimeFormat::H24,
+            "12h" => TimeFormat::H12,
+            other => return Err(format!("TIME_FORMAT must be 24h or 12h, got '{}'.", other)),
+        };
+
+        let eta_format = match crate::env_or_file("ETA_FORMAT")?.unwrap_or_default().trim().to_ascii_lowercase().as_str() {
+            "" | "minutes" => EtaFormat::Minutes,
+            "decimal" => EtaFormat::Decimal,
+            "seconds" => EtaFormat::Seconds,
+            other => return Err(format!("ETA_FORMAT must be minutes, decimal or seconds, got '{}'.", other)),
+        };
+        let eta_max_mins: u32 = crate::env_parse("ETA_MAX_MINS", 10)?;
+
+        Ok(Style {
+            zone: Zone::from_env()?,
+            units,
+            time_format,
+            eta_format,
+            eta_cap_secs: f64::from(eta_max_mins) * 60.0,
+        })
+    }
+
+    /// Time of day, e.g. "08:45" or "8:45 AM"
+    pub fn time(&self, t: DateTime<Utc>) -> String {
+        let pattern = match self.time_format {
+            TimeFormat::H24 => "%H:%M",
+            TimeFormat::H12 => "%-I:%M %p",
+        };
+        self.zone.format(t, pattern)
+    }
+
+    /// Time of day with seconds, e.g. "08:45:10" or "8:45:10 AM"
+    pub fn time_with_seconds(&self, t: DateTime<Utc>) -> String {
+        let pattern = match self.time_format {
+            TimeFormat::H24 => "%H:%M:%S",
+            TimeFormat::H12 => "%-I:%M:%S %p",
+        };
+        self.zone.format(t, pattern)
+    }
+
+    pub fn distance(&self, meters: f64) -> String {
+        format_distance(meters, self.units)
+    }
+
+    pub fn eta(&self, secs: f64) -> String {
+        if secs > self.eta_cap_secs {
+            return format!("{}+ min", (self.eta_cap_secs / 60.0).round());
+        }
+        format_eta(secs, self.eta_format)
+    }
+}
+
+/// Formats an ETA in the given style, without any cap
+pub fn format_eta(secs: f64, fmt: EtaFormat) -> String {
+    let secs = secs.max(0.0);
+    match fmt {
+        EtaFormat::Seconds if secs < 120.0 => format!("{} s", ((secs / 5.0).round() * 5.0).max(5.0)),
+        EtaFormat::Minutes | EtaFormat::Seconds => format!("{} min", (secs / 60.0).ceil().max(1.0)),
+        EtaFormat::Decimal => format!("{} min", ((secs / 30.0).round() / 2.0).max(0.5)),
+    }
+}
+
+/// Formats a duration compactly: "40 s", "2 min 5 s", "1 h 3 min"
+pub fn format_duration(secs: i64) -> String {
+    let secs = secs.max(0);
+    match secs {
+        0..=59 => format!("{} s", secs),
+        60..=3599 if secs % 60 == 0 => format!("{} min", secs / 60),
+        60..=3599 => format!("{} min {} s", secs / 60, secs % 60),
+        _ => format!("{} h {} min", secs / 3600, (secs % 3600) / 60),
+    }
+}
+
+/// Formats a distance with rounding that suits the size: "140 m" / "1.2 km" or "150 yd" / "0.8 mi"
+pub fn format_distance(meters: f64, units: Units) -> String {
+    match units {
+        Units::Metric if meters < 1000.0 => format!("{} m", round_to(meters, 10.0)),
+        Units::Metric => format!("{:.1} km", meters / 1000.0),
+        Units::Imperial => {
+            let yards = meters / METERS_PER_YARD;
+            // Below a quarter mile yards read better than "0.1 mi"
+            if yards < YARDS_PER_MILE / 4.0 {
+                format!("{} yd", round_to(yards, 10.0))
+            } else {
+                format!("{:.1} mi", yards / YARDS_PER_MILE)
+            }
+        }
+    }
+}
+
+// Rounds to the nearest multiple of `step`, but never shows 0 for a non-zero distance
+fn round_to(value: f64, step: f64) -> i64 {
+    let rounded = ((value / step).round() * step) as i64;
+    if rounded == 0 && value > 0.0 {
+        value.ceil() as i64
+    } else {
+        rounded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::with_env;
+    use chrono::TimeZone;
+
+    fn style(units: Units, time_format: TimeFormat) -> Style {
+        Style { zone: Zone::Named(chrono_tz::Europe::London), units, time_format, eta_format: EtaFormat::Minutes, eta_cap_secs: 600.0 }
+    }
+
+    #[test]
+    fn distances_in_metric_and_imperial() {
+        let cases = [
+            (0.0, "0 m", "0 yd"),
+            (3.0, "3 m", "4 yd"),
+            (144.0, "140 m", "160 yd"),
+            (999.0, "1000 m", "0.6 mi"),
+            (1234.0, "1.2 km", "0.8 mi"),
+            (402.0, "400 m", "440 yd"),
+            (403.0, "400 m", "0.3 mi"), // Just past a quarter mile
+        ];
+        for (meters, metric, imperial) in cases {
+            assert_eq!(format_distance(meters, Units::Metric), metric, "{} m", meters);
+            assert_eq!(format_distance(meters, Units::Imperial), imperial, "{} m", meters);
+        }
+    }
+
+    #[test]
+    fn times_in_24_and_12_hour_clocks() {
+        let t = Utc.with_ymd_and_hms(2024, 7, 1, 12, 45, 10).unwrap(); // 13:45:10 in London
+        assert_eq!(style(Units::Metric, TimeFormat::H24).time(t), "13:45");
+        assert_eq!(style(Units::Metric, TimeFormat::H24).time_with_seconds(t), "13:45:10");
+        assert_eq!(style(Units::Metric, TimeFormat::H12).time(t), "1:45 PM");
+        assert_eq!(style(Units::Metric, TimeFormat::H12).time_with_seconds(Utc.with_ymd_and_hms(2024, 7, 1, 7, 0, 0).unwrap()), "8:00:00 AM");
+    }
+
+    #[test]
+    fn durations_read_compactly() {
+        let cases = [(-5, "0 s"), (40, "40 s"), (60, "1 min"), (125, "2 min 5 s"), (3600, "1 h 0 min"), (3780, "1 h 3 min")];
+        for (secs, text) in cases {
+            assert_eq!(format_duration(secs), text, "{} s", secs);
+        }
+    }
+
+    #[test]
+    fn etas_in_each_format() {
+        let cases = [
+            (0.0, "1 min", "0.5 min", "5 s"),
+            (44.0, "1 min", "0.5 min", "45 s"),
+            (61.0, "2 min", "1 min", "60 s"),
+            (119.0, "2 min", "2 min", "120 s"),
+            (150.0, "3 min", "2.5 min", "3 min"),
+            (-10.0, "1 min", "0.5 min", "5 s"),
+        ];
+        for (secs, minutes, decimal, seconds) in cases {
+            assert_eq!(format_eta(secs, EtaFormat::Minutes), minutes, "{} s", secs);
+            assert_eq!(format_eta(secs, EtaFormat::Decimal), decimal, "{} s", secs);
+            assert_eq!(format_eta(secs, EtaFormat::Seconds), seconds, "{} s", secs);
+        }
+    }
+
+    #[test]
+    fn etas_past_the_cap_show_as_a_floor() {
+        let capped = with_env(&[("ETA_FORMAT", "decimal"), ("ETA_MAX_MINS", "15")], Style::from_env).unwrap();
+        assert_eq!(capped.eta(900.0), "15 min");
+        assert_eq!(capped.eta(901.0), "15+ min");
+        assert_eq!(capped.eta(36_000.0), "15+ min");
+        assert_eq!(style(Units::Metric, TimeFormat::H24).eta(3000.0), "10+ min");
+        assert_eq!(
+            with_env(&[("ETA_FORMAT", "hours")], Style::from_env),
+            Err("ETA_FORMAT must be minutes, decimal or seconds, got 'hours'.".to_string())
+        );
+    }
+
+    #[test]
+    fn style_settings_are_read_case_insensitively_and_checked() {
+        let style = with_env(&[("UNITS", " Imperial "), ("TIME_FORMAT", "12H")], Style::from_env).unwrap();
+        assert_eq!((style.units, style.time_format), (Units::Imperial, TimeFormat::H12));
+        assert_eq!(with_env(&[("UNITS", "furlongs")], Style::from_env), Err("UNITS must be metric or imperial, got 'furlongs'.".to_string()));
+        assert_eq!(with_env(&[("TIME_FORMAT", "13h")], Style::from_env), Err("TIME_FORMAT must be 24h or 12h, got '13h'.".to_string()));
+    }
+}
@@ -0,0 +1,242 @@
+// Distances between positions and the stops near them. Kept free of the rest of the crate (it
+// works on anything `Located`) so the benches under benches/ can build it on its own, as is
+// stop_index.rs.
+
+use std::f64::consts::PI;
+
+const EARTH_RADIUS: f64 = 6371e3; // meters
+
+/// Stop lists at least this long have their distances computed in parallel (with the `parallel` feature).
+/// From benches/parallel.rs with rayon held to one core, which leaves just its overhead: for one
+/// vehicle's closest_stop that was ~7 µs at 100 stops and ~13 µs at 300, as much as the serial pass
+/// itself (4 and 13 µs), so two cores couldn't win it back. At 1000 stops the serial pass takes 47 µs
+/// and the overhead is lost in the noise, so splitting it in two saves ~20 µs per vehicle.
+/// nearest_stop_batch's overhead is smaller (under 10 µs for 100 vehicles); it keeps the same limit
+/// so every pass switches together.
+#[cfg(feature = "parallel")]
+pub const PARALLEL_MIN_STOPS: usize = 1000;
+
+/// Something with a position and a radius around it, in meters
+pub trait Located {
+    fn lat(&self) -> f64;
+    fn lng(&self) -> f64;
+    fn radius(&self) -> f64;
+}
+
+/// Haversine formula to calculate the distance (in meters) between two latitude/longitude points
+pub fn haversine_distance(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    // Convert degrees to radians
+    let lat1_rad = lat1 * PI / 180.0;
+    let lat2_rad = lat2 * PI / 180.0;
+    let delta_lat = (lat2 - lat1) * PI / 180.0;
+    let delta_lon = (lon2 - lon1) * PI / 180.0;
+
+    // Haversine formula
+    let a = f64::sin(delta_lat / 2.0).powi(2)
+        + f64::cos(lat1_rad) * f64::cos(lat2_rad) * f64::sin(delta_lon / 2.0).powi(2);
+    let c = 2.0 * f64::atan2(f64::sqrt(a), f64::sqrt(1.0 - a));
+
+    // Distance in meters
+    EARTH_RADIUS * c
+}
+
+/// The closest stop to a position regardless of radius, with its distance in meters
+pub fn closest_stop<T: Located + Sync>(lat: f64, lng: f64, stops: &[T]) -> Option<(&T, f64)> {
+    #[cfg(feature = "parallel")]
+    if stops.len() >= PARALLEL_MIN_STOPS {
+        return closest_stop_parallel(lat, lng, stops);
+    }
+    closest_stop_serial(lat, lng, stops)
+}
+
+pub fn closest_stop_serial<T: Located>(lat: f64, lng: f64, stops: &[T]) -> Option<(&T, f64)> {
+    stops
+        .iter()
+        .map(|stop| (stop, haversine_distance(lat, lng, stop.lat(), stop.lng())))
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+}
+
+#[cfg(feature = "parallel")]
+pub fn closest_stop_parallel<T: Located + Sync>(lat: f64, lng: f64, stops: &[T]) -> Option<(&T, f64)> {
+    use rayon::prelude::*;
+
+    // Ties resolve to the earlier stop, as in the serial path, since rayon reduces in order
+    stops
+        .par_iter()
+        .map(|stop| (stop, haversine_distance(lat, lng, stop.lat(), stop.lng())))
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+}
+
+/// The first stop, in list order, whose radius contains the position, with its distance in meters
+pub fn first_stop_within<T: Located + Sync>(lat: f64, lng: f64, stops: &[T]) -> Option<(&T, f64)> {
+    #[cfg(feature = "parallel")]
+    if stops.len() >= PARALLEL_MIN_STOPS {
+        return first_stop_within_parallel(lat, lng, stops);
+    }
+    first_stop_within_serial(lat, lng, stops)
+}
+
+pub fn first_stop_within_serial<T: Located>(lat: f64, lng: f64, stops: &[T]) -> Option<(&T, f64)> {
+    stops.iter().find_map(|stop| {
+        let distance = haversine_distance(lat, lng, stop.lat(), stop.lng());
+        (distance <= stop.radius()).then_some((stop, distance))
+    })
+}
+
+#[cfg(feature = "parallel")]
+pub fn first_stop_within_parallel<T: Located + Sync>(lat: f64, lng: f64, stops: &[T]) -> Option<(&T, f64)> {
+    use rayon::prelude::*;
+
+    // find_map_first keeps the serial answer: the first stop in list order whose radius contains the position
+    stops.par_iter().find_map_first(|stop| {
+        let distance = haversine_distance(lat, lng, stop.lat(), stop.lng());
+        (distance <= stop.radius()).then_some((stop, distance))
+    })
+}
+
+/// The closest stop to each of many positions, as `closest_stop` gives it (same distances, same
+/// tie-breaks), with each stop's trig worked out once rather than once per position: about 14%
+/// quicker than calling `closest_stop` for each of 100 vehicles (benches/nearest_stop.rs)
+pub fn nearest_stop_batch<'a, T: Located + Sync>(positions: &[(f64, f64)], stops: &'a [T]) -> Vec<Option<(&'a T, f64)>> {
+    #[cfg(feature = "parallel")]
+    if stops.len() >= PARALLEL_MIN_STOPS {
+        return nearest_stop_batch_parallel(positions, stops);
+    }
+    nearest_stop_batch_serial(positions, stops)
+}
+
+pub fn nearest_stop_batch_serial<'a, T: Located>(positions: &[(f64, f64)], stops: &'a [T]) -> Vec<Option<(&'a T, f64)>> {
+    let cos_lats = cos_lats(stops);
+    positions.iter().map(|&(lat, lng)| nearest_of(lat, lng, stops, &cos_lats)).collect()
+}
+
+#[cfg(feature = "parallel")]
+pub fn nearest_stop_batch_parallel<'a, T: Located + Sync>(positions: &[(f64, f64)], stops: &'a [T]) -> Vec<Option<(&'a T, f64)>> {
+    use rayon::prelude::*;
+
+    let cos_lats = cos_lats(stops);
+    positions.par_iter().map(|&(lat, lng)| nearest_of(lat, lng, stops, &cos_lats)).collect()
+}
+
+fn cos_lats<T: Located>(stops: &[T]) -> Vec<f64> {
+    stops.iter().map(|stop| f64::cos(stop.lat() * PI / 180.0)).collect()
+}
+
+/// `closest_stop_serial` with the stops' latitude cosines worked out already
+fn nearest_of<'a, T: Located>(lat: f64, lng: f64, stops: &'a [T], cos_lats: &[f64]) -> Option<(&'a T, f64)> {
+    let cos_lat = f64::cos(lat * PI / 180.0);
+    stops
+        .iter()
+        .zip(cos_lats)
+        .map(|(stop, cos_stop_lat)| {
+            let delta_lat = (stop.lat() - lat) * PI / 180.0;
+            let delta_lon = (stop.lng() - lng) * PI / 180.0;
+            let a = f64::sin(delta_lat / 2.0).powi(2) + cos_lat * cos_stop_lat * f64::sin(delta_lon / 2.0).powi(2);
+            let c = 2.0 * f64::atan2(f64::sqrt(a), f64::sqrt(1.0 - a));
+            (stop, EARTH_RADIUS * c)
+        })
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{RngExt, SeedableRng};
+
+    struct Stop(f64, f64);
+
+    impl Located for Stop {
+        fn lat(&self) -> f64 {
+            self.0
+        }
+        fn lng(&self) -> f64 {
+            self.1
+        }
+        fn radius(&self) -> f64 {
+            200.0
+        }
+    }
+
+    /// `n` positions within about 20 km of 53.0,-1.5
+    fn positions(rng: &mut StdRng, n: usize) -> Vec<(f64, f64)> {
+        (0..n).map(|_| (53.0 + rng.random_range(-0.2..0.2), -1.5 + rng.random_range(-0.3..0.3))).collect()
+    }
+
+    #[test]
+    fn closest_and_first_within_break_ties_by_list_order() {
+        // Two stops at the same spot, the second also within reach
+        let stops = [Stop(53.01, -1.5), Stop(53.0, -1.5), Stop(53.0, -1.5), Stop(53.0005, -1.5)];
+        assert!(std::ptr::eq(closest_stop(53.0001, -1.5, &stops).unwrap().0, &stops[1]));
+        assert!(std::ptr::eq(first_stop_within(53.0004, -1.5, &stops).unwrap().0, &stops[1]));
+        assert!(first_stop_within(53.005, -1.5, &stops).is_none());
+        assert!(closest_stop(53.0, -1.5, &[] as &[Stop]).is_none());
+    }
+
+    #[test]
+    fn batch_matches_closest_stop_at_random_positions() {
+        let mut rng = StdRng::seed_from_u64(141);
+        for stop_count in [1, 2, 50, 400] {
+            let stops: Vec<Stop> = positions(&mut rng, stop_count).into_iter().map(|(lat, lng)| Stop(lat, lng)).collect();
+            let buses = positions(&mut rng, 200);
+            for (&(lat, lng), batched) in buses.iter().zip(nearest_stop_batch(&buses, &stops)) {
+                let (expected, expected_distance) = closest_stop(lat, lng, &stops).unwrap();
+                let (stop, distance) = batched.unwrap();
+                assert!(std::ptr::eq(stop, expected), "different stop for {}, {}", lat, lng);
+                assert!((distance - expected_distance).abs() < 1e-6, "{} vs {} m for {}, {}", distance, expected_distance, lat, lng);
+            }
+        }
+    }
+
+    #[test]
+    fn batch_breaks_ties_like_closest_stop() {
+        // Two stops at the same spot, and a bus exactly between two others
+        let stops = [Stop(53.0, -1.5), Stop(53.0, -1.5), Stop(53.01, -1.49), Stop(53.01, -1.51)];
+        let buses = [(53.001, -1.5), (53.01, -1.5)];
+        let batched = nearest_stop_batch(&buses, &stops);
+        for (&(lat, lng), batched) in buses.iter().zip(batched) {
+            let (expected, _) = closest_stop(lat, lng, &stops).unwrap();
+            assert!(std::ptr::eq(batched.unwrap().0, expected));
+        }
+        assert!(std::ptr::eq(closest_stop(53.001, -1.5, &stops).unwrap().0, &stops[0]));
+    }
+
+    #[test]
+    fn no_stops_no_nearest() {
+        let stops: [Stop; 0] = [];
+        assert_eq!(nearest_stop_batch(&[(53.0, -1.5)], &stops).len(), 1);
+        assert!(nearest_stop_batch(&[(53.0, -1.5)], &stops)[0].is_none());
+        assert!(closest_stop(53.0, -1.5, &stops).is_none());
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn parallel_and_serial_agree() {
+        let mut rng = StdRng::seed_from_u64(123);
+        let mut stops: Vec<Stop> = positions(&mut rng, PARALLEL_MIN_STOPS * 3).into_iter().map(|(lat, lng)| Stop(lat, lng)).collect();
+        // Ties: the same stop again later in the list, where the serial pass never picks it
+        for i in (0..stops.len()).step_by(7) {
+            stops.push(Stop(stops[i].0, stops[i].1));
+        }
+        let buses = positions(&mut rng, 300);
+
+        for &(lat, lng) in &buses {
+            let serial = closest_stop_serial(lat, lng, &stops).unwrap();
+            let parallel = closest_stop_parallel(lat, lng, &stops).unwrap();
+            assert!(std::ptr::eq(parallel.0, serial.0));
+            assert_eq!(parallel.1, serial.1);
+
+            match (first_stop_within_parallel(lat, lng, &stops), first_stop_within_serial(lat, lng, &stops)) {
+                (Some(parallel), Some(serial)) => assert!(std::ptr::eq(parallel.0, serial.0)),
+                (parallel, serial) => assert_eq!(parallel.is_some(), serial.is_some()),
+            }
+        }
+        let serial = nearest_stop_batch_serial(&buses, &stops);
+        for (parallel, serial) in nearest_stop_batch_parallel(&buses, &stops).into_iter().zip(&serial) {
+            assert!(std::ptr::eq(parallel.unwrap().0, serial.unwrap().0));
+            assert_eq!(parallel.unwrap().1, serial.unwrap().1);
+        }
+        let found = buses.iter().filter(|&&(lat, lng)| first_stop_within_serial(lat, lng, &stops).is_some()).count();
+        assert!(found > 50, "only {} of the positions were at a stop", found);
+    }
+}
@@ -0,0 +1,112 @@
+// Gotify push notifications: each alert is POSTed to <GOTIFY_URL>/message with an application token
+
+use reqwest::Client;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use tracing::warn;
+
+use crate::config_file::{group_destinations, GroupEntry};
+use crate::error::TrackerError;
+use crate::notify::AlertKind;
+use crate::telegram::SendError;
+use crate::{env_flag, env_or_file, http_client_builder};
+
+/// Priorities on Gotify's 0-10 scale. The Android app pops up 8 and above and plays a sound from 4.
+const ARRIVAL_PRIORITY: u8 = 8;
+const EARLY_WARNING_PRIORITY: u8 = 5;
+const NOTICE_PRIORITY: u8 = 3; // Departures and outages
+const NOTICE_TITLE: &str = "Bus tracker";
+
+#[derive(Debug, Clone)]
+pub struct Gotify {
+    client: Client,
+    url: String, // The /message endpoint
+    token: String,
+    group_tokens: HashMap<String, String>, // Stop group -> the application its alerts go to
+}
+
+impl Gotify {
+    /// Returns None when GOTIFY_URL isn't set, leaving Gotify disabled
+    pub fn from_env(client: Client, groups: &BTreeMap<String, GroupEntry>) -> Result<Option<Gotify>, TrackerError> {
+        let base_url = match env_or_file("GOTIFY_URL")? {
+            Some(url) if !url.trim().is_empty() => url.trim().trim_end_matches('/').to_string(),
+            _ => return Ok(None),
+        };
+        let token = env_or_file("GOTIFY_TOKEN")?.ok_or_else(|| "GOTIFY_URL is set but GOTIFY_TOKEN isn't.".to_string())?;
+
+        // Self-hosted servers often have self-signed certificates. Only this notifier's client skips
+        // the check; Telegram and the bus API keep verifying.
+        let client = if env_flag("GOTIFY_INSECURE_SKIP_VERIFY", false)? {
+            warn!("Not verifying Gotify's TLS certificate (GOTIFY_INSECURE_SKIP_VERIFY)");
+            http_client_builder()?
+                .danger_accept_invalid_certs(true)
+                .build()
+                .map_err(|e| format!("Could not set up the Gotify HTTP client: {}", e))?
+        } else {
+            client
+        };
+
+        let group_tokens = group_destinations(groups, |group| group.gotify_token.clone().filter(|token| !token.trim().is_empty()));
+        Ok(Some(Gotify { client, url: format!("{}/message", base_url), token, group_tokens }))
+    }
+
+    /// Title and priority for an alert at `stop`
+    pub fn alert_headline(stop: &str, kind: AlertKind) -> (String, u8) {
+        let priority = match kind {
+            AlertKind::Arrival => ARRIVAL_PRIORITY,
+            AlertKind::EarlyWarning => EARLY_WARNING_PRIORITY,
+        };
+        (format!("Bus near {}", stop), priority)
+    }
+
+    /// Title and priority for a plain notice
+    pub fn notice_headline() -> (String, u8) {
+        (NOTICE_TITLE.to_string(), NOTICE_PRIORITY)
+    }
+
+    /// Sends with `group`'s application token, or GOTIFY_TOKEN for ungrouped alerts and notices
+    pub async fn send(&self, group: Option<&str>, title: &str, text: &str, priority: u8) -> Result<(), SendError> {
+        let token = group.and_then(|group| self.group_tokens.get(group)).unwrap_or(&self.token);
+        let response = self
+            .client
+            .post(&self.url)
+            .header("X-Gotify-Key", token)
+            .json(&json!({ "title": title, "message": text, "priority": priority }))
+            .send()
+            .await?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+
+        // Errors come back as {"error": "Unauthorized", "errorCode": 401, "errorDescription": "..."}
+        let body: Value = response.json().await.unwrap_or_default();
+        Err(SendError::Api {
+            code: i64::from(status.as_u16()),
+            description: body["errorDescription"].as_str().unwrap_or(status.as_str()).to_string(),
+            retry_after: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{with_env, MockServer};
+
+    #[tokio::test]
+    async fn group_alerts_use_the_groups_own_token() {
+        let server = MockServer::start(vec![(200, "{}".to_string())]);
+        let groups = BTreeMap::from([("home".to_string(), GroupEntry { gotify_token: Some("home-token".to_string()), ..GroupEntry::default() })]);
+        let vars = [("GOTIFY_URL", server.url.as_str()), ("GOTIFY_TOKEN", "main-token")];
+        let gotify = with_env(&vars, || Gotify::from_env(Client::new(), &groups)).unwrap().unwrap();
+
+        let (title, priority) = Gotify::alert_headline("Home", AlertKind::Arrival);
+        gotify.send(Some("home"), &title, "Bus 7", priority).await.unwrap();
+        gotify.send(Some("gym"), &title, "Bus 9", priority).await.unwrap();
+        let keys: Vec<String> = server.requests().iter().map(|request| request.header("x-gotify-key").unwrap().to_string()).collect();
+        assert_eq!(keys, ["home-token", "main-token"]);
+        let body: Value = serde_json::from_str(&server.requests()[0].body).unwrap();
+        assert_eq!(body, json!({ "title": "Bus near Home", "message": "Bus 7", "priority": ARRIVAL_PRIORITY }));
+    }
+}
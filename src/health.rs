@@ -0,0 +1,169 @@
+// Tracks consecutive failed API cycles so outages are reported once, and eventually fatal
+
+use std::time::Instant;
+
+use crate::env_parse;
+use crate::format::format_duration;
+
+/// What the caller should do after recording a cycle's outcome
+#[derive(Debug, Clone, PartialEq)]
+pub enum FailureEvent {
+    /// The alert threshold was just reached: tell the user the API is unreachable
+    Outage { failures: u32, last_error: String },
+    /// The exit threshold was reached: give up so a supervisor can react
+    GiveUp { failures: u32, last_error: String },
+    /// A cycle succeeded after an outage was reported
+    Recovered { failures: u32, down_secs: u64 },
+}
+
+#[derive(Debug)]
+pub struct FailureTracker {
+    consecutive: u32,
+    first_failure: Option<Instant>, // Start of the current run of failures
+    outage_reported: bool,
+    alert_after: u32,
+    exit_after: u32,
+}
+
+impl FailureTracker {
+    pub fn new(alert_after: u32, exit_after: u32) -> FailureTracker {
+        FailureTracker {
+            consecutive: 0,
+            first_failure: None,
+            outage_reported: false,
+            alert_after: alert_after.max(1),
+            exit_after: exit_after.max(1),
+        }
+    }
+
+    /// Reads `API_FAILURE_ALERT_THRESHOLD` (default 3) and `API_FAILURE_EXIT_THRESHOLD` (default 30)
+    pub fn from_env() -> Result<FailureTracker, String> {
+        Ok(FailureTracker::new(
+            env_parse("API_FAILURE_ALERT_THRESHOLD", 3)?,
+            env_parse("API_FAILURE_EXIT_THRESHOLD", 30)?,
+        ))
+    }
+
+    pub fn record_failure(&mut self, error: &str) -> Option<FailureEvent> {
+        self.consecutive += 1;
+        self.first_failure.get_or_insert_with(Instant::now);
+
+        if self.consecutive >= self.exit_after {
+            return Some(FailureEvent::GiveUp {
+                failures: self.consecutive,
+                last_error: error.to_string(),
+            });
+        }
+
+        if self.consecutive >= self.alert_after && !self.outage_reported {
+            self.outage_reported = true;
+            return Some(FailureEvent::Outage {
+                failures: self.consecutive,
+                last_error: error.to_string(),
+            });
+        }
+
+        None
+    }
+
+    /// Failed cycles since the last success
+    pub fn consecutive(&self) -> u32 {
+        self.consecutive
+    }
+
+    pub fn record_success(&mut self) -> Option<FailureEvent> {
+        let failures = self.consecutive;
+        let reported = self.outage_reported;
+        let down_secs = self.first_failure.take().map_or(0, |at| at.elapsed().as_secs());
+        self.consecutive = 0;
+        self.outage_reported = false;
+
+        reported.then_some(FailureEvent::Recovered { failures, down_secs })
+    }
+}
+
+impl FailureEvent {
+    /// One-line notification text for this transition
+    pub fn message(&self) -> String {
+        match self {
+            FailureEvent::Outage { failures, last_error } => format!(
+                "Tracker is unable to reach the Stagecoach API ({} failed attempts, last error: {})",
+                failures, last_error
+            ),
+            FailureEvent::GiveUp { failures, last_error } => format!(
+                "Tracker is giving up after {} failed attempts to reach the Stagecoach API (last error: {})",
+                failures, last_error
+            ),
+            FailureEvent::Recovered { failures, down_secs } => format!(
+                "Tracker reached the Stagecoach API again after {} failed attempts ({} without bus data)",
+                failures,
+                format_duration(*down_secs as i64)
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds `results` (Err for a failed cycle) through a tracker, returning what each one reported
+    fn events(tracker: &mut FailureTracker, results: &[Result<(), &str>]) -> Vec<Option<FailureEvent>> {
+        results
+            .iter()
+            .map(|result| match result {
+                Ok(()) => tracker.record_success(),
+                Err(e) => tracker.record_failure(e),
+            })
+            .collect()
+    }
+
+    fn outage(failures: u32, last_error: &str) -> Option<FailureEvent> {
+        Some(FailureEvent::Outage { failures, last_error: last_error.to_string() })
+    }
+
+    #[test]
+    fn an_outage_is_reported_once_at_the_threshold_then_its_recovery() {
+        let mut tracker = FailureTracker::new(3, 30);
+        let results = [Ok(()), Err("timeout"), Err("timeout"), Err("HTTP 503"), Err("timeout"), Err("timeout"), Ok(()), Ok(())];
+        assert_eq!(
+            events(&mut tracker, &results),
+            [None, None, None, outage(3, "HTTP 503"), None, None, Some(FailureEvent::Recovered { failures: 5, down_secs: 0 }), None]
+        );
+        assert_eq!(tracker.consecutive(), 0);
+
+        // The next outage is reported afresh
+        assert_eq!(events(&mut tracker, &[Err("a"), Err("b"), Err("c")]), [None, None, outage(3, "c")]);
+    }
+
+    #[test]
+    fn failures_short_of_the_threshold_report_nothing_either_way() {
+        let mut tracker = FailureTracker::new(3, 30);
+        let results = [Err("timeout"), Err("timeout"), Ok(()), Err("timeout"), Err("timeout"), Ok(())];
+        assert!(events(&mut tracker, &results).iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn enough_failures_give_up_every_cycle_from_the_exit_threshold() {
+        let mut tracker = FailureTracker::new(2, 4);
+        let results = [Err("a"), Err("b"), Err("c"), Err("d"), Err("e")];
+        let give_up = |failures, last_error: &str| Some(FailureEvent::GiveUp { failures, last_error: last_error.to_string() });
+        assert_eq!(events(&mut tracker, &results), [None, outage(2, "b"), None, give_up(4, "d"), give_up(5, "e")]);
+
+        // Thresholds of zero count as one
+        let mut tracker = FailureTracker::new(0, 0);
+        assert_eq!(events(&mut tracker, &[Err("a")]), [give_up(1, "a")]);
+    }
+
+    #[test]
+    fn messages_name_the_attempts_and_the_error() {
+        assert_eq!(
+            outage(3, "HTTP 503").unwrap().message(),
+            "Tracker is unable to reach the Stagecoach API (3 failed attempts, last error: HTTP 503)"
+        );
+        assert_eq!(
+            FailureEvent::Recovered { failures: 5, down_secs: 150 }.message(),
+            "Tracker reached the Stagecoach API again after 5 failed attempts (2 min 30 s without bus data)"
+        );
+    }
+}
@@ -0,0 +1,204 @@
+// `report heatmap`: bins the positions recorded with HISTORY_POSITIONS into a grid of roughly
+// square cells and prints the count per cell as CSV or GeoJSON polygons.
+
+use chrono::{Days, NaiveDate, NaiveTime};
+use clap::{Args, ValueEnum};
+use serde_json::json;
+use std::collections::HashMap;
+
+use crate::format::Style;
+use crate::open_history;
+
+const METERS_PER_DEGREE_LAT: f64 = 111_320.0;
+
+#[derive(Debug, Args)]
+pub struct HeatmapArgs {
+    /// Cell size in meters
+    #[arg(long, default_value_t = 100.0)]
+    cell_size: f64,
+    /// Only positions of this service
+    #[arg(long)]
+    service: Option<String>,
+    /// First day, e.g. 2024-05-01
+    #[arg(long)]
+    from: Option<NaiveDate>,
+    /// Last day (inclusive)
+    #[arg(long)]
+    to: Option<NaiveDate>,
+    #[arg(long, value_enum, default_value_t = Format::Csv)]
+    format: Format,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+enum Format {
+    Csv,
+    Geojson,
+}
+
+/// A grid cell: the row counts `cell_size` steps of latitude from the equator, the column steps
+/// of longitude sized for the row's latitude, so cells stay about square away from the equator
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct Cell {
+    row: i64,
+    col: i64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Grid {
+    cell_size: f64, // meters
+}
+
+impl Grid {
+    fn lat_step(&self) -> f64 {
+        self.cell_size / METERS_PER_DEGREE_LAT
+    }
+
+    /// Degrees of longitude spanning `cell_size` at the middle of `row`
+    fn lng_step(&self, row: i64) -> f64 {
+        let lat = (row as f64 + 0.5) * self.lat_step();
+        self.cell_size / (METERS_PER_DEGREE_LAT * lat.to_radians().cos().max(0.01))
+    }
+
+    fn cell(&self, lat: f64, lng: f64) -> Cell {
+        let row = (lat / self.lat_step()).floor() as i64;
+        Cell { row, col: (lng / self.lng_step(row)).floor() as i64 }
+    }
+
+    /// South-west and north-east corners of a cell, as (lat, lng)
+    fn bounds(&self, cell: Cell) -> ((f64, f64), (f64, f64)) {
+        let (lat_step, lng_step) = (self.lat_step(), self.lng_step(cell.row));
+        let south = cell.row as f64 * lat_step;
+        let west = cell.col as f64 * lng_step;
+        ((south, west), (south + lat_step, west + lng_step))
+    }
+}
+
+/// Builds the report and returns it ready to print
+pub fn run(args: &HeatmapArgs) -> Result<String, String> {
+    if !(args.cell_size.is_finite() && args.cell_size >= 1.0) {
+        return Err("--cell-size must be at least 1 meter.".to_string());
+    }
+    if let (Some(from), Some(to)) = (args.from, args.to) {
+        if to < from {
+            return Err("--to is before --from.".to_string());
+        }
+    }
+    let zone = Style::from_env()?.zone;
+    let history = open_history()?.ok_or_else(|| "Set HISTORY_DB to the history database to report on.".to_string())?;
+
+    let local_midnight = |date: NaiveDate| zone.instant_at(date.and_time(NaiveTime::MIN));
+    let start = args.from.map(|from| local_midnight(from).ok_or("--from has no local midnight.")).transpose()?;
+    let end = args
+        .to
+        .map(|to| to.checked_add_days(Days::new(1)).and_then(local_midnight).ok_or("--to is out of range."))
+        .transpose()?;
+
+    let grid = Grid { cell_size: args.cell_size };
+    let mut counts: HashMap<Cell, u64> = HashMap::new();
+    history
+        .for_each_position(start, end, args.service.as_deref(), |lat, lng| *counts.entry(grid.cell(lat, lng)).or_insert(0) += 1)
+        .map_err(|e| format!("Could not read history: {}", e))?;
+    if counts.is_empty() {
+        return Err("No positions recorded in that range (positions are only kept with HISTORY_POSITIONS=true).".to_string());
+    }
+
+    let mut cells: Vec<(Cell, u64)> = counts.into_iter().collect();
+    cells.sort();
+    Ok(match args.format {
+        Format::Csv => render_csv(&grid, &cells),
+        Format::Geojson => render_geojson(&grid, &cells),
+    })
+}
+
+/// One row per cell, located at the cell's centre
+fn render_csv(grid: &Grid, cells: &[(Cell, u64)]) -> String {
+    let mut out = "lat,lng,count".to_string();
+    for (cell, count) in cells {
+        let ((south, west), (north, east)) = grid.bounds(*cell);
+        out.push_str(&format!("\n{:.6},{:.6},{}", (south + north) / 2.0, (west + east) / 2.0, count));
+    }
+    out
+}
+
+fn render_geojson(grid: &Grid, cells: &[(Cell, u64)]) -> String {
+    let features: Vec<_> = cells
+        .iter()
+        .map(|(cell, count)| {
+            let ((south, west), (north, east)) = grid.bounds(*cell);
+            json!({
+                "type": "Feature",
+                "geometry": {
+                    "type": "Polygon",
+                    "coordinates": [[[west, south], [east, south], [east, north], [west, north], [west, south]]],
+                },
+                "properties": { "count": count },
+            })
+        })
+        .collect();
+    format!("{:#}", json!({ "type": "FeatureCollection", "features": features }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::haversine_distance;
+
+    const GRID: Grid = Grid { cell_size: 100.0 };
+
+    #[test]
+    fn each_position_lies_within_its_cells_bounds() {
+        for (lat, lng) in [(53.4808, -2.2426), (-33.8688, 151.2093), (0.0004, -0.0004), (64.1466, -21.9426)] {
+            let ((south, west), (north, east)) = GRID.bounds(GRID.cell(lat, lng));
+            assert!(south <= lat && lat < north, "{} outside {}..{}", lat, south, north);
+            assert!(west <= lng && lng < east, "{} outside {}..{}", lng, west, east);
+        }
+    }
+
+    #[test]
+    fn a_position_on_a_boundary_belongs_to_the_cell_north_and_east_of_it() {
+        let cell = Cell { row: 5947, col: -34 };
+        let ((south, west), (north, east)) = GRID.bounds(cell);
+        assert_eq!(GRID.cell(south, west), cell);
+        assert_eq!(GRID.cell(north, (west + east) / 2.0), Cell { row: cell.row + 1, ..cell });
+        assert_eq!(GRID.cell((south + north) / 2.0, east), Cell { col: cell.col + 1, ..cell });
+        // Either side of the equator and the prime meridian
+        assert_eq!(GRID.cell(0.0, 0.0), Cell { row: 0, col: 0 });
+        assert_eq!(GRID.cell(-1e-9, -1e-9), Cell { row: -1, col: -1 });
+    }
+
+    #[test]
+    fn cells_are_about_square_at_any_latitude() {
+        for lat in [0.0, 35.0, 53.48, 70.0] {
+            let ((south, west), (north, east)) = GRID.bounds(GRID.cell(lat, 1.0));
+            let middle = (south + north) / 2.0;
+            let height = haversine_distance(south, west, north, west);
+            let width = haversine_distance(middle, west, middle, east);
+            assert!((height - 100.0).abs() < 1.0, "{} m high at {}", height, lat);
+            assert!((width - 100.0).abs() < 1.0, "{} m wide at {}", width, lat);
+        }
+    }
+
+    #[test]
+    fn renders_counts_per_cell() {
+        let a = GRID.cell(53.4808, -2.2426);
+        let b = GRID.cell(53.4830, -2.2426);
+        assert_ne!(a, b);
+        let cells = [(a, 2), (b, 1)];
+
+        let csv = render_csv(&GRID, &cells);
+        let rows: Vec<&str> = csv.lines().collect();
+        assert_eq!(rows[0], "lat,lng,count");
+        assert_eq!(rows.len(), 3);
+        assert!(rows[1].ends_with(",2") && rows[2].ends_with(",1"));
+        let centre: Vec<f64> = rows[1].split(',').take(2).map(|n| n.parse().unwrap()).collect();
+        assert_eq!(GRID.cell(centre[0], centre[1]), a);
+
+        let geojson: serde_json::Value = serde_json::from_str(&render_geojson(&GRID, &cells)).unwrap();
+        let features = geojson["features"].as_array().unwrap();
+        assert_eq!(features.len(), 2);
+        assert_eq!(features[0]["properties"]["count"], 2);
+        let ring = features[0]["geometry"]["coordinates"][0].as_array().unwrap();
+        assert_eq!(ring.len(), 5);
+        assert_eq!(ring[0], ring[4]);
+    }
+}
@@ -0,0 +1,177 @@
+// Optional SQLite history of stop visits (HISTORY_DB), used for dwell-time statistics, and with
+// HISTORY_POSITIONS of every position seen, for the heatmap report
+
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection};
+
+pub struct History {
+    conn: Connection,
+}
+
+/// Dwell-time statistics for one stop, over visits with an observed departure
+#[derive(Debug, Clone)]
+pub struct DwellStats {
+    pub stop: String,
+    pub visits: u32,
+    pub open_visits: u32, // Arrivals with no departure seen (e.g. the run ended first)
+    pub min_secs: Option<i64>,
+    pub avg_secs: Option<f64>,
+    pub max_secs: Option<i64>,
+}
+
+/// One recorded arrival at a stop
+#[derive(Debug, Clone)]
+pub struct Arrival {
+    pub service: String,
+    pub stop: String,
+    pub arrived_at: DateTime<Utc>,
+}
+
+impl History {
+    pub fn open(path: &str) -> Result<History, rusqlite::Error> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS visits (
+                id          INTEGER PRIMARY KEY,
+                service     TEXT NOT NULL,
+                vehicle     TEXT NOT NULL,
+                stop        TEXT NOT NULL,
+                arrived_at  TEXT NOT NULL,
+                departed_at TEXT,
+                dwell_secs  INTEGER
+            );
+            CREATE INDEX IF NOT EXISTS visits_stop ON visits (stop);
+            CREATE TABLE IF NOT EXISTS positions (
+                service     TEXT NOT NULL,
+                vehicle     TEXT NOT NULL,
+                lat         REAL NOT NULL,
+                lng         REAL NOT NULL,
+                at          TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS positions_at ON positions (at);",
+        )?;
+        Ok(History { conn })
+    }
+
+    /// Records a bus entering a stop's radius, returning the visit's row id
+    pub fn record_arrival(&self, service: &str, vehicle: &str, stop: &str, at: DateTime<Utc>) -> Result<i64, rusqlite::Error> {
+        self.conn.execute(
+            "INSERT INTO visits (service, vehicle, stop, arrived_at) VALUES (?1, ?2, ?3, ?4)",
+            params![service, vehicle, stop, at.to_rfc3339()],
+        )?;
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    pub fn record_departure(&self, id: i64, at: DateTime<Utc>, dwell_secs: i64) -> Result<(), rusqlite::Error> {
+        self.conn.execute(
+            "UPDATE visits SET departed_at = ?1, dwell_secs = ?2 WHERE id = ?3",
+            params![at.to_rfc3339(), dwell_secs, id],
+        )?;
+        Ok(())
+    }
+
+    /// Records one cycle's vehicle positions as (service, vehicle, lat, lng, fix time)
+    pub fn record_positions(&self, positions: &[(&str, String, f64, f64, DateTime<Utc>)]) -> Result<(), rusqlite::Error> {
+        let transaction = self.conn.unchecked_transaction()?;
+        {
+            let mut statement = transaction.prepare_cached("INSERT INTO positions (service, vehicle, lat, lng, at) VALUES (?1, ?2, ?3, ?4, ?5)")?;
+            for (service, vehicle, lat, lng, at) in positions {
+                statement.execute(params![service, vehicle, lat, lng, at.to_rfc3339()])?;
+            }
+        }
+        transaction.commit()
+    }
+
+    /// Calls `each` with the (lat, lng) of every recorded position in [from, to), optionally for
+    /// one service. Rows are read one at a time, so the history can be larger than memory.
+    pub fn for_each_position(
+        &self,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+        service: Option<&str>,
+        mut each: impl FnMut(f64, f64),
+    ) -> Result<(), rusqlite::Error> {
+        let mut statement = self.conn.prepare(
+            "SELECT lat, lng FROM positions
+             WHERE (?1 IS NULL OR at >= ?1) AND (?2 IS NULL OR at < ?2) AND (?3 IS NULL OR service = ?3 COLLATE NOCASE)",
+        )?;
+        let mut rows = statement.query(params![from.map(|t| t.to_rfc3339()), to.map(|t| t.to_rfc3339()), service])?;
+        while let Some(row) = rows.next()? {
+            each(row.get(0)?, row.get(1)?);
+        }
+        Ok(())
+    }
+
+    /// Arrivals since `since`, optionally for one service, ordered by service, stop and time
+    pub fn arrivals_since(&self, since: DateTime<Utc>, service: Option<&str>) -> Result<Vec<Arrival>, rusqlite::Error> {
+        let mut statement = self.conn.prepare(
+            "SELECT service, stop, arrived_at FROM visits
+             WHERE arrived_at >= ?1 AND (?2 IS NULL OR service = ?2 COLLATE NOCASE)
+             ORDER BY service, stop, arrived_at",
+        )?;
+        let rows = statement.query_map(params![since.to_rfc3339(), service], arrival_from_row)?;
+        rows.collect()
+    }
+
+    /// Arrivals of one service (any stop) in [from, to), oldest first
+    pub fn service_arrivals(&self, service: &str, from: DateTime<Utc>, to: DateTime<Utc>) -> Result<Vec<Arrival>, rusqlite::Error> {
+        let mut statement = self.conn.prepare(
+            "SELECT service, stop, arrived_at FROM visits
+             WHERE service = ?1 COLLATE NOCASE AND arrived_at >= ?2 AND arrived_at < ?3
+             ORDER BY arrived_at",
+        )?;
+        let rows = statement.query_map(params![service, from.to_rfc3339(), to.to_rfc3339()], arrival_from_row)?;
+        rows.collect()
+    }
+
+    pub fn dwell_stats(&self) -> Result<Vec<DwellStats>, rusqlite::Error> {
+        let mut statement = self.conn.prepare(
+            "SELECT stop, COUNT(*), SUM(dwell_secs IS NULL), MIN(dwell_secs), AVG(dwell_secs), MAX(dwell_secs)
+             FROM visits GROUP BY stop ORDER BY stop",
+        )?;
+        let rows = statement.query_map([], |row| {
+            Ok(DwellStats {
+                stop: row.get(0)?,
+                visits: row.get(1)?,
+                open_visits: row.get(2)?,
+                min_secs: row.get(3)?,
+                avg_secs: row.get(4)?,
+                max_secs: row.get(5)?,
+            })
+        })?;
+        rows.collect()
+    }
+}
+
+fn arrival_from_row(row: &rusqlite::Row) -> Result<Arrival, rusqlite::Error> {
+    let arrived_at: String = row.get(2)?;
+    Ok(Arrival {
+        service: row.get(0)?,
+        stop: row.get(1)?,
+        arrived_at: DateTime::parse_from_rfc3339(&arrived_at)
+            .map(|t| t.with_timezone(&Utc))
+            .map_err(|e| rusqlite::Error::FromSqlConversionFailure(2, rusqlite::types::Type::Text, Box::new(e)))?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::fixed_now;
+
+    #[test]
+    fn dwell_stats_cover_departed_and_open_visits_per_stop() {
+        let history = History::open(":memory:").unwrap();
+        for dwell in [20, 40] {
+            let id = history.record_arrival("7", "fleet 1", "Market Square", fixed_now()).unwrap();
+            history.record_departure(id, fixed_now() + chrono::Duration::seconds(dwell), dwell).unwrap();
+        }
+        history.record_arrival("7", "fleet 2", "Market Square", fixed_now()).unwrap();
+
+        let stats = history.dwell_stats().unwrap();
+        assert_eq!(stats.len(), 1);
+        let stats = &stats[0];
+        assert_eq!((stats.stop.as_str(), stats.visits, stats.open_visits), ("Market Square", 3, 1));
+        assert_eq!((stats.min_secs, stats.avg_secs, stats.max_secs), (Some(20), Some(30.0), Some(40)));
+    }
+}
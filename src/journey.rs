@@ -0,0 +1,141 @@
+// Following one bus's journey to a stop. A bus is adopted with /follow <fleet>, or, with
+// FOLLOW_FIRST_BUS, the first bus of the run seen heading for a stop that serves it from
+// outside every stop's radius. A progress line goes out every FOLLOW_UPDATE_SECS, on its own
+// schedule rather than the alerts', until the bus is inside its stop's radius; following then
+// ends and the stop's usual arrival alert takes over.
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::format::format_duration;
+use crate::routes::Along;
+use crate::stop_index::StopIndex;
+use crate::telegram::AlertedBus;
+use crate::{
+    distance_to_stop, eta_secs, find_nearest_stop, haversine_distance, is_approaching, normalise_vehicle_id, route_to_stop, vehicle_direction, BusStop,
+    Config, Vehicle,
+};
+
+const LOST_AFTER_SECS: i64 = 5 * 60; // Following ends once the bus has been missing from the API this long
+
+#[derive(Debug, Clone)]
+pub struct Journey {
+    key: String,     // Vehicle::key of the bus being followed
+    service: String,
+    vehicle: String, // Vehicle::identifier, for messages
+    stop: String,    // The stop it's heading for
+    last_seen: DateTime<Utc>,
+    last_update: Option<DateTime<Utc>>, // When the last progress line went out
+}
+
+/// What this cycle's response means for the bus being followed
+#[derive(Debug)]
+pub enum Progress {
+    Update(String), // A progress line is due
+    Waiting,        // Nothing to say yet
+    Arrived,        // In the stop's radius: the arrival alert takes over
+    Passed,         // The stop is behind it along its route
+    Lost,           // Missing from the API (or its stop no longer watched) for too long
+}
+
+impl Journey {
+    /// Follows `vehicle` to the watched stop it's heading for, if it's heading for one
+    pub fn start(vehicle: &Vehicle, stops: &[BusStop], config: &Config, now: DateTime<Utc>) -> Option<Journey> {
+        let (stop, _) = heading_for(vehicle, stops, config)?;
+        Some(Journey {
+            key: vehicle.key(),
+            service: vehicle.service_number.clone(),
+            vehicle: vehicle.identifier(),
+            stop: stop.name.clone(),
+            last_seen: now,
+            last_update: None,
+        })
+    }
+
+    /// Waits for the bus an alert was about to reach that alert's stop ("stop after this bus")
+    pub fn to_alerted_stop(bus: &AlertedBus, now: DateTime<Utc>) -> Journey {
+        Journey {
+            key: bus.key.clone(),
+            service: bus.service.clone(),
+            vehicle: bus.vehicle.clone(),
+            stop: bus.stop.clone(),
+            last_seen: now,
+            last_update: None,
+        }
+    }
+
+    /// The first of `vehicles` that's outside every stop's radius and heading for one of them (FOLLOW_FIRST_BUS)
+    pub fn adopt_first(vehicles: &[Vehicle], stops: &[BusStop], config: &Config, now: DateTime<Utc>) -> Option<Journey> {
+        vehicles
+            .iter()
+            .filter(|vehicle| vehicle.in_service() && find_nearest_stop(vehicle.lat, vehicle.lng, stops, &StopIndex::default()).is_none())
+            .find_map(|vehicle| Journey::start(vehicle, stops, config, now))
+    }
+
+    /// "bus 7 (fleet 47201) to Main Street"
+    pub fn describe(&self, config: &Config) -> String {
+        format!("bus {} ({}) to {}", config.service_labels.label(&self.service), self.vehicle, self.stop)
+    }
+
+    /// Where the bus has got to, and whether a progress line is due `every` so often
+    pub fn progress(&mut self, vehicles: &[Vehicle], stops: &[BusStop], config: &Config, every: Duration, now: DateTime<Utc>) -> Progress {
+        let (Some(vehicle), Some(stop)) = (vehicles.iter().find(|vehicle| vehicle.key() == self.key), stops.iter().find(|stop| stop.name == self.stop)) else {
+            return if now - self.last_seen >= Duration::seconds(LOST_AFTER_SECS) { Progress::Lost } else { Progress::Waiting };
+        };
+        self.last_seen = now;
+
+        let distance = haversine_distance(vehicle.lat, vehicle.lng, stop.lat, stop.lng);
+        if distance <= stop.radius {
+            return Progress::Arrived;
+        }
+        let to_go = match route_to_stop(vehicle, stop, config) {
+            Along::Ahead(along) => along,
+            Along::Behind => return Progress::Passed,
+            Along::OffRoute => distance,
+        };
+        if self.last_update.is_some_and(|at| now - at < every) {
+            return Progress::Waiting;
+        }
+        self.last_update = Some(now);
+
+        let eta = match eta_secs(to_go, vehicle.speed) {
+            Some(secs) => format!(", ~{}", config.style.eta(secs)),
+            None => String::new(),
+        };
+        Progress::Update(format!(
+            "Bus {} ({}): {} away{}",
+            config.service_labels.label(&self.service),
+            self.vehicle,
+            config.style.distance(to_go),
+            eta
+        ))
+    }
+
+    /// Why following ended, for the notice that says so
+    pub fn ended(&self, progress: &Progress, config: &Config) -> Option<String> {
+        match progress {
+            Progress::Passed => Some(format!("Stopped following {}: it has gone past the stop.", self.describe(config))),
+            Progress::Lost => Some(format!(
+                "Stopped following {}: it hasn't been seen for {}.",
+                self.describe(config),
+                format_duration(LOST_AFTER_SECS)
+            )),
+            Progress::Update(_) | Progress::Waiting | Progress::Arrived => None,
+        }
+    }
+}
+
+/// The vehicle with this fleet number (or registration), as typed after /follow
+pub fn find_by_fleet<'a>(vehicles: &'a [Vehicle], fleet: &str) -> Option<&'a Vehicle> {
+    let wanted = normalise_vehicle_id(fleet);
+    vehicles.iter().find(|vehicle| [&vehicle.fleet_number, &vehicle.registration].into_iter().flatten().any(|id| normalise_vehicle_id(id) == wanted))
+}
+
+/// The nearest watched stop that serves the bus (in its direction) and lies ahead of it, with the distance to go
+fn heading_for<'a>(vehicle: &Vehicle, stops: &'a [BusStop], config: &Config) -> Option<(&'a BusStop, f64)> {
+    let direction = vehicle_direction(vehicle, config);
+    stops
+        .iter()
+        .filter(|stop| stop.serves(&vehicle.service_number, &direction) && is_approaching(vehicle, stop, config))
+        .filter_map(|stop| Some((stop, distance_to_stop(vehicle, stop, config)?)))
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+}
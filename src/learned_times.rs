@@ -0,0 +1,190 @@
+// `report learned-times`: infers a timetable from the arrivals in HISTORY_DB by clustering each
+// service's arrival times at a stop, per weekday. Can print it as [[timetable]] entries for the
+// config file, so `report punctuality` works without the official timetable.
+
+use chrono::{Datelike, Days, NaiveDate, NaiveTime, Timelike, Utc, Weekday};
+use clap::{Args, ValueEnum};
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::format::Style;
+use crate::history::Arrival;
+use crate::{open_history, stop_names};
+
+const DEFAULT_DAYS: u64 = 14; // History looked at when --from isn't given
+const WEEK: [Weekday; 7] = [Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Thu, Weekday::Fri, Weekday::Sat, Weekday::Sun];
+
+#[derive(Debug, Args)]
+pub struct LearnedTimesArgs {
+    /// First day, e.g. 2024-05-01 (default: two weeks ago)
+    #[arg(long)]
+    from: Option<NaiveDate>,
+    /// Last day (inclusive, default: today)
+    #[arg(long)]
+    to: Option<NaiveDate>,
+    #[arg(long)]
+    service: Option<String>,
+    #[arg(long)]
+    stop: Option<String>,
+    /// Arrivals this many minutes apart on different days count as the same journey
+    #[arg(long, default_value_t = 5)]
+    tolerance_mins: u32,
+    /// Leave out times seen on fewer days than this
+    #[arg(long, default_value_t = 2)]
+    min_days: usize,
+    #[arg(long, value_enum, default_value_t = Format::Text)]
+    format: Format,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+enum Format {
+    Text,
+    /// [[timetable]] entries to paste into the config file
+    Timetable,
+}
+
+/// A typical arrival time and the days it was seen on
+#[derive(Debug, Clone)]
+struct LearnedTime {
+    time: NaiveTime, // Median of the clustered arrivals, to the minute
+    days_seen: usize,
+}
+
+/// Learned times for one service at one stop, by weekday
+#[derive(Debug, Default)]
+struct Route {
+    stop: String,                                      // First spelling seen
+    days_observed: BTreeMap<u32, BTreeSet<NaiveDate>>, // Weekday (from Monday) -> dates with any arrival
+    arrivals: BTreeMap<u32, Vec<(u32, NaiveDate)>>,    // Weekday -> (local second of day, date)
+    times: BTreeMap<u32, Vec<LearnedTime>>,
+}
+
+/// Builds the report and returns it ready to print
+pub fn run(args: &LearnedTimesArgs) -> Result<String, String> {
+    let zone = Style::from_env()?.zone;
+    let history = open_history()?.ok_or_else(|| "Set HISTORY_DB to the history database to report on.".to_string())?;
+
+    let today = zone.local_time(Utc::now()).date();
+    let to = args.to.unwrap_or(today);
+    let from = args.from.or_else(|| to.checked_sub_days(Days::new(DEFAULT_DAYS - 1))).ok_or("--to is out of range.")?;
+    if to < from {
+        return Err("--to is before --from.".to_string());
+    }
+    let local_midnight = |date: NaiveDate| zone.instant_at(date.and_time(NaiveTime::MIN));
+    let start = local_midnight(from).ok_or("--from has no local midnight.")?;
+    let end = to.checked_add_days(Days::new(1)).and_then(local_midnight).ok_or("--to is out of range.")?;
+
+    let arrivals: Vec<Arrival> = history
+        .arrivals_since(start, args.service.as_deref())
+        .map_err(|e| format!("Could not read history: {}", e))?
+        .into_iter()
+        .filter(|arrival| arrival.arrived_at < end && args.stop.as_deref().is_none_or(|stop| stop_names::same_stop(&arrival.stop, stop)))
+        .collect();
+    if arrivals.is_empty() {
+        return Err(format!("No arrivals recorded between {} and {}.", from, to));
+    }
+
+    // (service, canonical stop) -> route
+    let mut routes: BTreeMap<(String, String), Route> = BTreeMap::new();
+    for arrival in &arrivals {
+        let local = zone.local_time(arrival.arrived_at);
+        let weekday = local.weekday().num_days_from_monday();
+        let route = routes.entry((arrival.service.clone(), stop_names::canonical(&arrival.stop))).or_default();
+        if route.stop.is_empty() {
+            route.stop = arrival.stop.clone();
+        }
+        route.days_observed.entry(weekday).or_default().insert(local.date());
+        route.arrivals.entry(weekday).or_default().push((local.time().num_seconds_from_midnight(), local.date()));
+    }
+    for route in routes.values_mut() {
+        for (weekday, arrivals) in &route.arrivals {
+            let times = cluster(arrivals, args.tolerance_mins * 60, args.min_days);
+            if !times.is_empty() {
+                route.times.insert(*weekday, times);
+            }
+        }
+    }
+
+    Ok(match args.format {
+        Format::Text => render_text(&routes, from, to, args),
+        Format::Timetable => render_timetable(&routes),
+    })
+}
+
+/// Groups arrival times that fall within `tolerance_secs` of the running cluster average, and keeps
+/// clusters seen on at least `min_days` different dates
+fn cluster(arrivals: &[(u32, NaiveDate)], tolerance_secs: u32, min_days: usize) -> Vec<LearnedTime> {
+    let mut sorted = arrivals.to_vec();
+    sorted.sort();
+
+    let mut clusters: Vec<Vec<(u32, NaiveDate)>> = Vec::new();
+    for arrival in sorted {
+        match clusters.last_mut() {
+            Some(current) if arrival.0 - mean_secs(current) <= tolerance_secs => current.push(arrival),
+            _ => clusters.push(vec![arrival]),
+        }
+    }
+
+    clusters
+        .into_iter()
+        .filter_map(|members| {
+            let days_seen = members.iter().map(|(_, date)| date).collect::<BTreeSet<_>>().len();
+            let median = members[members.len() / 2].0;
+            let time = NaiveTime::from_num_seconds_from_midnight_opt(median - median % 60, 0)?;
+            (days_seen >= min_days).then_some(LearnedTime { time, days_seen })
+        })
+        .collect()
+}
+
+fn mean_secs(members: &[(u32, NaiveDate)]) -> u32 {
+    (members.iter().map(|(secs, _)| u64::from(*secs)).sum::<u64>() / members.len() as u64) as u32
+}
+
+fn render_text(routes: &BTreeMap<(String, String), Route>, from: NaiveDate, to: NaiveDate, args: &LearnedTimesArgs) -> String {
+    let mut out = format!(
+        "Learned times, {} to {} (arrivals within {} min grouped, seen on at least {} days)",
+        from, to, args.tolerance_mins, args.min_days
+    );
+    let mut any = false;
+    for ((service, _), route) in routes.iter().filter(|(_, route)| !route.times.is_empty()) {
+        any = true;
+        out.push_str(&format!("\n{} at {}", service, route.stop));
+        for (weekday, times) in &route.times {
+            let observed = route.days_observed.get(weekday).map_or(0, BTreeSet::len);
+            let times: Vec<String> = times
+                .iter()
+                .map(|learned| format!("{} ({}/{})", learned.time.format("%H:%M"), learned.days_seen, observed))
+                .collect();
+            out.push_str(&format!("\n  {}: {}", WEEK[*weekday as usize], times.join(", ")));
+        }
+    }
+    if !any {
+        out.push_str("\nNo time was seen often enough; try a longer range or a lower --min-days.");
+    }
+    out
+}
+
+/// One [[timetable]] entry per service and stop for each distinct set of times, listing the weekdays it applies to
+fn render_timetable(routes: &BTreeMap<(String, String), Route>) -> String {
+    let quote = |text: &str| toml::Value::String(text.to_string()).to_string();
+    let mut entries: Vec<String> = Vec::new();
+    for ((service, _), route) in routes {
+        let mut by_times: BTreeMap<Vec<NaiveTime>, Vec<Weekday>> = BTreeMap::new();
+        for (weekday, times) in &route.times {
+            by_times.entry(times.iter().map(|learned| learned.time).collect()).or_default().push(WEEK[*weekday as usize]);
+        }
+        for (times, days) in by_times {
+            let times: Vec<String> = times.iter().map(|time| quote(&time.format("%H:%M").to_string())).collect();
+            let mut entry = format!("[[timetable]]\nservice = {}\nstop = {}\ntimes = [{}]", quote(service), quote(&route.stop), times.join(", "));
+            if days.len() < WEEK.len() {
+                let days: Vec<String> = days.iter().map(|day| quote(&day.to_string())).collect();
+                entry.push_str(&format!("\ndays = [{}]", days.join(", ")));
+            }
+            entries.push(entry);
+        }
+    }
+    if entries.is_empty() {
+        return "# No time was seen often enough to learn.".to_string();
+    }
+    entries.join("\n\n")
+}
+
@@ -0,0 +1,92 @@
+// Log output: human-readable lines on stderr plus optional rotating JSON files (LOG_FILE)
+
+use std::fmt;
+use std::path::Path;
+
+use chrono::Utc;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::time::FormatTime;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+use crate::env_or_file;
+use crate::timezone::Zone;
+
+const DEFAULT_RETENTION: usize = 7; // Rotated files kept on disk
+
+/// Log timestamps in the configured TIMEZONE rather than UTC
+struct ZoneTimer(Zone);
+
+impl FormatTime for ZoneTimer {
+    fn format_time(&self, w: &mut Writer<'_>) -> fmt::Result {
+        write!(w, "{}", self.0.format(Utc::now(), "%Y-%m-%d %H:%M:%S%.3f %z"))
+    }
+}
+
+/// Installs the global logger. `LOG_LEVEL` takes the usual filter syntax (e.g. `debug` or
+/// `bus_notification_app=debug`), and `LOG_FILE` enables file output rotated per `LOG_ROTATION`
+/// (daily, hourly or never) keeping the newest `LOG_RETENTION` files.
+pub fn init(quiet: bool, zone: Zone) -> Result<(), String> {
+    let level = env_or_file("LOG_LEVEL")?;
+    let filter = || level.as_deref().and_then(|level| EnvFilter::try_new(level).ok()).unwrap_or_else(|| EnvFilter::new("info"));
+
+    let stderr_layer = (!quiet).then(|| {
+        tracing_subscriber::fmt::layer()
+            .with_writer(std::io::stderr)
+            .with_timer(ZoneTimer(zone))
+            .with_target(false)
+            .with_filter(filter())
+    });
+
+    let file_layer = match env_or_file("LOG_FILE")? {
+        Some(path) if !path.trim().is_empty() => Some(
+            tracing_subscriber::fmt::layer()
+                .json()
+                .with_writer(file_appender(path.trim())?)
+                .with_timer(ZoneTimer(zone))
+                .with_filter(filter()),
+        ),
+        _ => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(stderr_layer)
+        .with(file_layer)
+        .try_init()
+        .map_err(|e| format!("Could not install logger: {}", e))
+}
+
+fn file_appender(path: &str) -> Result<RollingFileAppender, String> {
+    let path = Path::new(path);
+    let directory = path.parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let prefix = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| format!("LOG_FILE '{}' does not name a file.", path.display()))?;
+
+    let rotation = match env_or_file("LOG_ROTATION")?.unwrap_or_else(|| "daily".to_string()).to_ascii_lowercase().as_str() {
+        "daily" => Rotation::DAILY,
+        "hourly" => Rotation::HOURLY,
+        "never" => Rotation::NEVER,
+        other => return Err(format!("LOG_ROTATION must be daily, hourly or never, got '{}'.", other)),
+    };
+
+    let retention: usize = match env_or_file("LOG_RETENTION")? {
+        Some(value) => value
+            .trim()
+            .parse()
+            .ok()
+            .filter(|n| *n > 0)
+            .ok_or_else(|| format!("LOG_RETENTION must be a positive whole number, got '{}'.", value))?,
+        None => DEFAULT_RETENTION,
+    };
+
+    RollingFileAppender::builder()
+        .rotation(rotation)
+        .filename_prefix(prefix)
+        .max_log_files(retention)
+        .build(directory)
+        .map_err(|e| format!("Could not open log file '{}': {}", path.display(), e))
+}
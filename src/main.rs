@@ -1,214 +1,2484 @@
+mod absence;
+mod alerts;
+mod commands;
+mod config_edit;
+mod config_file;
+mod cycle;
+mod daemon;
+mod debug_server;
+mod direction;
+mod discover;
+mod dwell;
+mod error;
+mod fields;
+mod format;
+mod geo;
+mod gotify;
+mod health;
+mod heatmap;
+mod history;
+mod journey;
+mod learned_times;
+mod logging;
+mod matrix;
+mod notify;
+mod ntfy;
+mod punctuality;
+mod reverse_geocode;
+mod routes;
+mod schedule;
+mod service_labels;
+mod session;
+mod setup;
+#[cfg(feature = "sound")]
+mod sound;
+mod state_file;
+mod stop_index;
+mod stop_names;
+mod telegram;
+#[cfg(test)]
+mod test_support;
+mod timezone;
+mod tracker;
+mod tracks;
+mod twilio;
+mod webhook;
+
+use clap::{Parser, Subcommand};
 use dotenv::dotenv;
+use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::env;
+use std::fs;
+use std::future::Future;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
 use reqwest::Client;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
 use tokio::time::{self, Duration, Instant};
-use chrono::{Local, Timelike};
+use chrono::{DateTime, TimeZone, Utc, Weekday};
 use serde_json::Value;
 use std::f64::consts::PI;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::{Arc, Mutex};
+use absence::AbsenceWatch;
+use alerts::{AlertTracker, Cooldown};
+use config_file::{ConfigFile, StopEntry};
+use cycle::CycleEvent;
+use direction::Direction;
+use error::TrackerError;
+use fields::FieldMap;
+use format::Style;
+use geo::{closest_stop, first_stop_within, haversine_distance, nearest_stop_batch, Located};
+use health::FailureTracker;
+use history::History;
+use notify::Notifiers;
+use reverse_geocode::ReverseGeocoder;
+use routes::{Along, Routes};
+use schedule::PollSchedule;
+use service_labels::ServiceLabels;
+use session::{Session, SharedStatus, Status, DEFAULT_GROUP};
+use state_file::StateFile;
+use stop_index::StopIndex;
+use telegram::{CommandOrigin, Controls, LiveMessage, LoopRequest, SharedControls, Telegram};
+use timezone::{QuietHours, TimeWindow, Zone};
+use tracker::{FirstMatch, Signals, SystemClock, Tracker};
+use tracks::Tracks;
+use tracing::{debug, error, info, info_span, warn, Instrument, Span};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct BusStop {
     name: String,
     lat: f64,
     lng: f64,
+    radius: f64,           // Meters; a bus within this distance is "near" the stop
+    group: Option<String>, // Stops sharing a group alert once for the whole group
+    services: Vec<String>, // Only these services alert here, optionally one way ("7:towards City Centre"); empty means all
+    days: Vec<Weekday>,    // Days the stop is watched (in TIMEZONE); empty means every day
+    quiet: Option<QuietHours>, // When its alerts are silent; None follows SILENT_HOURS
+}
+
+impl Located for BusStop {
+    fn lat(&self) -> f64 {
+        self.lat
+    }
+    fn lng(&self) -> f64 {
+        self.lng
+    }
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+}
+
+impl BusStop {
+    /// Whether alerts for this service, going this way, are wanted at this stop
+    fn serves(&self, service_number: &str, direction: &Direction) -> bool {
+        self.services.is_empty() || self.services.iter().any(|entry| direction::service_matches(entry, service_number, direction))
+    }
+
+    fn active_on(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+}
+
+/// A single vehicle from the API's `services` array
+#[derive(Debug, Clone)]
+struct Vehicle {
+    service_number: String,
+    service_description: String,
+    fleet_number: Option<String>,
+    registration: Option<String>,
+    lat: f64,
+    lng: f64,
+    speed: Option<f64>,                // km/h, when the API reports it
+    heading: Option<f64>,              // Degrees clockwise from north, when the API reports it
+    updated_at: Option<DateTime<Utc>>, // When the position fix was taken
+    upcoming_stops: Option<Vec<String>>, // Stop names still ahead on the route, when the API reports them
+}
+
+/// Settings read once from the environment at startup
+#[derive(Debug)]
+struct Config {
+    lat: f64,
+    lng: f64,
+    radius: Option<u32>,   // Meters around the centre to query; only optional with DERIVE_RADIUS, where it caps the derived one
+    derive_radius: bool,   // Query just far enough to cover the stops (DERIVE_RADIUS)
+    poll_interval_secs: u64,
+    stale_fix_secs: i64,    // Vehicles with an older position are ignored
+    fix_age_warn_secs: i64, // Alerts mention the position age above this
+    min_speed: Option<f64>, // km/h; slower vehicles (e.g. parked in a layby) don't alert
+    missing_speed_passes: bool, // Whether vehicles without a reported speed clear MIN_SPEED
+    style: Style,               // Timezone, units and time format for human-readable output
+    alert_cooldown: Cooldown,   // Minimum gap between alerts for the same bus and stop/group
+    gps_file: Option<String>,   // File holding "lat,lng" that overrides LAT/LNG while present
+    api_url: String,            // The vehicle API (API_URL, for a mirror or a test server)
+    api_retries: u32,           // Extra attempts at the primary API each cycle before giving up on it
+    fallback_api_url: Option<String>, // Tried once the primary's retries are exhausted
+    require_route_match: bool,  // Only alert when the bus's route data lists the stop
+    alert_template: Option<String>, // Custom alert text with {service}, {label}, {description}, {direction}, {vehicle}, {stop}, {group}
+    fields: FieldMap,               // JSON keys for each vehicle field (FIELD_MAP)
+    include_operator_link: bool,    // Append a link to the operator's live map to alerts
+    geofence: Option<Vec<(f64, f64)>>, // Polygon (lat, lng vertices) vehicles must be inside
+    max_services_per_cycle: Option<usize>, // Only the nearest N vehicles are processed each cycle
+    max_plausible_speed_kmh: f64, // Fixes implying a faster jump from the last one are GPS glitches
+    confirmation_cycles: u32,     // Polls a bus must stay in a stop's radius before it alerts
+    early_warning_radius: Option<f64>, // Meters; buses this close to a stop (but not in its radius) get a heads-up
+    warmup_secs: i64,                  // After startup, observe without alerting for this long
+    report_closest_approach: bool,     // Log each service's closest approach to a stop when the run ends
+    wall_clock_budget: bool,           // Count the run length in wall-clock time, including any suspend
+    align_polls: bool,                 // Poll on round wall-clock multiples of the interval (:00, :10, ...)
+    export_geojson: Option<String>,    // File the run's alerts are written to as GeoJSON at shutdown
+    require_approaching_heading: bool, // Only alert when the stop lies ahead of the bus's heading
+    heading_tolerance_deg: f64,        // How far off the heading the stop may be and still count as ahead
+    lifecycle_notifications: bool,     // Announce startup and clean shutdown through the notifiers
+    dedup_distance_bucket_m: f64,      // Distances in send-dedup keys are rounded to this; 0 keeps them exact
+    skip_unknown_services: bool,       // Ignore vehicles with no real service number (depot moves, engineering runs)
+    exclusion_zone: Option<ExclusionZone>, // Vehicles in here (e.g. the depot) are never considered
+    parked_speed_kmh: f64,                 // Below this a vehicle counts as standing still...
+    parked_after: Option<chrono::Duration>, // ...and is ignored once it has for this long (PARKED_AFTER_MINS)
+    ignore_vehicles: Vec<String>,           // Fleet numbers or registrations skipped before anything else
+    client_version: String,                 // Sent as the API's client_version parameter
+    history_positions: bool,                // Also record every vehicle position in HISTORY_DB, for `report heatmap`
+    service_labels: ServiceLabels,          // How services are shown in messages ([service_labels])
+    routes: Routes,                         // Service routes ([[routes]]) for distances along the road
+    schedule: Option<PollSchedule>,         // When polling is on ([schedule] in the config file); None: always
+    run_length: Duration,                   // How long one run (or --daemon session) lasts (RUN_MINUTES)
+    max_run_length: Duration,               // /extend and SIGUSR2 can't take a run past this (MAX_RUN_MINUTES)
+    default_extension: Duration,            // Added by SIGUSR2 or a bare /extend (EXTEND_MINUTES)
+    follow_first_bus: bool,                 // Follow the first bus seen heading for a stop (FOLLOW_FIRST_BUS)
+    follow_update_every: chrono::Duration,  // Gap between progress lines for a followed bus (FOLLOW_UPDATE_SECS)
+    detect_bunching: bool,                  // Report two or more buses in one stop's radius at once (DETECT_BUNCHING)
+    priority_services: Vec<String>,         // Services whose arrivals alert every cycle, past cooldowns and send dedup (PRIORITY_SERVICES)
+    heartbeat_url: Option<String>,          // GET after each cycle the API answered, for a dead-man's switch (HEARTBEAT_URL)
+}
+
+const NEXT_ACTIVATIONS: usize = 3; // Upcoming schedule starts shown at startup and in /status
+const ACTIVATION_FORMAT: &str = "%a %d %b %H:%M";
+const DEFAULT_API_URL: &str = "https://api.stagecoach-technology.net/vehicle-tracking/v1/vehicles";
+const DEFAULT_CLIENT_VERSION: &str = "UKBUS_APP";
+const OPERATOR_MAP_URL: &str = "https://www.stagecoachbus.com/bus-tracker";
+const API_RETRY_DELAY: Duration = Duration::from_secs(2); // Multiplied by the attempt number
+const DEFAULT_STOP_RADIUS: f64 = 200.0; // meters
+const LIVE_MESSAGE_BUSES: usize = 5; // Nearest buses shown in the live message
+const NOW_BUSES: usize = 5; // Nearest buses listed in reply to /now
+const NOW_MAX_AGE: Duration = Duration::from_secs(10); // /now reuses the last cycle's data if it's this fresh
+const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+#[cfg(feature = "keyring")]
+const KEYRING_SERVICE: &str = "stagecoach-tracker"; // Service name of USE_KEYRING entries
+const SCRIPT_TIMEOUT: Duration = Duration::from_secs(30 * 60); // Default run length: 30 minutes
+
+/// Whether the poll interval floor has been warned about; it's only worth saying once per run
+static POLL_FLOOR_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// Sends Telegram alerts when Stagecoach buses approach your stops
+#[derive(Debug, Parser)]
+#[command(version)]
+struct Cli {
+    /// Don't log to stderr (file logging via LOG_FILE still applies)
+    #[arg(long)]
+    quiet: bool,
+
+    /// Don't check the Telegram bot token and chat ids before tracking starts
+    #[arg(long)]
+    skip_validation: bool,
+
+    /// TOML config file with stops, named groups and alert template
+    #[arg(long, env = "CONFIG_FILE", global = true)]
+    config: Option<PathBuf>,
+
+    /// Stay running and start a session at each start time of the config file's [schedule]
+    #[arg(long, env = "DAEMON")]
+    daemon: bool,
+
+    /// End the run at the first arrival alert; if time runs out first, exit with code 4
+    #[arg(long, env = "UNTIL_FIRST_MATCH")]
+    until_first_match: bool,
+
+    /// With --until-first-match, only an alert for this service ends the run
+    #[arg(long, requires = "until_first_match")]
+    match_service: Option<String>,
+
+    /// With --until-first-match, only an alert at this stop ends the run
+    #[arg(long, requires = "until_first_match")]
+    match_stop: Option<String>,
+
+    /// Run only this profile from the config file, instead of the ones scheduled for now
+    #[arg(long, env = "PROFILE")]
+    profile: Option<String>,
+
+    /// Read stops from this file ("-" for stdin) instead of BUS_STOPS: the BUS_STOPS format or CSV lines
+    #[arg(long)]
+    stops: Option<PathBuf>,
+
+    /// Keep this JSON file up to date with each stop's nearest bus and the tracker's health (see state_file.rs)
+    #[arg(long, env = "STATE_FILE")]
+    state_file: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Print per-stop dwell-time statistics from the history database (HISTORY_DB)
+    Stats,
+
+    /// Add a stop to the config file
+    AddStop {
+        #[arg(long)]
+        name: String,
+        #[arg(long, allow_negative_numbers = true)]
+        lat: f64,
+        #[arg(long, allow_negative_numbers = true)]
+        lng: f64,
+        /// Meters (default 200)
+        #[arg(long)]
+        radius: Option<f64>,
+        /// Only alert for these services, e.g. 7,X24
+        #[arg(long, value_delimiter = ',')]
+        services: Vec<String>,
+    },
+
+    /// Remove a stop from the config file
+    RemoveStop {
+        #[arg(long)]
+        name: String,
+    },
+
+    /// Interactively create a config file, then send a test notification
+    Setup(setup::SetupArgs),
+
+    /// Find the bus stops nearby and add the chosen ones to the config file
+    Discover(discover::DiscoverArgs),
+
+    /// Send a test notification through every configured channel
+    TestNotify,
+
+    /// Reports over the history database (HISTORY_DB)
+    #[command(subcommand)]
+    Report(punctuality::Report),
+}
+
+/// Why the tracker stopped, which decides the process exit code
+#[derive(Debug, Clone, PartialEq)]
+enum ExitReason {
+    Completed,                // Run duration elapsed or a clean, requested shutdown
+    ConfigError(String),      // Missing or malformed settings
+    ApiFailure,               // The Stagecoach API kept failing past API_FAILURE_EXIT_THRESHOLD
+    NotifierStartup(String),  // A notification sink couldn't be set up at startup
+    NoMatch,                  // --until-first-match: the run ended without a matching alert
 }
 
-const API_URL: &str = "https://api.stagecoach-technology.net/vehicle-tracking/v1/vehicles";
-const SCRIPT_TIMEOUT: Duration = Duration::from_secs(30 * 60); // 30 minutes
+impl ExitReason {
+    fn code(&self) -> i32 {
+        match self {
+            ExitReason::Completed => 0,
+            ExitReason::ConfigError(_) => 1,
+            ExitReason::ApiFailure => 2,
+            ExitReason::NotifierStartup(_) => 3,
+            ExitReason::NoMatch => 4,
+        }
+    }
+}
 
 #[tokio::main]
 async fn main() {
-    dotenv().ok(); // Load .env file
+    dotenv().ok(); // Load .env file (before parsing so CONFIG_FILE can come from it)
+    let cli = Cli::parse();
+
+    let options = RunOptions {
+        skip_validation: cli.skip_validation,
+        summary: false,
+        until_first_match: cli.until_first_match.then_some(FirstMatch { service: cli.match_service, stop: cli.match_stop }),
+        state_file: cli.state_file,
+    };
+    let reason = match Zone::from_env().and_then(|zone| logging::init(cli.quiet, zone)) {
+        Ok(()) => match cli.command {
+            None if cli.daemon => daemon::run(cli.config, cli.profile, cli.stops, options).await,
+            None => run(cli.config, cli.profile, cli.stops, options).await,
+            Some(Command::Stats) => stats(),
+            Some(Command::AddStop { name, lat, lng, radius, services }) => {
+                let stop = config_edit::NewStop { name, lat, lng, radius, services };
+                edit_stops(cli.config, |path| config_edit::add_stop(path, &stop))
+            }
+            Some(Command::RemoveStop { name }) => edit_stops(cli.config, |path| {
+                config_edit::remove_stop(path, &name).map(|()| format!("Removed stop '{}'.", name))
+            }),
+            Some(Command::Setup(args)) => match setup::run(cli.config, &args).await {
+                Ok(_) if args.skip_test() => ExitReason::Completed,
+                Ok(path) => test_notify(Some(path)).await,
+                Err(e) => ExitReason::ConfigError(e),
+            },
+            Some(Command::Discover(args)) => match discover::run(cli.config, &args).await {
+                Ok(output) => {
+                    println!("{}", output);
+                    ExitReason::Completed
+                }
+                Err(e) => ExitReason::ConfigError(e),
+            },
+            Some(Command::TestNotify) => test_notify(cli.config).await,
+            Some(Command::Report(report)) => match punctuality::run(cli.config.as_deref(), &report) {
+                Ok(output) => {
+                    println!("{}", output);
+                    ExitReason::Completed
+                }
+                Err(e) => ExitReason::ConfigError(e),
+            },
+        },
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitReason::ConfigError(e)
+        }
+    };
+
+    match &reason {
+        ExitReason::Completed => {}
+        ExitReason::ConfigError(e) => error!("Configuration error: {}", e),
+        ExitReason::ApiFailure => error!("Exiting: the Stagecoach API is unreachable."),
+        ExitReason::NotifierStartup(e) => error!("Could not start notifications: {}", e),
+        ExitReason::NoMatch => info!("Run ended without a matching arrival."),
+    }
+    process::exit(reason.code());
+}
+
+/// How the trackers of a run behave, from the command line
+#[derive(Debug, Clone, Default)]
+struct RunOptions {
+    skip_validation: bool,
+    summary: bool, // End with a session summary (each --daemon session)
+    until_first_match: Option<FirstMatch>,
+    state_file: Option<PathBuf>, // Written by the first profile's tracker (--state-file)
+}
+
+/// One run of the trackers: each profile's, until they stop
+async fn run(config_path: Option<PathBuf>, profile: Option<String>, stops_path: Option<PathBuf>, options: RunOptions) -> ExitReason {
+    let (config_file, stops_input) = match (ConfigFile::load_opt(config_path.as_deref()), StopsInput::read(stops_path.as_deref())) {
+        (Ok(file), Ok(input)) => (file, input),
+        (Err(e), _) | (_, Err(e)) => return ExitReason::ConfigError(e),
+    };
+    let profiles = match Zone::from_env().and_then(|zone| config_file.profiles(profile.as_deref(), zone.local_time(Utc::now()))) {
+        Ok(profiles) => profiles,
+        Err(e) => return ExitReason::ConfigError(e),
+    };
+    if !config_file.profiles.is_empty() {
+        let names: Vec<&str> = profiles.iter().filter_map(|(name, _)| name.as_deref()).collect();
+        let how = if profile.is_some() { "chosen with --profile" } else { "scheduled for now" };
+        info!("Running profile(s) {} ({}).", names.join(", "), how);
+    }
 
-    let client = Client::new();
-    let bus_stops = load_bus_stops();
-    let start_time = Instant::now(); // Track start time of script.
+    // Every profile gets its own tracker; they share the HTTP client and the mute/stop controls,
+    // and Telegram commands are answered by the first one
+    let client = match http_client() {
+        Ok(client) => client,
+        Err(e) => return ExitReason::ConfigError(e),
+    };
+    let controls: SharedControls = Arc::new(Mutex::new(Controls::default()));
+    let mut trackers = JoinSet::new();
+    for (index, (name, file)) in profiles.into_iter().enumerate() {
+        let span = match &name {
+            Some(name) => info_span!("profile", name = %name),
+            None => Span::none(),
+        };
+        let reload = StopsReload {
+            config_path: config_path.clone(),
+            profile: name.clone(),
+            stops_path: stops_path.clone(),
+            input: stops_input.clone(),
+        };
+        let tracker = start_tracker(&file, reload, client.clone(), controls.clone(), index == 0, &options)
+            .instrument(span.clone())
+            .await;
+        match tracker {
+            Ok(tracker) => trackers.spawn(tracker.instrument(span)),
+            Err(reason) => return reason,
+        };
+    }
 
-    loop {
-        // Stop execution if 30 minutes have passed
-        if start_time.elapsed() >= SCRIPT_TIMEOUT {
-            println!("Script completed successfully after 30 minutes!");
-            return;
+    // The first profile to stop for a reason other than completing decides the exit code,
+    // except that with --until-first-match one profile's match is enough
+    let mut reason = ExitReason::Completed;
+    let mut completed = false;
+    while let Some(result) = trackers.join_next().await {
+        match result {
+            Ok(ExitReason::Completed) => completed = true,
+            Ok(failed) if reason == ExitReason::Completed => reason = failed,
+            Ok(_) => {}
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
         }
+    }
+    if reason == ExitReason::NoMatch && completed {
+        return ExitReason::Completed;
+    }
+    reason
+}
 
-        let now = Local::now();
-        println!("\nCurrent time: {:02}:{:02}:{:02}", now.hour(), now.minute(), now.second());
+/// Sets up one tracker from its (profile's) config and returns the future that runs it
+async fn start_tracker(
+    config_file: &ConfigFile,
+    reload: StopsReload,
+    client: Client,
+    controls: SharedControls,
+    answer_commands: bool,
+    options: &RunOptions,
+) -> Result<impl Future<Output = ExitReason> + Send, ExitReason> {
+    let config = Config::from_env(config_file).map_err(|e| ExitReason::ConfigError(e.to_string()))?;
+    info!("Using timezone: {}", config.style.zone.name());
+    if !config.ignore_vehicles.is_empty() {
+        info!("Ignoring {} vehicle(s) listed in IGNORE_VEHICLES", config.ignore_vehicles.len());
+    }
+    if let Some(schedule) = &config.schedule {
+        let next = schedule.next_activations(config.style.zone.local_time(Utc::now()), NEXT_ACTIVATIONS);
+        let next: Vec<String> = next.iter().map(|at| at.format(ACTIVATION_FORMAT).to_string()).collect();
+        info!("Polling on schedule; next starts: {}", if next.is_empty() { "none".to_string() } else { next.join(", ") });
+    }
 
-        if let Err(e) = check_buses(&client, &bus_stops).await {
-            eprintln!("Error checking buses: {}", e);
+    let bus_stops = load_bus_stops(&config_file.stops, reload.input.as_ref()).map_err(ExitReason::ConfigError)?;
+    if let Some(stop) = options.until_first_match.as_ref().and_then(|first| first.stop.as_deref()) {
+        if !bus_stops.iter().any(|bus_stop| stop_names::same_stop(&bus_stop.name, stop)) {
+            warn!("--match-stop '{}' isn't one of the watched stops, so no alert will match it", stop);
         }
+    }
+    let (live, failures, history, absence) = match (LiveMessage::from_env(), FailureTracker::from_env(), open_history(), AbsenceWatch::from_env()) {
+        (Ok(live), Ok(failures), Ok(history), Ok(absence)) => (live, failures, history, absence),
+        (Err(e), _, _, _) | (_, Err(e), _, _) | (_, _, Err(e), _) | (_, _, _, Err(e)) => return Err(ExitReason::ConfigError(e)),
+    };
+
+    let telegram = Telegram::from_env(client.clone(), config.style, config_file)
+        .map_err(|e| ExitReason::NotifierStartup(e.to_string()))?;
+    if !options.skip_validation {
+        // Catch a bad token or chat id now rather than at the first alert
+        telegram.validate().await.map_err(|e| ExitReason::NotifierStartup(e.to_string()))?;
+    }
+    let notifiers = Notifiers::from_env(client.clone(), telegram.clone(), &config_file.groups).map_err(|e| ExitReason::NotifierStartup(e.to_string()))?;
+    if !options.skip_validation {
+        notifiers.validate().await.map_err(|e| ExitReason::NotifierStartup(e.to_string()))?;
+    }
+    let status: SharedStatus = Arc::new(Mutex::new(Status {
+        groups: group_labels(&bus_stops),
+        service_labels: config.service_labels.clone(),
+        stops: debug_server::stops_json(&bus_stops),
+        ..Status::default()
+    }));
+    let tracks = Tracks::new(config.max_plausible_speed_kmh);
+    let mut session = Session::new(AlertTracker::new(config.alert_cooldown), tracks, live, history, absence);
+    session.geocoder = ReverseGeocoder::from_env(client.clone()).map_err(ExitReason::ConfigError)?;
+    let signals = Signals::listen().map_err(ExitReason::ConfigError)?;
 
-        time::sleep(Duration::from_secs(10)).await;
+    // Handle presses of the alert buttons and commands in the background; /now and /history
+    // come back over a channel so only the tracker touches the bus API and the history database
+    let (requests, request_receiver) = mpsc::channel::<LoopRequest>(8);
+    let mut background = Vec::new();
+    if answer_commands {
+        background.push(tokio::spawn(telegram::poll_updates(telegram.clone(), controls.clone(), status.clone(), requests)).abort_handle());
     }
+    // One debug endpoint per process; with profiles it shows the first
+    if let (true, Some(addr)) = (answer_commands, env_or_file("DEBUG_HTTP_ADDR").map_err(ExitReason::ConfigError)?.filter(|addr| !addr.trim().is_empty())) {
+        let listener = TcpListener::bind(addr.trim())
+            .await
+            .map_err(|e| ExitReason::ConfigError(format!("Could not listen on DEBUG_HTTP_ADDR {}: {}", addr.trim(), e)))?;
+        let settings = debug_server::settings_json(&config, &notifiers.channels());
+        background.push(tokio::spawn(debug_server::serve(listener, settings, status.clone())).abort_handle());
+    }
+
+    let tracker = Tracker {
+        client,
+        config,
+        bus_stops,
+        telegram,
+        notifiers,
+        controls,
+        status,
+        session,
+        failures,
+        clock: Box::new(SystemClock),
+        latest: None,
+        last_cycle_at: None,
+        reload,
+        active_stops: Vec::new(),
+        active_date: None,
+        polling: None,
+        summary: options.summary,
+        until_first_match: options.until_first_match.clone(),
+        background,
+        deadline: Instant::now(),
+        extended: Duration::ZERO,
+        end_warned: false,
+        journey: None,
+        followed_first: false,
+        stop_after: None,
+        state_file: options.state_file.clone().filter(|_| answer_commands).map(StateFile::new),
+    };
+    Ok(tracker.run(signals, request_receiver))
 }
 
-// Load bus stops from .env file
-fn load_bus_stops() -> Vec<BusStop> {
-    let stops_str = match env::var("BUS_STOPS") {
-        Ok(value) => value,
-        Err(_) => {
-            eprintln!("Warning: BUS_STOPS environment variable not set. No bus stops loaded.");
-            return Vec::new();  // Return an empty vector if the variable is missing
+/// Sends a test message through every notification channel the config sets up
+async fn test_notify(config_path: Option<PathBuf>) -> ExitReason {
+    let (config_file, style, client) = match (ConfigFile::load_opt(config_path.as_deref()), Style::from_env(), http_client()) {
+        (Ok(file), Ok(style), Ok(client)) => (file, style, client),
+        (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => return ExitReason::ConfigError(e),
+    };
+    let mut notifiers = match Telegram::from_env(client.clone(), style, &config_file)
+        .and_then(|telegram| Notifiers::from_env(client, telegram, &config_file.groups))
+    {
+        Ok(notifiers) => notifiers,
+        Err(e) => return ExitReason::NotifierStartup(e.to_string()),
+    };
+
+    match notifiers.send_test("Test notification: bus alerts will arrive here.").await {
+        Ok(()) => {
+            println!("Test notification sent.");
+            ExitReason::Completed
+        }
+        Err(e) => ExitReason::NotifierStartup(format!("Test notification failed: {}", e)),
+    }
+}
+
+/// Prints min/avg/max dwell time per stop from the history database
+fn stats() -> ExitReason {
+    let history = match open_history() {
+        Ok(Some(history)) => history,
+        Ok(None) => return ExitReason::ConfigError("Set HISTORY_DB to the history database to read stats from.".to_string()),
+        Err(e) => return ExitReason::ConfigError(e),
+    };
+    let stats = match history.dwell_stats() {
+        Ok(stats) => stats,
+        Err(e) => return ExitReason::ConfigError(format!("Could not read history: {}", e)),
+    };
+
+    if stats.is_empty() {
+        println!("No visits recorded yet.");
+    }
+    for stop in stats {
+        let show = |secs: Option<i64>| secs.map(format::format_duration).unwrap_or_else(|| "-".to_string());
+        println!(
+            "{}: {} visits, dwell min {} / avg {} / max {} ({} without a departure)",
+            stop.stop,
+            stop.visits,
+            show(stop.min_secs),
+            show(stop.avg_secs.map(|avg| avg.round() as i64)),
+            show(stop.max_secs),
+            stop.open_visits
+        );
+    }
+    ExitReason::Completed
+}
+
+/// Runs an add-stop/remove-stop edit against the config file and prints its result
+fn edit_stops(config_path: Option<PathBuf>, edit: impl FnOnce(&std::path::Path) -> Result<String, String>) -> ExitReason {
+    let Some(path) = config_path else {
+        return ExitReason::ConfigError("Pass --config (or set CONFIG_FILE) to say which config file to edit.".to_string());
+    };
+    match edit(&path) {
+        Ok(output) => {
+            println!("{}", output.trim_end());
+            ExitReason::Completed
+        }
+        Err(e) => ExitReason::ConfigError(e),
+    }
+}
+
+/// Builds the HTTP client for the whole process: our user agent and HTTP_TIMEOUT_SECS on every request.
+/// Clones share one connection pool, so everything gets a clone of this rather than its own client.
+fn http_client() -> Result<Client, String> {
+    http_client_builder()?.build().map_err(|e| format!("Could not set up the HTTP client: {}", e))
+}
+
+/// The settings behind `http_client`, for the odd notifier that needs a client of its own
+fn http_client_builder() -> Result<reqwest::ClientBuilder, String> {
+    Ok(Client::builder()
+        .user_agent(USER_AGENT)
+        .connect_timeout(Duration::from_secs(10))
+        .timeout(Duration::from_secs(env_parse("HTTP_TIMEOUT_SECS", 15)?)))
+}
+
+/// Opens HISTORY_DB if configured
+fn open_history() -> Result<Option<History>, String> {
+    match env_or_file("HISTORY_DB")? {
+        Some(path) if !path.trim().is_empty() => History::open(path.trim())
+            .map(Some)
+            .map_err(|e| format!("Could not open HISTORY_DB {}: {}", path, e)),
+        _ => Ok(None),
+    }
+}
+
+/// ALERT_COOLDOWN_SECS (default 5 minutes) or, instead, ALERT_COOLDOWN_CYCLES poll cycles
+fn alert_cooldown() -> Result<Cooldown, String> {
+    match (env_parse_opt::<i64>("ALERT_COOLDOWN_SECS")?, env_parse_opt::<u64>("ALERT_COOLDOWN_CYCLES")?) {
+        (Some(_), Some(_)) => Err("Set ALERT_COOLDOWN_SECS or ALERT_COOLDOWN_CYCLES, not both.".to_string()),
+        (_, Some(cycles)) => Ok(Cooldown::Cycles(cycles)),
+        (secs, None) => Ok(Cooldown::Time(chrono::Duration::seconds(secs.unwrap_or(300)))),
+    }
+}
+
+impl Config {
+    fn from_env(file: &ConfigFile) -> Result<Config, TrackerError> {
+        let derive_radius = env_flag("DERIVE_RADIUS", false)?;
+        Ok(Config {
+            lat: coordinate_setting("LAT", file.lat, "the latitude to search around")?,
+            lng: coordinate_setting("LNG", file.lng, "the longitude to search around")?,
+            radius: if derive_radius {
+                env_parse_opt("RADIUS")?.or(file.radius)
+            } else {
+                Some(env_or_config("RADIUS", file.radius, "the search radius in meters (a whole number)")?)
+            },
+            derive_radius,
+            poll_interval_secs: poll_interval_secs(file)?,
+            stale_fix_secs: env_parse("STALE_FIX_SECS", 180)?, // 3 minutes
+            fix_age_warn_secs: env_parse("FIX_AGE_WARN_SECS", 60)?,
+            min_speed: env_parse_opt("MIN_SPEED")?,
+            missing_speed_passes: env_flag("MISSING_SPEED_PASSES", true)?,
+            style: Style::from_env()?,
+            alert_cooldown: alert_cooldown()?,
+            gps_file: env_or_file("GPS_FILE")?.filter(|p| !p.trim().is_empty()),
+            api_url: env_or_file("API_URL")?.map(|url| url.trim().to_string()).filter(|url| !url.is_empty()).unwrap_or_else(|| DEFAULT_API_URL.to_string()),
+            api_retries: env_parse("API_RETRIES", 1)?,
+            fallback_api_url: env_or_file("FALLBACK_API_URL")?.filter(|url| !url.trim().is_empty()),
+            require_route_match: env_flag("REQUIRE_ROUTE_MATCH", false)?,
+            alert_template: env_or_file("ALERT_TEMPLATE")?.or_else(|| file.alert_template.clone()),
+            fields: FieldMap::from_env(&file.field_map)?,
+            include_operator_link: env_flag("INCLUDE_OPERATOR_LINK", false)?,
+            geofence: env_or_file("GEOFENCE")?.filter(|g| !g.trim().is_empty()).map(|g| parse_polygon("GEOFENCE", &g)).transpose()?,
+            max_services_per_cycle: env_parse_opt("MAX_SERVICES_PER_CYCLE")?,
+            max_plausible_speed_kmh: env_parse("MAX_PLAUSIBLE_SPEED_KMH", 130.0)?,
+            confirmation_cycles: env_parse("CONFIRMATION_CYCLES", 1)?.max(1),
+            early_warning_radius: env_parse_opt("EARLY_WARNING_RADIUS")?,
+            warmup_secs: env_parse("WARMUP_SECS", 0)?,
+            report_closest_approach: env_flag("REPORT_CLOSEST_APPROACH", false)?,
+            wall_clock_budget: env_flag("WALL_CLOCK_BUDGET", false)?,
+            align_polls: env_flag("ALIGN_POLLS", false)?,
+            export_geojson: env_or_file("EXPORT_GEOJSON")?.filter(|path| !path.trim().is_empty()),
+            require_approaching_heading: env_flag("REQUIRE_APPROACHING_HEADING", false)?,
+            heading_tolerance_deg: env_parse("HEADING_TOLERANCE_DEG", 90.0)?,
+            lifecycle_notifications: env_flag("LIFECYCLE_NOTIFICATIONS", false)?,
+            dedup_distance_bucket_m: env_parse("DEDUP_DISTANCE_BUCKET_M", 25.0)?,
+            skip_unknown_services: env_flag("SKIP_UNKNOWN_SERVICES", true)?,
+            exclusion_zone: env_or_file("EXCLUSION_ZONE")?.filter(|zone| !zone.trim().is_empty()).map(|zone| ExclusionZone::parse(&zone)).transpose()?,
+            parked_speed_kmh: env_parse("PARKED_SPEED_KMH", 2.0)?,
+            parked_after: env_parse_opt::<i64>("PARKED_AFTER_MINS")?.map(chrono::Duration::minutes),
+            ignore_vehicles: env_or_file("IGNORE_VEHICLES")?
+                .unwrap_or_default()
+                .split(',')
+                .map(normalise_vehicle_id)
+                .filter(|id| !id.is_empty())
+                .collect(),
+            client_version: env_or_file("CLIENT_VERSION")?
+                .map(|version| version.trim().to_string())
+                .filter(|version| !version.is_empty())
+                .unwrap_or_else(|| DEFAULT_CLIENT_VERSION.to_string()),
+            history_positions: env_flag("HISTORY_POSITIONS", false)?,
+            service_labels: ServiceLabels::new(&file.service_labels),
+            routes: Routes::load(&file.routes, env_parse("ROUTE_MAX_OFFSET_M", 50.0)?)?,
+            schedule: PollSchedule::parse(&file.schedule)?,
+            run_length: env_parse_opt::<u64>("RUN_MINUTES")?.map_or(SCRIPT_TIMEOUT, |mins| Duration::from_secs(mins * 60)),
+            max_run_length: Duration::from_secs(env_parse("MAX_RUN_MINUTES", 120)? * 60),
+            default_extension: Duration::from_secs(env_parse("EXTEND_MINUTES", 15)? * 60),
+            follow_first_bus: env_flag("FOLLOW_FIRST_BUS", false)?,
+            follow_update_every: chrono::Duration::seconds(env_parse("FOLLOW_UPDATE_SECS", 60)?),
+            detect_bunching: env_flag("DETECT_BUNCHING", false)?,
+            priority_services: env_or_file("PRIORITY_SERVICES")?
+                .unwrap_or_default()
+                .split(',')
+                .map(|service| service.trim().to_string())
+                .filter(|service| !service.is_empty())
+                .collect(),
+            heartbeat_url: env_or_file("HEARTBEAT_URL")?.map(|url| url.trim().to_string()).filter(|url| !url.is_empty()),
+        })
+    }
+}
+
+impl Vehicle {
+    /// False for vehicles reported with a blank or placeholder ("Unknown") service number
+    fn in_service(&self) -> bool {
+        let service = self.service_number.trim();
+        !service.is_empty() && !service.eq_ignore_ascii_case("unknown")
+    }
+
+    /// Parses one entry of the `services` array, returning None if it has no usable position
+    fn from_json(service: &Value, fields: &FieldMap) -> Option<Vehicle> {
+        let field = |key: &str| fields::get(service, key);
+        let lat = json_f64(field(&fields.latitude))?;
+        let lng = json_f64(field(&fields.longitude))?;
+
+        Some(Vehicle {
+            service_number: json_string(field(&fields.service_number)).unwrap_or_else(|| "Unknown".to_string()),
+            service_description: json_string(field(&fields.service_description)).unwrap_or_else(|| "No description".to_string()),
+            fleet_number: json_string(field(&fields.fleet_number)),
+            registration: json_string(field(&fields.registration)),
+            lat,
+            lng,
+            speed: json_f64(field(&fields.speed)),
+            heading: json_f64(field(&fields.heading)),
+            updated_at: json_timestamp(field(&fields.update_time)),
+            upcoming_stops: json_upcoming_stops(service),
+        })
+    }
+
+    /// Stable key for this physical vehicle, used for alert cooldowns
+    fn key(&self) -> String {
+        match (&self.fleet_number, &self.registration) {
+            (Some(fleet), _) => format!("fleet:{}", fleet),
+            (None, Some(reg)) => format!("reg:{}", reg),
+            (None, None) => format!("service:{}", self.service_number),
+        }
+    }
+
+    /// Fleet number and registration (where known), used to tell buses on the same service apart
+    fn identifier(&self) -> String {
+        match (&self.fleet_number, &self.registration) {
+            (Some(fleet), Some(reg)) => format!("fleet {} / {}", fleet, reg),
+            (Some(fleet), None) => format!("fleet {}", fleet),
+            (None, Some(reg)) => reg.clone(),
+            (None, None) => "unknown vehicle".to_string(),
+        }
+    }
+
+    /// Whether IGNORE_VEHICLES lists this vehicle's fleet number or registration
+    fn is_ignored(&self, ignore: &[String]) -> bool {
+        let ids = [&self.fleet_number, &self.registration];
+        ids.into_iter().flatten().any(|id| ignore.contains(&normalise_vehicle_id(id)))
+    }
+
+    /// Age of the position fix in seconds, if the API told us when it was taken
+    fn fix_age_secs(&self, now: DateTime<Utc>) -> Option<i64> {
+        self.updated_at.map(|t| (now - t).num_seconds().max(0))
+    }
+}
+
+/// Fleet numbers and registrations compare without case or spaces ("SN19 ABC" = "sn19abc")
+fn normalise_vehicle_id(id: &str) -> String {
+    id.chars().filter(|c| !c.is_whitespace()).collect::<String>().to_ascii_uppercase()
+}
+
+/// Where to search around this cycle: the position in GPS_FILE if it can be read, otherwise LAT/LNG.
+/// The file is re-read every cycle so the search follows a moving user.
+fn current_center(config: &Config) -> (f64, f64) {
+    let Some(path) = &config.gps_file else {
+        return (config.lat, config.lng);
+    };
+
+    match fs::read_to_string(path) {
+        Ok(contents) => match parse_gps_position(&contents) {
+            Some(position) => position,
+            None => {
+                warn!("GPS_FILE {} does not contain a valid lat,lng (with decimal commas, separate them with ';' or a space). Using LAT/LNG.", path);
+                (config.lat, config.lng)
+            }
+        },
+        Err(e) => {
+            debug!("GPS_FILE {} not readable ({}). Using LAT/LNG.", path, e);
+            (config.lat, config.lng)
+        }
+    }
+}
+
+// GPS_FILE holds a single "lat,lng" line. With decimal commas ("53,4") the two need another
+// separator, "53,4;-2,98" or "53,4 -2,98"; "53,4,-2,98" could be split either way, so it's refused.
+fn parse_gps_position(contents: &str) -> Option<(f64, f64)> {
+    let contents = contents.trim();
+    let (lat, lng) = if let Some(pair) = contents.split_once(';') {
+        pair
+    } else if contents.matches(',').count() == 1 {
+        contents.split_once(',')?
+    } else {
+        let mut parts = contents.split_whitespace();
+        let pair = (parts.next()?, parts.next()?);
+        if parts.next().is_some() {
+            return None;
+        }
+        pair
+    };
+    let (lat, _) = parse_coordinate(lat)?;
+    let (lng, _) = parse_coordinate(lng)?;
+
+    ((-90.0..=90.0).contains(&lat) && (-180.0..=180.0).contains(&lng)).then_some((lat, lng))
+}
+
+/// Parses one coordinate copied from wherever: whitespace and degree signs are dropped, and a
+/// lone comma with no point is read as a decimal comma ("55,95" is 55.95). Returns the value and
+/// whether the text needed any of that, so callers can say what they did.
+fn parse_coordinate(text: &str) -> Option<(f64, bool)> {
+    let trimmed = text.trim();
+    let mut cleaned: String = trimmed.chars().filter(|c| !c.is_whitespace() && !['°', 'º', '˚'].contains(c)).collect();
+    if cleaned.matches(',').count() == 1 && !cleaned.contains('.') {
+        cleaned = cleaned.replace(',', ".");
+    }
+    let value: f64 = cleaned.parse().ok().filter(|value: &f64| value.is_finite())?;
+    Some((value, cleaned != trimmed))
+}
+
+/// Collects upcoming stop names from whichever route fields the response includes:
+/// `nextStopName`, or a `nextStops` / `onwardCalls` / `route` array of names or stop objects.
+/// Returns None when there's no route information at all.
+fn json_upcoming_stops(service: &Value) -> Option<Vec<String>> {
+    let mut stops: Vec<String> = Vec::new();
+
+    if let Some(name) = json_string(&service["nextStopName"]) {
+        stops.push(name);
+    }
+    for field in ["nextStops", "onwardCalls", "route"] {
+        for entry in service[field].as_array().into_iter().flatten() {
+            let name = json_string(entry)
+                .or_else(|| json_string(&entry["stopName"]))
+                .or_else(|| json_string(&entry["name"]));
+            stops.extend(name);
+        }
+    }
+
+    (!stops.is_empty()).then_some(stops)
+}
+
+/// Whether the bus's route serves `stop_name`. Buses without route data always pass,
+/// so REQUIRE_ROUTE_MATCH can't silence everything when the API omits the fields.
+fn route_serves_stop(upcoming_stops: Option<&[String]>, stop_name: &str) -> bool {
+    let normalise = |name: &str| name.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase();
+
+    match upcoming_stops {
+        None => true,
+        Some(stops) => stops.iter().any(|stop| normalise(stop) == normalise(stop_name)),
+    }
+}
+
+/// Parses a polygon setting such as GEOFENCE: "lat,lng;lat,lng;lat,lng", the vertices in order
+fn parse_polygon(name: &str, value: &str) -> Result<Vec<(f64, f64)>, String> {
+    let vertices = value
+        .split(';')
+        .filter(|vertex| !vertex.trim().is_empty())
+        .map(|vertex| {
+            parse_gps_position(vertex).ok_or_else(|| format!("{} has an invalid vertex '{}'; expected lat,lng.", name, vertex.trim()))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if vertices.len() < 3 {
+        return Err(format!("{} needs at least three lat,lng vertices separated by ';'.", name));
+    }
+    Ok(vertices)
+}
+
+/// An area where vehicles are never considered, such as a bus depot
+#[derive(Debug, Clone, PartialEq)]
+enum ExclusionZone {
+    Circle { lat: f64, lng: f64, radius: f64 },
+    Polygon(Vec<(f64, f64)>),
+}
+
+impl ExclusionZone {
+    /// Parses EXCLUSION_ZONE: "lat,lng,radius" for a circle (radius in meters) or a GEOFENCE-style polygon
+    fn parse(value: &str) -> Result<ExclusionZone, String> {
+        if value.contains(';') {
+            return parse_polygon("EXCLUSION_ZONE", value).map(ExclusionZone::Polygon);
+        }
+        let parts: Vec<Option<f64>> = value.split(',').map(|part| part.trim().parse().ok()).collect();
+        match parts.as_slice() {
+            [Some(lat), Some(lng), Some(radius)] if *radius > 0.0 && (-90.0..=90.0).contains(lat) && (-180.0..=180.0).contains(lng) => {
+                Ok(ExclusionZone::Circle { lat: *lat, lng: *lng, radius: *radius })
+            }
+            _ => Err(format!("EXCLUSION_ZONE must be \"lat,lng,radius\" or \"lat,lng;lat,lng;lat,lng\", got '{}'.", value)),
+        }
+    }
+
+    fn contains(&self, lat: f64, lng: f64) -> bool {
+        match self {
+            ExclusionZone::Circle { lat: zone_lat, lng: zone_lng, radius } => haversine_distance(*zone_lat, *zone_lng, lat, lng) <= *radius,
+            ExclusionZone::Polygon(vertices) => point_in_polygon(lat, lng, vertices),
+        }
+    }
+}
+
+/// Ray casting: counts how many polygon edges a ray east from the point crosses; an odd
+/// count means inside. Points on an edge count as inside, which ray casting alone leaves to
+/// chance. Treats lat/lng as flat, which is fine at neighbourhood scale.
+fn point_in_polygon(lat: f64, lng: f64, verts: &[(f64, f64)]) -> bool {
+    if on_polygon_edge(lat, lng, verts) {
+        return true;
+    }
+    let mut inside = false;
+    let mut j = verts.len().wrapping_sub(1);
+    for i in 0..verts.len() {
+        let (lat_i, lng_i) = verts[i];
+        let (lat_j, lng_j) = verts[j];
+        if (lat_i > lat) != (lat_j > lat) && lng < (lng_j - lng_i) * (lat - lat_i) / (lat_j - lat_i) + lng_i {
+            inside = !inside;
+        }
+        j = i;
+    }
+    inside
+}
+
+fn on_polygon_edge(lat: f64, lng: f64, verts: &[(f64, f64)]) -> bool {
+    const TOLERANCE: f64 = 1e-12; // In squared degrees; far below GPS precision
+    verts.iter().zip(verts.iter().cycle().skip(1)).any(|(&(lat_a, lng_a), &(lat_b, lng_b))| {
+        let cross = (lat_b - lat_a) * (lng - lng_a) - (lng_b - lng_a) * (lat - lat_a);
+        cross.abs() <= TOLERANCE
+            && lat >= lat_a.min(lat_b) - TOLERANCE
+            && lat <= lat_a.max(lat_b) + TOLERANCE
+            && lng >= lng_a.min(lng_b) - TOLERANCE
+            && lng <= lng_a.max(lng_b) + TOLERANCE
+    })
+}
+
+/// Whether a vehicle is moving fast enough to count as arriving rather than parked.
+/// Vehicles that don't report a speed pass unless `missing_passes` is false.
+fn passes_min_speed(speed: Option<f64>, min_speed: Option<f64>, missing_passes: bool) -> bool {
+    match (speed, min_speed) {
+        (_, None) => true,
+        (Some(speed), Some(min)) => speed >= min,
+        (None, Some(_)) => missing_passes,
+    }
+}
+
+// The API returns most values as strings, but accept plain JSON numbers too
+fn json_string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) if !s.trim().is_empty() => Some(s.trim().to_string()),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+fn json_f64(value: &Value) -> Option<f64> {
+    match value {
+        Value::String(s) => s.trim().parse().ok(),
+        Value::Number(n) => n.as_f64(),
+        _ => None,
+    }
+}
+
+// updateTime is epoch milliseconds; seconds and RFC 3339 strings are handled as well
+fn json_timestamp(value: &Value) -> Option<DateTime<Utc>> {
+    if let Some(Ok(t)) = value.as_str().map(DateTime::parse_from_rfc3339) {
+        return Some(t.with_timezone(&Utc));
+    }
+
+    let raw = json_f64(value)? as i64;
+    if raw > 100_000_000_000 {
+        Utc.timestamp_millis_opt(raw).single()
+    } else {
+        Utc.timestamp_opt(raw, 0).single()
+    }
+}
+
+/// Fills an alert template's {service}, {description}, {vehicle}, {stop} and {group} placeholders
+fn render_alert_template(template: &str, vehicle: &Vehicle, direction: &Direction, stop: &BusStop, labels: &ServiceLabels) -> String {
+    template
+        .replace("{service}", &vehicle.service_number)
+        .replace("{label}", labels.label(&vehicle.service_number))
+        .replace("{description}", &vehicle.service_description)
+        .replace("{direction}", &direction.to_string())
+        .replace("{vehicle}", &vehicle.identifier())
+        .replace("{stop}", &stop.name)
+        .replace("{group}", stop.group.as_deref().unwrap_or(DEFAULT_GROUP))
+}
+
+/// Link to the operator's live map, centred on the bus and filtered to its service
+fn operator_link(service: &str, lat: f64, lng: f64) -> String {
+    format!("{}?service={}&lat={:.5}&lng={:.5}", OPERATOR_MAP_URL, urlencode(service), lat, lng)
+}
+
+/// Percent-encodes everything but unreserved characters, for use in a URL query
+fn urlencode(text: &str) -> String {
+    text.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Distinct group labels in use, with ungrouped stops falling under DEFAULT_GROUP
+fn group_labels(stops: &[BusStop]) -> Vec<String> {
+    let mut groups: Vec<String> = stops
+        .iter()
+        .map(|stop| stop.group.clone().unwrap_or_else(|| DEFAULT_GROUP.to_string()))
+        .collect();
+    groups.sort();
+    groups.dedup();
+    groups
+}
+
+/// Stop definitions in the BUS_STOPS format (or CSV, one stop per line), and where they came from
+#[derive(Debug, Clone)]
+struct StopsInput {
+    source: String, // For messages: "BUS_STOPS", "stdin" or the file name
+    text: String,
+}
+
+impl StopsInput {
+    /// Reads `--stops` (a file, or stdin for "-") if given, otherwise the BUS_STOPS variable
+    fn read(path: Option<&Path>) -> Result<Option<StopsInput>, String> {
+        match path {
+            Some(path) if path == Path::new("-") => {
+                let mut text = String::new();
+                io::stdin().read_to_string(&mut text).map_err(|e| format!("Could not read stops from stdin: {}", e))?;
+                Ok(Some(StopsInput { source: "stdin".to_string(), text }))
+            }
+            Some(path) => {
+                let text = fs::read_to_string(path).map_err(|e| format!("Could not read stops file {}: {}", path.display(), e))?;
+                Ok(Some(StopsInput { source: path.display().to_string(), text }))
+            }
+            None => Ok(env_or_file("BUS_STOPS")?.map(|text| StopsInput { source: "BUS_STOPS".to_string(), text })),
+        }
+    }
+
+    /// The text in BUS_STOPS form. Several lines are taken as CSV: one stop per line,
+    /// with a "name,lat,lng,..." header line skipped.
+    fn entries_text(&self) -> String {
+        let lines: Vec<&str> = self.text.lines().map(str::trim).filter(|line| !line.is_empty()).collect();
+        if lines.len() < 2 {
+            return self.text.trim().to_string();
+        }
+        let header = lines[0].split(',').next().is_some_and(|field| field.trim().eq_ignore_ascii_case("name"));
+        lines[usize::from(header)..].join(";")
+    }
+}
+
+/// Where a tracker's stops come from, so SIGHUP can read them again
+#[derive(Debug, Clone)]
+struct StopsReload {
+    config_path: Option<PathBuf>,
+    profile: Option<String>, // The profile whose stops these are
+    stops_path: Option<PathBuf>,
+    input: Option<StopsInput>, // As read at startup
+}
+
+impl StopsReload {
+    /// Re-reads the config file and the --stops file. BUS_STOPS can't change under a running
+    /// process, and stdin can't be read twice, so stops from those stay as they were.
+    fn load(&mut self) -> Result<Vec<BusStop>, String> {
+        let mut file = ConfigFile::load_opt(self.config_path.as_deref())?;
+        if let Some(profile) = &self.profile {
+            file = file.profiles(Some(profile), Utc::now().naive_utc())?.remove(0).1;
+        }
+        if let Some(path) = self.stops_path.as_deref().filter(|path| *path != Path::new("-")) {
+            self.input = StopsInput::read(Some(path))?;
+        }
+        load_bus_stops(&file.stops, self.input.as_ref())
+    }
+}
+
+/// A config file stop's quiet_days/quiet_hours, if it sets either
+fn stop_quiet_hours(entry: &StopEntry) -> Result<Option<QuietHours>, String> {
+    if entry.quiet_hours.as_deref().is_some_and(|hours| hours.trim().eq_ignore_ascii_case("never")) {
+        if !entry.quiet_days.is_empty() {
+            return Err(format!("Config file stop '{}' has quiet_days but quiet_hours = \"never\".", entry.name));
+        }
+        return Ok(Some(QuietHours::Never));
+    }
+    if entry.quiet_days.is_empty() && entry.quiet_hours.is_none() {
+        return Ok(None);
+    }
+
+    let days = entry
+        .quiet_days
+        .iter()
+        .map(|day| day.parse::<Weekday>().map_err(|_| format!("Config file stop '{}' has an unknown quiet day '{}'.", entry.name, day)))
+        .collect::<Result<_, _>>()?;
+    let window = match &entry.quiet_hours {
+        Some(hours) => Some(
+            TimeWindow::parse(hours)
+                .ok_or_else(|| format!("Config file stop '{}': quiet_hours must look like 22:00-07:00 or \"never\".", entry.name))?,
+        ),
+        None => None,
+    };
+    Ok(Some(QuietHours::During { days, window }))
+}
+
+// Load bus stops from the config file's [[stops]] and BUS_STOPS (or --stops)
+fn load_bus_stops(file_stops: &[StopEntry], input: Option<&StopsInput>) -> Result<Vec<BusStop>, String> {
+    let mut stops = Vec::new();
+    for entry in file_stops {
+        if !(-90.0..=90.0).contains(&entry.lat) || !(-180.0..=180.0).contains(&entry.lng) {
+            return Err(format!("Config file stop '{}' has invalid coordinates.", entry.name));
+        }
+        if entry.radius.is_some_and(|radius| radius <= 0.0) {
+            return Err(format!("Config file stop '{}' has an invalid radius.", entry.name));
+        }
+        let days = entry
+            .days
+            .iter()
+            .map(|day| day.parse::<Weekday>().map_err(|_| format!("Config file stop '{}' has an unknown day '{}'.", entry.name, day)))
+            .collect::<Result<_, _>>()?;
+        stops.push(BusStop {
+            name: entry.name.clone(),
+            lat: entry.lat,
+            lng: entry.lng,
+            radius: entry.radius.unwrap_or(DEFAULT_STOP_RADIUS),
+            group: entry.group.clone(),
+            services: entry.services.clone(),
+            days,
+            quiet: stop_quiet_hours(entry)?,
+        });
+    }
+
+    let input = match input {
+        Some(input) => input,
+        None if !stops.is_empty() => {
+            let stops = merge_duplicate_stops(stops);
+            info!("Loaded {} bus stops from the config file.", stops.len());
+            return Ok(stops);
+        }
+        None => {
+            warn!("BUS_STOPS environment variable not set. No bus stops loaded.");
+            return Ok(Vec::new());  // Return an empty vector if the variable is missing
         }
     };
 
     // Split the string into individual stops, ensuring that invalid or empty entries are ignored
-    let stops = stops_str
-        .split(';')  // Split by semicolon for multiple stops
+    let entries = split_stop_entries(&input.entries_text(), &input.source);
+
+    let env_stops = entries
+        .iter()
         .filter_map(|s| {
             let mut parts = s.split(',');
-            // Check if there are exactly 3 parts (name, lat, lng), if not, skip
+            // Check there are at least 3 parts (name, lat, lng), if not, skip
             if let (Some(name), Some(lat), Some(lng)) = (
                 parts.next().map(|x| x.trim()),
                 parts.next().map(|x| x.trim()),
                 parts.next().map(|x| x.trim())
             ) {
-                // Parse latitude and longitude safely, log and skip invalid ones
-                let lat = lat.parse::<f64>().ok();
-                let lng = lng.parse::<f64>().ok();
+                // Parse latitude and longitude tolerantly, log and skip invalid ones
+                let coordinate = |text: &str| {
+                    let (value, cleaned) = parse_coordinate(text)?;
+                    if cleaned {
+                        warn!("Read coordinate '{}' of bus stop {} as {}.", text, name, value);
+                    }
+                    Some(value)
+                };
+                let lat = coordinate(lat);
+                let lng = coordinate(lng);
+
+                // Optional 4th field is the stop's radius (blank for the default), 5th its group
+                let radius = match parts.next().map(|x| x.trim()).filter(|x| !x.is_empty()) {
+                    Some(radius) => match radius.parse::<f64>() {
+                        Ok(radius) if radius > 0.0 => radius,
+                        _ => {
+                            warn!("Invalid radius for bus stop {}. Skipping.", name);
+                            return None;
+                        }
+                    },
+                    None => DEFAULT_STOP_RADIUS,
+                };
+                let group = parts.next().map(|x| x.trim().to_string()).filter(|x| !x.is_empty());
+
                 if let (Some(lat), Some(lng)) = (lat, lng) {
                     Some(BusStop {
                         name: name.to_string(),
                         lat,
                         lng,
+                        radius,
+                        group,
+                        services: Vec::new(),
+                        days: Vec::new(),
+                        quiet: None,
                     })
                 } else {
-                    eprintln!("Warning: Invalid coordinates for a bus stop. Skipping.");
+                    warn!("Invalid coordinates for a bus stop. Skipping.");
                     None
                 }
             } else {
-                eprintln!("Warning: Invalid bus stop format. Skipping entry.");
+                warn!("Invalid bus stop format. Skipping entry.");
                 None
             }
         })
         .collect::<Vec<BusStop>>();
 
     // If you need to debug, consider logging the count of bus stops instead of their details
-    if !stops.is_empty() {
-        println!("Loaded {} bus stops.", stops.len());
+    let from_env = env_stops.len();
+    stops.extend(env_stops);
+    let stops = merge_duplicate_stops(stops);
+    if from_env > 0 {
+        info!("Loaded {} bus stops.", stops.len());
+    } else if entries.is_empty() {
+        info!("No valid bus stops found in {}.", input.source);
     } else {
-        println!("No valid bus stops found.");
+        // Configured, but nothing usable: the tracker would run forever without matching anything
+        warn!(
+            "None of the {} entries in {} are valid, so no bus can ever match. \
+             Expected name,lat,lng[,radius[,group]] entries separated by ';'.",
+            entries.len(),
+            input.source
+        );
+        if env_flag("STRICT_STOPS", false)? {
+            return Err(format!("STRICT_STOPS is set and {} contains no valid bus stops.", input.source));
+        }
     }
 
-    stops
+    Ok(stops)
 }
 
+/// Drops stops whose canonical name repeats an earlier one (e.g. "Main St adj" after "Main Street (adj)"),
+/// reporting each merge; the first spelling is the one used in messages. Entries for different days are kept.
+fn merge_duplicate_stops(stops: Vec<BusStop>) -> Vec<BusStop> {
+    let mut merged: Vec<BusStop> = Vec::with_capacity(stops.len());
+    for stop in stops {
+        match merged.iter().find(|kept| stop_names::same_stop(&kept.name, &stop.name) && kept.days == stop.days) {
+            Some(kept) => info!("Merged duplicate stop '{}' into '{}'.", stop.name, kept.name),
+            None => merged.push(stop),
+        }
+    }
+    merged
+}
 
-async fn check_buses(client: &Client, bus_stops: &[BusStop]) -> Result<(), reqwest::Error> {
-    let lat: f64 = env::var("LAT")
-    .expect("Missing LAT in environment variables. Please set LAT to the correct latitude.")
-    .parse()
-    .expect("LAT must be a valid floating-point number.");
+/// Splits BUS_STOPS-style text into "name,lat,lng[,radius[,group]]" entries. When some entry has the wrong
+/// number of fields (usually ',' and ';' mixed up), the whole value is re-read as a sequence of
+/// name, lat, lng groups regardless of delimiter, with a warning saying what it was read as.
+fn split_stop_entries(text: &str, source: &str) -> Vec<String> {
+    let entries: Vec<String> = text
+        .split(';')  // Split by semicolon for multiple stops
+        .filter(|s| !s.trim().is_empty())
+        .map(str::to_string)
+        .collect();
+    if entries.iter().all(|entry| (3..=5).contains(&entry.split(',').count())) {
+        return entries;
+    }
 
-    let lng: f64 = env::var("LNG")
-        .expect("Missing LNG in environment variables. Please set LNG to the correct longitude.")
-        .parse()
-        .expect("LNG must be a valid floating-point number.");
+    let is_number = |token: &str| parse_coordinate(token).is_some();
+    let tokens: Vec<&str> = text.split([',', ';']).map(str::trim).collect();
+    let starts_stop = |i: usize| {
+        i + 2 < tokens.len() && !tokens[i].is_empty() && !is_number(tokens[i]) && is_number(tokens[i + 1]) && is_number(tokens[i + 2])
+    };
 
-    let radius: u32 = env::var("RADIUS")
-        .expect("Missing RADIUS in environment variables. Please set RADIUS to a valid integer (in meters).")
-        .parse()
-        .expect("RADIUS must be a valid integer.");
+    let mut recovered = Vec::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        if tokens[i].is_empty() {
+            i += 1; // Stray or trailing delimiter
+            continue;
+        }
+        if !starts_stop(i) {
+            return entries; // Not just a delimiter mix-up; let the usual warnings explain
+        }
+        // Up to two optional fields (radius, group) before the next stop begins; a radius has to be
+        // a number (or blank), so a name there starts something that isn't a whole stop
+        let mut end = i + 3;
+        while end < tokens.len() && end < i + 5 && !starts_stop(end) {
+            if end == i + 3 && !tokens[end].is_empty() && tokens[end].parse::<f64>().is_err() {
+                break;
+            }
+            end += 1;
+        }
+        recovered.push(tokens[i..end].join(","));
+        i = end;
+    }
+
+    let names: Vec<&str> = recovered.iter().filter_map(|entry| entry.split(',').next()).collect();
+    warn!(
+        "{} mixes up ',' and ';': expected name,lat,lng[,radius[,group]] entries separated by ';'. \
+         Read it as {} stop(s): {}.",
+        source,
+        recovered.len(),
+        names.join(", ")
+    );
+    recovered
+}
 
-    println!("Checking buses within {} meters of location ({}, {})", radius, lat, lng);
 
-    let url = format!(
-        "{}?client_version=UKBUS_APP&descriptive_fields=1&lat={}&lng={}&radius={}",
-        API_URL, lat, lng, radius
+/// Queries the vehicle API around the current centre, failing on HTTP or JSON decode errors.
+/// The primary API is retried API_RETRIES times; only then is FALLBACK_API_URL tried, if set.
+async fn fetch_services(client: &Client, config: &Config, bus_stops: &[BusStop]) -> Result<Value, TrackerError> {
+    let (lat, lng) = current_center(config);
+    let radius = query_radius(config, lat, lng, bus_stops);
+    info!("Checking buses within {} of location ({}, {})", config.style.distance(radius as f64), lat, lng);
+
+    let query = format!(
+        "client_version={}&descriptive_fields=1&lat={}&lng={}&radius={}",
+        urlencode(&config.client_version),
+        lat,
+        lng,
+        radius
     );
 
-    let response = client.get(&url).send().await?.json::<Value>().await?;
+    let mut attempt = 0;
+    let primary_error = loop {
+        match fetch_json(client, &config.api_url, &query).await {
+            Ok(response) => return Ok(response),
+            Err(e) if attempt < config.api_retries => {
+                attempt += 1;
+                warn!("Error fetching buses (attempt {} of {}): {}", attempt, config.api_retries + 1, e);
+                time::sleep(API_RETRY_DELAY * attempt).await;
+            }
+            Err(e) => break e,
+        }
+    };
 
-    if let Some(services) = response["services"].as_array() {
-        for service in services {
-            if let (Some(bus_lat), Some(bus_lng)) = (
-                service["latitude"].as_str().and_then(|s| s.parse::<f64>().ok()),
-                service["longitude"].as_str().and_then(|s| s.parse::<f64>().ok()),
-            ) {
-                // Get the service number (serviceNumber) and description (serviceDescription)
-                let service_number = service["serviceNumber"].as_str().unwrap_or("Unknown");
-                let service_description = service["serviceDescription"].as_str().unwrap_or("No description");
-        
-                // Print the current bus's location and service details
-                // println!("Found (Bus {} [{}]): lat = {}, lng = {}", service_number, service_description, bus_lat, bus_lng);
-        
-                if let Some(nearby_stop) = find_nearest_stop(bus_lat, bus_lng, bus_stops) {
-                    let message = format!(
-                        "Bus ({}) {} is near **{}**!",
-                        service_number, service_description, nearby_stop
-                    );
-        
-                    send_telegram_message(&message).await?;
-                    // println!("Bus {} found near: {}", service_number, nearby_stop);
-                }
-                // else {
-                //     println!("Bus {} is not near any stops.", service_number);
-                // }
+    match &config.fallback_api_url {
+        Some(fallback) => {
+            warn!("Primary API failed ({}); trying FALLBACK_API_URL", primary_error);
+            fetch_json(client, fallback, &query).await
+        }
+        None => Err(primary_error),
+    }
+}
+
+/// GETs `base_url` with the query appended and decodes the JSON body.
+/// The fallback is expected to answer in the same shape as the primary.
+async fn fetch_json(client: &Client, base_url: &str, query: &str) -> Result<Value, TrackerError> {
+    let separator = if base_url.contains('?') { '&' } else { '?' };
+    let url = format!("{}{}{}", base_url, separator, query);
+    let body = client.get(&url).send().await?.error_for_status()?.text().await?;
+
+    let response: Value = serde_json::from_str(&body).map_err(|e| TrackerError::Parse(format!("vehicle API returned invalid JSON: {}", e)))?;
+    if !response.is_object() {
+        return Err(TrackerError::Parse("vehicle API returned something other than a JSON object".to_string()));
+    }
+    Ok(response)
+}
+
+/// The usable ones of a response's vehicles; with MAX_SERVICES_PER_CYCLE, only the nearest of them.
+/// Unusable vehicles go first, so a nearby stale or parked bus never takes a usable one's place.
+fn vehicles_to_check(mut vehicles: Vec<Vehicle>, config: &Config, bus_stops: &[BusStop], now: DateTime<Utc>) -> Vec<Vehicle> {
+    vehicles.retain(|vehicle| is_usable(vehicle, vehicle.fix_age_secs(now), config));
+    if let Some(limit) = config.max_services_per_cycle {
+        nearest_vehicles(&mut vehicles, bus_stops, limit);
+    }
+    vehicles
+}
+
+/// Collapses entries for the same vehicle in one response, keeping the freshest fix (the later
+/// entry when the fixes are equally old or undated). Entries sharing a fleet number or a
+/// registration are the same vehicle even if one of them lacks the other identifier, which the
+/// merged entry then takes from it. Vehicles with neither can't be told apart, so they're all kept.
+fn dedup_vehicles(vehicles: &mut Vec<Vehicle>) {
+    let mut kept: Vec<Option<Vehicle>> = Vec::with_capacity(vehicles.len());
+    let mut by_fleet: HashMap<String, usize> = HashMap::new();
+    let mut by_registration: HashMap<String, usize> = HashMap::new();
+    for vehicle in vehicles.drain(..) {
+        let mut same: Vec<usize> = [
+            vehicle.fleet_number.as_ref().and_then(|fleet| by_fleet.get(fleet)),
+            vehicle.registration.as_ref().and_then(|reg| by_registration.get(reg)),
+        ]
+        .into_iter()
+        .flatten()
+        .copied()
+        .collect();
+        same.sort_unstable();
+        same.dedup();
+
+        let Some(&into) = same.first() else {
+            if vehicle.fleet_number.is_some() || vehicle.registration.is_some() {
+                index_vehicle(&vehicle, kept.len(), &mut by_fleet, &mut by_registration);
             }
+            kept.push(Some(vehicle));
+            continue;
+        };
+        debug!("Vehicle {} appears more than once in the response; keeping the freshest entry", vehicle.identifier());
+        // The fleet number may match one earlier entry and the registration another: all three are one bus
+        let mut merged = vehicle;
+        for &i in same.iter().rev() {
+            let earlier = kept[i].take().expect("indexed entries are kept");
+            merged = merge_vehicle_entries(earlier, merged);
         }
-    } else {
-        println!("No services found in the response.");
+        for index in by_fleet.values_mut().chain(by_registration.values_mut()) {
+            if same.contains(index) {
+                *index = into;
+            }
+        }
+        index_vehicle(&merged, into, &mut by_fleet, &mut by_registration);
+        kept[into] = Some(merged);
+    }
+    *vehicles = kept.into_iter().flatten().collect();
+}
+
+fn index_vehicle(vehicle: &Vehicle, at: usize, by_fleet: &mut HashMap<String, usize>, by_registration: &mut HashMap<String, usize>) {
+    if let Some(fleet) = &vehicle.fleet_number {
+        by_fleet.insert(fleet.clone(), at);
+    }
+    if let Some(reg) = &vehicle.registration {
+        by_registration.insert(reg.clone(), at);
+    }
+}
+
+/// One entry for a vehicle listed twice: the fresher fix (`later` when they're equally old), with
+/// any identifier it lacks taken from the other entry
+fn merge_vehicle_entries(earlier: Vehicle, later: Vehicle) -> Vehicle {
+    let (mut fresher, other) = if later.updated_at >= earlier.updated_at { (later, earlier) } else { (earlier, later) };
+    fresher.fleet_number = fresher.fleet_number.or(other.fleet_number);
+    fresher.registration = fresher.registration.or(other.registration);
+    fresher
+}
+
+/// The vehicles in a response worth looking at, before check_buses's own per-cycle filtering
+fn usable_vehicles(response: &Value, config: &Config, now: DateTime<Utc>) -> Vec<Vehicle> {
+    let mut vehicles: Vec<Vehicle> =
+        response["services"].as_array().into_iter().flatten().filter_map(|service| Vehicle::from_json(service, &config.fields)).collect();
+    dedup_vehicles(&mut vehicles);
+    vehicles.retain(|vehicle| !vehicle.is_ignored(&config.ignore_vehicles) && is_usable(vehicle, vehicle.fix_age_secs(now), config));
+    vehicles
+}
+
+/// Keeps only the `limit` vehicles closest to any stop (MAX_SERVICES_PER_CYCLE), nearest first
+fn nearest_vehicles(vehicles: &mut Vec<Vehicle>, bus_stops: &[BusStop], limit: usize) {
+    if vehicles.len() <= limit {
+        return;
     }
-    
-    
-    Ok(())
+    // Each vehicle's distance is worked out once up front, not on every comparison
+    let positions: Vec<(f64, f64)> = vehicles.iter().map(|vehicle| (vehicle.lat, vehicle.lng)).collect();
+    let mut ranked: Vec<(f64, Vehicle)> = nearest_stop_batch(&positions, bus_stops)
+        .into_iter()
+        .map(|nearest| nearest.map_or(f64::INFINITY, |(_, d)| d))
+        .zip(vehicles.drain(..))
+        .collect();
+    ranked.sort_by(|(a_distance, a), (b_distance, b)| {
+        session::nearest_then_name((*a_distance, &a.service_number), (*b_distance, &b.service_number)).then_with(|| a.key().cmp(&b.key()))
+    });
+    debug!("Processing the nearest {} of {} vehicles", limit, ranked.len());
+    vehicles.extend(ranked.into_iter().take(limit).map(|(_, vehicle)| vehicle));
 }
 
+/// Whether a vehicle's report is worth acting on: it's in service, its position is recent
+/// enough and, with MIN_SPEED set, it's moving
+fn is_usable(vehicle: &Vehicle, fix_age: Option<i64>, config: &Config) -> bool {
+    if config.skip_unknown_services && !vehicle.in_service() {
+        debug!("Skipping vehicle {}: no service number", vehicle.identifier());
+        return false;
+    }
+
+    // Skip positions that are too old to say anything about where the bus is now
+    if let Some(age) = fix_age {
+        if age > config.stale_fix_secs {
+            debug!("Skipping bus {} ({}): position {} s old", vehicle.service_number, vehicle.identifier(), age);
+            return false;
+        }
+    }
+
+    if !passes_min_speed(vehicle.speed, config.min_speed, config.missing_speed_passes) {
+        debug!("Skipping bus {} ({}): moving below MIN_SPEED", vehicle.service_number, vehicle.identifier());
+        return false;
+    }
+
+    if let Some(geofence) = &config.geofence {
+        if !point_in_polygon(vehicle.lat, vehicle.lng, geofence) {
+            debug!("Skipping bus {} ({}): outside GEOFENCE", vehicle.service_number, vehicle.identifier());
+            return false;
+        }
+    }
+
+    if config.exclusion_zone.as_ref().is_some_and(|zone| zone.contains(vehicle.lat, vehicle.lng)) {
+        debug!("Skipping bus {} ({}): inside EXCLUSION_ZONE", vehicle.service_number, vehicle.identifier());
+        return false;
+    }
+
+    true
+}
+
+/// "Bus 7 [fleet 10812]: 350 m from Main Street, ~2 min" with the distance to its closest stop
+fn bus_line(vehicle: &Vehicle, config: &Config, bus_stops: &[BusStop]) -> Option<(f64, String)> {
+    let (stop, distance) = closest_stop(vehicle.lat, vehicle.lng, bus_stops)?;
+    let to_go = match route_to_stop(vehicle, stop, config) {
+        Along::Ahead(along) => along,
+        Along::Behind | Along::OffRoute => distance,
+    };
+    let eta = match eta_secs(to_go, vehicle.speed) {
+        Some(secs) => format!(", ~{}", config.style.eta(secs)),
+        None => String::new(),
+    };
+    Some((
+        distance,
+        format!(
+            "Bus {} [{}]: {} from {}{}",
+            config.service_labels.label(&vehicle.service_number),
+            vehicle.identifier(),
+            config.style.distance(distance),
+            stop.name,
+            eta
+        ),
+    ))
+}
+
+/// Orders (distance, line) pairs from `bus_line` nearest first; equally close buses sort by their line
+fn nearest_line_first(a: &(f64, String), b: &(f64, String)) -> Ordering {
+    session::nearest_then_name((a.0, &a.1), (b.0, &b.1))
+}
+
+/// The reply to /now: the closest usable buses in `response`, nearest first
+fn render_nearest(response: &Value, config: &Config, bus_stops: &[BusStop], now: DateTime<Utc>) -> String {
+    let mut lines: Vec<(f64, String)> = response["services"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|service| Vehicle::from_json(service, &config.fields))
+        .filter(|vehicle| !vehicle.is_ignored(&config.ignore_vehicles) && is_usable(vehicle, vehicle.fix_age_secs(now), config))
+        .filter_map(|vehicle| bus_line(&vehicle, config, bus_stops))
+        .collect();
+    lines.sort_by(nearest_line_first);
+
+    if lines.is_empty() {
+        return "No buses nearby right now.".to_string();
+    }
+    lines.iter().take(NOW_BUSES).map(|(_, line)| line.as_str()).collect::<Vec<_>>().join("\n")
+}
+
+/// RADIUS, or with DERIVE_RADIUS the distance from the centre to the farthest edge of any stop's
+/// radius (or EARLY_WARNING_RADIUS, if wider), so the API only returns buses that could alert.
+/// RADIUS, if also set, caps the derived radius; with no stops it falls back to RADIUS, else a stop's default radius.
+fn query_radius(config: &Config, lat: f64, lng: f64, bus_stops: &[BusStop]) -> u32 {
+    if let (false, Some(radius)) = (config.derive_radius, config.radius) {
+        return radius;
+    }
+    let derived = derived_radius(lat, lng, bus_stops, config.early_warning_radius);
+    match (derived, config.radius) {
+        (Some(derived), Some(cap)) => derived.min(cap),
+        (Some(radius), None) | (None, Some(radius)) => radius,
+        (None, None) => DEFAULT_STOP_RADIUS as u32,
+    }
+}
+
+/// The smallest radius around (lat, lng), in whole meters, that contains every stop's radius
+/// (widened to `early_warning_radius` where that is larger). None without stops.
+fn derived_radius(lat: f64, lng: f64, bus_stops: &[BusStop], early_warning_radius: Option<f64>) -> Option<u32> {
+    bus_stops
+        .iter()
+        .map(|stop| haversine_distance(lat, lng, stop.lat, stop.lng) + stop.radius.max(early_warning_radius.unwrap_or(0.0)))
+        .max_by(f64::total_cmp)
+        .map(|reach| reach.ceil().min(u32::MAX as f64) as u32)
+}
 
-/// Haversine formula to calculate the distance (in meters) between two latitude/longitude points
-fn haversine_distance(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
-    // Earth radius in meters
-    const EARTH_RADIUS: f64 = 6371e3; // meters
+/// Answers a /now request, fetching afresh unless the last cycle's data is recent enough
+async fn answer_now(
+    request: &CommandOrigin,
+    client: &Client,
+    config: &Config,
+    bus_stops: &[BusStop],
+    latest: &mut Option<(Instant, Value)>,
+    telegram: &Telegram,
+    now: DateTime<Utc>,
+) {
+    if latest.as_ref().is_none_or(|(at, _)| at.elapsed() >= NOW_MAX_AGE) {
+        match fetch_services(client, config, bus_stops).await {
+            Ok(response) => *latest = Some((Instant::now(), response)),
+            Err(e) => error!("Error fetching buses for /now: {}", e),
+        }
+    }
 
-    // Convert degrees to radians
+    let text = match latest {
+        Some((at, response)) if at.elapsed() < NOW_MAX_AGE => render_nearest(response, config, bus_stops, now),
+        _ => "Couldn't reach the bus API just now, please try again shortly.".to_string(),
+    };
+    if let Err(e) = telegram.reply(request, &text, None).await {
+        error!("Error answering /now: {}", e);
+    }
+}
+
+/// Today's arrivals from the history database, one page of them, and whether there are more pages
+fn history_page(history: Option<&History>, service: Option<&str>, page: usize, style: &Style, now: DateTime<Utc>) -> (String, bool) {
+    match history {
+        None => ("History isn't enabled. Set HISTORY_DB to record arrivals.".to_string(), false),
+        Some(history) => match history.arrivals_since(style.zone.start_of_day(now), service) {
+            Ok(arrivals) => commands::render_history(&arrivals, service, page, style),
+            Err(e) => {
+                error!("Error reading history: {}", e);
+                ("Couldn't read the history database.".to_string(), false)
+            }
+        },
+    }
+}
+
+/// Replies with a page from `history_page`, with a button for the next one if there is one.
+/// The page is rendered first because the database connection can't be held across the send.
+async fn answer_history(origin: &CommandOrigin, service: Option<&str>, page: usize, reply: (String, bool), telegram: &Telegram) {
+    let (text, more) = reply;
+    let keyboard = more.then(|| telegram::history_keyboard(page + 1, service));
+    if let Err(e) = telegram.reply(origin, &text, keyboard).await {
+        error!("Error answering /history: {}", e);
+    }
+}
+
+/// Sends what `cycle::decide` makes of a response, unless alerts are muted, and refreshes the live message
+async fn check_buses(
+    response: &Value,
+    config: &Config,
+    bus_stops: &[BusStop],
+    notifiers: &mut Notifiers,
+    controls: &SharedControls,
+    session: &mut Session,
+    now: DateTime<Utc>,
+) {
+    let Some(cycle) = cycle::decide(response, config, bus_stops, session, now) else {
+        return;
+    };
+
+    for event in cycle.events {
+        let muted = controls.lock().unwrap().is_muted(now);
+        match event {
+            CycleEvent::Alert { mut alert, road_at, link } => {
+                if let (Some((lat, lng)), Some(geocoder)) = (road_at, &mut session.geocoder) {
+                    if let Some(road) = geocoder.road(lat, lng).await {
+                        alert.text.push_str(&format!(" (currently on {})", road));
+                    }
+                }
+                if let Some(link) = link {
+                    alert.text.push('\n');
+                    alert.text.push_str(&link);
+                }
+                if muted {
+                    info!("Muted, not sending: {}", alert.text);
+                } else {
+                    notifiers.send_alert(&alert).await;
+                }
+            }
+            CycleEvent::Absent(message) | CycleEvent::Departed(message) | CycleEvent::Bunched(message) => {
+                if !muted {
+                    notifiers.send_message(&message).await;
+                }
+            }
+        }
+    }
+
+    if let Some(live) = session.live.as_mut() {
+        let body = if cycle.live_lines.is_empty() {
+            "No buses near any stop.".to_string()
+        } else {
+            cycle.live_lines.iter().take(LIVE_MESSAGE_BUSES).map(|(_, line)| line.as_str()).collect::<Vec<_>>().join("\n")
+        };
+        live.update(&notifiers.telegram, &config.style.time_with_seconds(now), &body).await;
+    }
+}
+
+/// Initial bearing from the first point to the second, in degrees clockwise from north (0..360)
+fn bearing(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
     let lat1_rad = lat1 * PI / 180.0;
     let lat2_rad = lat2 * PI / 180.0;
-    let delta_lat = (lat2 - lat1) * PI / 180.0;
     let delta_lon = (lon2 - lon1) * PI / 180.0;
 
-    // Haversine formula
-    let a = f64::sin(delta_lat / 2.0).powi(2)
-        + f64::cos(lat1_rad) * f64::cos(lat2_rad) * f64::sin(delta_lon / 2.0).powi(2);
-    let c = 2.0 * f64::atan2(f64::sqrt(a), f64::sqrt(1.0 - a));
+    let y = f64::sin(delta_lon) * f64::cos(lat2_rad);
+    let x = f64::cos(lat1_rad) * f64::sin(lat2_rad) - f64::sin(lat1_rad) * f64::cos(lat2_rad) * f64::cos(delta_lon);
+    (f64::atan2(y, x) * 180.0 / PI).rem_euclid(360.0)
+}
 
-    // Distance in meters
-    EARTH_RADIUS * c
+/// Whether the stop lies within `tolerance` degrees of the bus's heading, i.e. the bus is driving towards it.
+/// Buses that don't report a heading get the benefit of the doubt.
+fn stop_is_ahead(vehicle: &Vehicle, stop: &BusStop, tolerance: f64) -> bool {
+    let Some(heading) = vehicle.heading else {
+        return true;
+    };
+    let to_stop = bearing(vehicle.lat, vehicle.lng, stop.lat, stop.lng);
+    let off_course = (to_stop - heading).rem_euclid(360.0);
+    off_course.min(360.0 - off_course) <= tolerance
 }
 
+/// Which way the bus is going, from its description with its upcoming stops or its heading along its route
+fn vehicle_direction(vehicle: &Vehicle, config: &Config) -> Direction {
+    let forward = config.routes.runs_forward(&vehicle.service_number, (vehicle.lat, vehicle.lng), vehicle.heading);
+    direction::infer(&vehicle.service_description, vehicle.upcoming_stops.as_deref(), forward)
+}
 
-/// Finds the nearest bus stop within 200 meters using the Haversine formula
-fn find_nearest_stop(bus_lat: f64, bus_lng: f64, bus_stops: &[BusStop]) -> Option<String> {
-    const MAX_DISTANCE_METERS: f64 = 200.0; // If the bus is within a radius of 200m from any bus stop.
+/// The service description for messages, with the direction when it's known
+fn describe_service(vehicle: &Vehicle, direction: &Direction) -> String {
+    match direction {
+        Direction::Towards(_) => format!("{} ({})", vehicle.service_description, direction),
+        Direction::Unknown => vehicle.service_description.clone(),
+    }
+}
 
-    for stop in bus_stops {
-        let distance = haversine_distance(bus_lat, bus_lng, stop.lat, stop.lng);
+/// How far the bus still has to go to the stop: along its route where [[routes]] has it, otherwise
+/// in a straight line. None when the route has already taken it past the stop.
+fn distance_to_stop(vehicle: &Vehicle, stop: &BusStop, config: &Config) -> Option<f64> {
+    match route_to_stop(vehicle, stop, config) {
+        Along::Ahead(along) => Some(along),
+        Along::Behind => None,
+        Along::OffRoute => Some(haversine_distance(vehicle.lat, vehicle.lng, stop.lat, stop.lng)),
+    }
+}
 
-        // Print debugging information
-        // println!("Stop {} - {:.2} meters away from bus (Lat: {}, Lng: {})", stop.name, distance, stop.lat, stop.lng);
+/// Where the stop lies along the bus's route, if [[routes]] has one for its service
+fn route_to_stop(vehicle: &Vehicle, stop: &BusStop, config: &Config) -> Along {
+    if config.routes.is_empty() {
+        return Along::OffRoute;
+    }
+    config.routes.along(&vehicle.service_number, (vehicle.lat, vehicle.lng), vehicle.heading, (stop.lat, stop.lng))
+}
+
+/// Whether the bus is heading for the stop: ahead of it along its route, or without a route
+/// (or off it), within HEADING_TOLERANCE_DEG of its heading
+fn is_approaching(vehicle: &Vehicle, stop: &BusStop, config: &Config) -> bool {
+    match route_to_stop(vehicle, stop, config) {
+        Along::Ahead(_) => true,
+        Along::Behind => false,
+        Along::OffRoute => stop_is_ahead(vehicle, stop, config.heading_tolerance_deg),
+    }
+}
+
+/// Rough time to cover `distance` meters at the reported speed (km/h), assuming a straight line
+fn eta_secs(distance: f64, speed: Option<f64>) -> Option<f64> {
+    const MIN_SPEED_FOR_ETA: f64 = 1.0; // km/h; slower than this the estimate is meaningless
+
+    speed.filter(|s| *s >= MIN_SPEED_FOR_ETA).map(|s| distance / (s / 3.6))
+}
+
+/// Rounds a distance to the nearest multiple of `bucket` meters (unchanged if `bucket` is 0)
+fn bucket_distance(distance: f64, bucket: f64) -> f64 {
+    if bucket > 0.0 {
+        (distance / bucket).round() * bucket
+    } else {
+        distance
+    }
+}
+
+/// Finds a bus stop whose radius (200 meters unless configured) contains the bus, using the Haversine formula.
+/// Returns the stop and the bus's distance from it.
+/// With an index over `bus_stops`, only the stops it lists as candidates are measured; the answer is the same.
+fn find_nearest_stop<'a>(bus_lat: f64, bus_lng: f64, bus_stops: &'a [BusStop], index: &StopIndex) -> Option<(&'a BusStop, f64)> {
+    let found = match index.candidates(bus_lat, bus_lng, bus_stops.len()) {
+        Some(candidates) => candidates.into_iter().map(|i| &bus_stops[i]).find_map(|stop| {
+            let distance = haversine_distance(bus_lat, bus_lng, stop.lat, stop.lng);
+            (distance <= stop.radius).then_some((stop, distance))
+        }),
+        None => first_stop_within(bus_lat, bus_lng, bus_stops),
+    };
+    if found.is_none() {
+        log_closest_miss(bus_lat, bus_lng, bus_stops);
+    }
+    found
+}
 
-        // Check if the bus is within 200 meters of the stop
-        if distance <= MAX_DISTANCE_METERS {
-            // println!("Bus is within range of stop: {}", stop.name);
-            return Some(stop.name.clone());
+/// Debug log for a bus that isn't in any stop's radius, with how close it came, for tuning radii.
+/// Finding the closest stop costs another pass over the stops, so it's skipped unless debug logging is on.
+fn log_closest_miss(bus_lat: f64, bus_lng: f64, bus_stops: &[BusStop]) {
+    if !tracing::enabled!(tracing::Level::DEBUG) {
+        return;
+    }
+    match closest_stop(bus_lat, bus_lng, bus_stops) {
+        Some((stop, distance)) => debug!(
+            "No bus found near any stop; closest is {} at {:.0} m, {:.0} m outside its {:.0} m radius",
+            stop.name,
+            distance,
+            distance - stop.radius,
+            stop.radius
+        ),
+        None => debug!("No bus found near any stop."),
+    }
+}
+
+/// Resolves POLL_INTERVAL_SECS (or the config file's value), clamped to at least MIN_POLL_SECS
+/// so a typo can't hammer the API. The clamp is warned about once per run.
+fn poll_interval_secs(file: &ConfigFile) -> Result<u64, String> {
+    let interval = env_parse_opt("POLL_INTERVAL_SECS")?.or(file.poll_interval_secs).unwrap_or(10);
+    let floor = env_parse("MIN_POLL_SECS", 5u64)?.max(1);
+    if interval < floor {
+        if !POLL_FLOOR_WARNED.swap(true, AtomicOrdering::Relaxed) {
+            warn!("Poll interval of {} s is below MIN_POLL_SECS; polling every {} s instead.", interval, floor);
         }
+        return Ok(floor);
     }
+    Ok(interval)
+}
 
-    println!("No bus found near any stop.");
-    None
+/// Reads a boolean flag such as `STRICT=true`, accepting true/false, yes/no, on/off and 1/0
+fn env_flag(name: &str, default: bool) -> Result<bool, String> {
+    match env_or_file(name)? {
+        Some(value) => match value.trim().to_ascii_lowercase().as_str() {
+            "1" | "true" | "yes" | "on" => Ok(true),
+            "0" | "false" | "no" | "off" => Ok(false),
+            _ => Err(format!("{} must be true or false, got '{}'.", name, value)),
+        },
+        None => Ok(default),
+    }
 }
 
-// Send notification to Telegram
-async fn send_telegram_message(message: &str) -> Result<(), reqwest::Error> {
-    let client = Client::new();
-    let bot_token = env::var("TELEGRAM_BOT_TOKEN").expect("Missing TELEGRAM_BOT_TOKEN in .env");
-    let chat_id = env::var("TELEGRAM_CHAT_ID").expect("Missing TELEGRAM_CHAT_ID in .env");
+/// Reads and parses an optional setting, returning None when it isn't set
+fn env_parse_opt<T: FromStr>(name: &str) -> Result<Option<T>, String> {
+    match env_or_file(name)? {
+        Some(value) if !value.trim().is_empty() => value
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("{} has an invalid value '{}'.", name, value)),
+        _ => Ok(None),
+    }
+}
 
-    let url = format!(
-        "https://api.telegram.org/bot{}/sendMessage?chat_id={}&text={}",
-        bot_token, chat_id, message
-    );
+/// Reads and parses a setting, falling back to `default` when it isn't set
+fn env_parse<T: FromStr>(name: &str, default: T) -> Result<T, String> {
+    Ok(env_parse_opt(name)?.unwrap_or(default))
+}
+
+/// Reads and parses a setting that must be present, either in the environment or the config file;
+/// `what` describes it for the error message
+fn env_or_config<T: FromStr>(name: &str, from_file: Option<T>, what: &str) -> Result<T, String> {
+    match env_parse_opt(name)?.or(from_file) {
+        Some(value) => Ok(value),
+        None => Err(format!("Missing {}. Please set it (or `{}` in the config file) to {}.", name, name.to_ascii_lowercase(), what)),
+    }
+}
+
+/// Like `env_or_config` for LAT/LNG, but tolerant of degree signs and a decimal comma in the
+/// environment value (see `parse_coordinate`), with a warning when it had to tidy one up
+fn coordinate_setting(name: &str, from_file: Option<f64>, what: &str) -> Result<f64, String> {
+    match env_or_file(name)?.filter(|value| !value.trim().is_empty()) {
+        Some(value) => {
+            let (coordinate, cleaned) = parse_coordinate(&value).ok_or_else(|| format!("{} has an invalid value '{}'.", name, value))?;
+            if cleaned {
+                warn!("Read {} '{}' as {}.", name, value.trim(), coordinate);
+            }
+            Ok(coordinate)
+        }
+        None => env_or_config(name, from_file, what),
+    }
+}
+
+/// A secret from the OS keyring when USE_KEYRING is set, falling back to `env_or_file` when the
+/// keyring has no entry for it (or can't be read). Entries are stored under the service
+/// "stagecoach-tracker" with the variable's name as the user.
+fn secret(name: &str) -> Result<Option<String>, String> {
+    secret_from(name, keyring_secret)
+}
+
+/// `secret`, looking entries up with `keyring`
+fn secret_from(name: &str, keyring: impl Fn(&str) -> Result<Option<String>, String>) -> Result<Option<String>, String> {
+    if env_flag("USE_KEYRING", false)? {
+        match keyring(name) {
+            Ok(Some(value)) => return Ok(Some(value)),
+            Ok(None) => debug!("No {} in the OS keyring; using the environment", name),
+            Err(e) => warn!("Could not read {} from the OS keyring, using the environment: {}", name, e),
+        }
+    }
+    env_or_file(name)
+}
+
+#[cfg(feature = "keyring")]
+fn keyring_secret(name: &str) -> Result<Option<String>, String> {
+    let entry = keyring::Entry::new(KEYRING_SERVICE, name).map_err(|e| e.to_string())?;
+    match entry.get_password() {
+        Ok(value) => Ok(Some(value)),
+        Err(keyring::Error::NoEntry) => Ok(None),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[cfg(not(feature = "keyring"))]
+fn keyring_secret(_name: &str) -> Result<Option<String>, String> {
+    Err("this build doesn't include the keyring feature".to_string())
+}
+
+/// Reads a setting from `<NAME>_FILE` (a path, as mounted by Docker/Kubernetes secrets)
+/// if set, otherwise from the `<NAME>` environment variable itself. Every setting read
+/// through here (including env_flag/env_parse) accepts the _FILE form.
+fn env_or_file(name: &str) -> Result<Option<String>, String> {
+    let file_var = format!("{}_FILE", name);
+    let path = match env::var(&file_var) {
+        Ok(path) if !path.trim().is_empty() => path,
+        _ => return Ok(env::var(name).ok()),
+    };
+    if env::var(name).is_ok() {
+        return Err(format!("Both {} and {} are set; use only one.", name, file_var));
+    }
+
+    let contents = fs::read_to_string(&path).map_err(|e| format!("Could not read {} ({}): {}", file_var, path, e))?;
+    Ok(Some(contents.trim_end_matches(['\r', '\n']).to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, with_env};
+
+    #[test]
+    fn env_or_file_reads_the_variable_or_the_file_it_names() {
+        let path = env::temp_dir().join(format!("env-or-file-test-{}", process::id()));
+        fs::write(&path, "from-file\r\n").unwrap();
+        let path = path.to_str().unwrap();
+
+        let direct = with_env(&[("SECRET_TEST_VALUE", "from-env")], || env_or_file("SECRET_TEST_VALUE"));
+        let from_file = with_env(&[("SECRET_TEST_VALUE_FILE", path)], || env_or_file("SECRET_TEST_VALUE"));
+        let both = with_env(&[("SECRET_TEST_VALUE", "from-env"), ("SECRET_TEST_VALUE_FILE", path)], || env_or_file("SECRET_TEST_VALUE"));
+        fs::remove_file(path).unwrap();
+        let unreadable = with_env(&[("SECRET_TEST_VALUE_FILE", path)], || env_or_file("SECRET_TEST_VALUE"));
+
+        assert_eq!(direct, Ok(Some("from-env".to_string())));
+        assert_eq!(from_file, Ok(Some("from-file".to_string())));
+        assert_eq!(both, Err("Both SECRET_TEST_VALUE and SECRET_TEST_VALUE_FILE are set; use only one.".to_string()));
+        assert!(unreadable.unwrap_err().starts_with(&format!("Could not read SECRET_TEST_VALUE_FILE ({}): ", path)));
+        assert_eq!(with_env(&[], || env_or_file("SECRET_TEST_VALUE")), Ok(None));
+    }
+
+    #[test]
+    fn secret_prefers_the_keyring() {
+        let value = with_env(&[("USE_KEYRING", "1"), ("TELEGRAM_BOT_TOKEN", "from-env")], || {
+            secret_from("TELEGRAM_BOT_TOKEN", |_| Ok(Some("from-keyring".to_string())))
+        });
+        assert_eq!(value, Ok(Some("from-keyring".to_string())));
+    }
+
+    #[test]
+    fn secret_falls_back_when_the_keyring_entry_is_missing_or_unreadable() {
+        let path = env::temp_dir().join(format!("secret-test-{}", process::id()));
+        fs::write(&path, "from-file\n").unwrap();
+        let path = path.to_str().unwrap();
+
+        let missing = with_env(&[("USE_KEYRING", "1"), ("TELEGRAM_BOT_TOKEN", "from-env")], || secret_from("TELEGRAM_BOT_TOKEN", |_| Ok(None)));
+        let unreadable = with_env(&[("USE_KEYRING", "1"), ("TELEGRAM_CHAT_ID_FILE", path)], || {
+            secret_from("TELEGRAM_CHAT_ID", |_| Err("no secret service".to_string()))
+        });
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(missing, Ok(Some("from-env".to_string())));
+        assert_eq!(unreadable, Ok(Some("from-file".to_string())));
+    }
+
+    #[test]
+    fn secret_ignores_the_keyring_unless_asked() {
+        let value = with_env(&[("TELEGRAM_BOT_TOKEN", "from-env")], || {
+            secret_from("TELEGRAM_BOT_TOKEN", |_| panic!("the keyring was read without USE_KEYRING"))
+        });
+        assert_eq!(value, Ok(Some("from-env".to_string())));
+    }
+
+    #[test]
+    fn settings_beyond_the_secrets_accept_the_file_form_too() {
+        let dir = env::temp_dir().join(format!("settings-file-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = |name: &str, contents: &str| {
+            let path = dir.join(name);
+            fs::write(&path, contents).unwrap();
+            path.to_str().unwrap().to_string()
+        };
+        let files = [
+            ("UNITS_FILE", file("units", "imperial\n")),
+            ("TIMEZONE_FILE", file("timezone", "Europe/Paris\n")),
+            ("ALERT_TEMPLATE_FILE", file("template", "{service} at {stop}\n")),
+            ("PRIORITY_SERVICES_FILE", file("priority", "7, 9\n")),
+        ];
+        let mut vars = vec![("LAT", "53.0"), ("LNG", "-1.5"), ("RADIUS", "1000")];
+        vars.extend(files.iter().map(|(name, path)| (*name, path.as_str())));
+        let config = with_env(&vars, || Config::from_env(&ConfigFile::default())).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(config.style.units, crate::format::Units::Imperial);
+        assert_eq!(config.style.zone, Zone::Named(chrono_tz::Europe::Paris));
+        assert_eq!(config.alert_template.as_deref(), Some("{service} at {stop}"));
+        assert_eq!(config.priority_services, ["7", "9"]);
+    }
+
+    #[test]
+    fn min_speed_lets_moving_buses_through() {
+        assert!(passes_min_speed(Some(12.0), Some(5.0), true));
+        assert!(passes_min_speed(Some(5.0), Some(5.0), true));
+        assert!(!passes_min_speed(Some(4.9), Some(5.0), true));
+        assert!(!passes_min_speed(Some(0.0), Some(5.0), false));
+        assert!(passes_min_speed(None, None, false));
+        assert!(passes_min_speed(Some(0.0), None, false));
+    }
+
+    #[test]
+    fn buses_without_a_speed_pass_min_speed_unless_told_otherwise() {
+        let config = crate::test_support::config(&[("MIN_SPEED", "5")]);
+        assert_eq!(config.min_speed, Some(5.0));
+        assert!(passes_min_speed(None, config.min_speed, config.missing_speed_passes));
+
+        let config = crate::test_support::config(&[("MIN_SPEED", "5"), ("MISSING_SPEED_PASSES", "false")]);
+        assert!(!passes_min_speed(None, config.min_speed, config.missing_speed_passes));
+    }
+
+    fn bus_stops(text: &str) -> StopsInput {
+        StopsInput { source: "BUS_STOPS".to_string(), text: text.to_string() }
+    }
+
+    #[test]
+    fn all_invalid_bus_stops_warn_and_fail_under_strict_stops() {
+        let invalid = bus_stops("Home,north,west;Work,53.0");
+        let (stops, logs) = test_support::logged(|| load_bus_stops(&[], Some(&invalid)));
+        assert_eq!(stops.map(|stops| stops.len()), Ok(0));
+        assert!(logs.contains(" WARN "), "{}", logs);
+        assert!(logs.contains("None of the 2 entries in BUS_STOPS are valid, so no bus can ever match."), "{}", logs);
+
+        let strict = [("STRICT_STOPS", "1")];
+        assert_eq!(
+            with_env(&strict, || load_bus_stops(&[], Some(&invalid))).map(|stops| stops.len()),
+            Err("STRICT_STOPS is set and BUS_STOPS contains no valid bus stops.".to_string())
+        );
+
+        // One usable entry is enough
+        let partly_valid = bus_stops("Home,53.0,-1.5;Work,53.0");
+        assert_eq!(with_env(&strict, || load_bus_stops(&[], Some(&partly_valid))).map(|stops| stops.len()), Ok(1));
+    }
+
+    #[test]
+    fn stops_load_from_a_bus_stops_line_or_csv_alike() {
+        let names = |input: StopsInput| -> Vec<(String, f64, f64, f64, Option<String>)> {
+            let stops = load_bus_stops(&[], Some(&input)).unwrap();
+            stops.into_iter().map(|stop| (stop.name, stop.lat, stop.lng, stop.radius, stop.group)).collect()
+        };
+        let expected = [
+            ("Home".to_string(), 53.0, -1.5, DEFAULT_STOP_RADIUS, None),
+            ("Work".to_string(), 53.1, -1.4, 150.0, Some("commute".to_string())),
+        ];
+        let csv = |text: &str| StopsInput { source: "stdin".to_string(), text: text.to_string() };
+
+        assert_eq!(names(bus_stops("Home,53.0,-1.5;Work,53.1,-1.4,150,commute")), expected);
+        assert_eq!(names(csv("name,lat,lng,radius,group\nHome,53.0,-1.5\n\nWork,53.1,-1.4,150,commute\n")), expected);
+        assert_eq!(names(csv("Home,53.0,-1.5\r\nWork,53.1,-1.4,150,commute\r\n")), expected);
+        assert_eq!(names(csv("Home,53.0,-1.5,,\n")), expected[..1]);
+
+        // Read from a file, it's named in the warnings
+        let path = env::temp_dir().join(format!("stops-file-test-{}.csv", process::id()));
+        fs::write(&path, "name,lat,lng\nHome,north,west\n").unwrap();
+        let input = StopsInput::read(Some(&path)).unwrap().unwrap();
+        let _ = fs::remove_file(&path);
+        let (stops, logs) = test_support::logged(|| load_bus_stops(&[], Some(&input)).unwrap());
+        assert!(stops.is_empty());
+        assert!(logs.contains(&format!("None of the 1 entries in {} are valid", path.display())), "{}", logs);
+        assert!(StopsInput::read(Some(&path)).unwrap_err().starts_with("Could not read stops file"));
+    }
+
+    #[test]
+    fn config_file_stops_come_first_and_duplicates_merge() {
+        let file: ConfigFile = toml::from_str("[[stops]]\nname = \"Main Street (adj)\"\nlat = 53.0\nlng = -1.5\nradius = 80").unwrap();
+        let stops = load_bus_stops(&file.stops, Some(&bus_stops("Main St adj,53.0,-1.5;Work,53.1,-1.4"))).unwrap();
+        let stops: Vec<(&str, f64)> = stops.iter().map(|stop| (stop.name.as_str(), stop.radius)).collect();
+        assert_eq!(stops, [("Main Street (adj)", 80.0), ("Work", DEFAULT_STOP_RADIUS)]);
+
+        let bad: ConfigFile = toml::from_str("[[stops]]\nname = \"Nowhere\"\nlat = 91.0\nlng = -1.5").unwrap();
+        assert_eq!(load_bus_stops(&bad.stops, None).unwrap_err(), "Config file stop 'Nowhere' has invalid coordinates.");
+    }
+
+    /// A service 7 bus at a position, heading that way (degrees from north) if given
+    fn vehicle_at(lat: f64, lng: f64, heading: Option<f64>) -> Vehicle {
+        Vehicle {
+            service_number: "7".to_string(),
+            service_description: String::new(),
+            fleet_number: Some("1".to_string()),
+            registration: None,
+            lat,
+            lng,
+            speed: Some(20.0),
+            heading,
+            updated_at: None,
+            upcoming_stops: None,
+        }
+    }
+
+    #[test]
+    fn bearings_point_the_right_way() {
+        let cases = [((53.01, -1.5), 0.0), ((53.0, -1.49), 90.0), ((52.99, -1.5), 180.0), ((53.0, -1.51), 270.0)];
+        for ((lat, lng), expected) in cases {
+            let bearing = bearing(53.0, -1.5, lat, lng);
+            assert!((bearing - expected).abs() < 0.01, "to {}, {}: {}", lat, lng, bearing);
+        }
+        let north_east = bearing(53.0, -1.5, 53.01, -1.485);
+        assert!((40.0..50.0).contains(&north_east), "{}", north_east);
+    }
+
+    #[test]
+    fn stops_are_ahead_within_the_heading_tolerance_either_side() {
+        let stop = test_support::stop("Market Square", 53.0, -1.5);
+        // (bus position, heading, ahead with the default 90° tolerance, ahead with 30°)
+        let cases = [
+            ((52.995, -1.5), Some(0.0), true, true),     // South of the stop, heading north: straight at it
+            ((52.995, -1.5), Some(180.0), false, false), // ...heading south: driving away
+            ((52.995, -1.5), Some(350.0), true, true),   // Either side of north
+            ((52.995, -1.5), Some(20.0), true, true),
+            ((52.995, -1.5), Some(60.0), true, false),
+            ((52.995, -1.5), Some(270.0), true, false), // Side on, right at the default tolerance
+            ((52.995, -1.5), Some(120.0), false, false),
+            ((53.0, -1.508), Some(90.0), true, true), // West of it, heading east
+            ((53.0, -1.508), Some(270.0), false, false),
+            ((53.005, -1.505), Some(135.0), true, true), // North-west, heading south-east
+            ((53.005, -1.505), Some(315.0), false, false),
+            ((52.995, -1.5), None, true, true), // No heading: given the benefit of the doubt
+        ];
+        for ((lat, lng), heading, wide, narrow) in cases {
+            let bus = vehicle_at(lat, lng, heading);
+            assert_eq!(stop_is_ahead(&bus, &stop, 90.0), wide, "{}, {} heading {:?}", lat, lng, heading);
+            assert_eq!(stop_is_ahead(&bus, &stop, 30.0), narrow, "{}, {} heading {:?} within 30°", lat, lng, heading);
+        }
+
+        // Without a route for the service, is_approaching goes by heading and HEADING_TOLERANCE_DEG
+        let config = test_support::config(&[("HEADING_TOLERANCE_DEG", "30")]);
+        assert!(is_approaching(&vehicle_at(52.995, -1.5, Some(20.0)), &stop, &config));
+        assert!(!is_approaching(&vehicle_at(52.995, -1.5, Some(60.0)), &stop, &config));
+    }
+
+    #[test]
+    fn each_cycle_searches_around_the_latest_gps_file_position() {
+        let path = env::temp_dir().join(format!("gps-file-test-{}", process::id()));
+        let path = path.to_str().unwrap();
+        let config = test_support::config(&[("GPS_FILE", path), ("LAT", "53.0"), ("LNG", "-1.5")]);
 
-    let _response = client.get(&url).send().await?;
-    // println!("Telegram message sent: {:?}", response.text().await?);
-    Ok(())
+        fs::write(path, "53.1,-1.4\n").unwrap();
+        assert_eq!(current_center(&config), (53.1, -1.4));
+        fs::write(path, "53.2, -1.3\n").unwrap(); // The user moved
+        assert_eq!(current_center(&config), (53.2, -1.3));
+        fs::write(path, "53.2,north\n").unwrap();
+        assert_eq!(current_center(&config), (53.0, -1.5));
+        fs::remove_file(path).unwrap();
+        assert_eq!(current_center(&config), (53.0, -1.5));
+    }
+
+    #[tokio::test]
+    async fn the_fallback_api_is_only_asked_once_the_primary_has_failed_every_retry() {
+        let fallback_reply = r#"{ "services": [], "from": "fallback" }"#;
+        let primary_ok = test_support::MockServer::start(vec![(200, r#"{ "services": [] }"#.to_string())]);
+        let primary_down = test_support::MockServer::start(vec![(503, "{}".to_string())]);
+        let fallback = test_support::MockServer::start(vec![(200, fallback_reply.to_string())]);
+        let client = Client::new();
+
+        let config = test_support::config(&[("API_URL", &primary_ok.url), ("FALLBACK_API_URL", &fallback.url)]);
+        let response = fetch_services(&client, &config, &[]).await.unwrap();
+        assert_eq!(response, serde_json::json!({ "services": [] }));
+        assert_eq!(fallback.requests().len(), 0);
+
+        let config = test_support::config(&[("API_URL", &primary_down.url), ("API_RETRIES", "0"), ("FALLBACK_API_URL", &fallback.url)]);
+        let response = fetch_services(&client, &config, &[]).await.unwrap();
+        assert_eq!(response["from"], "fallback");
+        assert_eq!(primary_down.requests().len(), 1);
+        assert_eq!(fallback.requests().len(), 1);
+        // The fallback gets the same query
+        assert_eq!(fallback.requests()[0].path, primary_down.requests()[0].path);
+
+        let config = test_support::config(&[("API_URL", &primary_down.url), ("API_RETRIES", "0")]);
+        assert!(fetch_services(&client, &config, &[]).await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_primary_is_retried_before_the_fallback() {
+        let primary = test_support::MockServer::start(vec![(500, "{}".to_string()), (500, "{}".to_string()), (200, r#"{ "services": [] }"#.to_string())]);
+        let fallback = test_support::MockServer::start(vec![(200, r#"{ "services": [] }"#.to_string())]);
+        let config = test_support::config(&[("API_URL", &primary.url), ("API_RETRIES", "2"), ("FALLBACK_API_URL", &fallback.url)]);
+
+        let started = time::Instant::now();
+        fetch_services(&Client::new(), &config, &[]).await.unwrap();
+        assert_eq!(primary.requests().len(), 3);
+        assert_eq!(fallback.requests().len(), 0);
+        assert!(started.elapsed() >= API_RETRY_DELAY * 3, "backed off for only {:?}", started.elapsed());
+    }
+
+    #[tokio::test]
+    async fn the_query_carries_the_client_version() {
+        let api = test_support::MockServer::start(vec![(200, r#"{ "services": [] }"#.to_string())]);
+        let client = Client::new();
+        let with_key = format!("{}/vehicles?key=abc", api.url);
+        for vars in [vec![], vec![("CLIENT_VERSION", " MY APP/2&x=1 ")], vec![("CLIENT_VERSION", " ")], vec![("API_URL", with_key.as_str())]] {
+            let vars: Vec<(&str, &str)> = [("API_URL", api.url.as_str()), ("LAT", "53.0"), ("LNG", "-1.5"), ("RADIUS", "800")].into_iter().chain(vars).collect();
+            let config = test_support::config(&vars);
+            fetch_services(&client, &config, &[]).await.unwrap();
+        }
+
+        let paths: Vec<String> = api.requests().into_iter().map(|request| request.path).collect();
+        assert_eq!(
+            paths,
+            [
+                "/?client_version=UKBUS_APP&descriptive_fields=1&lat=53&lng=-1.5&radius=800",
+                "/?client_version=MY%20APP%2F2%26x%3D1&descriptive_fields=1&lat=53&lng=-1.5&radius=800",
+                "/?client_version=UKBUS_APP&descriptive_fields=1&lat=53&lng=-1.5&radius=800", // Blank: the default
+                "/vehicles?key=abc&client_version=UKBUS_APP&descriptive_fields=1&lat=53&lng=-1.5&radius=800",
+            ]
+        );
+    }
+
+    #[test]
+    fn derived_radius_reaches_the_far_edge_of_the_farthest_stop() {
+        // 556 m north with a 200 m radius reaches 756 m; 222 m south with 500 m only 722 m
+        let mut north = test_support::stop("North", 53.005, -1.5);
+        north.radius = 200.0;
+        let mut south = test_support::stop("South", 52.998, -1.5);
+        south.radius = 500.0;
+        let stops = [north, south];
+        assert_eq!(derived_radius(53.0, -1.5, &stops, None), Some(756));
+        assert_eq!(derived_radius(53.0, -1.5, &stops[1..], None), Some(723));
+        // An early-warning radius wider than a stop's own widens its reach
+        assert_eq!(derived_radius(53.0, -1.5, &stops, Some(600.0)), Some(1156));
+        assert_eq!(derived_radius(53.0, -1.5, &stops, Some(100.0)), Some(756));
+        assert_eq!(derived_radius(53.0, -1.5, &[], None), None);
+
+        let radius = |vars: &[(&str, &str)], stops: &[BusStop]| {
+            let config = test_support::config(vars);
+            query_radius(&config, config.lat, config.lng, stops)
+        };
+        assert_eq!(radius(&[], &stops), 1000); // Without DERIVE_RADIUS, RADIUS as it is
+        assert_eq!(radius(&[("DERIVE_RADIUS", "true"), ("RADIUS", "")], &stops), 756);
+        assert_eq!(radius(&[("DERIVE_RADIUS", "true"), ("RADIUS", "5000")], &stops), 756);
+        assert_eq!(radius(&[("DERIVE_RADIUS", "true"), ("RADIUS", "500")], &stops), 500); // RADIUS caps it
+        assert_eq!(radius(&[("DERIVE_RADIUS", "true"), ("RADIUS", "500")], &[]), 500);
+        assert_eq!(radius(&[("DERIVE_RADIUS", "true"), ("RADIUS", "")], &[]), DEFAULT_STOP_RADIUS as u32);
+    }
+
+    #[test]
+    fn a_bus_at_no_stop_logs_the_closest_miss() {
+        let mut home = test_support::stop("Home", 53.0, -1.5);
+        home.radius = 200.0;
+        let far = test_support::stop("Far", 53.1, -1.5);
+        let stops = vec![far, home.clone()];
+        // 556 m north of Home
+        let (found, logs) = test_support::logged(|| find_nearest_stop(53.005, -1.5, &stops, &StopIndex::default()));
+        assert!(found.is_none());
+        assert!(logs.contains("No bus found near any stop; closest is Home at 556 m, 356 m outside its 200 m radius"), "{}", logs);
+
+        // The same through the grid index a long stop list gets
+        let mut many: Vec<BusStop> = (0..40).map(|n| test_support::stop(&format!("Far {}", n), 53.1 + n as f64 * 0.001, -1.5)).collect();
+        many.push(home);
+        let index = StopIndex::new(&many);
+        let (found, logs) = test_support::logged(|| find_nearest_stop(53.005, -1.5, &many, &index));
+        assert!(found.is_none());
+        assert!(logs.contains("closest is Home at 556 m, 356 m outside its 200 m radius"), "{}", logs);
+
+        // A match logs nothing, and with no stops there's no closest one to name
+        let (found, logs) = test_support::logged(|| find_nearest_stop(53.001, -1.5, &stops, &StopIndex::default()));
+        assert_eq!(found.map(|(stop, _)| stop.name.as_str()), Some("Home"));
+        assert_eq!(logs, "");
+        let (_, logs) = test_support::logged(|| find_nearest_stop(53.005, -1.5, &[], &StopIndex::default()));
+        assert!(logs.contains("No bus found near any stop."), "{}", logs);
+    }
+
+    #[test]
+    fn messy_coordinates_are_tidied_and_invalid_ones_refused() {
+        let tidied = [
+            ("53.4", Some((53.4, false))),
+            (" -2.98 ", Some((-2.98, false))),
+            ("53,4", Some((53.4, true))),
+            ("-2,98", Some((-2.98, true))),
+            ("53.4°", Some((53.4, true))),
+            ("53 ,4 º", Some((53.4, true))),
+            ("-2.98˚", Some((-2.98, true))),
+            ("53", Some((53.0, false))),
+        ];
+        let refused = ["", "north", "53.4N", "53,4,1", "53.4,1", "1,000.5", "NaN", "inf", "53..4", "°"];
+        for (text, expected) in tidied.into_iter().chain(refused.into_iter().map(|text| (text, None))) {
+            assert_eq!(parse_coordinate(text), expected, "{:?}", text);
+        }
+
+        let (lat, logs) = test_support::logged(|| with_env(&[("LAT", " 53,4° ")], || coordinate_setting("LAT", None, "the latitude")));
+        assert_eq!(lat, Ok(53.4));
+        assert!(logs.contains("Read LAT '53,4°' as 53.4."), "{}", logs);
+        assert_eq!(with_env(&[("LAT", "53,4,1")], || coordinate_setting("LAT", None, "the latitude")), Err("LAT has an invalid value '53,4,1'.".to_string()));
+    }
+
+    #[test]
+    fn gps_positions_with_decimal_commas_need_another_separator() {
+        let positions = [
+            ("53.4,-2.98\n", Some((53.4, -2.98))),
+            ("53.4, -2.98", Some((53.4, -2.98))),
+            ("53.4 -2.98", Some((53.4, -2.98))),
+            ("53,4;-2,98", Some((53.4, -2.98))),
+            (" 53,4 ; -2,98 ", Some((53.4, -2.98))),
+            ("53,4 -2,98", Some((53.4, -2.98))),
+            ("53,4\t-2,98\n", Some((53.4, -2.98))),
+            ("53.4°, -2.98°", Some((53.4, -2.98))),
+            // Ambiguous: is it 53.4, -2.98 or 53, 4, -2, 98?
+            ("53,4,-2,98", None),
+            ("48,85,2", None),
+            ("53,4, -2,98", None),
+            ("53.4", None),
+            ("53.4 -2.98 7", None),
+            ("91,0", None), // Out of range
+            ("53.4,-181", None),
+            ("", None),
+        ];
+        for (contents, expected) in positions {
+            assert_eq!(parse_gps_position(contents), expected, "{:?}", contents);
+        }
+    }
+
+    #[test]
+    fn operator_link_centres_on_the_bus_and_names_its_service() {
+        assert_eq!(operator_link("7", 53.000312345, -1.50021), "https://www.stagecoachbus.com/bus-tracker?service=7&lat=53.00031&lng=-1.50021");
+        assert_eq!(operator_link("X1 A&B", 53.0, -1.5), "https://www.stagecoachbus.com/bus-tracker?service=X1%20A%26B&lat=53.00000&lng=-1.50000");
+    }
+
+    #[test]
+    fn point_in_polygon_convex_concave_and_on_the_edge() {
+        let square = [(53.0, -1.5), (53.0, -1.4), (53.1, -1.4), (53.1, -1.5)];
+        assert!(point_in_polygon(53.05, -1.45, &square));
+        assert!(!point_in_polygon(53.15, -1.45, &square));
+        assert!(!point_in_polygon(53.05, -1.35, &square));
+
+        // A U open to the north: the notch between its arms is outside
+        let u = [(53.0, -1.5), (53.0, -1.2), (53.3, -1.2), (53.3, -1.3), (53.1, -1.3), (53.1, -1.4), (53.3, -1.4), (53.3, -1.5)];
+        assert!(point_in_polygon(53.2, -1.45, &u)); // West arm
+        assert!(point_in_polygon(53.2, -1.25, &u)); // East arm
+        assert!(point_in_polygon(53.05, -1.35, &u)); // Base
+        assert!(!point_in_polygon(53.2, -1.35, &u)); // Notch
+        assert!(!point_in_polygon(53.4, -1.35, &u));
+
+        // Every edge and corner is in, whichever side ray casting would have put it on
+        for (lat, lng) in [(53.0, -1.45), (53.1, -1.45), (53.05, -1.5), (53.05, -1.4), (53.0, -1.5), (53.1, -1.4)] {
+            assert!(point_in_polygon(lat, lng, &square), "{}, {} is on the square's edge", lat, lng);
+        }
+        for (lat, lng) in [(53.2, -1.3), (53.1, -1.35), (53.2, -1.4), (53.3, -1.25)] {
+            assert!(point_in_polygon(lat, lng, &u), "{}, {} is on the U's edge", lat, lng);
+        }
+        assert!(!point_in_polygon(53.05, -1.5001, &square));
+    }
+
+    #[test]
+    fn mixed_up_bus_stop_delimiters_are_recovered_with_a_warning() {
+        let stops = |text: &str| {
+            let (stops, logs) = test_support::logged(|| load_bus_stops(&[], Some(&bus_stops(text))).unwrap());
+            let stops: Vec<(String, f64, f64, f64, Option<String>)> =
+                stops.into_iter().map(|stop| (stop.name, stop.lat, stop.lng, stop.radius, stop.group)).collect();
+            (stops, logs)
+        };
+        let home = ("Home".to_string(), 53.0, -1.5, DEFAULT_STOP_RADIUS, None);
+        let work = ("Work".to_string(), 53.1, -1.4, 150.0, Some("commute".to_string()));
+
+        // Properly delimited: no warning
+        let (read, logs) = stops("Home,53.0,-1.5;Work,53.1,-1.4,150,commute");
+        assert_eq!(read, [home.clone(), work.clone()]);
+        assert!(!logs.contains("WARN"), "{}", logs);
+
+        for mixed in ["Home,53.0,-1.5,Work,53.1,-1.4,150,commute", "Home;53.0;-1.5;Work,53.1,-1.4,150,commute;", "Home,53.0;-1.5,Work;53.1,-1.4;150;commute"] {
+            let (read, logs) = stops(mixed);
+            assert_eq!(read, [home.clone(), work.clone()], "{}", mixed);
+            assert!(logs.contains("BUS_STOPS mixes up ',' and ';'"), "{}: {}", mixed, logs);
+            assert!(logs.contains("Read it as 2 stop(s): Home, Work."), "{}: {}", mixed, logs);
+        }
+    }
+
+    #[test]
+    fn unrecoverable_bus_stops_are_skipped_with_the_usual_warnings() {
+        let (stops, logs) = test_support::logged(|| load_bus_stops(&[], Some(&bus_stops("Home,53.0,-1.5;Work,53.1"))).unwrap());
+        assert_eq!(stops.iter().map(|stop| stop.name.as_str()).collect::<Vec<_>>(), ["Home"]);
+        assert!(logs.contains("Invalid bus stop format. Skipping entry."), "{}", logs);
+        assert!(!logs.contains("mixes up"), "{}", logs);
+    }
+
+    #[test]
+    fn poll_intervals_below_the_floor_are_raised_to_it_with_a_warning() {
+        // Each case as if it were the first resolution of the run
+        let interval = |vars: &[(&str, &str)], file: ConfigFile| {
+            with_env(vars, || {
+                POLL_FLOOR_WARNED.store(false, AtomicOrdering::Relaxed);
+                test_support::logged(|| poll_interval_secs(&file).unwrap())
+            })
+        };
+        let from_file = |secs| ConfigFile { poll_interval_secs: Some(secs), ..ConfigFile::default() };
+
+        let (secs, logs) = interval(&[("POLL_INTERVAL_SECS", "30")], ConfigFile::default());
+        assert_eq!(secs, 30);
+        assert!(logs.is_empty(), "{}", logs);
+
+        let (secs, logs) = interval(&[("POLL_INTERVAL_SECS", "2")], ConfigFile::default());
+        assert_eq!(secs, 5);
+        assert!(logs.contains("Poll interval of 2 s is below MIN_POLL_SECS; polling every 5 s instead."), "{}", logs);
+
+        // The config file's value is held to the same floor, and MIN_POLL_SECS moves it (but never below 1 s)
+        let (secs, logs) = interval(&[("MIN_POLL_SECS", "20")], from_file(15));
+        assert_eq!(secs, 20);
+        assert!(logs.contains("Poll interval of 15 s is below MIN_POLL_SECS; polling every 20 s instead."), "{}", logs);
+        assert_eq!(interval(&[("POLL_INTERVAL_SECS", "0"), ("MIN_POLL_SECS", "0")], ConfigFile::default()).0, 1);
+        assert_eq!(interval(&[("POLL_INTERVAL_SECS", "5")], ConfigFile::default()).1, "");
+
+        // Once per run, however often it's resolved
+        let (secs, logs) = with_env(&[("POLL_INTERVAL_SECS", "2")], || {
+            POLL_FLOOR_WARNED.store(false, AtomicOrdering::Relaxed);
+            test_support::logged(|| [poll_interval_secs(&ConfigFile::default()), poll_interval_secs(&from_file(3))])
+        });
+        assert_eq!(secs, [Ok(5), Ok(5)]);
+        assert_eq!(logs.matches("below MIN_POLL_SECS").count(), 1, "{}", logs);
+    }
+
+    #[test]
+    fn each_exit_reason_has_its_own_code() {
+        let reasons = [
+            (ExitReason::Completed, 0),
+            (ExitReason::ConfigError("no LAT".to_string()), 1),
+            (ExitReason::ApiFailure, 2),
+            (ExitReason::NotifierStartup("bad token".to_string()), 3),
+            (ExitReason::NoMatch, 4),
+        ];
+        for (reason, code) in reasons {
+            assert_eq!(reason.code(), code, "{:?}", reason);
+        }
+    }
+
+    #[test]
+    fn malformed_settings_are_reported_rather_than_panicking() {
+        let config = with_env(&[("LAT", "north"), ("LNG", "-1.5"), ("RADIUS", "1000")], || Config::from_env(&ConfigFile::default()));
+        assert!(config.is_err());
+    }
+
+    #[test]
+    fn route_match_needs_the_stop_on_the_route_only_when_there_is_one() {
+        // Fleet 1's route serves the stop, fleet 2's doesn't, and fleet 3 has no route data
+        let response = serde_json::json!({ "services": [
+            { "serviceNumber": "7", "fleetNumber": "1", "latitude": "53.0001", "longitude": "-1.5",
+              "nextStops": [{ "stopName": "Bus Station" }, { "stopName": "market  SQUARE" }] },
+            { "serviceNumber": "7", "fleetNumber": "2", "latitude": "53.0002", "longitude": "-1.5",
+              "nextStopName": "Bus Station", "route": ["Hospital"] },
+            { "serviceNumber": "7", "fleetNumber": "3", "latitude": "53.0003", "longitude": "-1.5" }
+        ] });
+        let vehicles: Vec<Vehicle> = response["services"].as_array().unwrap().iter().filter_map(|service| Vehicle::from_json(service, &FieldMap::default())).collect();
+        assert_eq!(vehicles[1].upcoming_stops.as_deref(), Some(&["Bus Station".to_string(), "Hospital".to_string()][..]));
+
+        let served: Vec<bool> = vehicles.iter().map(|vehicle| route_serves_stop(vehicle.upcoming_stops.as_deref(), "Market Square")).collect();
+        assert_eq!(served, [true, false, true]);
+        assert!(test_support::config(&[("REQUIRE_ROUTE_MATCH", "true")]).require_route_match);
+        assert!(!test_support::config(&[]).require_route_match);
+    }
+
+    #[test]
+    fn stops_with_services_only_serve_those() {
+        let mut stop = test_support::stop("Market Square", 53.0, -1.5);
+        assert!(stop.serves("7", &Direction::Unknown));
+        stop.services = vec!["7".to_string(), "X24".to_string()];
+        assert!(stop.serves("x24", &Direction::Unknown));
+        assert!(!stop.serves("9", &Direction::Unknown));
+    }
+
+    #[test]
+    fn remapped_fields_read_like_the_usual_ones() {
+        let usual = serde_json::json!({ "serviceNumber": "7", "fleetNumber": "10812", "latitude": "53.0003", "longitude": "-1.5002", "speed": "18" });
+        let remapped = serde_json::json!({ "route": "7", "vehicle": { "fleet": "10812" }, "position": { "lat": "53.0003", "lng": "-1.5002" }, "speed": "18" });
+        let config = test_support::config(&[("FIELD_MAP", "serviceNumber=route, fleetNumber=vehicle.fleet, latitude=position.lat, longitude=position.lng")]);
+
+        let read = |service: &Value, fields: &FieldMap| {
+            let vehicle = Vehicle::from_json(service, fields).expect("has a position");
+            (vehicle.key(), vehicle.service_number, vehicle.lat, vehicle.lng, vehicle.speed)
+        };
+        assert_eq!(read(&remapped, &config.fields), read(&usual, &FieldMap::default()));
+        assert!(Vehicle::from_json(&remapped, &FieldMap::default()).is_none());
+    }
+
+    #[test]
+    fn alert_cooldown_is_seconds_or_cycles_but_not_both() {
+        assert_eq!(with_env(&[], alert_cooldown), Ok(Cooldown::Time(chrono::Duration::seconds(300))));
+        assert_eq!(with_env(&[("ALERT_COOLDOWN_SECS", "60")], alert_cooldown), Ok(Cooldown::Time(chrono::Duration::seconds(60))));
+        assert_eq!(with_env(&[("ALERT_COOLDOWN_CYCLES", "3")], alert_cooldown), Ok(Cooldown::Cycles(3)));
+        assert_eq!(
+            with_env(&[("ALERT_COOLDOWN_SECS", "60"), ("ALERT_COOLDOWN_CYCLES", "3")], alert_cooldown),
+            Err("Set ALERT_COOLDOWN_SECS or ALERT_COOLDOWN_CYCLES, not both.".to_string())
+        );
+    }
 }
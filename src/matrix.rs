@@ -0,0 +1,121 @@
+// Matrix room notifications: each alert is sent as an m.room.message event through the
+// client-server API, with the **stop name** in bold in the HTML body
+
+use reqwest::Client;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::time::Duration;
+use tracing::info;
+
+use crate::config_file::{group_destinations, GroupEntry};
+use crate::error::TrackerError;
+use crate::telegram::SendError;
+use crate::{env_or_file, urlencode};
+
+#[derive(Debug)]
+pub struct Matrix {
+    client: Client,
+    homeserver: String,
+    access_token: String,
+    room_id: String,
+    group_rooms: HashMap<String, String>, // Stop group -> the room its alerts go to
+    txn_prefix: String, // Unique to this run, so transaction ids never repeat across restarts
+    next_txn: AtomicU64,
+}
+
+impl Matrix {
+    /// Returns None when MATRIX_ROOM_ID isn't set, leaving Matrix disabled
+    pub fn from_env(client: Client, groups: &BTreeMap<String, GroupEntry>) -> Result<Option<Matrix>, TrackerError> {
+        let room_id = match env_or_file("MATRIX_ROOM_ID")? {
+            Some(room_id) if !room_id.trim().is_empty() => room_id.trim().to_string(),
+            _ => return Ok(None),
+        };
+        let homeserver = env_or_file("MATRIX_HOMESERVER")?
+            .filter(|url| !url.trim().is_empty())
+            .ok_or_else(|| "MATRIX_ROOM_ID is set but MATRIX_HOMESERVER isn't.".to_string())?;
+        let access_token = env_or_file("MATRIX_ACCESS_TOKEN")?
+            .ok_or_else(|| "MATRIX_ROOM_ID is set but MATRIX_ACCESS_TOKEN isn't.".to_string())?;
+
+        Ok(Some(Matrix {
+            client,
+            homeserver: homeserver.trim().trim_end_matches('/').to_string(),
+            access_token,
+            room_id,
+            group_rooms: group_destinations(groups, |group| group.matrix_room_id.as_deref().map(str::trim).filter(|room| !room.is_empty()).map(str::to_string)),
+            txn_prefix: format!("stagecoach-{}", chrono::Utc::now().timestamp_millis()),
+            next_txn: AtomicU64::new(0),
+        }))
+    }
+
+    /// Checks the access token with /whoami
+    pub async fn validate(&self) -> Result<(), TrackerError> {
+        let url = format!("{}/_matrix/client/v3/account/whoami", self.homeserver);
+        let response = self
+            .client
+            .get(&url)
+            .bearer_auth(&self.access_token)
+            .send()
+            .await
+            .map_err(|e| TrackerError::Notify(format!("Could not reach the Matrix homeserver: {}", e)))?;
+        let me = check_response(response).await.map_err(|e| TrackerError::Notify(format!("Matrix rejected the access token: {}", e)))?;
+        info!("Matrix user: {}", me["user_id"].as_str().unwrap_or("?"));
+        Ok(())
+    }
+
+    /// A fresh transaction id. Retrying a send with the same id makes the homeserver
+    /// return the original event instead of posting it again.
+    pub fn new_txn_id(&self) -> String {
+        format!("{}-{}", self.txn_prefix, self.next_txn.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Posts to `group`'s room, or MATRIX_ROOM_ID for ungrouped alerts and notices
+    pub async fn send(&self, group: Option<&str>, txn_id: &str, text: &str) -> Result<(), SendError> {
+        let room_id = group.and_then(|group| self.group_rooms.get(group)).unwrap_or(&self.room_id);
+        let url = format!(
+            "{}/_matrix/client/v3/rooms/{}/send/m.room.message/{}",
+            self.homeserver,
+            urlencode(room_id),
+            urlencode(txn_id)
+        );
+        let body = json!({
+            "msgtype": "m.text",
+            "body": text.replace("**", ""),
+            "format": "org.matrix.custom.html",
+            "formatted_body": to_html(text),
+        });
+        let response = self.client.put(&url).bearer_auth(&self.access_token).json(&body).send().await?;
+        check_response(response).await.map(|_| ())
+    }
+}
+
+/// Returns the reply body, or the error from {"errcode": "M_FORBIDDEN", "error": "...", "retry_after_ms": ...}
+async fn check_response(response: reqwest::Response) -> Result<Value, SendError> {
+    let status = response.status();
+    let body: Value = response.json().await.unwrap_or_default();
+    if status.is_success() {
+        return Ok(body);
+    }
+    Err(SendError::Api {
+        code: i64::from(status.as_u16()),
+        description: match (body["errcode"].as_str(), body["error"].as_str()) {
+            (Some(code), Some(error)) => format!("{}: {}", code, error),
+            _ => status.to_string(),
+        },
+        retry_after: body["retry_after_ms"].as_u64().map(|ms| Duration::from_millis(ms).as_secs().max(1)),
+    })
+}
+
+/// Escapes the text for HTML and turns **bold** into <b>bold</b>
+fn to_html(text: &str) -> String {
+    let escaped = text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+    let mut html = String::new();
+    for (i, part) in escaped.split("**").enumerate() {
+        if i % 2 == 1 {
+            html.push_str(&format!("<b>{}</b>", part));
+        } else {
+            html.push_str(part);
+        }
+    }
+    html.replace('\n', "<br>")
+}
@@ -0,0 +1,819 @@
+// Fan-out of alerts and notices to every configured notification channel, with a retry
+// queue for Telegram, Gotify and Matrix sends that failed for a temporary reason. Messages
+// longer than a channel takes are split into several sends or truncated (MESSAGE_OVERFLOW).
+// With SUPPRESS_REPEATS, a message whose exact text already went out in the last
+// REPEAT_WINDOW_MINS is dropped as a last check, whatever produced it twice. SEND_DEDUP_SECS
+// does the same for an alert sent twice to the same destination in a short burst; both are off
+// unless set.
+
+use reqwest::Client;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use tokio::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+use crate::config_file::GroupEntry;
+use crate::direction::Direction;
+use crate::{env_flag, env_or_file, env_parse};
+use crate::error::TrackerError;
+use crate::gotify::Gotify;
+use crate::matrix::Matrix;
+use crate::ntfy::Ntfy;
+#[cfg(feature = "sound")]
+use crate::sound::Sound;
+use crate::telegram::{AlertedBus, SendError, Telegram};
+use crate::twilio::Twilio;
+use crate::webhook::Webhook;
+
+const RETRY_DELAY: Duration = Duration::from_secs(30); // Unless Telegram asks for longer
+const MAX_ATTEMPTS: u32 = 5;
+const MAX_PENDING: usize = 50; // Oldest queued sends are dropped beyond this
+const MAX_RECENT_SENDS: usize = 256; // Hashes remembered for SEND_DEDUP_SECS
+const ELLIPSIS: char = '…';
+
+/// Hashes of recently sent messages, so an identical one inside the TTL is dropped
+/// whatever caused the repeat. Once it's full the least recently used hash goes first,
+/// where dropping a duplicate counts as a use; the TTL still runs from the send.
+#[derive(Debug)]
+struct RecentSends {
+    ttl: Duration,
+    sent: VecDeque<(u64, Instant)>, // Least recently used first, with when each was sent
+}
+
+impl RecentSends {
+    fn new(ttl: Duration) -> RecentSends {
+        RecentSends { ttl, sent: VecDeque::new() }
+    }
+
+    /// Records the message and returns whether it was already sent within the TTL
+    fn is_duplicate(&mut self, text: &str, target: Option<&str>, now: Instant) -> bool {
+        if self.ttl.is_zero() {
+            return false;
+        }
+        self.expire(now);
+        let hash = hash(text, target);
+        if let Some(index) = self.sent.iter().position(|(sent, _)| *sent == hash) {
+            let used = self.sent.remove(index).expect("position is in range");
+            self.sent.push_back(used);
+            return true;
+        }
+        self.push(hash, now);
+        false
+    }
+
+    /// Records a message sent whether or not it was a duplicate, starting its TTL over
+    fn remember(&mut self, text: &str, target: Option<&str>, now: Instant) {
+        if self.ttl.is_zero() {
+            return;
+        }
+        self.expire(now);
+        let hash = hash(text, target);
+        self.sent.retain(|(sent, _)| *sent != hash);
+        self.push(hash, now);
+    }
+
+    fn expire(&mut self, now: Instant) {
+        self.sent.retain(|(_, at)| now.duration_since(*at) < self.ttl);
+    }
+
+    fn push(&mut self, hash: u64, now: Instant) {
+        if self.sent.len() >= MAX_RECENT_SENDS {
+            self.sent.pop_front();
+        }
+        self.sent.push_back((hash, now));
+    }
+}
+
+fn hash(text: &str, target: Option<&str>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (text, target).hash(&mut hasher);
+    hasher.finish()
+}
+
+/// What happens to a message longer than a channel's maximum
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Overflow {
+    Split,    // Several sends, broken at line ends or spaces where possible
+    Truncate, // One send, cut short with an ellipsis
+}
+
+/// The longest message each channel takes, in characters; 0 for no limit
+#[derive(Debug)]
+struct MaxLengths {
+    overflow: Overflow,
+    telegram: usize,
+    ntfy: usize,
+    gotify: usize,
+    matrix: usize,
+    sms: usize,
+}
+
+impl MaxLengths {
+    fn from_env() -> Result<MaxLengths, String> {
+        let overflow = match env_or_file("MESSAGE_OVERFLOW")?.unwrap_or_default().trim().to_ascii_lowercase().as_str() {
+            "" | "split" => Overflow::Split,
+            "truncate" => Overflow::Truncate,
+            other => return Err(format!("MESSAGE_OVERFLOW must be split or truncate, got '{}'.", other)),
+        };
+        Ok(MaxLengths {
+            overflow,
+            telegram: env_parse("TELEGRAM_MAX_LENGTH", 4096)?, // Telegram rejects longer texts
+            ntfy: env_parse("NTFY_MAX_LENGTH", 4096)?,         // Longer ones arrive as an attachment
+            gotify: env_parse("GOTIFY_MAX_LENGTH", 0)?,
+            matrix: env_parse("MATRIX_MAX_LENGTH", 0)?,
+            sms: env_parse("SMS_MAX_LENGTH", 1600)?, // Twilio's limit on a message body
+        })
+    }
+
+    fn fit(&self, text: &str, max: usize) -> Vec<String> {
+        fit(text, max, self.overflow)
+    }
+}
+
+/// Cuts `text` down to pieces of at most `max` characters (0: no limit)
+fn fit(text: &str, max: usize, overflow: Overflow) -> Vec<String> {
+    if max == 0 || text.chars().count() <= max {
+        return vec![text.to_string()];
+    }
+    if overflow == Overflow::Truncate || max == 1 {
+        let mut cut: String = text.chars().take(max - 1).collect();
+        cut.push(ELLIPSIS);
+        return vec![cut];
+    }
+
+    let mut pieces = Vec::new();
+    let mut rest = text;
+    while rest.chars().count() > max {
+        // Byte offset just past the first `max` characters, then the last line end or space before it
+        let limit = rest.char_indices().nth(max).map_or(rest.len(), |(at, _)| at);
+        let at = rest[..limit].rfind('\n').or_else(|| rest[..limit].rfind(' ')).filter(|at| *at > 0).unwrap_or(limit);
+        let piece = rest[..at].trim_end();
+        if !piece.is_empty() {
+            pieces.push(piece.to_string());
+        }
+        rest = rest[at..].trim_start();
+    }
+    if !rest.is_empty() {
+        pieces.push(rest.to_string());
+    }
+    pieces
+}
+
+/// What kind of alert is being sent, for channels that rank them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AlertKind {
+    Arrival,
+    EarlyWarning, // Within EARLY_WARNING_RADIUS but not at the stop yet
+}
+
+/// A bus alert on its way out
+#[derive(Debug, Clone, PartialEq)]
+pub struct Alert {
+    pub text: String,
+    pub dedup_key: String, // Stands in for the text when checking for repeats, leaving out details that change every poll
+    pub bus: AlertedBus,
+    pub direction: Direction,
+    pub group: Option<String>,
+    pub kind: AlertKind,
+    pub quiet: Option<bool>, // The stop's own quiet hours, if it has them; None follows SILENT_HOURS
+    pub priority: bool,      // Sent even if it repeats a recent message (PRIORITY_SERVICES)
+}
+
+/// Where a queued send goes
+#[derive(Debug)]
+enum Destination {
+    Telegram { chat_id: String, bus: Option<AlertedBus>, silent: Option<bool> }, // The bus is set for alerts, which carry the mute/stop keyboard
+    Gotify { group: Option<String>, title: String, priority: u8 },
+    Matrix { group: Option<String>, txn_id: String }, // The id is kept across retries so the homeserver can drop repeats
+}
+
+impl std::fmt::Display for Destination {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Destination::Telegram { chat_id, .. } => write!(f, "Telegram chat {}", chat_id),
+            Destination::Gotify { .. } => write!(f, "Gotify"),
+            Destination::Matrix { .. } => write!(f, "Matrix"),
+        }
+    }
+}
+
+/// A send waiting to be retried
+#[derive(Debug)]
+struct PendingSend {
+    destination: Destination,
+    text: String,
+    attempts: u32,
+    not_before: Instant,
+}
+
+#[derive(Debug)]
+pub struct Notifiers {
+    pub telegram: Telegram,
+    ntfy: Option<Ntfy>,
+    gotify: Option<Gotify>,
+    matrix: Option<Matrix>,
+    twilio: Option<Twilio>,
+    webhook: Option<Webhook>,
+    #[cfg(feature = "sound")]
+    sound: Option<Sound>,
+    pending: VecDeque<PendingSend>,
+    failed_sends: u64, // Every failed attempt on any channel, for /status and the snapshot
+    recent: RecentSends,
+    repeats: RecentSends, // By rendered text alone (SUPPRESS_REPEATS); zero TTL when off
+    suppressed_repeats: u64,
+    max_lengths: MaxLengths,
+}
+
+impl Notifiers {
+    /// `groups` gives the per-group destinations from the config file
+    pub fn from_env(client: Client, telegram: Telegram, groups: &BTreeMap<String, GroupEntry>) -> Result<Notifiers, TrackerError> {
+        Ok(Notifiers {
+            telegram,
+            ntfy: Ntfy::from_env(client.clone(), groups)?,
+            gotify: Gotify::from_env(client.clone(), groups)?,
+            matrix: Matrix::from_env(client.clone(), groups)?,
+            twilio: Twilio::from_env(client.clone(), groups)?,
+            webhook: Webhook::from_env(client, groups)?,
+            #[cfg(feature = "sound")]
+            sound: Sound::from_env()?,
+            pending: VecDeque::new(),
+            failed_sends: 0,
+            recent: RecentSends::new(Duration::from_secs(env_parse("SEND_DEDUP_SECS", 0)?)),
+            repeats: RecentSends::new(match env_flag("SUPPRESS_REPEATS", false)? {
+                true => Duration::from_secs(env_parse("REPEAT_WINDOW_MINS", 10)? * 60),
+                false => Duration::ZERO,
+            }),
+            suppressed_repeats: 0,
+            max_lengths: MaxLengths::from_env()?,
+        })
+    }
+
+    /// Names of the channels alerts go out on
+    pub fn channels(&self) -> Vec<&'static str> {
+        let mut channels = vec!["telegram"];
+        let optional = [
+            ("ntfy", self.ntfy.is_some()),
+            ("gotify", self.gotify.is_some()),
+            ("matrix", self.matrix.is_some()),
+            ("twilio", self.twilio.is_some()),
+            ("webhook", self.webhook.is_some()),
+            #[cfg(feature = "sound")]
+            ("sound", self.sound.is_some()),
+        ];
+        channels.extend(optional.into_iter().filter(|(_, on)| *on).map(|(name, _)| name));
+        channels
+    }
+
+    pub fn failed_sends(&self) -> u64 {
+        self.failed_sends
+    }
+
+    /// Sends dropped because the same text went out within REPEAT_WINDOW_MINS
+    pub fn suppressed_repeats(&self) -> u64 {
+        self.suppressed_repeats
+    }
+
+    /// Records `text` and returns whether it repeats one sent within the window (SUPPRESS_REPEATS)
+    fn is_repeat(&mut self, text: &str) -> bool {
+        if !self.repeats.is_duplicate(text, None, Instant::now()) {
+            return false;
+        }
+        self.suppressed_repeats += 1;
+        info!("Not sending a repeat of a message sent in the last {} min: {}", self.repeats.ttl.as_secs() / 60, text);
+        true
+    }
+
+    /// Sends an alert about its bus everywhere, to its group's destinations where it has its own.
+    /// A channel being down never stops the others.
+    pub async fn send_alert(&mut self, alert: &Alert) {
+        let text = &alert.text;
+        let group = alert.group.as_deref();
+        if alert.priority {
+            // Sent whatever went out recently, but remembered like any other send
+            self.recent.remember(&alert.dedup_key, group, Instant::now());
+            self.repeats.remember(text, None, Instant::now());
+        } else if self.recent.is_duplicate(&alert.dedup_key, group, Instant::now()) {
+            info!("Not sending a duplicate of a recent message: {}", text);
+            return;
+        } else if self.is_repeat(text) {
+            return;
+        }
+        for chat_id in self.telegram.alert_chats(group) {
+            self.send_telegram(chat_id, text, Some(&alert.bus), alert.quiet).await;
+        }
+        self.send_ntfy(group, text).await;
+        let (title, priority) = Gotify::alert_headline(&alert.bus.stop, alert.kind);
+        self.send_gotify(group, title, text, priority).await;
+        self.send_matrix(group, text).await;
+        self.send_sms(group, text).await;
+        self.send_webhook(group, Webhook::alert_payload(alert)).await;
+        #[cfg(feature = "sound")]
+        if let Some(sound) = &self.sound {
+            if !alert.quiet.unwrap_or_else(|| self.telegram.in_silent_hours()) {
+                sound.play(alert.kind);
+            }
+        }
+    }
+
+    /// Sends a plain notice (departures, API outages) everywhere but SMS
+    pub async fn send_message(&mut self, text: &str) {
+        if self.recent.is_duplicate(text, None, Instant::now()) {
+            info!("Not sending a duplicate of a recent message: {}", text);
+            return;
+        }
+        if self.is_repeat(text) {
+            return;
+        }
+        for chat_id in self.telegram.alert_chats(None) {
+            self.send_telegram(chat_id, text, None, None).await;
+        }
+        self.send_ntfy(None, text).await;
+        let (title, priority) = Gotify::notice_headline();
+        self.send_gotify(None, title, text, priority).await;
+        self.send_matrix(None, text).await;
+        self.send_webhook(None, Webhook::notice_payload(text)).await;
+    }
+
+    /// Sends `text` to every channel, failing on the first one that doesn't accept it
+    pub async fn send_test(&mut self, text: &str) -> Result<(), TrackerError> {
+        self.telegram.send_message(text).await.map_err(|e| TrackerError::Notify(format!("Telegram: {}", e)))?;
+        if let Some(ntfy) = &self.ntfy {
+            ntfy.send(None, text).await.map_err(|e| TrackerError::Notify(format!("ntfy: {}", e)))?;
+        }
+        if let Some(gotify) = &self.gotify {
+            let (title, priority) = Gotify::notice_headline();
+            gotify.send(None, &title, text, priority).await.map_err(|e| TrackerError::Notify(format!("Gotify: {}", e)))?;
+        }
+        if let Some(matrix) = &self.matrix {
+            matrix.send(None, &matrix.new_txn_id(), text).await.map_err(|e| TrackerError::Notify(format!("Matrix: {}", e)))?;
+        }
+        if let Some(twilio) = &mut self.twilio {
+            twilio.send(None, text).await?;
+        }
+        if let Some(webhook) = &self.webhook {
+            webhook.send(None, &Webhook::notice_payload(text)).await.map_err(|e| TrackerError::Notify(format!("webhook: {}", e)))?;
+        }
+        Ok(())
+    }
+
+    /// Checks the credentials of the optional channels that can be checked without sending
+    /// (Telegram has its own `validate`)
+    pub async fn validate(&self) -> Result<(), TrackerError> {
+        if let Some(matrix) = &self.matrix {
+            matrix.validate().await?;
+        }
+        Ok(())
+    }
+
+    /// Retries queued sends that are due
+    pub async fn retry_pending(&mut self) {
+        let now = Instant::now();
+        let (due, waiting): (VecDeque<_>, VecDeque<_>) = self.pending.drain(..).partition(|send| send.not_before <= now);
+        self.pending = waiting;
+
+        for send in due {
+            let Some(mut send) = self.defer_while_rate_limited(send) else {
+                continue;
+            };
+            send.attempts += 1;
+            match self.deliver(&send).await {
+                Ok(()) => info!("Delivered queued message to {} on attempt {}", send.destination, send.attempts),
+                Err(e) => self.record_failure(send, e),
+            }
+        }
+    }
+
+    async fn deliver(&self, send: &PendingSend) -> Result<(), SendError> {
+        match &send.destination {
+            Destination::Telegram { chat_id, bus, silent } => {
+                self.telegram.send_to(chat_id, &send.text, bus.as_ref(), *silent).await.map(|_| ())
+            }
+            Destination::Gotify { group, title, priority } => match &self.gotify {
+                Some(gotify) => gotify.send(group.as_deref(), title, &send.text, *priority).await,
+                None => Ok(()),
+            },
+            Destination::Matrix { group, txn_id } => match &self.matrix {
+                Some(matrix) => matrix.send(group.as_deref(), txn_id, &send.text).await,
+                None => Ok(()),
+            },
+        }
+    }
+
+    /// Sends once, queueing the send for retries if that fails
+    async fn send_to(&mut self, destination: Destination, text: &str) {
+        let send = PendingSend { destination, text: text.to_string(), attempts: 0, not_before: Instant::now() };
+        let Some(mut send) = self.defer_while_rate_limited(send) else {
+            return;
+        };
+        send.attempts = 1;
+        if let Err(e) = self.deliver(&send).await {
+            self.record_failure(send, e);
+        }
+    }
+
+    /// The alert buttons go on the last piece only, under the end of the message
+    async fn send_telegram(&mut self, chat_id: String, text: &str, bus: Option<&AlertedBus>, silent: Option<bool>) {
+        let pieces = self.max_lengths.fit(text, self.max_lengths.telegram);
+        let last = pieces.len() - 1;
+        for (index, piece) in pieces.into_iter().enumerate() {
+            let destination = Destination::Telegram { chat_id: chat_id.clone(), bus: bus.filter(|_| index == last).cloned(), silent };
+            self.send_to(destination, &piece).await;
+        }
+    }
+
+    async fn send_gotify(&mut self, group: Option<&str>, title: String, text: &str, priority: u8) {
+        if self.gotify.is_some() {
+            for piece in self.max_lengths.fit(text, self.max_lengths.gotify) {
+                let destination = Destination::Gotify { group: group.map(str::to_string), title: title.clone(), priority };
+                self.send_to(destination, &piece).await;
+            }
+        }
+    }
+
+    async fn send_matrix(&mut self, group: Option<&str>, text: &str) {
+        let Some(matrix) = &self.matrix else {
+            return;
+        };
+        let pieces: Vec<(String, String)> =
+            self.max_lengths.fit(text, self.max_lengths.matrix).into_iter().map(|piece| (matrix.new_txn_id(), piece)).collect();
+        for (txn_id, piece) in pieces {
+            self.send_to(Destination::Matrix { group: group.map(str::to_string), txn_id }, &piece).await;
+        }
+    }
+
+    async fn send_sms(&mut self, group: Option<&str>, text: &str) {
+        let pieces = self.max_lengths.fit(text, self.max_lengths.sms);
+        if let Some(twilio) = &mut self.twilio {
+            for piece in pieces {
+                if let Err(e) = twilio.send(group, &piece).await {
+                    self.failed_sends += 1;
+                    error!("Error sending SMS: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Queues a Telegram send until the rate limit from an earlier 429 passes, without using up
+    /// an attempt. Returns the send if it can go now.
+    fn defer_while_rate_limited(&mut self, mut send: PendingSend) -> Option<PendingSend> {
+        let until = match send.destination {
+            Destination::Telegram { .. } => self.telegram.blocked_until(),
+            _ => None,
+        };
+        let Some(until) = until else {
+            return Some(send);
+        };
+        info!("Holding a message to {} until Telegram's rate limit passes", send.destination);
+        if self.pending.len() >= MAX_PENDING {
+            warn!("Retry queue is full; dropping the oldest message");
+            self.pending.pop_front();
+        }
+        send.not_before = until;
+        self.pending.push_back(send);
+        None
+    }
+
+    /// Counts and logs a failed send, queueing it for another go if the error is temporary
+    fn record_failure(&mut self, mut send: PendingSend, e: SendError) {
+        self.failed_sends += 1;
+        error!("Error sending message to {}: {}", send.destination, e);
+
+        if !e.is_retryable() || send.attempts >= MAX_ATTEMPTS {
+            return;
+        }
+        if self.pending.len() >= MAX_PENDING {
+            warn!("Retry queue is full; dropping the oldest message");
+            self.pending.pop_front();
+        }
+        send.not_before = Instant::now() + e.retry_after().unwrap_or(RETRY_DELAY);
+        self.pending.push_back(send);
+    }
+
+    async fn send_ntfy(&mut self, group: Option<&str>, text: &str) {
+        if let Some(ntfy) = &self.ntfy {
+            for piece in self.max_lengths.fit(text, self.max_lengths.ntfy) {
+                if let Err(e) = ntfy.send(group, &piece).await {
+                    self.failed_sends += 1;
+                    error!("Error sending ntfy notification: {}", e);
+                }
+            }
+        }
+    }
+
+    async fn send_webhook(&mut self, group: Option<&str>, payload: serde_json::Value) {
+        if let Some(webhook) = &self.webhook {
+            if let Err(e) = webhook.send(group, &payload).await {
+                self.failed_sends += 1;
+                error!("Error sending webhook: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_file::ConfigFile;
+    use crate::format::Style;
+    use crate::test_support::{with_env, MockServer};
+
+    const OK: &str = r#"{"ok":true,"result":{"message_id":7}}"#;
+
+    /// Notifiers with every channel pointed at `server`, plus `vars`
+    fn notifiers(server: &MockServer, file: &ConfigFile, vars: &[(&str, &str)]) -> Notifiers {
+        let url = server.url.as_str();
+        let mut all = vec![
+            ("TELEGRAM_API_URL", url),
+            ("TELEGRAM_BOT_TOKEN", "T"),
+            ("TELEGRAM_CHAT_ID", "100"),
+            ("NTFY_URL", url),
+            ("NTFY_TOPIC", "buses"),
+            ("GOTIFY_URL", url),
+            ("GOTIFY_TOKEN", "main-token"),
+            ("MATRIX_HOMESERVER", url),
+            ("MATRIX_ROOM_ID", "!main:example.org"),
+            ("MATRIX_ACCESS_TOKEN", "M"),
+            ("TWILIO_API_URL", url),
+            ("TWILIO_ACCOUNT_SID", "AC1"),
+            ("TWILIO_AUTH_TOKEN", "A"),
+            ("TWILIO_FROM", "+447700900000"),
+            ("TWILIO_TO", "+447700900001"),
+        ];
+        let webhook_url = format!("{}/hook", url);
+        all.push(("WEBHOOK_URL", &webhook_url));
+        all.extend_from_slice(vars);
+        with_env(&all, || {
+            let telegram = Telegram::from_env(Client::new(), Style::from_env().unwrap(), file).unwrap();
+            Notifiers::from_env(Client::new(), telegram, &file.groups).unwrap()
+        })
+    }
+
+    fn alert(text: &str, group: Option<&str>) -> Alert {
+        Alert {
+            text: text.to_string(),
+            dedup_key: text.to_string(),
+            bus: AlertedBus {
+                key: "fleet:10812".to_string(),
+                service: "7".to_string(),
+                vehicle: "10812".to_string(),
+                stop: "Market Square".to_string(),
+            },
+            direction: Direction::Unknown,
+            group: group.map(str::to_string),
+            kind: AlertKind::Arrival,
+            quiet: None,
+            priority: false,
+        }
+    }
+
+    #[test]
+    fn recent_sends_drop_repeats_of_a_message_to_the_same_place_within_the_ttl() {
+        let start = Instant::now();
+        let mut recent = RecentSends::new(Duration::from_secs(60));
+        assert!(!recent.is_duplicate("Bus 7 is near Home", None, start));
+        assert!(recent.is_duplicate("Bus 7 is near Home", None, start + Duration::from_secs(59)));
+        assert!(!recent.is_duplicate("Bus 9 is near Home", None, start + Duration::from_secs(1)));
+        assert!(!recent.is_duplicate("Bus 7 is near Home", Some("work"), start + Duration::from_secs(1)));
+        assert!(recent.is_duplicate("Bus 7 is near Home", Some("work"), start + Duration::from_secs(2)));
+
+        // The window runs from the send, so a message repeated every 59 s still goes out every minute
+        assert!(!recent.is_duplicate("Bus 7 is near Home", None, start + Duration::from_secs(60)));
+        assert!(recent.is_duplicate("Bus 7 is near Home", None, start + Duration::from_secs(119)));
+    }
+
+    #[test]
+    fn recent_sends_are_off_with_a_zero_ttl() {
+        let mut recent = RecentSends::new(Duration::ZERO);
+        let now = Instant::now();
+        assert!(!recent.is_duplicate("Bus 7", None, now));
+        assert!(!recent.is_duplicate("Bus 7", None, now));
+        assert!(recent.sent.is_empty());
+    }
+
+    #[test]
+    fn a_full_recent_sends_forgets_the_least_recently_used_first() {
+        let now = Instant::now();
+        let mut recent = RecentSends::new(Duration::from_secs(60));
+        for n in 0..MAX_RECENT_SENDS {
+            recent.is_duplicate(&format!("Bus {}", n), None, now);
+        }
+        assert!(recent.is_duplicate("Bus 0", None, now)); // Now the most recently used
+        recent.is_duplicate("One more", None, now);
+
+        assert_eq!(recent.sent.len(), MAX_RECENT_SENDS);
+        assert!(recent.is_duplicate("Bus 0", None, now));
+        assert!(!recent.is_duplicate("Bus 1", None, now), "Bus 1 was the least recently used");
+    }
+
+    #[test]
+    fn send_dedup_is_off_unless_set() {
+        let vars = [("TELEGRAM_BOT_TOKEN", "T"), ("TELEGRAM_CHAT_ID", "100")];
+        let notifiers = with_env(&vars, || {
+            let telegram = Telegram::from_env(Client::new(), Style::from_env().unwrap(), &ConfigFile::default()).unwrap();
+            Notifiers::from_env(Client::new(), telegram, &BTreeMap::new()).unwrap()
+        });
+        assert!(notifiers.recent.ttl.is_zero());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn identical_text_goes_out_once_per_window_on_every_channel() {
+        let server = MockServer::start(vec![(200, OK.to_string())]);
+        let mut notifiers = notifiers(&server, &ConfigFile::default(), &[("SUPPRESS_REPEATS", "true"), ("REPEAT_WINDOW_MINS", "1")]);
+        // Two vehicle ids for one physical bus: different dedup keys, the same text
+        let first = alert("Bus 7 is near Home", None);
+        let second = Alert {
+            bus: AlertedBus { vehicle: "10813".to_string(), ..first.bus.clone() },
+            dedup_key: "7 10813 Home".to_string(),
+            ..first.clone()
+        };
+
+        notifiers.send_alert(&first).await;
+        let one_round = server.requests().len();
+        assert_eq!(one_round, 6, "one send per channel");
+        notifiers.send_alert(&second).await;
+        notifiers.send_message("Bus 7 is near Home").await; // A notice counts too
+        assert_eq!(server.requests().len(), one_round);
+        assert_eq!(notifiers.suppressed_repeats(), 2);
+
+        notifiers.send_alert(&alert("Bus 9 is near Home", None)).await;
+        tokio::time::advance(Duration::from_secs(60)).await;
+        notifiers.send_alert(&second).await;
+        assert_eq!(server.requests().len(), 3 * one_round);
+        assert_eq!(notifiers.suppressed_repeats(), 2);
+
+        // Off unless set
+        let server = MockServer::start(vec![(200, OK.to_string())]);
+        let mut unsuppressed = self::notifiers(&server, &ConfigFile::default(), &[]);
+        unsuppressed.send_alert(&first).await;
+        unsuppressed.send_alert(&second).await;
+        assert_eq!(telegram_sends(&server), 2);
+        assert_eq!(unsuppressed.suppressed_repeats(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn telegram_errors_are_counted_and_only_temporary_ones_retried() {
+        let server = MockServer::start(vec![
+            (200, r#"{"ok":false,"error_code":400,"description":"Bad Request: chat not found"}"#.to_string()),
+            (502, r#"{"ok":false,"error_code":502,"description":"Bad Gateway"}"#.to_string()),
+            (200, OK.to_string()),
+        ]);
+        let vars = [("TELEGRAM_API_URL", server.url.as_str()), ("TELEGRAM_BOT_TOKEN", "T"), ("TELEGRAM_CHAT_ID", "100")];
+        let mut notifiers = with_env(&vars, || {
+            let telegram = Telegram::from_env(Client::new(), Style::from_env().unwrap(), &ConfigFile::default()).unwrap();
+            Notifiers::from_env(Client::new(), telegram, &BTreeMap::new()).unwrap()
+        });
+
+        // A 400 fails for good, even when it comes with HTTP 200
+        notifiers.send_message("first").await;
+        assert_eq!((notifiers.failed_sends(), notifiers.pending.len()), (1, 0));
+
+        notifiers.send_message("second").await;
+        assert_eq!((notifiers.failed_sends(), notifiers.pending.len()), (2, 1));
+        notifiers.retry_pending().await;
+        assert_eq!(server.requests().len(), 2, "retried before RETRY_DELAY");
+
+        tokio::time::advance(RETRY_DELAY).await;
+        notifiers.retry_pending().await;
+        assert_eq!((notifiers.failed_sends(), notifiers.pending.len()), (2, 0));
+        let texts: Vec<String> = server.requests().iter().map(|request| serde_json::from_str::<serde_json::Value>(&request.body).unwrap()["text"].to_string()).collect();
+        assert_eq!(texts, [r#""first""#, r#""second""#, r#""second""#]);
+    }
+
+    /// A batch of `n` alert lines, each about 30 characters
+    fn batch(n: usize) -> String {
+        (1..=n).map(|bus| format!("Bus {} is near Market Square", bus)).collect::<Vec<_>>().join("\n")
+    }
+
+    #[test]
+    fn a_long_batch_splits_at_line_ends_into_pieces_within_the_limit() {
+        let text = batch(12);
+        let pieces = fit(&text, 100, Overflow::Split);
+        assert_eq!(pieces.len(), 4);
+        assert!(pieces.iter().all(|piece| piece.chars().count() <= 100), "{:?}", pieces);
+        assert_eq!(pieces[0], batch(3));
+        // Nothing is lost or reordered: the pieces are the batch's lines, broken only between them
+        assert_eq!(pieces.join("\n"), text);
+
+        // A line longer than the limit breaks at a space, and a word longer than it mid-word
+        let pieces = fit("Bus 7 is near Market Square now", 12, Overflow::Split);
+        assert_eq!(pieces, ["Bus 7 is", "near Market", "Square now"]);
+        assert_eq!(fit("Stagecoach", 4, Overflow::Split), ["Stag", "ecoa", "ch"]);
+        // Characters, not bytes, count towards the limit
+        assert_eq!(fit("äöü äöü", 3, Overflow::Split), ["äöü", "äöü"]);
+
+        assert_eq!(fit(&text, text.chars().count(), Overflow::Split), vec![text.clone()]);
+        assert_eq!(fit(&text, 0, Overflow::Split), vec![text.clone()]); // No limit
+    }
+
+    #[test]
+    fn truncating_keeps_one_piece_ending_in_an_ellipsis() {
+        let text = batch(12);
+        let pieces = fit(&text, 100, Overflow::Truncate);
+        assert_eq!(pieces.len(), 1);
+        assert_eq!(pieces[0].chars().count(), 100);
+        assert!(pieces[0].ends_with(ELLIPSIS));
+        assert!(text.starts_with(pieces[0].trim_end_matches(ELLIPSIS)));
+        assert_eq!(fit("Bus 7", 5, Overflow::Truncate), ["Bus 7"]);
+        assert_eq!(fit("Bus 77", 5, Overflow::Truncate), ["Bus …"]);
+    }
+
+    #[tokio::test]
+    async fn each_channel_splits_to_its_own_limit() {
+        let server = MockServer::start(vec![(200, OK.to_string())]);
+        let mut notifiers = notifiers(&server, &ConfigFile::default(), &[("TELEGRAM_MAX_LENGTH", "100"), ("SMS_MAX_LENGTH", "200"), ("NTFY_MAX_LENGTH", "0")]);
+        notifiers.send_alert(&alert(&batch(12), None)).await;
+
+        let requests = server.requests();
+        let telegram: Vec<String> = requests
+            .iter()
+            .filter(|request| request.path.ends_with("/sendMessage"))
+            .map(|request| serde_json::from_str::<serde_json::Value>(&request.body).unwrap()["text"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(telegram.len(), 4);
+        assert!(telegram.iter().all(|piece| piece.chars().count() <= 100), "{:?}", telegram);
+        assert_eq!(requests.iter().filter(|request| request.path.ends_with("Messages.json")).count(), 2);
+        assert_eq!(requests.iter().filter(|request| request.path == "/buses").count(), 1);
+    }
+
+    /// Where each request went: the path, plus the header or body detail that names the destination
+    fn destinations(server: &MockServer) -> Vec<String> {
+        server
+            .requests()
+            .into_iter()
+            .map(|request| match request.header("x-gotify-key") {
+                Some(token) => format!("{} key={}", request.path, token),
+                None if request.path.contains("/bot") => {
+                    let body: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+                    format!("{} chat={}", request.path, body["chat_id"].as_str().unwrap())
+                }
+                None if request.path.contains("Messages.json") => format!("{} {}", request.path, request.body.split('&').nth(1).unwrap()),
+                None if request.path.contains("/_matrix/") => request.path.rsplit_once('/').unwrap().0.to_string(), // Less the transaction id
+                None => request.path,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn group_alerts_go_to_the_group_on_every_channel() {
+        let server = MockServer::start(vec![(200, OK.to_string())]);
+        let home_hook = format!("{}/home-hook", server.url);
+        let file: ConfigFile = toml::from_str(&format!(
+            r#"
+            [groups.home]
+            telegram_chat_ids = ["-100200"]
+            ntfy_topic = "home-buses"
+            gotify_token = "home-token"
+            matrix_room_id = "!home:example.org"
+            sms_to = ["+447700900002"]
+            webhook_url = "{}"
+            "#,
+            home_hook
+        ))
+        .unwrap();
+        let mut notifiers = notifiers(&server, &file, &[]);
+
+        notifiers.send_alert(&alert("Bus 7 is near Home", Some("home"))).await;
+        notifiers.send_alert(&alert("Bus 9 is near Work", None)).await;
+
+        assert_eq!(
+            destinations(&server),
+            [
+                "/botT/sendMessage chat=-100200",
+                "/home-buses",
+                "/message key=home-token",
+                "/_matrix/client/v3/rooms/%21home%3Aexample.org/send/m.room.message",
+                "/2010-04-01/Accounts/AC1/Messages.json To=%2B447700900002",
+                "/home-hook",
+                "/botT/sendMessage chat=100",
+                "/buses",
+                "/message key=main-token",
+                "/_matrix/client/v3/rooms/%21main%3Aexample.org/send/m.room.message",
+                "/2010-04-01/Accounts/AC1/Messages.json To=%2B447700900001",
+                "/hook",
+            ]
+        );
+    }
+
+    /// How many messages went to Telegram
+    fn telegram_sends(server: &MockServer) -> usize {
+        server.requests().iter().filter(|request| request.path.ends_with("/sendMessage")).count()
+    }
+
+    #[tokio::test]
+    async fn priority_alerts_skip_the_dedup_window_but_are_remembered() {
+        let server = MockServer::start(vec![(200, OK.to_string())]);
+        let mut notifiers = notifiers(&server, &ConfigFile::default(), &[("SEND_DEDUP_SECS", "60"), ("SUPPRESS_REPEATS", "true")]);
+        let priority = Alert { priority: true, ..alert("Bus 7 is near Home", None) };
+
+        notifiers.send_alert(&priority).await;
+        notifiers.send_alert(&priority).await;
+        assert_eq!(telegram_sends(&server), 2);
+        notifiers.send_alert(&alert("Bus 7 is near Home", None)).await;
+        assert_eq!(telegram_sends(&server), 2, "an ordinary alert repeating a priority one still counts as a duplicate");
+
+        notifiers.send_alert(&alert("Bus 9 is near Home", None)).await;
+        notifiers.send_alert(&alert("Bus 9 is near Home", None)).await;
+        assert_eq!(telegram_sends(&server), 3);
+    }
+}
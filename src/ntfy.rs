@@ -0,0 +1,138 @@
+// ntfy push notifications: each alert is POSTed as plain text to <NTFY_URL>/<NTFY_TOPIC>,
+// or to the alert's group's ntfy_topic on the same server
+
+use reqwest::Client;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::config_file::{group_destinations, GroupEntry};
+use crate::env_or_file;
+use crate::error::TrackerError;
+
+const DEFAULT_URL: &str = "https://ntfy.sh";
+
+#[derive(Debug, Clone)]
+pub struct Ntfy {
+    client: Client,
+    url: String, // Full topic URL, e.g. https://ntfy.sh/my-buses
+    group_urls: HashMap<String, String>, // Stop group -> its own topic's URL
+    title: Option<String>,
+    priority: Option<String>,
+    token: Option<String>, // Access token for protected topics on self-hosted servers
+}
+
+impl Ntfy {
+    /// Returns None when NTFY_TOPIC isn't set, leaving ntfy disabled
+    pub fn from_env(client: Client, groups: &BTreeMap<String, GroupEntry>) -> Result<Option<Ntfy>, TrackerError> {
+        // Anyone who knows a topic on the public server can read it, so it's a secret too
+        let topic = match env_or_file("NTFY_TOPIC")? {
+            Some(topic) if !topic.trim().is_empty() => topic.trim().trim_matches('/').to_string(),
+            _ => return Ok(None),
+        };
+        let base_url = env_or_file("NTFY_URL")?.unwrap_or_else(|| DEFAULT_URL.to_string());
+        let topic_url = |topic: &str| format!("{}/{}", base_url.trim_end_matches('/'), topic.trim().trim_matches('/'));
+
+        let priority = match env_or_file("NTFY_PRIORITY")? {
+            Some(value) => Some(parse_priority(&value).ok_or_else(|| {
+                TrackerError::Config(format!("NTFY_PRIORITY must be 1-5 or one of min, low, default, high, urgent, got '{}'.", value))
+            })?),
+            None => None,
+        };
+
+        Ok(Some(Ntfy {
+            client,
+            url: topic_url(&topic),
+            group_urls: group_destinations(groups, |group| group.ntfy_topic.as_deref().filter(|topic| !topic.trim().is_empty()).map(topic_url)),
+            title: env_or_file("NTFY_TITLE")?.filter(|title| !title.trim().is_empty()),
+            priority,
+            token: env_or_file("NTFY_TOKEN")?,
+        }))
+    }
+
+    /// Posts `text` to `group`'s topic, or NTFY_TOPIC for ungrouped alerts and notices
+    pub async fn send(&self, group: Option<&str>, text: &str) -> Result<(), TrackerError> {
+        let url = group.and_then(|group| self.group_urls.get(group)).unwrap_or(&self.url);
+        let mut request = self.client.post(url).body(text.to_string());
+        if let Some(title) = &self.title {
+            request = request.header("Title", title);
+        }
+        if let Some(priority) = &self.priority {
+            request = request.header("Priority", priority);
+        }
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+
+        request.send().await?.error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Normalises a priority to the form ntfy accepts in the Priority header
+fn parse_priority(value: &str) -> Option<String> {
+    let value = value.trim().to_ascii_lowercase();
+    match value.as_str() {
+        "1" | "2" | "3" | "4" | "5" | "min" | "low" | "default" | "high" | "urgent" | "max" => Some(value),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{with_env, MockServer};
+
+    fn ntfy(vars: &[(&str, &str)]) -> Result<Option<Ntfy>, TrackerError> {
+        with_env(vars, || Ntfy::from_env(Client::new(), &BTreeMap::new()))
+    }
+
+    #[tokio::test]
+    async fn alerts_are_posted_as_text_with_the_configured_headers() {
+        let server = MockServer::start(vec![(200, "{}".to_string())]);
+        let base_url = format!("{}/", server.url);
+        let vars = [("NTFY_URL", base_url.as_str()), ("NTFY_TOPIC", "/my-buses/"), ("NTFY_TITLE", "Buses"), ("NTFY_PRIORITY", " High "), ("NTFY_TOKEN", "tk_secret")];
+        let ntfy = ntfy(&vars).unwrap().unwrap();
+
+        ntfy.send(None, "Bus 7 is near **Market Square**!").await.unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        let request = &requests[0];
+        assert_eq!((request.method.as_str(), request.path.as_str()), ("POST", "/my-buses"));
+        assert_eq!(request.body, "Bus 7 is near **Market Square**!");
+        assert_eq!(request.header("title"), Some("Buses"));
+        assert_eq!(request.header("priority"), Some("high"));
+        assert_eq!(request.header("authorization"), Some("Bearer tk_secret"));
+    }
+
+    #[tokio::test]
+    async fn optional_headers_are_left_out_and_errors_surface() {
+        let server = MockServer::start(vec![(403, r#"{"error":"forbidden"}"#.to_string())]);
+        let ntfy = ntfy(&[("NTFY_URL", &server.url), ("NTFY_TOPIC", "buses")]).unwrap().unwrap();
+
+        assert!(ntfy.send(None, "Bus 9").await.is_err());
+        let request = &server.requests()[0];
+        assert_eq!(request.body, "Bus 9");
+        assert!(["title", "priority", "authorization"].iter().all(|name| request.header(name).is_none()), "{:?}", request.headers);
+    }
+
+    #[tokio::test]
+    async fn group_alerts_go_to_the_groups_own_topic() {
+        let server = MockServer::start(vec![(200, "{}".to_string())]);
+        let groups = BTreeMap::from([("home".to_string(), GroupEntry { ntfy_topic: Some("home-buses".to_string()), ..GroupEntry::default() })]);
+        let vars = [("NTFY_URL", server.url.as_str()), ("NTFY_TOPIC", "buses")];
+        let ntfy = with_env(&vars, || Ntfy::from_env(Client::new(), &groups)).unwrap().unwrap();
+
+        ntfy.send(Some("home"), "Bus 7").await.unwrap();
+        ntfy.send(Some("gym"), "Bus 9").await.unwrap();
+        ntfy.send(None, "Tracking ends").await.unwrap();
+        let paths: Vec<String> = server.requests().into_iter().map(|request| request.path).collect();
+        assert_eq!(paths, ["/home-buses", "/buses", "/buses"]);
+    }
+
+    #[test]
+    fn ntfy_is_off_without_a_topic_and_checks_the_priority() {
+        assert!(ntfy(&[("NTFY_TOPIC", " ")]).unwrap().is_none());
+        let error = ntfy(&[("NTFY_TOPIC", "buses"), ("NTFY_PRIORITY", "loud")]).unwrap_err();
+        assert_eq!(error.to_string(), "NTFY_PRIORITY must be 1-5 or one of min, low, default, high, urgent, got 'loud'.");
+    }
+}
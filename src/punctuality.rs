@@ -0,0 +1,211 @@
+// `report punctuality`: compares recorded arrivals (HISTORY_DB) with the config file's [[timetable]]
+// and prints per-day deviation stats as text, CSV or JSON.
+
+use chrono::{DateTime, Datelike, Days, NaiveDate, NaiveTime, Utc};
+use clap::{Args, Subcommand, ValueEnum};
+use serde_json::json;
+use std::path::Path;
+
+use crate::config_file::ConfigFile;
+use crate::format::{format_duration, Style};
+use crate::heatmap::{self, HeatmapArgs};
+use crate::history::Arrival;
+use crate::learned_times::{self, LearnedTimesArgs};
+use crate::{open_history, stop_names};
+
+const ON_TIME_SECS: i64 = 180; // Within this of the scheduled time (either way) counts as on time
+const MAX_EARLY_SECS: i64 = 300; // Arrivals further from a scheduled time than these belong to another journey
+const MAX_LATE_SECS: i64 = 1800;
+
+#[derive(Debug, Subcommand)]
+pub enum Report {
+    /// Per-day deviation of recorded arrivals from the timetable
+    Punctuality(PunctualityArgs),
+    /// Counts of recorded positions (HISTORY_POSITIONS) per grid cell
+    Heatmap(HeatmapArgs),
+    /// Typical arrival times per service, stop and weekday, inferred from recorded arrivals
+    LearnedTimes(LearnedTimesArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct PunctualityArgs {
+    /// First day, e.g. 2024-05-01
+    #[arg(long)]
+    from: NaiveDate,
+    /// Last day (inclusive)
+    #[arg(long)]
+    to: NaiveDate,
+    #[arg(long)]
+    service: String,
+    #[arg(long)]
+    stop: String,
+    #[arg(long, value_enum, default_value_t = Format::Text)]
+    format: Format,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+enum Format {
+    Text,
+    Csv,
+    Json,
+}
+
+/// One day with scheduled journeys
+#[derive(Debug)]
+struct DayStats {
+    date: NaiveDate,
+    scheduled: usize,
+    deviations: Vec<(i64, DateTime<Utc>)>, // (seconds late, negative if early; arrival time) per journey seen
+}
+
+impl DayStats {
+    fn on_time_pct(&self) -> Option<f64> {
+        let on_time = self.deviations.iter().filter(|(secs, _)| secs.abs() <= ON_TIME_SECS).count();
+        (!self.deviations.is_empty()).then(|| on_time as f64 * 100.0 / self.deviations.len() as f64)
+    }
+
+    fn mean_late_secs(&self) -> Option<f64> {
+        let total: i64 = self.deviations.iter().map(|(secs, _)| secs).sum();
+        (!self.deviations.is_empty()).then(|| total as f64 / self.deviations.len() as f64)
+    }
+
+    fn worst(&self) -> Option<(i64, DateTime<Utc>)> {
+        self.deviations.iter().copied().max_by_key(|(secs, _)| secs.abs())
+    }
+}
+
+/// Builds the report and returns it ready to print
+pub fn run(config_path: Option<&Path>, report: &Report) -> Result<String, String> {
+    let args = match report {
+        Report::Punctuality(args) => args,
+        Report::Heatmap(args) => return heatmap::run(args),
+        Report::LearnedTimes(args) => return learned_times::run(args),
+    };
+    if args.to < args.from {
+        return Err("--to is before --from.".to_string());
+    }
+    let file = ConfigFile::load_opt(config_path)?;
+    let style = Style::from_env()?;
+    let history = open_history()?.ok_or_else(|| "Set HISTORY_DB to the history database to report on.".to_string())?;
+
+    let timetable: Vec<_> = file
+        .timetable
+        .iter()
+        .filter(|entry| entry.service.eq_ignore_ascii_case(&args.service) && stop_names::same_stop(&entry.stop, &args.stop))
+        .collect();
+    if timetable.is_empty() {
+        return Err(format!("The config file has no [[timetable]] for {} at {}.", args.service, args.stop));
+    }
+
+    let zone = style.zone;
+    let local_midnight = |date: NaiveDate| zone.instant_at(date.and_time(NaiveTime::MIN));
+    let start = local_midnight(args.from).ok_or("--from has no local midnight.")?;
+    let end = args.to.checked_add_days(Days::new(1)).and_then(local_midnight).ok_or("--to is out of range.")?;
+    let arrivals: Vec<Arrival> = history
+        .service_arrivals(&args.service, start - chrono::Duration::seconds(MAX_EARLY_SECS), end + chrono::Duration::seconds(MAX_LATE_SECS))
+        .map_err(|e| format!("Could not read history: {}", e))?
+        .into_iter()
+        .filter(|arrival| stop_names::same_stop(&arrival.stop, &args.stop))
+        .collect();
+
+    // Each scheduled time takes the earliest unclaimed arrival close enough to it
+    let mut claimed = vec![false; arrivals.len()];
+    let mut days = Vec::new();
+    for date in args.from.iter_days().take_while(|date| *date <= args.to) {
+        let mut times: Vec<NaiveTime> = timetable.iter().filter(|entry| entry.runs_on(date.weekday())).flat_map(|entry| entry.times()).collect();
+        times.sort();
+        times.dedup();
+        if times.is_empty() {
+            continue; // No service that day
+        }
+
+        let mut day = DayStats { date, scheduled: times.len(), deviations: Vec::new() };
+        for scheduled in times.iter().filter_map(|time| zone.instant_at(date.and_time(*time))) {
+            let found = arrivals.iter().enumerate().find(|(i, arrival)| {
+                let late = (arrival.arrived_at - scheduled).num_seconds();
+                !claimed[*i] && (-MAX_EARLY_SECS..=MAX_LATE_SECS).contains(&late)
+            });
+            if let Some((i, arrival)) = found {
+                claimed[i] = true;
+                day.deviations.push(((arrival.arrived_at - scheduled).num_seconds(), arrival.arrived_at));
+            }
+        }
+        days.push(day);
+    }
+
+    Ok(match args.format {
+        Format::Text => render_text(args, &days, &style),
+        Format::Csv => render_csv(&days),
+        Format::Json => render_json(&days),
+    })
+}
+
+fn render_text(args: &PunctualityArgs, days: &[DayStats], style: &Style) -> String {
+    let mut out = format!(
+        "Punctuality of {} at {}, {} to {} (within {} counts as on time)",
+        args.service,
+        args.stop,
+        args.from,
+        args.to,
+        format_duration(ON_TIME_SECS)
+    );
+    if days.is_empty() {
+        out.push_str("\nNo scheduled journeys in that range.");
+    }
+    let lateness = |secs: i64| match secs {
+        0 => "on time".to_string(),
+        secs if secs > 0 => format!("{} late", format_duration(secs)),
+        secs => format!("{} early", format_duration(-secs)),
+    };
+    for day in days {
+        out.push_str(&format!("\n{} {}: {}/{} seen", day.date, day.date.weekday(), day.deviations.len(), day.scheduled));
+        match (day.on_time_pct(), day.mean_late_secs(), day.worst()) {
+            (Some(pct), Some(mean), Some((worst, at))) => out.push_str(&format!(
+                ", {:.0}% on time, mean {}, worst {} at {}",
+                pct,
+                lateness(mean.round() as i64),
+                lateness(worst),
+                style.time(at)
+            )),
+            _ => out.push_str(", no arrivals recorded"),
+        }
+    }
+    out
+}
+
+fn render_csv(days: &[DayStats]) -> String {
+    let mut out = "date,scheduled,seen,on_time_pct,mean_late_secs,worst_late_secs,worst_at".to_string();
+    let show = |value: Option<String>| value.unwrap_or_default();
+    for day in days {
+        let worst = day.worst();
+        out.push_str(&format!(
+            "\n{},{},{},{},{},{},{}",
+            day.date,
+            day.scheduled,
+            day.deviations.len(),
+            show(day.on_time_pct().map(|pct| format!("{:.1}", pct))),
+            show(day.mean_late_secs().map(|mean| format!("{:.0}", mean))),
+            show(worst.map(|(secs, _)| secs.to_string())),
+            show(worst.map(|(_, at)| at.to_rfc3339()))
+        ));
+    }
+    out
+}
+
+fn render_json(days: &[DayStats]) -> String {
+    let rows: Vec<_> = days
+        .iter()
+        .map(|day| {
+            json!({
+                "date": day.date.to_string(),
+                "scheduled": day.scheduled,
+                "seen": day.deviations.len(),
+                "on_time_pct": day.on_time_pct(),
+                "mean_late_secs": day.mean_late_secs(),
+                "worst_late_secs": day.worst().map(|(secs, _)| secs),
+                "worst_at": day.worst().map(|(_, at)| at.to_rfc3339()),
+            })
+        })
+        .collect();
+    format!("{:#}", json!(rows))
+}
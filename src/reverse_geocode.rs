@@ -0,0 +1,91 @@
+// Optional road names in arrival alerts ("... is near **Main Street**! (currently on Station Road)"),
+// looked up with Nominatim's reverse geocoding. Off unless REVERSE_GEOCODE is set, since the public
+// server's usage policy allows at most one request a second from an identified client: requests
+// carry our User-Agent, are spaced a second apart across the whole process, and answers are cached
+// on the position rounded to about 10 m. A slow or failed lookup just leaves the road out.
+
+use reqwest::Client;
+use serde_json::Value;
+use std::collections::VecDeque;
+use tokio::sync::Mutex;
+use tokio::time::{self, Duration, Instant};
+use tracing::debug;
+
+use crate::{env_flag, env_or_file, env_parse};
+
+const DEFAULT_URL: &str = "https://nominatim.openstreetmap.org/reverse";
+const MIN_GAP: Duration = Duration::from_secs(1); // Between requests, per Nominatim's usage policy
+const CACHE_SIZE: usize = 128;
+const ROUNDING: f64 = 1e4; // Decimal places kept in cache keys: 4, about 10 m
+
+/// When the last request went out, shared by every profile's geocoder
+static LAST_REQUEST: Mutex<Option<Instant>> = Mutex::const_new(None);
+
+#[derive(Debug)]
+pub struct ReverseGeocoder {
+    client: Client,
+    url: String,
+    timeout: Duration,
+    cache: VecDeque<((i64, i64), Option<String>)>, // Least recently used first; None: no road there
+}
+
+impl ReverseGeocoder {
+    /// Returns None unless REVERSE_GEOCODE is set
+    pub fn from_env(client: Client) -> Result<Option<ReverseGeocoder>, String> {
+        if !env_flag("REVERSE_GEOCODE", false)? {
+            return Ok(None);
+        }
+        let url = env_or_file("REVERSE_GEOCODE_URL")?.map(|url| url.trim().to_string()).filter(|url| !url.is_empty());
+        Ok(Some(ReverseGeocoder {
+            client,
+            url: url.unwrap_or_else(|| DEFAULT_URL.to_string()),
+            timeout: Duration::from_millis(env_parse("REVERSE_GEOCODE_TIMEOUT_MS", 2000)?),
+            cache: VecDeque::new(),
+        }))
+    }
+
+    /// The road at a position, if the lookup answers in time and finds one
+    pub async fn road(&mut self, lat: f64, lng: f64) -> Option<String> {
+        let key = ((lat * ROUNDING).round() as i64, (lng * ROUNDING).round() as i64);
+        if let Some(index) = self.cache.iter().position(|(cached, _)| *cached == key) {
+            let entry = self.cache.remove(index)?;
+            let road = entry.1.clone();
+            self.cache.push_back(entry);
+            return road;
+        }
+
+        let road = match self.lookup(key.0 as f64 / ROUNDING, key.1 as f64 / ROUNDING).await {
+            Ok(road) => road,
+            Err(e) => {
+                debug!("Reverse geocoding {}, {} failed: {}", lat, lng, e);
+                return None; // Not cached, so a later alert here tries again
+            }
+        };
+        if self.cache.len() >= CACHE_SIZE {
+            self.cache.pop_front();
+        }
+        self.cache.push_back((key, road.clone()));
+        road
+    }
+
+    async fn lookup(&self, lat: f64, lng: f64) -> Result<Option<String>, reqwest::Error> {
+        let mut last = LAST_REQUEST.lock().await;
+        if let Some(at) = *last {
+            time::sleep_until(at + MIN_GAP).await;
+        }
+        *last = Some(Instant::now());
+        drop(last);
+
+        let reply: Value = self
+            .client
+            .get(&self.url)
+            .query(&[("format", "jsonv2"), ("zoom", "17"), ("lat", &lat.to_string()), ("lon", &lng.to_string())])
+            .timeout(self.timeout)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(reply["address"]["road"].as_str().map(str::trim).filter(|road| !road.is_empty()).map(str::to_string))
+    }
+}
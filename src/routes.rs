@@ -0,0 +1,435 @@
+// Service routes from the config file's [[routes]], so distances to a stop can follow the road
+// instead of a straight line, which badly underestimates how far a bus still has to go around a
+// one-way system. Each route is a line of points, given one of three ways:
+//
+//     [[routes]]
+//     service = "7"
+//     polyline = "_p~iF~ps|U_ulLnnqC_mqNvxq`@"   # encoded polyline (precision 5)
+//
+//     [[routes]]
+//     service = "X24"
+//     points = [[53.40, -2.98], [53.41, -2.97]]  # [lat, lng] pairs
+//
+//     [[routes]]
+//     service = "9"
+//     geojson = "routes/9.geojson"               # a LineString, or the first one in a FeatureCollection
+//
+// A service can have several routes (one per direction, say). The bus and the stop are projected
+// onto the line, and the distance between the two projections is the distance along the route.
+// A line that passes a stop more than once (a loop) counts the next pass ahead of the bus.
+
+use serde::Deserialize;
+use serde_json::Value;
+use std::fs;
+
+use crate::{bearing, haversine_distance};
+
+const EARTH_RADIUS: f64 = 6371e3; // meters, as in haversine_distance
+const HEADING_TOLERANCE_DEG: f64 = 90.0; // How far a bus's heading may be off the line's direction and still run along it
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteEntry {
+    pub service: String,
+    pub polyline: Option<String>,
+    #[serde(default)]
+    pub points: Vec<[f64; 2]>,
+    pub geojson: Option<String>, // File path
+}
+
+/// Where a stop lies along a route, as seen from a bus
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Along {
+    Ahead(f64), // Meters still to travel along the route
+    Behind,     // The bus has passed it and the route doesn't come back round
+    OffRoute,   // No route for the service, or the bus or stop is too far from it to say
+}
+
+#[derive(Debug, Clone)]
+struct Route {
+    service: String,
+    points: Vec<(f64, f64)>, // (lat, lng)
+    cumulative: Vec<f64>,    // Meters along the route at each point
+    closed: bool,            // Ends where it starts, so travel wraps round
+}
+
+/// The closest point of one pass of the route near a position
+#[derive(Debug, Clone, Copy)]
+struct Pass {
+    along: f64,  // Meters from the start of the route
+    offset: f64, // Meters from the position to the line
+    segment: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Routes {
+    routes: Vec<Route>,
+    max_offset: f64, // Meters a bus or stop may be from the line and still be on it (ROUTE_MAX_OFFSET_M)
+}
+
+impl Routes {
+    pub fn load(entries: &[RouteEntry], max_offset: f64) -> Result<Routes, String> {
+        let routes = entries.iter().map(|entry| Route::load(entry, max_offset)).collect::<Result<Vec<_>, _>>()?;
+        Ok(Routes { routes, max_offset })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    /// Whether a bus heading `heading` at `bus` runs the way the service's route is drawn (true) or
+    /// against it (false). None without a heading, when it's off the route, or when the service
+    /// has more than one route, since which end each is drawn from can't be told apart.
+    pub fn runs_forward(&self, service: &str, bus: (f64, f64), heading: Option<f64>) -> Option<bool> {
+        let heading = heading?;
+        let mut routes = self.routes.iter().filter(|route| route.service.eq_ignore_ascii_case(service.trim()));
+        let (Some(route), None) = (routes.next(), routes.next()) else {
+            return None;
+        };
+        let forward = !route.passes(bus, Some(heading), self.max_offset).is_empty();
+        let backward = !route.passes(bus, Some((heading + 180.0).rem_euclid(360.0)), self.max_offset).is_empty();
+        match (forward, backward) {
+            (true, false) => Some(true),
+            (false, true) => Some(false),
+            _ => None, // Off the route, or on a stretch it runs both ways
+        }
+    }
+
+    /// Where `stop` lies along the service's route(s) from a bus at `bus` heading `heading`.
+    /// With several routes, the nearest one that places both wins; ahead beats behind.
+    pub fn along(&self, service: &str, bus: (f64, f64), heading: Option<f64>, stop: (f64, f64)) -> Along {
+        let results = self
+            .routes
+            .iter()
+            .filter(|route| route.service.eq_ignore_ascii_case(service.trim()))
+            .map(|route| route.along(bus, heading, stop, self.max_offset));
+        let mut best = Along::OffRoute;
+        for result in results {
+            best = match (best, result) {
+                (Along::Ahead(current), Along::Ahead(distance)) => Along::Ahead(current.min(distance)),
+                (Along::Ahead(_), _) => best,
+                (_, Along::Ahead(_)) | (Along::OffRoute, Along::Behind) => result,
+                _ => best,
+            };
+        }
+        best
+    }
+}
+
+impl Route {
+    fn load(entry: &RouteEntry, max_offset: f64) -> Result<Route, String> {
+        let describe = || format!("Route for service {}", entry.service);
+        let points = match (&entry.polyline, entry.points.is_empty(), &entry.geojson) {
+            (Some(polyline), true, None) => {
+                decode_polyline(polyline.trim()).ok_or_else(|| format!("{} has an invalid encoded polyline.", describe()))?
+            }
+            (None, false, None) => entry.points.iter().map(|[lat, lng]| (*lat, *lng)).collect(),
+            (None, true, Some(path)) => {
+                let contents = fs::read_to_string(path).map_err(|e| format!("{}: could not read {}: {}", describe(), path, e))?;
+                let geojson: Value = serde_json::from_str(&contents).map_err(|e| format!("{}: {} isn't valid JSON: {}", describe(), path, e))?;
+                geojson_line(&geojson).ok_or_else(|| format!("{}: {} has no LineString.", describe(), path))?
+            }
+            _ => return Err(format!("{} needs exactly one of polyline, points or geojson.", describe())),
+        };
+        if points.len() < 2 {
+            return Err(format!("{} needs at least two points.", describe()));
+        }
+        if let Some((lat, lng)) = points.iter().find(|(lat, lng)| !((-90.0..=90.0).contains(lat) && (-180.0..=180.0).contains(lng))) {
+            return Err(format!("{} has an invalid point ({}, {}).", describe(), lat, lng));
+        }
+
+        let mut cumulative = vec![0.0];
+        for pair in points.windows(2) {
+            let (x, y) = local_xy(pair[0], pair[1]);
+            cumulative.push(cumulative[cumulative.len() - 1] + x.hypot(y));
+        }
+        let (first, last) = (points[0], points[points.len() - 1]);
+        let closed = haversine_distance(first.0, first.1, last.0, last.1) <= max_offset;
+        Ok(Route { service: entry.service.trim().to_string(), points, cumulative, closed })
+    }
+
+    fn length(&self) -> f64 {
+        self.cumulative[self.cumulative.len() - 1]
+    }
+
+    fn along(&self, bus: (f64, f64), heading: Option<f64>, stop: (f64, f64), max_offset: f64) -> Along {
+        // Where the line doubles back on itself, the pass running the way the bus is heading is the one it's on
+        let bus_pass = self.passes(bus, heading, max_offset).into_iter().min_by(|a, b| a.offset.total_cmp(&b.offset));
+        let stop_passes = self.passes(stop, None, max_offset);
+        let (Some(bus_pass), false) = (bus_pass, stop_passes.is_empty()) else {
+            return Along::OffRoute;
+        };
+
+        let ahead = stop_passes.iter().map(|pass| pass.along - bus_pass.along).filter(|distance| *distance >= 0.0).min_by(f64::total_cmp);
+        match ahead {
+            Some(distance) => Along::Ahead(distance),
+            // Round the loop to the first pass of the stop
+            None if self.closed => {
+                let first = stop_passes.iter().map(|pass| pass.along).min_by(f64::total_cmp).unwrap_or_default();
+                Along::Ahead(self.length() - bus_pass.along + first)
+            }
+            None => Along::Behind,
+        }
+    }
+
+    /// Every separate stretch of the line that comes within `max_offset` of `position` (running
+    /// within HEADING_TOLERANCE_DEG of `heading`, if given), as its closest point. Consecutive
+    /// segments whose closest points are about the same spot (around a bend) count as one stretch;
+    /// where the line turns back on itself they don't, so both directions are kept.
+    fn passes(&self, position: (f64, f64), heading: Option<f64>, max_offset: f64) -> Vec<Pass> {
+        let mut passes: Vec<Pass> = Vec::new();
+        for segment in 0..self.points.len() - 1 {
+            let pass = self.project(segment, position);
+            if pass.offset > max_offset || heading.is_some_and(|heading| !self.runs_along(segment, heading)) {
+                continue;
+            }
+            match passes.last_mut() {
+                Some(current) if current.segment + 1 == segment && pass.along - current.along <= 2.0 * max_offset => {
+                    if pass.offset < current.offset {
+                        *current = pass;
+                    }
+                }
+                _ => passes.push(pass),
+            }
+        }
+        passes
+    }
+
+    /// The closest point of one segment to `position`
+    fn project(&self, segment: usize, position: (f64, f64)) -> Pass {
+        let start = self.points[segment];
+        let (dx, dy) = local_xy(start, self.points[segment + 1]);
+        let (px, py) = local_xy(start, position);
+        let length_sq = dx * dx + dy * dy;
+        let t = if length_sq > 0.0 { ((px * dx + py * dy) / length_sq).clamp(0.0, 1.0) } else { 0.0 };
+        Pass {
+            along: self.cumulative[segment] + t * length_sq.sqrt(),
+            offset: (px - t * dx).hypot(py - t * dy),
+            segment,
+        }
+    }
+
+    fn runs_along(&self, segment: usize, heading: f64) -> bool {
+        let (start, end) = (self.points[segment], self.points[segment + 1]);
+        let off_course = (bearing(start.0, start.1, end.0, end.1) - heading).rem_euclid(360.0);
+        off_course.min(360.0 - off_course) <= HEADING_TOLERANCE_DEG
+    }
+}
+
+/// Meters east and north of `origin` to `point`, on a flat projection that holds over the few
+/// hundred meters of a segment
+fn local_xy(origin: (f64, f64), point: (f64, f64)) -> (f64, f64) {
+    let x = (point.1 - origin.1).to_radians() * origin.0.to_radians().cos() * EARTH_RADIUS;
+    let y = (point.0 - origin.0).to_radians() * EARTH_RADIUS;
+    (x, y)
+}
+
+/// Decodes an encoded polyline (precision 5) into (lat, lng) points
+fn decode_polyline(encoded: &str) -> Option<Vec<(f64, f64)>> {
+    let mut bytes = encoded.bytes();
+    let mut next = || -> Option<Option<i64>> {
+        let mut result: i64 = 0;
+        let mut shift = 0;
+        loop {
+            let Some(byte) = bytes.next() else {
+                // Running out between values is the normal end; mid-value it's a broken string
+                return if shift == 0 { Some(None) } else { None };
+            };
+            let chunk = i64::from(byte).checked_sub(63).filter(|chunk| (0..64).contains(chunk))?;
+            result |= (chunk & 0x1f) << shift;
+            shift += 5;
+            if chunk < 0x20 {
+                break;
+            }
+            if shift > 60 {
+                return None;
+            }
+        }
+        Some(Some(if result & 1 == 1 { !(result >> 1) } else { result >> 1 }))
+    };
+
+    let (mut lat, mut lng) = (0i64, 0i64);
+    let mut points = Vec::new();
+    while let Some(delta_lat) = next()? {
+        lat += delta_lat;
+        lng += next()??;
+        points.push((lat as f64 / 1e5, lng as f64 / 1e5));
+    }
+    Some(points)
+}
+
+/// The (lat, lng) points of a LineString geometry, Feature, or the first LineString in a FeatureCollection
+fn geojson_line(geojson: &Value) -> Option<Vec<(f64, f64)>> {
+    match geojson["type"].as_str()? {
+        "LineString" => geojson["coordinates"]
+            .as_array()?
+            .iter()
+            .map(|position| Some((position[1].as_f64()?, position[0].as_f64()?)))
+            .collect(),
+        "Feature" => geojson_line(&geojson["geometry"]),
+        "FeatureCollection" => geojson["features"].as_array()?.iter().find_map(geojson_line),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORIGIN: (f64, f64) = (53.0, -1.5);
+
+    /// The point `east` and `north` meters from ORIGIN, on local_xy's projection
+    fn at(east: f64, north: f64) -> (f64, f64) {
+        let lat = ORIGIN.0 + (north / EARTH_RADIUS).to_degrees();
+        let lng = ORIGIN.1 + (east / (EARTH_RADIUS * ORIGIN.0.to_radians().cos())).to_degrees();
+        (lat, lng)
+    }
+
+    /// Routes for service 7 through `lines` of (east, north) points, 30 m from the line still counting as on it
+    fn routes(lines: &[&[(f64, f64)]]) -> Routes {
+        let entries: Vec<RouteEntry> = lines
+            .iter()
+            .map(|line| RouteEntry {
+                service: "7".to_string(),
+                polyline: None,
+                points: line.iter().map(|&(east, north)| at(east, north)).map(|(lat, lng)| [lat, lng]).collect(),
+                geojson: None,
+            })
+            .collect();
+        Routes::load(&entries, 30.0).unwrap()
+    }
+
+    #[track_caller]
+    fn assert_ahead(along: Along, meters: f64) {
+        match along {
+            Along::Ahead(distance) => assert!((distance - meters).abs() < 2.0, "{} m ahead, expected {}", distance, meters),
+            other => panic!("{:?}, expected {} m ahead", other, meters),
+        }
+    }
+
+    const NORTH: Option<f64> = Some(0.0);
+    const EAST: Option<f64> = Some(90.0);
+    const SOUTH: Option<f64> = Some(180.0);
+    const WEST: Option<f64> = Some(270.0);
+
+    #[test]
+    fn distance_follows_a_straight_route_and_stops_behind_are_behind() {
+        let routes = routes(&[&[(0.0, 0.0), (0.0, 1000.0)]]);
+        assert_ahead(routes.along("7", at(10.0, 100.0), NORTH, at(0.0, 600.0)), 500.0);
+        assert_ahead(routes.along(" 7 ", at(0.0, 100.0), None, at(-20.0, 600.0)), 500.0);
+        assert_eq!(routes.along("7", at(0.0, 600.0), NORTH, at(0.0, 100.0)), Along::Behind);
+
+        // Too far off the line, the wrong way along it, or another service
+        assert_eq!(routes.along("7", at(100.0, 100.0), NORTH, at(0.0, 600.0)), Along::OffRoute);
+        assert_eq!(routes.along("7", at(0.0, 100.0), NORTH, at(100.0, 600.0)), Along::OffRoute);
+        assert_eq!(routes.along("7", at(0.0, 100.0), SOUTH, at(0.0, 600.0)), Along::OffRoute);
+        assert_eq!(routes.along("9", at(0.0, 100.0), NORTH, at(0.0, 600.0)), Along::OffRoute);
+    }
+
+    #[test]
+    fn the_distance_goes_round_the_bends_not_across_them() {
+        // Up one side of a block and down the other: the stop is 100 m across, 1100 m round
+        let routes = routes(&[&[(0.0, 0.0), (0.0, 500.0), (100.0, 500.0), (100.0, 0.0)]]);
+        assert_ahead(routes.along("7", at(0.0, 0.0), NORTH, at(100.0, 0.0)), 1100.0);
+        assert_ahead(routes.along("7", at(50.0, 500.0), EAST, at(100.0, 400.0)), 150.0);
+    }
+
+    #[test]
+    fn a_line_doubling_back_takes_the_pass_the_bus_is_heading_along() {
+        // Out along a street and back down it: the stop at 500 m is passed at 500 and 1500 m along
+        let routes = routes(&[&[(0.0, 0.0), (0.0, 1000.0), (0.0, 200.0)]]);
+        let stop = at(0.0, 500.0);
+        assert_ahead(routes.along("7", at(0.0, 300.0), NORTH, stop), 200.0);
+        assert_ahead(routes.along("7", at(0.0, 700.0), SOUTH, stop), 200.0);
+        assert_ahead(routes.along("7", at(0.0, 700.0), NORTH, stop), 800.0); // To the end and back
+        assert_eq!(routes.along("7", at(0.0, 300.0), SOUTH, stop), Along::Behind);
+    }
+
+    #[test]
+    fn a_stop_passed_twice_counts_the_next_pass_ahead() {
+        // North past the stop, round three sides of a square and west through it again
+        let routes = routes(&[&[(0.0, 0.0), (0.0, 1000.0), (500.0, 1000.0), (500.0, 500.0), (-500.0, 500.0)]]);
+        let stop = at(0.0, 500.0); // 500 and 2500 m along
+        assert_ahead(routes.along("7", at(0.0, 200.0), NORTH, stop), 300.0);
+        assert_ahead(routes.along("7", at(0.0, 800.0), NORTH, stop), 1700.0);
+        assert_ahead(routes.along("7", at(500.0, 700.0), SOUTH, stop), 700.0);
+        // Westbound on the second pass, the first pass is behind but the second still ahead
+        assert_ahead(routes.along("7", at(200.0, 500.0), WEST, stop), 200.0);
+        assert_eq!(routes.along("7", at(-300.0, 500.0), WEST, stop), Along::Behind);
+    }
+
+    #[test]
+    fn a_closed_loop_wraps_round_to_the_stop() {
+        let routes = routes(&[&[(0.0, 0.0), (0.0, 1000.0), (1000.0, 1000.0), (1000.0, 0.0), (0.0, 0.0)]]);
+        let stop = at(0.0, 200.0);
+        assert_ahead(routes.along("7", at(0.0, 100.0), NORTH, stop), 100.0);
+        assert_ahead(routes.along("7", at(500.0, 0.0), WEST, stop), 700.0);
+        assert_ahead(routes.along("7", at(0.0, 300.0), NORTH, stop), 3900.0); // All the way round
+    }
+
+    #[test]
+    fn with_several_routes_the_nearest_ahead_wins() {
+        let north = [(0.0, 0.0), (0.0, 1000.0)];
+        let branch = [(0.0, 0.0), (0.0, 300.0), (300.0, 300.0), (300.0, 600.0), (0.0, 600.0)];
+        let stop = at(0.0, 600.0);
+        assert_ahead(routes(&[&branch, &north]).along("7", at(0.0, 100.0), NORTH, stop), 500.0);
+        // A route that places the stop behind doesn't hide one that has it ahead
+        let south = [(0.0, 1000.0), (0.0, 0.0)];
+        assert_ahead(routes(&[&north, &south]).along("7", at(0.0, 700.0), None, stop), 100.0);
+        assert_eq!(routes(&[&north]).along("7", at(0.0, 700.0), None, stop), Along::Behind);
+    }
+
+    #[test]
+    fn runs_forward_needs_a_heading_and_a_single_route() {
+        let line = [(0.0, 0.0), (0.0, 1000.0)];
+        let single = routes(&[&line]);
+        assert_eq!(single.runs_forward("7", at(0.0, 500.0), NORTH), Some(true));
+        assert_eq!(single.runs_forward("7", at(0.0, 500.0), Some(200.0)), Some(false));
+        assert_eq!(single.runs_forward("7", at(0.0, 500.0), None), None);
+        assert_eq!(single.runs_forward("7", at(200.0, 500.0), NORTH), None);
+        assert_eq!(routes(&[&line, &line]).runs_forward("7", at(0.0, 500.0), NORTH), None);
+        // Out and back along one street runs both ways
+        assert_eq!(routes(&[&[(0.0, 0.0), (0.0, 1000.0), (0.0, 0.0)]]).runs_forward("7", at(0.0, 500.0), NORTH), None);
+    }
+
+    #[test]
+    fn polylines_decode_and_broken_ones_are_refused() {
+        assert_eq!(decode_polyline("_p~iF~ps|U_ulLnnqC_mqNvxq`@"), Some(vec![(38.5, -120.2), (40.7, -120.95), (43.252, -126.453)]));
+        assert_eq!(decode_polyline(""), Some(vec![]));
+        assert_eq!(decode_polyline("_p~iF~ps|U_ulL"), None); // A latitude with no longitude
+        assert_eq!(decode_polyline("_p~iF~ps|"), None); // Cut off mid-value
+        assert_eq!(decode_polyline("_p~iF ps|U"), None);
+    }
+
+    #[test]
+    fn geojson_lines_come_from_a_geometry_feature_or_collection() {
+        let line = serde_json::json!({ "type": "LineString", "coordinates": [[-1.5, 53.0], [-1.49, 53.01]] });
+        let expected = Some(vec![(53.0, -1.5), (53.01, -1.49)]);
+        assert_eq!(geojson_line(&line), expected);
+        let feature = serde_json::json!({ "type": "Feature", "geometry": line, "properties": {} });
+        assert_eq!(geojson_line(&feature), expected);
+        let point = serde_json::json!({ "type": "Feature", "geometry": { "type": "Point", "coordinates": [-1.5, 53.0] } });
+        assert_eq!(geojson_line(&serde_json::json!({ "type": "FeatureCollection", "features": [point, feature] })), expected);
+        assert_eq!(geojson_line(&point), None);
+    }
+
+    #[test]
+    fn each_route_needs_one_source_and_two_valid_points() {
+        let entry = |polyline: Option<&str>, points: Vec<[f64; 2]>| RouteEntry {
+            service: "7".to_string(),
+            polyline: polyline.map(str::to_string),
+            points,
+            geojson: None,
+        };
+        let load = |entry: RouteEntry| Routes::load(&[entry], 30.0).map(|routes| routes.routes.len());
+        assert_eq!(load(entry(Some("_p~iF~ps|U_ulLnnqC"), vec![])), Ok(1));
+        assert_eq!(
+            load(entry(Some("_p~iF~ps|U_ulLnnqC"), vec![[53.0, -1.5], [53.1, -1.5]])),
+            Err("Route for service 7 needs exactly one of polyline, points or geojson.".to_string())
+        );
+        assert_eq!(load(entry(None, vec![])), Err("Route for service 7 needs exactly one of polyline, points or geojson.".to_string()));
+        assert_eq!(load(entry(None, vec![[53.0, -1.5]])), Err("Route for service 7 needs at least two points.".to_string()));
+        assert_eq!(load(entry(None, vec![[53.0, -1.5], [-1.5, 253.0]])), Err("Route for service 7 has an invalid point (-1.5, 253).".to_string()));
+        assert_eq!(load(entry(Some("_p~iF~ps|"), vec![])), Err("Route for service 7 has an invalid encoded polyline.".to_string()));
+    }
+}
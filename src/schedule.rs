@@ -0,0 +1,163 @@
+// When polling is on, from the config file's [schedule]: cron expressions listing the minutes to
+// poll in (local time, TIMEZONE) and dates to skip entirely.
+//
+//     [schedule]
+//     cron = ["30-59 7 * * Mon-Fri", "0-30 8 * * Mon-Fri"]  # weekdays 07:30-08:30
+//     except = ["2024-05-31"]                               # e.g. the last Friday of the month
+
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, Timelike, Utc};
+use cron::Schedule;
+use serde::Deserialize;
+use std::str::FromStr;
+
+const MAX_MATCHES_SCANNED: usize = 100_000; // Per expression, when looking for the next activations
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduleEntry {
+    #[serde(default)]
+    pub cron: Vec<String>, // Standard 5-field (minute hour day month weekday) or 6/7-field with seconds
+    #[serde(default)]
+    pub except: Vec<String>, // YYYY-MM-DD
+}
+
+#[derive(Debug, Clone)]
+pub struct PollSchedule {
+    expressions: Vec<Schedule>,
+    except: Vec<NaiveDate>,
+}
+
+impl PollSchedule {
+    /// None when the entry has no expressions, i.e. polling is always on
+    pub fn parse(entry: &ScheduleEntry) -> Result<Option<PollSchedule>, String> {
+        if entry.cron.is_empty() {
+            if !entry.except.is_empty() {
+                return Err("[schedule] has except dates but no cron expressions.".to_string());
+            }
+            return Ok(None);
+        }
+        let expressions = entry
+            .cron
+            .iter()
+            .map(|expression| {
+                let trimmed = expression.trim();
+                // The cron crate wants a seconds field; polling is decided per minute, so standard
+                // five-field expressions match at the start of each minute
+                let full = if trimmed.split_whitespace().count() == 5 { format!("0 {}", trimmed) } else { trimmed.to_string() };
+                Schedule::from_str(&full).map_err(|e| {
+                    // The parser's message repeats the expression with a caret under the problem; the reason is on the last line
+                    let reason = e.to_string().lines().last().unwrap_or_default().to_string();
+                    format!("[schedule] has an invalid cron expression '{}': {}", expression, reason)
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let except = entry
+            .except
+            .iter()
+            .map(|date| {
+                NaiveDate::parse_from_str(date.trim(), "%Y-%m-%d").map_err(|_| format!("[schedule] has an invalid except date '{}'; expected YYYY-MM-DD.", date))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Some(PollSchedule { expressions, except }))
+    }
+
+    /// Whether polling is on during the minute containing `local`
+    pub fn is_active(&self, local: NaiveDateTime) -> bool {
+        let minute = start_of_minute(local);
+        !self.except.contains(&minute.date()) && self.expressions.iter().any(|expression| expression.includes(minute.and_utc()))
+    }
+
+    /// The next `count` local times after `local` at which polling switches on
+    pub fn next_activations(&self, local: NaiveDateTime, count: usize) -> Vec<NaiveDateTime> {
+        // Expressions are evaluated on wall-clock time, which is treated as UTC only to drive the cron crate
+        let after: DateTime<Utc> = local.and_utc();
+        let mut activations: Vec<NaiveDateTime> = self
+            .expressions
+            .iter()
+            .flat_map(|expression| {
+                expression
+                    .after(&after)
+                    .take(MAX_MATCHES_SCANNED)
+                    .map(|at| start_of_minute(at.naive_utc()))
+                    .filter(|at| self.is_active(*at) && !self.is_active(*at - Duration::minutes(1)))
+                    .take(count)
+            })
+            .collect();
+        activations.sort();
+        activations.dedup();
+        activations.truncate(count);
+        activations
+    }
+}
+
+fn start_of_minute(local: NaiveDateTime) -> NaiveDateTime {
+    local.with_second(0).and_then(|t| t.with_nanosecond(0)).unwrap_or(local)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Weekdays 07:30-08:30, as in the example above, skipping Friday 2024-03-08
+    fn schedule() -> PollSchedule {
+        let entry = ScheduleEntry {
+            cron: vec!["30-59 7 * * Mon-Fri".to_string(), " 0-30 8 * * Mon-Fri ".to_string()],
+            except: vec!["2024-03-08".to_string()],
+        };
+        PollSchedule::parse(&entry).unwrap().unwrap()
+    }
+
+    fn local(text: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    #[test]
+    fn polling_is_on_in_the_listed_minutes_except_on_excepted_dates() {
+        let schedule = schedule();
+        // 2024-03-04 is a Monday
+        for (at, active) in [
+            ("2024-03-04 07:29:59", false),
+            ("2024-03-04 07:30:00", true),
+            ("2024-03-04 07:59:45", true),
+            ("2024-03-04 08:00:10", true),
+            ("2024-03-04 08:30:59", true),
+            ("2024-03-04 08:31:00", false),
+            ("2024-03-08 07:45:00", false), // Excepted
+            ("2024-03-09 07:45:00", false), // Saturday
+            ("2024-03-11 07:45:00", true),
+        ] {
+            assert_eq!(schedule.is_active(local(at)), active, "{}", at);
+        }
+    }
+
+    #[test]
+    fn activations_are_where_polling_switches_on_not_each_matching_minute() {
+        let schedule = schedule();
+        // The 08:00 expression carries on from the 07:30 one, so each weekday starts once, and the
+        // excepted Friday and the weekend are skipped
+        assert_eq!(
+            schedule.next_activations(local("2024-03-06 07:40:00"), 3),
+            [local("2024-03-07 07:30:00"), local("2024-03-11 07:30:00"), local("2024-03-12 07:30:00")]
+        );
+        assert_eq!(schedule.next_activations(local("2024-03-04 07:00:00"), 1), [local("2024-03-04 07:30:00")]);
+        assert!(schedule.next_activations(local("2024-03-04 07:00:00"), 0).is_empty());
+    }
+
+    #[test]
+    fn schedules_parse_or_say_what_is_wrong() {
+        let parse = |cron: &[&str], except: &[&str]| {
+            PollSchedule::parse(&ScheduleEntry {
+                cron: cron.iter().map(|s| s.to_string()).collect(),
+                except: except.iter().map(|s| s.to_string()).collect(),
+            })
+        };
+        assert!(parse(&[], &[]).unwrap().is_none());
+        // With a seconds field the expression is taken as it is
+        assert!(parse(&["0 */5 * * * *"], &[]).unwrap().unwrap().is_active(local("2024-03-04 09:05:30")));
+        assert_eq!(parse(&[], &["2024-03-08"]).unwrap_err(), "[schedule] has except dates but no cron expressions.");
+        assert_eq!(parse(&["* * * * *"], &["8 March"]).unwrap_err(), "[schedule] has an invalid except date '8 March'; expected YYYY-MM-DD.");
+        let error = parse(&["61 7 * * *"], &[]).unwrap_err();
+        assert!(error.starts_with("[schedule] has an invalid cron expression '61 7 * * *': "), "{}", error);
+        assert!(!error.contains('\n'), "{}", error);
+    }
+}
@@ -0,0 +1,34 @@
+// Display labels for services, from the config file's [service_labels], so each route is easy
+// to tell apart in messages:
+//
+//     [service_labels]
+//     7 = "🟦 7 (City)"
+//     X24 = "🟥 X24"
+
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Default)]
+pub struct ServiceLabels {
+    labels: BTreeMap<String, String>, // Uppercased service number -> label
+}
+
+impl ServiceLabels {
+    pub fn new(labels: &BTreeMap<String, String>) -> ServiceLabels {
+        ServiceLabels {
+            labels: labels.iter().map(|(service, label)| (service.trim().to_ascii_uppercase(), label.clone())).collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    /// The service's label, or its number when it has none
+    pub fn label<'a>(&'a self, service: &'a str) -> &'a str {
+        self.labels.get(&service.trim().to_ascii_uppercase()).map_or(service, String::as_str)
+    }
+
+    pub fn has_label(&self, service: &str) -> bool {
+        self.labels.contains_key(&service.trim().to_ascii_uppercase())
+    }
+}
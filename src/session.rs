@@ -0,0 +1,498 @@
+// Per-run state: alert cooldowns, the live message, and what the SIGUSR1 snapshot reports
+
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex};
+
+use crate::absence::AbsenceWatch;
+use crate::direction::Direction;
+use crate::alerts::AlertTracker;
+use crate::dwell::Presence;
+use crate::format::Style;
+use crate::history::History;
+use crate::reverse_geocode::ReverseGeocoder;
+use crate::service_labels::ServiceLabels;
+use crate::stop_index::StopIndex;
+use crate::telegram::LiveMessage;
+use crate::tracks::Tracks;
+
+const RECENT_ALERTS: usize = 10; // Alerts kept for the snapshot
+
+/// Label for stops that aren't in a named group
+pub const DEFAULT_GROUP: &str = "default";
+
+/// A bus currently within a stop's radius (as of the latest cycle)
+#[derive(Debug, Clone, PartialEq)]
+pub struct InRange {
+    pub service_number: String,
+    pub vehicle: String, // Human-readable identifier, e.g. "fleet 10812"
+    pub stop: String,
+    pub group: Option<String>,
+    pub distance: f64, // meters
+}
+
+/// Where a bus was at the latest cycle, for the live map
+#[derive(Debug, Clone)]
+pub struct Position {
+    pub service_number: String,
+    pub vehicle: String,
+    pub lat: f64,
+    pub lng: f64,
+}
+
+/// The order of every listing of buses and stops (/now, /status, the SIGUSR1 snapshot, /debug and
+/// the closest approaches): nearest first, and equally near ones by name, so it's the same every run
+pub fn nearest_then_name(a: (f64, &str), b: (f64, &str)) -> Ordering {
+    a.0.total_cmp(&b.0).then_with(|| a.1.cmp(b.1))
+}
+
+impl InRange {
+    /// nearest_then_name on the stop, then by service and vehicle
+    pub fn nearest_first(a: &InRange, b: &InRange) -> Ordering {
+        nearest_then_name((a.distance, &a.stop), (b.distance, &b.stop))
+            .then_with(|| a.service_number.cmp(&b.service_number))
+            .then_with(|| a.vehicle.cmp(&b.vehicle))
+    }
+}
+
+/// An alert that was raised (whether or not it was muted)
+#[derive(Debug, Clone)]
+pub struct AlertEvent {
+    pub at: DateTime<Utc>,
+    pub service_number: String,
+    pub vehicle: String,
+    pub stop: String,
+    pub group: Option<String>,
+    pub lat: f64, // Where the bus was when it alerted
+    pub lng: f64,
+    pub direction: Direction,
+}
+
+pub struct Session {
+    pub started_at: DateTime<Utc>,
+    pub alert_tracker: AlertTracker,
+    pub tracks: Tracks,
+    pub live: Option<LiveMessage>,
+    pub presence: Presence,
+    pub history: Option<History>,
+    pub in_range: Vec<InRange>,
+    pub positions: Vec<Position>, // Every usable bus at the latest cycle
+    pub alerts: Vec<AlertEvent>, // Every alert this run, oldest first
+    pub alerts_per_stop: BTreeMap<String, u32>,
+    pub closest_approach: BTreeMap<String, (String, f64)>, // Service -> (stop, meters), the closest it came this run
+    pub resumed: bool, // This cycle is the first after a suspend, so stale fixes shouldn't alert
+    pub ignored_observations: u64, // Sightings of IGNORE_VEHICLES vehicles dropped this run
+    pub absence: AbsenceWatch,
+    pub stop_index: StopIndex, // Over the tracker's active stops, rebuilt when they change
+    pub unlabelled_services: BTreeSet<String>, // Services seen this run that [service_labels] doesn't cover (when it's set)
+    pub bunched: BTreeSet<String>, // Stops with two or more buses of interest at the last cycle (DETECT_BUNCHING)
+    pub geocoder: Option<ReverseGeocoder>, // Road names for arrival alerts (REVERSE_GEOCODE)
+}
+
+impl Session {
+    pub fn new(alert_tracker: AlertTracker, tracks: Tracks, live: Option<LiveMessage>, history: Option<History>, absence: AbsenceWatch) -> Session {
+        Session {
+            started_at: Utc::now(),
+            alert_tracker,
+            tracks,
+            live,
+            presence: Presence::default(),
+            history,
+            in_range: Vec::new(),
+            positions: Vec::new(),
+            alerts: Vec::new(),
+            alerts_per_stop: BTreeMap::new(),
+            closest_approach: BTreeMap::new(),
+            resumed: false,
+            ignored_observations: 0,
+            absence,
+            stop_index: StopIndex::default(),
+            unlabelled_services: BTreeSet::new(),
+            bunched: BTreeSet::new(),
+            geocoder: None,
+        }
+    }
+
+    pub fn record_alert(&mut self, event: AlertEvent) {
+        *self.alerts_per_stop.entry(event.stop.clone()).or_default() += 1;
+        self.alerts.push(event);
+    }
+
+    /// Notes which stops have two or more buses this cycle (`at_stops`: stop -> its buses) and
+    /// returns those that didn't at the last one, so each bunch is reported once
+    pub fn newly_bunched(&mut self, at_stops: BTreeMap<String, Vec<String>>) -> Vec<(String, Vec<String>)> {
+        let bunches: BTreeMap<String, Vec<String>> = at_stops.into_iter().filter(|(_, buses)| buses.len() >= 2).collect();
+        let new = bunches.iter().filter(|(stop, _)| !self.bunched.contains(*stop)).map(|(stop, buses)| (stop.clone(), buses.clone())).collect();
+        self.bunched = bunches.into_keys().collect();
+        new
+    }
+
+    /// Keeps the smallest distance seen between a service and any stop
+    pub fn record_approach(&mut self, service_number: &str, stop: &str, distance: f64) {
+        match self.closest_approach.get_mut(service_number) {
+            Some(closest) if closest.1 <= distance => {}
+            Some(closest) => *closest = (stop.to_string(), distance),
+            None => {
+                self.closest_approach.insert(service_number.to_string(), (stop.to_string(), distance));
+            }
+        }
+    }
+}
+
+/// Renders the on-demand state dump logged on SIGUSR1
+pub fn render_snapshot(session: &Session, failed_sends: u64, suppressed_repeats: u64, style: &Style, now: DateTime<Utc>) -> String {
+    let mut out = format!("Snapshot at {}", style.time_with_seconds(now));
+
+    out.push_str(&format!("\nBuses in range ({}):", session.in_range.len()));
+    for bus in &session.in_range {
+        out.push_str(&format!(
+            "\n  Bus {} [{}] at {} ({})",
+            bus.service_number,
+            bus.vehicle,
+            bus.stop,
+            style.distance(bus.distance)
+        ));
+    }
+
+    let recent = &session.alerts[session.alerts.len().saturating_sub(RECENT_ALERTS)..];
+    out.push_str(&format!("\nRecent alerts ({}):", recent.len()));
+    for alert in recent {
+        out.push_str(&format!(
+            "\n  {} Bus {} [{}] near {}",
+            style.time(alert.at),
+            alert.service_number,
+            alert.vehicle,
+            alert.stop
+        ));
+        if let Some(group) = &alert.group {
+            out.push_str(&format!(" ({})", group));
+        }
+    }
+
+    out.push_str("\nAlerts per stop:");
+    if session.alerts_per_stop.is_empty() {
+        out.push_str(" none");
+    }
+    for (stop, count) in &session.alerts_per_stop {
+        out.push_str(&format!("\n  {}: {}", stop, count));
+    }
+
+    out.push_str(&format!("\nFailed sends: {}", failed_sends));
+    out.push_str(&format!("\nRepeats suppressed: {}", suppressed_repeats));
+    out
+}
+
+/// Renders the message sent when a --daemon session ends: how many alerts each stop had, and
+/// which services seen have no label yet
+pub fn render_session_summary(session: &Session, style: &Style, now: DateTime<Utc>) -> String {
+    let mut out = format!(
+        "Session {}-{} ended: {} alert(s)",
+        style.time(session.started_at),
+        style.time(now),
+        session.alerts.len()
+    );
+    for (stop, count) in &session.alerts_per_stop {
+        out.push_str(&format!("\n  {}: {}", stop, count));
+    }
+    if let Some(services) = unlabelled_services(session) {
+        out.push_str(&format!("\n{}", services));
+    }
+    out
+}
+
+/// "Services without a label: ...", when [service_labels] is set and some services seen aren't in it
+pub fn unlabelled_services(session: &Session) -> Option<String> {
+    if session.unlabelled_services.is_empty() {
+        return None;
+    }
+    let services: Vec<&str> = session.unlabelled_services.iter().map(String::as_str).collect();
+    Some(format!("Services without a label: {}", services.join(", ")))
+}
+
+/// Renders the closest approach to a stop of every service seen, for the end-of-run summary, closest first
+pub fn render_closest_approach(session: &Session, style: &Style) -> String {
+    let mut closest: Vec<_> = session.closest_approach.iter().collect();
+    closest.sort_by(|(a_service, (_, a)), (b_service, (_, b))| nearest_then_name((*a, a_service), (*b, b_service)));
+
+    let mut out = format!("Closest approach per service ({}):", closest.len());
+    for (service, (stop, distance)) in closest {
+        out.push_str(&format!("\n  Bus {}: {} from {}", service, style.distance(*distance), stop));
+    }
+    out
+}
+
+/// Every alert of the run as a GeoJSON FeatureCollection of points, for EXPORT_GEOJSON
+pub fn alerts_geojson(session: &Session) -> Value {
+    let features: Vec<Value> = session
+        .alerts
+        .iter()
+        .map(|alert| {
+            json!({
+                "type": "Feature",
+                "geometry": { "type": "Point", "coordinates": [alert.lng, alert.lat] },
+                "properties": {
+                    "service": alert.service_number,
+                    "vehicle": alert.vehicle,
+                    "stop": alert.stop,
+                    "group": alert.group,
+                    "direction": alert.direction.to_string(),
+                    "time": alert.at.to_rfc3339(),
+                },
+            })
+        })
+        .collect();
+    json!({ "type": "FeatureCollection", "features": features })
+}
+
+/// The latest cycle's results, shared with the Telegram command handler for /status and the debug endpoint
+#[derive(Debug, Default)]
+pub struct Status {
+    pub checked_at: Option<DateTime<Utc>>,
+    pub in_range: Vec<InRange>,
+    pub positions: Vec<Position>,
+    pub groups: Vec<String>, // Every group label in use, including DEFAULT_GROUP for ungrouped stops
+    pub failed_sends: u64,
+    pub suppressed_repeats: u64, // Sends dropped by SUPPRESS_REPEATS
+    pub active_stops: Option<Vec<String>>, // Today's stops, when some stops are only watched on certain days
+    pub stops: Vec<Value>,                 // Every loaded stop, as the debug endpoint shows it
+    pub api_down_since: Option<DateTime<Utc>>, // Set while fetches fail; in_range is then from checked_at and stale
+    pub next_activations: Option<Vec<String>>, // Upcoming local start times, when polling follows [schedule]
+    pub ends_at: Option<DateTime<Utc>>,        // When this run stops, including any /extend
+    pub service_labels: ServiceLabels,
+}
+
+pub type SharedStatus = Arc<Mutex<Status>>;
+
+/// Renders the reply to /status, optionally limited to one group
+pub fn render_status(status: &Status, group: Option<&str>, style: &Style) -> String {
+    if let Some(group) = group {
+        if !status.groups.iter().any(|g| g.eq_ignore_ascii_case(group)) {
+            return format!("Unknown group '{}'. Groups: {}", group, status.groups.join(", "));
+        }
+    }
+
+    let Some(checked_at) = status.checked_at else {
+        return "No buses checked yet.".to_string();
+    };
+
+    let buses: Vec<&InRange> = status
+        .in_range
+        .iter()
+        .filter(|bus| group.is_none_or(|g| bus.group.as_deref().unwrap_or(DEFAULT_GROUP).eq_ignore_ascii_case(g)))
+        .collect();
+
+    let mut out = match group {
+        Some(group) => format!("Buses near {} stops ({}):", group, buses.len()),
+        None => format!("Buses near your stops ({}):", buses.len()),
+    };
+    for bus in buses {
+        out.push_str(&format!(
+            "\nBus {} [{}] at {} ({})",
+            status.service_labels.label(&bus.service_number),
+            bus.vehicle,
+            bus.stop,
+            style.distance(bus.distance)
+        ));
+    }
+    if let Some(stops) = &status.active_stops {
+        out.push_str(&format!("\nWatching today: {}", if stops.is_empty() { "no stops".to_string() } else { stops.join(", ") }));
+    }
+    out.push_str(&format!("\nLast checked {}", style.time_with_seconds(checked_at)));
+    if let Some(ends_at) = status.ends_at {
+        out.push_str(&format!("\nTracking ends at {}", style.time(ends_at)));
+    }
+    if let Some(next) = &status.next_activations {
+        out.push_str(&format!("\nNext scheduled starts: {}", if next.is_empty() { "none".to_string() } else { next.join(", ") }));
+    }
+    if let Some(since) = status.api_down_since {
+        out.push_str(&format!("\nStale: the bus API has been unreachable since {}", style.time_with_seconds(since)));
+    }
+    if status.failed_sends > 0 {
+        out.push_str(&format!("\nFailed sends: {}", status.failed_sends));
+    }
+    if status.suppressed_repeats > 0 {
+        out.push_str(&format!("\nRepeats suppressed: {}", status.suppressed_repeats));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alerts::Cooldown;
+    use crate::test_support::{self, fixed_now};
+
+    fn in_range(service: &str, vehicle: &str, stop: &str, distance: f64) -> InRange {
+        InRange { service_number: service.to_string(), vehicle: vehicle.to_string(), stop: stop.to_string(), group: None, distance }
+    }
+
+    fn session() -> Session {
+        Session::new(AlertTracker::new(Cooldown::Time(chrono::Duration::seconds(300))), Tracks::new(130.0), None, None, AbsenceWatch::default())
+    }
+
+    #[test]
+    fn geojson_has_one_point_feature_per_alert() {
+        let mut session = session();
+        assert_eq!(alerts_geojson(&session), json!({ "type": "FeatureCollection", "features": [] }));
+
+        let alerts = [("7", "Market Square", None, 53.001, -1.502), ("9", "Station Road", Some("Work"), 53.004, -1.497), ("7", "Market Square", None, 53.0012, -1.5021)];
+        for (minute, (service, stop, group, lat, lng)) in alerts.into_iter().enumerate() {
+            session.record_alert(AlertEvent {
+                at: fixed_now() + chrono::Duration::minutes(minute as i64),
+                service_number: service.to_string(),
+                vehicle: format!("fleet {}", minute),
+                stop: stop.to_string(),
+                group: group.map(str::to_string),
+                lat,
+                lng,
+                direction: if minute == 1 { Direction::Towards("Hospital".to_string()) } else { Direction::Unknown },
+            });
+        }
+
+        // Written out as text and read back, as a GeoJSON reader would
+        let geojson: Value = serde_json::from_str(&alerts_geojson(&session).to_string()).unwrap();
+        assert_eq!(geojson["type"], "FeatureCollection");
+        let features = geojson["features"].as_array().unwrap();
+        assert_eq!(features.len(), alerts.len());
+        for (feature, (service, stop, _, lat, lng)) in features.iter().zip(alerts) {
+            assert_eq!(feature["type"], "Feature");
+            assert_eq!(feature["geometry"]["type"], "Point");
+            assert_eq!(feature["geometry"]["coordinates"], json!([lng, lat])); // GeoJSON puts longitude first
+            assert_eq!(feature["properties"]["service"], service);
+            assert_eq!(feature["properties"]["stop"], stop);
+        }
+        assert_eq!(
+            features[1]["properties"],
+            json!({
+                "service": "9",
+                "vehicle": "fleet 1",
+                "stop": "Station Road",
+                "group": "Work",
+                "direction": "towards Hospital",
+                "time": "2024-03-04T08:01:00+00:00",
+            })
+        );
+        assert_eq!(features[0]["properties"]["group"], Value::Null);
+    }
+
+    #[test]
+    fn closest_approach_keeps_each_services_running_minimum() {
+        let mut session = session();
+        let observations = [
+            ("7", "Market Square", 900.0),
+            ("9", "Station Road", 450.0),
+            ("7", "Station Road", 620.0),
+            ("7", "Market Square", 710.0), // Farther than its best so far
+            ("9", "Station Road", 450.0),
+            ("7", "Market Square", 130.0),
+            ("7", "Station Road", 480.0),
+        ];
+        for (service, stop, distance) in observations {
+            session.record_approach(service, stop, distance);
+        }
+        assert_eq!(
+            session.closest_approach,
+            BTreeMap::from([("7".to_string(), ("Market Square".to_string(), 130.0)), ("9".to_string(), ("Station Road".to_string(), 450.0))])
+        );
+    }
+
+    #[test]
+    fn status_can_be_limited_to_one_group() {
+        let mut school = in_range("7", "fleet 2", "School Lane", 80.0);
+        school.group = Some("school".to_string());
+        let status = Status {
+            checked_at: Some(fixed_now()),
+            in_range: vec![in_range("9", "fleet 1", "Market Square", 40.0), school],
+            groups: vec![DEFAULT_GROUP.to_string(), "school".to_string()],
+            ..Status::default()
+        };
+        let style = test_support::config(&[]).style;
+
+        assert_eq!(render_status(&status, Some("School"), &style), "Buses near School stops (1):\nBus 7 [fleet 2] at School Lane (80 m)\nLast checked 08:00:00");
+        assert!(render_status(&status, Some(DEFAULT_GROUP), &style).contains("Bus 9 [fleet 1] at Market Square (40 m)"));
+        assert!(render_status(&status, None, &style).starts_with("Buses near your stops (2):"));
+        assert_eq!(render_status(&status, Some("gym"), &style), format!("Unknown group 'gym'. Groups: {}, school", DEFAULT_GROUP));
+    }
+
+    #[test]
+    fn snapshot_renders_from_fixed_state() {
+        let config = test_support::config(&[]);
+        let mut session = session();
+        session.in_range = vec![in_range("7", "fleet 10812", "Market Square", 34.0), in_range("9", "fleet 10900", "Station Road", 112.0)];
+        // Twelve alerts, of which the snapshot shows the last ten
+        for minute in 0..12 {
+            let (stop, group) = if minute % 3 == 0 { ("Station Road", Some("Work")) } else { ("Market Square", None) };
+            session.record_alert(AlertEvent {
+                at: fixed_now() + chrono::Duration::minutes(minute),
+                service_number: "7".to_string(),
+                vehicle: format!("fleet {}", 10800 + minute),
+                stop: stop.to_string(),
+                group: group.map(str::to_string),
+                lat: 53.0,
+                lng: -1.5,
+                direction: Direction::Unknown,
+            });
+        }
+        insta::assert_snapshot!(render_snapshot(&session, 2, 1, &config.style, fixed_now() + chrono::Duration::minutes(12)));
+    }
+
+    #[test]
+    fn snapshot_of_a_quiet_session() {
+        let config = test_support::config(&[]);
+        insta::assert_snapshot!(render_snapshot(&session(), 0, 0, &config.style, fixed_now()), @r"
+        Snapshot at 08:00:00
+        Buses in range (0):
+        Recent alerts (0):
+        Alerts per stop: none
+        Failed sends: 0
+        Repeats suppressed: 0
+        ");
+    }
+
+    #[test]
+    fn nearest_first_then_by_name() {
+        assert_eq!(nearest_then_name((10.0, "B"), (20.0, "A")), Ordering::Less);
+        assert_eq!(nearest_then_name((20.0, "A"), (10.0, "B")), Ordering::Greater);
+        assert_eq!(nearest_then_name((10.0, "A"), (10.0, "B")), Ordering::Less);
+        assert_eq!(nearest_then_name((10.0, "B"), (10.0, "A")), Ordering::Greater);
+        assert_eq!(nearest_then_name((10.0, "A"), (10.0, "A")), Ordering::Equal);
+
+        let mut names = vec![(35.0, "Station Road"), (12.5, "Market Square"), (35.0, "Bus Station"), (12.5, "Abbey Road")];
+        names.sort_by(|a, b| nearest_then_name(*a, *b));
+        assert_eq!(names, [(12.5, "Abbey Road"), (12.5, "Market Square"), (35.0, "Bus Station"), (35.0, "Station Road")]);
+    }
+
+    #[test]
+    fn status_lists_buses_in_range_nearest_first_with_ties_by_stop_service_and_vehicle() {
+        let mut buses = vec![
+            in_range("9", "fleet 2", "Market Square", 40.0),
+            in_range("7", "fleet 3", "Station Road", 15.0),
+            in_range("7", "fleet 1", "Market Square", 15.0),
+            in_range("12", "fleet 4", "Market Square", 15.0),
+            in_range("7", "fleet 0", "Market Square", 15.0),
+        ];
+        buses.sort_by(InRange::nearest_first);
+        let status = Status { checked_at: Some(fixed_now()), in_range: buses, ..Status::default() };
+        let style = test_support::config(&[]).style;
+        let rendered = render_status(&status, None, &style);
+        let listed: Vec<&str> = rendered.lines().filter(|line| line.starts_with("Bus ")).map(|line| line.split(" at ").next().unwrap()).collect();
+        assert_eq!(listed, ["Bus 12 [fleet 4]", "Bus 7 [fleet 0]", "Bus 7 [fleet 1]", "Bus 7 [fleet 3]", "Bus 9 [fleet 2]"]);
+    }
+
+    #[test]
+    fn closest_approaches_are_listed_nearest_first_with_ties_by_service() {
+        let config = test_support::config(&[]);
+        let mut session = session();
+        for (service, stop, distance) in [("9", "Market Square", 120.0), ("7", "Station Road", 40.0), ("12", "Market Square", 40.0), ("X1", "Station Road", 5.0)] {
+            session.closest_approach.insert(service.to_string(), (stop.to_string(), distance));
+        }
+        let services: Vec<String> = render_closest_approach(&session, &config.style)
+            .lines()
+            .skip(1)
+            .map(|line| line.trim().split(':').next().unwrap().to_string())
+            .collect();
+        assert_eq!(services, ["Bus X1", "Bus 12", "Bus 7", "Bus 9"]);
+    }
+}
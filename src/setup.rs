@@ -0,0 +1,315 @@
+// `setup`: first-run wizard that asks for the essentials and writes a config file.
+// Every question has a flag, so the wizard can also be run from scripts (--non-interactive).
+
+use clap::Args;
+use reqwest::Client;
+use serde_json::Value;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::PathBuf;
+use tokio::time::Duration;
+use toml_edit::{value, DocumentMut};
+
+use crate::config_edit::{self, NewStop};
+use crate::{http_client, json_f64, telegram, urlencode};
+
+const DEFAULT_CONFIG_PATH: &str = "stagecoach-tracker.toml";
+const DEFAULT_GEOCODE_URL: &str = "https://api.postcodes.io/postcodes/{postcode}";
+const DEFAULT_RADIUS: u32 = 1000; // meters
+const DEFAULT_POLL_INTERVAL_SECS: u64 = 10;
+const DETECT_CHAT_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[derive(Debug, Args)]
+pub struct SetupArgs {
+    /// Latitude to search around
+    #[arg(long, allow_negative_numbers = true, requires = "lng")]
+    lat: Option<f64>,
+    /// Longitude to search around
+    #[arg(long, allow_negative_numbers = true, requires = "lat")]
+    lng: Option<f64>,
+    /// Postcode to look up instead of giving --lat/--lng
+    #[arg(long, conflicts_with = "lat")]
+    postcode: Option<String>,
+    /// Geocoding service; {postcode} is replaced with the postcode
+    #[arg(long, env = "GEOCODE_URL", default_value = DEFAULT_GEOCODE_URL)]
+    geocode_url: String,
+    /// Search radius in meters
+    #[arg(long)]
+    radius: Option<u32>,
+    /// Seconds between checks
+    #[arg(long)]
+    poll_interval: Option<u64>,
+    #[arg(long)]
+    telegram_token: Option<String>,
+    #[arg(long, allow_hyphen_values = true)]
+    telegram_chat_id: Option<String>,
+    /// Find the chat id by waiting for a /start message to the bot
+    #[arg(long, conflicts_with = "telegram_chat_id")]
+    detect_chat_id: bool,
+    /// A stop as "name,lat,lng" or "name,lat,lng,radius"; repeat for more
+    #[arg(long = "stop", allow_hyphen_values = true)]
+    stops: Vec<String>,
+    /// Replace an existing config file
+    #[arg(long)]
+    force: bool,
+    /// Don't send a test notification afterwards
+    #[arg(long)]
+    skip_test: bool,
+    /// Never prompt; anything required must come from flags
+    #[arg(long)]
+    non_interactive: bool,
+}
+
+impl SetupArgs {
+    pub fn skip_test(&self) -> bool {
+        self.skip_test
+    }
+}
+
+/// Asks questions on the terminal, or explains which flag was missing when there isn't one
+pub struct Prompter {
+    pub interactive: bool,
+}
+
+impl Prompter {
+    pub fn ask(&self, question: &str, default: Option<&str>, flag: &str) -> Result<String, String> {
+        if !self.interactive {
+            return default.map(str::to_string).ok_or_else(|| format!("Missing {} (not prompting).", flag));
+        }
+
+        loop {
+            match default {
+                Some(default) => print!("{} [{}]: ", question, default),
+                None => print!("{}: ", question),
+            }
+            io::stdout().flush().map_err(|e| e.to_string())?;
+
+            let mut answer = String::new();
+            if io::stdin().lock().read_line(&mut answer).map_err(|e| e.to_string())? == 0 {
+                return Err("Setup cancelled.".to_string());
+            }
+            match (answer.trim(), default) {
+                ("", Some(default)) => return Ok(default.to_string()),
+                ("", None) => continue,
+                (answer, _) => return Ok(answer.to_string()),
+            }
+        }
+    }
+
+    fn ask_parse<T: std::str::FromStr>(&self, question: &str, default: Option<&str>, flag: &str) -> Result<T, String> {
+        loop {
+            let answer = self.ask(question, default, flag)?;
+            match answer.parse() {
+                Ok(parsed) => return Ok(parsed),
+                Err(_) if self.interactive => println!("'{}' doesn't look right, please try again.", answer),
+                Err(_) => return Err(format!("Invalid value '{}' for {}.", answer, flag)),
+            }
+        }
+    }
+
+    pub fn confirm(&self, question: &str, default: bool) -> Result<bool, String> {
+        if !self.interactive {
+            return Ok(default);
+        }
+        let answer = self.ask(question, Some(if default { "Y/n" } else { "y/N" }), "")?;
+        Ok(match answer.to_ascii_lowercase().as_str() {
+            "y" | "yes" => true,
+            "n" | "no" => false,
+            _ => default,
+        })
+    }
+}
+
+/// Runs the wizard and returns the path of the config file it wrote
+pub async fn run(config_path: Option<PathBuf>, args: &SetupArgs) -> Result<PathBuf, String> {
+    let prompter = Prompter { interactive: !args.non_interactive && io::stdin().is_terminal() };
+    let client = http_client()?;
+    let path = config_path.unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_PATH));
+
+    if path.exists() && !args.force && !prompter.confirm(&format!("{} already exists. Replace it?", path.display()), false)? {
+        return Err(format!("{} already exists; pass --force to replace it.", path.display()));
+    }
+
+    let (lat, lng) = match (args.lat, args.lng, &args.postcode) {
+        (Some(lat), Some(lng), _) => (lat, lng),
+        (_, _, Some(postcode)) => geocode(&client, &args.geocode_url, postcode).await?,
+        _ => {
+            let answer = prompter.ask("Your location, as a postcode or \"lat,lng\"", None, "--lat/--lng or --postcode")?;
+            match parse_lat_lng(&answer) {
+                Some(position) => position,
+                None => geocode(&client, &args.geocode_url, &answer).await?,
+            }
+        }
+    };
+    config_edit::check_coordinates(lat, lng)?;
+    println!("Searching around {}, {}", lat, lng);
+
+    let radius = match args.radius {
+        Some(radius) => radius,
+        None => prompter.ask_parse("Search radius in meters", Some(&DEFAULT_RADIUS.to_string()), "--radius")?,
+    };
+    let poll_interval = match args.poll_interval {
+        Some(secs) => secs,
+        None => prompter.ask_parse("Seconds between checks", Some(&DEFAULT_POLL_INTERVAL_SECS.to_string()), "--poll-interval")?,
+    };
+
+    let token = match &args.telegram_token {
+        Some(token) => token.clone(),
+        None => prompter.ask("Telegram bot token (from @BotFather)", None, "--telegram-token")?,
+    };
+    let chat_id = match &args.telegram_chat_id {
+        Some(chat_id) => chat_id.clone(),
+        None if args.detect_chat_id || (prompter.interactive && prompter.confirm("Detect the chat id by sending /start to your bot?", true)?) => {
+            println!("Waiting up to {} s for a /start message to the bot...", DETECT_CHAT_TIMEOUT.as_secs());
+            let chat_id = telegram::wait_for_start(&client, &token, DETECT_CHAT_TIMEOUT).await?;
+            println!("Found chat {}", chat_id);
+            chat_id
+        }
+        None => prompter.ask("Telegram chat id", None, "--telegram-chat-id")?,
+    };
+
+    let mut stops = args.stops.iter().map(|stop| parse_stop(stop)).collect::<Result<Vec<_>, _>>()?;
+    if stops.is_empty() {
+        let here = format!("{},{}", lat, lng);
+        loop {
+            let name = prompter.ask("Stop name", None, "--stop")?;
+            let position = prompter.ask("Stop position as \"lat,lng\"", Some(&here), "--stop")?;
+            let (stop_lat, stop_lng) = parse_lat_lng(&position).ok_or_else(|| format!("Invalid position '{}'.", position))?;
+            let radius = prompter.ask_parse("Alert radius around the stop in meters", Some("200"), "--stop")?;
+            let stop = NewStop { name, lat: stop_lat, lng: stop_lng, radius: Some(radius), services: Vec::new() };
+            match stop.validate() {
+                Ok(()) => stops.push(stop),
+                Err(e) => println!("{}", e),
+            }
+            if !stops.is_empty() && !prompter.confirm("Add another stop?", false)? {
+                break;
+            }
+        }
+    }
+
+    let mut doc = DocumentMut::new();
+    doc["lat"] = value(lat);
+    doc["lng"] = value(lng);
+    doc["radius"] = value(i64::from(radius));
+    doc["poll_interval_secs"] = value(poll_interval as i64);
+    doc["telegram_bot_token"] = value(token);
+    doc["telegram_chat_id"] = value(chat_id);
+    config_edit::write_document(&path, &doc)?;
+    for stop in &stops {
+        config_edit::add_stop(&path, stop)?;
+    }
+
+    println!("Wrote {}. Run with --config {} (or set CONFIG_FILE).", path.display(), path.display());
+    Ok(path)
+}
+
+/// Parses "lat,lng"
+fn parse_lat_lng(text: &str) -> Option<(f64, f64)> {
+    let (lat, lng) = text.split_once(',')?;
+    Some((lat.trim().parse().ok()?, lng.trim().parse().ok()?))
+}
+
+/// Parses a --stop value: "name,lat,lng" with an optional ",radius"
+fn parse_stop(text: &str) -> Result<NewStop, String> {
+    let invalid = || format!("--stop must look like \"name,lat,lng\" or \"name,lat,lng,radius\", got '{}'.", text);
+    let parts: Vec<&str> = text.split(',').map(str::trim).collect();
+    let (name, lat, lng, radius) = match parts.as_slice() {
+        [name, lat, lng] => (name, lat, lng, None),
+        [name, lat, lng, radius] => (name, lat, lng, Some(radius.parse().map_err(|_| invalid())?)),
+        _ => return Err(invalid()),
+    };
+
+    let stop = NewStop {
+        name: name.to_string(),
+        lat: lat.parse().map_err(|_| invalid())?,
+        lng: lng.parse().map_err(|_| invalid())?,
+        radius,
+        services: Vec::new(),
+    };
+    stop.validate()?;
+    Ok(stop)
+}
+
+/// Looks a postcode up with the geocoding service. Understands postcodes.io-style
+/// (`result.latitude`) and Nominatim-style (`[{"lat": ..., "lon": ...}]`) replies.
+async fn geocode(client: &Client, url_template: &str, postcode: &str) -> Result<(f64, f64), String> {
+    let url = url_template.replace("{postcode}", &urlencode(postcode.trim()));
+    let response = client
+        .get(&url)
+        .send()
+        .await
+        .map_err(|e| format!("Could not reach the geocoding service: {}", e))?;
+    if !response.status().is_success() {
+        return Err(format!("Could not find postcode '{}' ({}).", postcode, response.status()));
+    }
+    let body: Value = response.json().await.map_err(|e| format!("Unexpected geocoding reply: {}", e))?;
+
+    let position = match (&body["result"], &body[0]) {
+        (result, _) if result.is_object() => json_f64(&result["latitude"]).zip(json_f64(&result["longitude"])),
+        (_, first) => json_f64(&first["lat"]).zip(json_f64(&first["lon"])),
+    };
+    position.ok_or_else(|| format!("Could not find postcode '{}'.", postcode))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_file::ConfigFile;
+    use crate::test_support::MockServer;
+    use std::env;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn flags(stops: &[&str]) -> SetupArgs {
+        SetupArgs {
+            lat: Some(53.0),
+            lng: Some(-1.5),
+            postcode: None,
+            geocode_url: DEFAULT_GEOCODE_URL.to_string(),
+            radius: Some(800),
+            poll_interval: None,
+            telegram_token: Some("123:abc".to_string()),
+            telegram_chat_id: Some("-100987654".to_string()),
+            detect_chat_id: false,
+            stops: stops.iter().map(|stop| stop.to_string()).collect(),
+            force: false,
+            skip_test: true,
+            non_interactive: true,
+        }
+    }
+
+    #[tokio::test]
+    async fn flags_alone_write_a_private_config_that_loads() {
+        let path = env::temp_dir().join(format!("setup-test-{}.toml", std::process::id()));
+        let written = run(Some(path.clone()), &flags(&["Home,53.0,-1.5", "Work, 53.1, -1.4, 150"])).await.unwrap();
+        assert_eq!(written, path);
+
+        let file = ConfigFile::load(&path).unwrap();
+        assert_eq!((file.lat, file.lng, file.radius, file.poll_interval_secs), (Some(53.0), Some(-1.5), Some(800), Some(10)));
+        assert_eq!(file.telegram_chat_id.as_deref(), Some("-100987654"));
+        let stops: Vec<(&str, Option<f64>)> = file.stops.iter().map(|stop| (stop.name.as_str(), stop.radius)).collect();
+        assert_eq!(stops, [("Home", None), ("Work", Some(150.0))]);
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        // Without --force an existing file is left alone
+        let error = run(Some(path.clone()), &flags(&["Gym,53.2,-1.3"])).await.unwrap_err();
+        assert_eq!(error, format!("{} already exists; pass --force to replace it.", path.display()));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn postcodes_are_looked_up_with_the_geocoding_service() {
+        let server = MockServer::start(vec![(200, r#"{"status":200,"result":{"latitude":53.1,"longitude":-1.4}}"#.to_string())]);
+        let url = format!("{}/postcodes/{{postcode}}", server.url);
+        assert_eq!(geocode(&Client::new(), &url, " SW1A 1AA ").await, Ok((53.1, -1.4)));
+        assert_eq!(server.requests()[0].path, "/postcodes/SW1A%201AA");
+    }
+
+    #[test]
+    fn stop_flags_need_a_name_and_a_position() {
+        assert_eq!(
+            parse_stop("Home,53.0").unwrap_err(),
+            "--stop must look like \"name,lat,lng\" or \"name,lat,lng,radius\", got 'Home,53.0'."
+        );
+        assert_eq!(parse_stop("Home,95,-1.5").unwrap_err(), "Invalid coordinates 95, -1.5.");
+    }
+}
@@ -0,0 +1,114 @@
+// Local audio alerts (the `sound` feature): a chime on the default output device for each alert,
+// or the terminal bell when no sound file is configured. Playback runs on its own thread, since
+// audio streams can't be moved between tasks.
+
+use rodio::{DeviceSinkBuilder, Float, MixerDeviceSink};
+use std::fs::File;
+use std::io::{self, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use tracing::{info, warn};
+
+use crate::error::TrackerError;
+use crate::notify::AlertKind;
+use crate::{env_flag, env_or_file, env_parse};
+
+#[derive(Debug)]
+pub struct Sound {
+    chimes: Sender<AlertKind>, // Closed once the player thread has given up on the audio device
+}
+
+/// What to play for each kind of alert; None rings the terminal bell
+#[derive(Debug)]
+struct Sounds {
+    arrival: Option<PathBuf>,
+    early_warning: Option<PathBuf>,
+    volume: Float,
+}
+
+impl Sound {
+    /// Returns None unless SOUND_ALERTS is on
+    pub fn from_env() -> Result<Option<Sound>, TrackerError> {
+        if !env_flag("SOUND_ALERTS", false)? {
+            return Ok(None);
+        }
+
+        let file = |name: &str| -> Result<Option<PathBuf>, TrackerError> {
+            match env_or_file(name)? {
+                Some(path) if !path.trim().is_empty() => {
+                    let path = PathBuf::from(path.trim());
+                    File::open(&path).map_err(|e| TrackerError::Config(format!("Cannot read {} '{}': {}", name, path.display(), e)))?;
+                    Ok(Some(path))
+                }
+                _ => Ok(None),
+            }
+        };
+        let arrival = file("SOUND_ARRIVAL_FILE")?;
+        let early_warning = file("SOUND_EARLY_WARNING_FILE")?.or_else(|| arrival.clone());
+        let volume: Float = env_parse("SOUND_VOLUME", 1.0)?;
+        if !(0.0..=1.0).contains(&volume) {
+            return Err(TrackerError::Config(format!("SOUND_VOLUME must be between 0 and 1, got {}.", volume)));
+        }
+
+        let sounds = Sounds { arrival, early_warning, volume };
+        let (chimes, receiver) = mpsc::channel();
+        thread::Builder::new()
+            .name("sound".to_string())
+            .spawn(move || play_chimes(receiver, sounds))
+            .map_err(|e| TrackerError::Config(format!("Could not start the sound player: {}", e)))?;
+        Ok(Some(Sound { chimes }))
+    }
+
+    /// Queues the chime for `kind`; does nothing once sound has been disabled
+    pub fn play(&self, kind: AlertKind) {
+        let _ = self.chimes.send(kind);
+    }
+}
+
+/// Plays a chime per alert until the tracker stops. Without an audio device it logs once and
+/// returns, which disables sound for the rest of the run.
+fn play_chimes(receiver: Receiver<AlertKind>, sounds: Sounds) {
+    let device = if sounds.arrival.is_some() || sounds.early_warning.is_some() {
+        match DeviceSinkBuilder::open_default_sink() {
+            Ok(mut device) => {
+                device.log_on_drop(false);
+                Some(device)
+            }
+            Err(e) => {
+                warn!("No audio output ({}); sound alerts are off for this run", e);
+                return;
+            }
+        }
+    } else {
+        info!("No SOUND_ARRIVAL_FILE set; sound alerts ring the terminal bell");
+        None
+    };
+
+    for kind in receiver {
+        let file = match kind {
+            AlertKind::Arrival => &sounds.arrival,
+            AlertKind::EarlyWarning => &sounds.early_warning,
+        };
+        match (&device, file) {
+            (Some(device), Some(path)) => {
+                if let Err(e) = play_file(device, path, sounds.volume) {
+                    warn!("Could not play {}: {}", path.display(), e);
+                }
+            }
+            _ => {
+                print!("\x07");
+                let _ = io::stdout().flush();
+            }
+        }
+    }
+}
+
+/// Plays a WAV or OGG file to the end
+fn play_file(device: &MixerDeviceSink, path: &Path, volume: Float) -> Result<(), String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    let player = rodio::play(device.mixer(), BufReader::new(file)).map_err(|e| e.to_string())?;
+    player.set_volume(volume);
+    player.sleep_until_end();
+    Ok(())
+}
@@ -0,0 +1,207 @@
+// Machine-readable state for scripts that just want the latest picture (an e-paper display, say),
+// written after each cycle to --state-file / STATE_FILE:
+//
+//     {
+//       "version": 1,
+//       "written_at": "2024-05-01T07:42:10+00:00",
+//       "stops": [
+//         { "name": "Main Street",
+//           "nearest": { "service": "7", "label": "7", "vehicle": "fleet 10812", "distance_m": 850, "eta_secs": 140 } },
+//         { "name": "Station", "nearest": null }
+//       ],
+//       "health": { "last_api_success": "2024-05-01T07:42:10+00:00", "api_down_since": null,
+//                   "consecutive_api_failures": 0, "failed_sends": 0, "suppressed_repeats": 0 }
+//     }
+//
+// `nearest` is the closest bus the stop would alert for that's still heading its way. The file
+// is written beside its final path and renamed over it, so readers never see half of it, and
+// isn't rewritten when only the timestamps would change. Fields may be added but are never
+// renamed or removed without bumping `version`.
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::{distance_to_stop, eta_secs, is_approaching, vehicle_direction, BusStop, Config, Vehicle};
+
+const VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct State {
+    pub version: u32,
+    pub written_at: String, // RFC 3339
+    pub stops: Vec<StopState>,
+    pub health: Health,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StopState {
+    pub name: String,
+    pub nearest: Option<NearestBus>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct NearestBus {
+    pub service: String,
+    pub label: String, // As shown in messages ([service_labels])
+    pub vehicle: String,
+    pub distance_m: u64,       // Still to go, along the route where [[routes]] has it
+    pub eta_secs: Option<u64>, // When the bus reports its speed
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Health {
+    pub last_api_success: Option<String>, // RFC 3339; as of the last write
+    pub api_down_since: Option<String>,   // RFC 3339; set while fetches fail
+    pub consecutive_api_failures: u32,
+    pub failed_sends: u64, // Notification sends that failed this run, on any channel
+    pub suppressed_repeats: u64, // Sends dropped this run as repeats of recent text (SUPPRESS_REPEATS)
+}
+
+#[derive(Debug)]
+pub struct StateFile {
+    path: PathBuf,
+    last: Option<State>, // What's in the file now
+}
+
+impl StateFile {
+    pub fn new(path: PathBuf) -> StateFile {
+        StateFile { path, last: None }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Writes the state, with `stops` None (the API didn't answer) keeping the stops last written.
+    /// Skips the write when nothing but the timestamps changed.
+    pub fn update(&mut self, stops: Option<Vec<StopState>>, health: Health, now: DateTime<Utc>) -> io::Result<()> {
+        let stops = match (stops, &self.last) {
+            (Some(stops), _) => stops,
+            (None, Some(last)) => last.stops.clone(),
+            (None, None) => Vec::new(),
+        };
+        let state = State { version: VERSION, written_at: now.to_rfc3339(), stops, health };
+        if self.last.as_ref().is_some_and(|last| !state.differs_from(last)) {
+            return Ok(());
+        }
+        write_atomically(&self.path, format!("{}\n", serde_json::to_string_pretty(&state)?).as_bytes())?;
+        self.last = Some(state);
+        Ok(())
+    }
+}
+
+impl State {
+    /// Whether anything other than `written_at` and `last_api_success` changed
+    fn differs_from(&self, other: &State) -> bool {
+        let health = |state: &State| Health { last_api_success: None, ..state.health.clone() };
+        self.stops != other.stops || health(self) != health(other)
+    }
+}
+
+/// Each stop's nearest bus still heading its way, among the services it alerts for
+pub fn stop_states(vehicles: &[Vehicle], stops: &[BusStop], config: &Config) -> Vec<StopState> {
+    let directions: Vec<_> = vehicles.iter().map(|vehicle| vehicle_direction(vehicle, config)).collect();
+    stops
+        .iter()
+        .map(|stop| {
+            let nearest = vehicles
+                .iter()
+                .zip(&directions)
+                .filter(|(vehicle, direction)| stop.serves(&vehicle.service_number, direction) && is_approaching(vehicle, stop, config))
+                .filter_map(|(vehicle, _)| Some((vehicle, distance_to_stop(vehicle, stop, config)?)))
+                .min_by(|a, b| a.1.total_cmp(&b.1))
+                .map(|(vehicle, distance)| NearestBus {
+                    service: vehicle.service_number.clone(),
+                    label: config.service_labels.label(&vehicle.service_number).to_string(),
+                    vehicle: vehicle.identifier(),
+                    distance_m: distance.round() as u64,
+                    eta_secs: eta_secs(distance, vehicle.speed).map(|secs| secs.round() as u64),
+                });
+            StopState { name: stop.name.clone(), nearest }
+        })
+        .collect()
+}
+
+/// Writes a temporary file in the same directory and renames it over `path`
+fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let name = path.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no file name"))?;
+    let temp = path.with_file_name(format!(".{}.tmp", name.to_string_lossy()));
+    let mut file = fs::File::create(&temp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&temp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::fixed_now;
+    use std::env;
+    use std::process;
+
+    fn health(last_api_success: DateTime<Utc>) -> Health {
+        Health {
+            last_api_success: Some(last_api_success.to_rfc3339()),
+            api_down_since: None,
+            consecutive_api_failures: 0,
+            failed_sends: 0,
+            suppressed_repeats: 0,
+        }
+    }
+
+    fn stops(distance_m: u64) -> Vec<StopState> {
+        vec![
+            StopState {
+                name: "Main Street".to_string(),
+                nearest: Some(NearestBus {
+                    service: "7".to_string(),
+                    label: "7".to_string(),
+                    vehicle: "fleet 10812".to_string(),
+                    distance_m,
+                    eta_secs: Some(140),
+                }),
+            },
+            StopState { name: "Station".to_string(), nearest: None },
+        ]
+    }
+
+    fn state_file(test: &str) -> StateFile {
+        StateFile::new(env::temp_dir().join(format!("state-file-{}-{}.json", test, process::id())))
+    }
+
+    #[test]
+    fn file_has_the_documented_shape() {
+        let mut file = state_file("shape");
+        file.update(Some(stops(850)), health(fixed_now()), fixed_now()).unwrap();
+        let written = fs::read_to_string(file.path()).unwrap();
+        fs::remove_file(file.path()).unwrap();
+        insta::assert_snapshot!(written);
+    }
+
+    #[test]
+    fn rewrites_only_when_more_than_the_timestamps_change() {
+        let mut file = state_file("timestamps");
+        file.update(Some(stops(850)), health(fixed_now()), fixed_now()).unwrap();
+        fs::remove_file(file.path()).unwrap();
+
+        let later = fixed_now() + chrono::Duration::seconds(30);
+        file.update(Some(stops(850)), health(later), later).unwrap();
+        assert!(!file.path().exists(), "rewritten though only the timestamps changed");
+
+        file.update(Some(stops(600)), health(later), later).unwrap();
+        let written: serde_json::Value = serde_json::from_str(&fs::read_to_string(file.path()).unwrap()).unwrap();
+        assert_eq!(written["stops"][0]["nearest"]["distance_m"], 600);
+        assert_eq!(written["written_at"], later.to_rfc3339());
+        fs::remove_file(file.path()).unwrap();
+
+        let down = Health { api_down_since: Some(later.to_rfc3339()), consecutive_api_failures: 1, ..health(later) };
+        file.update(None, down, later).unwrap();
+        let written: serde_json::Value = serde_json::from_str(&fs::read_to_string(file.path()).unwrap()).unwrap();
+        assert_eq!(written["stops"][0]["nearest"]["distance_m"], 600, "a failed fetch keeps the stops last written");
+        assert_eq!(written["health"]["consecutive_api_failures"], 1);
+        fs::remove_file(file.path()).unwrap();
+    }
+}
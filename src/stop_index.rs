@@ -0,0 +1,157 @@
+// Grid over the watched stops, so finding the stop a bus is at checks only the stops near it
+// rather than every stop. Cells are at least as wide as the largest stop radius, so a bus can
+// only be inside the radius of stops in its own cell or the eight around it. For 100 buses
+// against 5000 stops that's about 27 µs instead of 15 ms (benches/stop_index.rs).
+
+use std::collections::HashMap;
+
+use crate::geo::Located;
+
+const METERS_PER_DEGREE: f64 = 6371e3 * std::f64::consts::PI / 180.0; // Along a meridian, on the haversine sphere
+const MARGIN: f64 = 1.01; // Cells a little wider than strictly needed, against rounding at the edges
+const MIN_STOPS: usize = 32; // Below this a plain scan of 100 buses takes only ~0.1 ms anyway
+const MAX_ABS_LAT: f64 = 80.0; // Closer to the poles longitude cells get too wide to help
+
+#[derive(Debug, Default)]
+pub struct StopIndex {
+    grid: Option<Grid>, // None: scan every stop
+}
+
+#[derive(Debug)]
+struct Grid {
+    stops: usize, // How many stops it was built over, to catch being used with another list
+    lat_step: f64,
+    lng_step: f64,
+    cells: HashMap<(i64, i64), Vec<usize>>, // Cell -> positions in the stop list, ascending
+}
+
+impl StopIndex {
+    pub fn new<T: Located>(stops: &[T]) -> StopIndex {
+        let max_radius = stops.iter().map(|stop| stop.radius()).fold(0.0, f64::max);
+        let max_abs_lat = stops.iter().map(|stop| stop.lat().abs()).fold(0.0, f64::max);
+        // Stops near the antimeridian would need cells that wrap around; scanning is simpler
+        let near_antimeridian = stops.iter().any(|stop| stop.lng().abs() > 179.0);
+        if stops.len() < MIN_STOPS || max_radius <= 0.0 || max_abs_lat > MAX_ABS_LAT || near_antimeridian {
+            return StopIndex::default();
+        }
+
+        let lat_step = max_radius / METERS_PER_DEGREE * MARGIN;
+        // A degree of longitude is shortest at the highest latitude a bus in range could be at
+        let widest_lat = (max_abs_lat + lat_step).min(89.0);
+        let lng_step = max_radius / (METERS_PER_DEGREE * widest_lat.to_radians().cos()) * MARGIN;
+
+        let mut cells: HashMap<(i64, i64), Vec<usize>> = HashMap::new();
+        for (i, stop) in stops.iter().enumerate() {
+            cells.entry(cell(stop.lat(), stop.lng(), lat_step, lng_step)).or_default().push(i);
+        }
+        StopIndex { grid: Some(Grid { stops: stops.len(), lat_step, lng_step, cells }) }
+    }
+
+    /// Positions in a list of `stops` stops, in list order, of every stop whose radius could
+    /// contain (lat, lng). None when the index can't say, and every stop needs checking.
+    pub fn candidates(&self, lat: f64, lng: f64, stops: usize) -> Option<Vec<usize>> {
+        let grid = self.grid.as_ref().filter(|grid| grid.stops == stops)?;
+        let (row, col) = cell(lat, lng, grid.lat_step, grid.lng_step);
+        let mut found: Vec<usize> = (row.saturating_sub(1)..=row.saturating_add(1))
+            .flat_map(|r| (col.saturating_sub(1)..=col.saturating_add(1)).map(move |c| (r, c)))
+            .filter_map(|key| grid.cells.get(&key))
+            .flatten()
+            .copied()
+            .collect();
+        found.sort_unstable();
+        Some(found)
+    }
+}
+
+fn cell(lat: f64, lng: f64, lat_step: f64, lng_step: f64) -> (i64, i64) {
+    ((lat / lat_step).floor() as i64, (lng / lng_step).floor() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geo::haversine_distance;
+    use rand::rngs::StdRng;
+    use rand::{RngExt, SeedableRng};
+
+    struct Stop(f64, f64, f64);
+
+    impl Located for Stop {
+        fn lat(&self) -> f64 {
+            self.0
+        }
+        fn lng(&self) -> f64 {
+            self.1
+        }
+        fn radius(&self) -> f64 {
+            self.2
+        }
+    }
+
+    /// The first stop in list order whose radius contains the position, checking every stop
+    fn scan(lat: f64, lng: f64, stops: &[Stop]) -> Option<usize> {
+        stops.iter().position(|stop| haversine_distance(lat, lng, stop.0, stop.1) <= stop.2)
+    }
+
+    /// The same, checking only the index's candidates
+    fn indexed(lat: f64, lng: f64, stops: &[Stop], index: &StopIndex) -> Option<usize> {
+        let candidates = index.candidates(lat, lng, stops.len()).expect("the index is built");
+        candidates.into_iter().find(|&i| haversine_distance(lat, lng, stops[i].0, stops[i].1) <= stops[i].2)
+    }
+
+    fn random_stops(rng: &mut StdRng, n: usize, lat: f64) -> Vec<Stop> {
+        (0..n).map(|_| Stop(lat + rng.random_range(-0.03..0.03), -1.5 + rng.random_range(-0.05..0.05), rng.random_range(50.0..400.0))).collect()
+    }
+
+    #[test]
+    fn indexed_lookup_matches_a_full_scan() {
+        let mut rng = StdRng::seed_from_u64(142);
+        for lat in [53.0, -33.9, 0.0, 79.5] {
+            let stops = random_stops(&mut rng, 500, lat);
+            let index = StopIndex::new(&stops);
+            let mut found = 0;
+            for _ in 0..5000 {
+                let (bus_lat, bus_lng) = (lat + rng.random_range(-0.035..0.035), -1.5 + rng.random_range(-0.055..0.055));
+                let expected = scan(bus_lat, bus_lng, &stops);
+                assert_eq!(indexed(bus_lat, bus_lng, &stops, &index), expected, "at {}, {}", bus_lat, bus_lng);
+                found += expected.is_some() as usize;
+            }
+            assert!(found > 1000, "only {} of the positions were at a stop", found);
+        }
+    }
+
+    #[test]
+    fn buses_on_the_edge_of_a_radius_are_still_found() {
+        let mut rng = StdRng::seed_from_u64(1420);
+        let stops = random_stops(&mut rng, 200, 53.0);
+        let index = StopIndex::new(&stops);
+        for stop in &stops {
+            // Just inside the radius, due north, south, east and west
+            let lat_offset = stop.2 * 0.999 / METERS_PER_DEGREE;
+            let lng_offset = lat_offset / stop.0.to_radians().cos();
+            for (lat, lng) in [(stop.0 + lat_offset, stop.1), (stop.0 - lat_offset, stop.1), (stop.0, stop.1 + lng_offset), (stop.0, stop.1 - lng_offset)] {
+                assert!(scan(lat, lng, &stops).is_some());
+                assert_eq!(indexed(lat, lng, &stops, &index), scan(lat, lng, &stops), "at {}, {}", lat, lng);
+            }
+        }
+    }
+
+    #[test]
+    fn falls_back_to_scanning_where_a_grid_wont_help() {
+        let mut rng = StdRng::seed_from_u64(1421);
+        let few = random_stops(&mut rng, MIN_STOPS - 1, 53.0);
+        assert!(StopIndex::new(&few).candidates(53.0, -1.5, few.len()).is_none());
+
+        let mut polar = random_stops(&mut rng, 100, 53.0);
+        polar.push(Stop(85.0, 10.0, 200.0));
+        assert!(StopIndex::new(&polar).candidates(53.0, -1.5, polar.len()).is_none());
+
+        let mut antimeridian = random_stops(&mut rng, 100, 53.0);
+        antimeridian.push(Stop(-17.0, 179.5, 200.0));
+        assert!(StopIndex::new(&antimeridian).candidates(53.0, -1.5, antimeridian.len()).is_none());
+
+        // Built over one list, asked about another
+        let stops = random_stops(&mut rng, 100, 53.0);
+        assert!(StopIndex::new(&stops).candidates(53.0, -1.5, 99).is_none());
+    }
+}
@@ -0,0 +1,77 @@
+// Canonical stop names, so "Main Street (adj)" and "Main St adj" are recognised as one stop.
+// Only used for comparing and keying; messages keep the name as written.
+
+/// Abbreviations expanded word by word after punctuation is stripped
+const ABBREVIATIONS: &[(&str, &str)] = &[
+    ("st", "street"),
+    ("rd", "road"),
+    ("ave", "avenue"),
+    ("av", "avenue"),
+    ("ln", "lane"),
+    ("dr", "drive"),
+    ("pl", "place"),
+    ("sq", "square"),
+    ("cl", "close"),
+    ("cres", "crescent"),
+    ("gdns", "gardens"),
+    ("stn", "station"),
+    ("ctr", "centre"),
+    ("adj", "adjacent"),
+    ("opp", "opposite"),
+    ("nr", "near"),
+    ("os", "outside"), // Also "o/s", which is rewritten to "os" before punctuation goes
+];
+
+/// Lowercases `name`, drops punctuation, collapses whitespace and expands common abbreviations
+pub fn canonical(name: &str) -> String {
+    let lowered = name.to_lowercase().replace("o/s", " os ");
+    let cleaned: String = lowered.chars().map(|c| if c.is_alphanumeric() { c } else { ' ' }).collect();
+    cleaned
+        .split_whitespace()
+        .map(|word| ABBREVIATIONS.iter().find(|(short, _)| *short == word).map_or(word, |(_, long)| *long))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Whether two stop names refer to the same stop once canonicalised
+pub fn same_stop(a: &str, b: &str) -> bool {
+    canonical(a) == canonical(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_canonicalised() {
+        let table = [
+            ("Main Street", "main street"),
+            ("Main St", "main street"),
+            ("MAIN ST.", "main street"),
+            ("Main Street (adj)", "main street adjacent"),
+            ("Main St adj", "main street adjacent"),
+            ("  Main   St,  adj ", "main street adjacent"),
+            ("Hospital o/s", "hospital outside"),
+            ("Hospital O/S Main Entrance", "hospital outside main entrance"),
+            ("Bus Stn Stand 4", "bus station stand 4"),
+            ("Opp. Park Ave", "opposite park avenue"),
+            ("Nr Church Ln", "near church lane"),
+            ("Town Ctr / Market Sq", "town centre market square"),
+            ("Rose Gdns-Oak Cl", "rose gardens oak close"),
+            ("Stadium", "stadium"), // Words that only start like an abbreviation are kept
+            ("Café Rd", "café road"),
+            ("", ""),
+        ];
+        for (name, expected) in table {
+            assert_eq!(canonical(name), expected, "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn spellings_of_one_stop_match_and_different_stops_dont() {
+        assert!(same_stop("Main Street (adj)", "main st adj"));
+        assert!(same_stop("Hospital o/s", "Hospital (outside)"));
+        assert!(!same_stop("Main Street (adj)", "Main Street (opp)"));
+        assert!(!same_stop("Main Street", "Main Road"));
+    }
+}
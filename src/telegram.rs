@@ -0,0 +1,943 @@
+// Telegram Bot API client: alert delivery and the inline "mute" / "stop" buttons
+
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use reqwest::Client;
+use serde_json::{json, Value};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use tokio::sync::mpsc;
+use tokio::time::{self, Duration, Instant};
+use tracing::{error, info, warn};
+
+use crate::commands::{self, Response};
+use crate::config_file::ConfigFile;
+use crate::error::TrackerError;
+use crate::format::Style;
+use crate::session::SharedStatus;
+use crate::timezone::TimeWindow;
+use crate::{env_flag, env_or_file, env_parse, env_parse_opt, json_string, secret};
+
+const DEFAULT_API_URL: &str = "https://api.telegram.org";
+const MUTE_MINUTES: i64 = 30;
+const LONG_POLL_SECS: u64 = 30;
+const LONG_POLL_TIMEOUT: Duration = Duration::from_secs(LONG_POLL_SECS + 10); // Overrides HTTP_TIMEOUT_SECS for long polls
+const MAX_SENT_ALERTS: usize = 100; // Alerts remembered for their "stop after this bus" button
+
+/// Until when Telegram has asked us to stop calling it (a 429 with retry_after). Clones of a
+/// Telegram share one, so the notifiers, the live message and the update poller all hold back
+/// together, not just the call that got the 429.
+#[derive(Clone)]
+struct RateLimit {
+    until: Arc<Mutex<Option<Instant>>>,
+    clock: Arc<dyn Fn() -> Instant + Send + Sync>, // Instant::now outside tests
+}
+
+impl RateLimit {
+    fn new(clock: impl Fn() -> Instant + Send + Sync + 'static) -> RateLimit {
+        RateLimit { until: Arc::new(Mutex::new(None)), clock: Arc::new(clock) }
+    }
+
+    fn now(&self) -> Instant {
+        (self.clock)()
+    }
+}
+
+impl std::fmt::Debug for RateLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("RateLimit").field("until", &self.until).finish_non_exhaustive()
+    }
+}
+
+/// State changed from Telegram (via the alert buttons) and read by the main loop
+#[derive(Debug, Default)]
+pub struct Controls {
+    pub muted_until: Option<DateTime<Utc>>,
+    pub muted_for_run: bool,
+    pub stop_requested: bool,           // End the run now ("stop" on an alert no longer remembered)
+    pub stop_after: Option<AlertedBus>, // End the run once this bus reaches its stop
+}
+
+/// The bus an alert with buttons was about, so "stop after this bus" can wait for it
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlertedBus {
+    pub key: String, // Vehicle::key
+    pub service: String,
+    pub vehicle: String, // Vehicle::identifier, for messages
+    pub stop: String,
+}
+
+/// An alert sent with buttons, remembered so a press on it can tell which bus it was about
+#[derive(Debug, Clone)]
+struct SentAlert {
+    chat_id: String,
+    message_id: i64,
+    bus: AlertedBus,
+}
+
+pub type SharedControls = Arc<Mutex<Controls>>;
+
+impl Controls {
+    pub fn is_muted(&self, now: DateTime<Utc>) -> bool {
+        self.muted_for_run || self.muted_until.is_some_and(|until| now < until)
+    }
+}
+
+/// What a button press asks for, decoded from its callback_data
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ButtonAction {
+    Mute30,
+    MuteRun,
+    Stop,
+}
+
+impl ButtonAction {
+    fn from_callback_data(data: &str) -> Option<ButtonAction> {
+        match data {
+            "mute:30" => Some(ButtonAction::Mute30),
+            "mute:run" => Some(ButtonAction::MuteRun),
+            "stop" => Some(ButtonAction::Stop),
+            _ => None,
+        }
+    }
+
+    /// Applies the action and returns the confirmation shown to the user. `bus` is the one the
+    /// pressed alert was about, if it's still remembered.
+    fn apply(self, controls: &mut Controls, bus: Option<AlertedBus>, now: DateTime<Utc>, style: &Style) -> String {
+        match self {
+            ButtonAction::Mute30 => {
+                let until = now + ChronoDuration::minutes(MUTE_MINUTES);
+                controls.muted_until = Some(until);
+                format!("Muted until {}", style.time(until))
+            }
+            ButtonAction::MuteRun => {
+                controls.muted_for_run = true;
+                "Muted for the rest of this run".to_string()
+            }
+            ButtonAction::Stop => match bus {
+                Some(bus) => {
+                    let confirmation = format!("Got it, tracking stops once bus {} ({}) reaches {}", bus.service, bus.vehicle, bus.stop);
+                    controls.stop_after = Some(bus);
+                    confirmation
+                }
+                None => {
+                    controls.stop_requested = true;
+                    "Got it, tracking stopped".to_string()
+                }
+            },
+        }
+    }
+}
+
+/// The chat (and forum topic) a command came from, so replies land in the same place
+#[derive(Debug, Clone)]
+pub struct CommandOrigin {
+    pub chat_id: Value,
+    pub thread_id: Option<i64>,
+}
+
+impl CommandOrigin {
+    fn of(message: &Value) -> CommandOrigin {
+        CommandOrigin {
+            chat_id: message["chat"]["id"].clone(),
+            thread_id: message["message_thread_id"].as_i64(),
+        }
+    }
+}
+
+/// Commands the main loop answers, since it owns the API client and the history database
+#[derive(Debug, Clone)]
+pub enum LoopRequest {
+    Now(CommandOrigin),
+    History { origin: CommandOrigin, service: Option<String>, page: usize },
+    Extend { origin: CommandOrigin, minutes: Option<u64> },
+    Follow { origin: CommandOrigin, fleet: Option<String> },
+}
+
+/// The "More" button under a page of /history
+pub fn history_keyboard(next_page: usize, service: Option<&str>) -> Value {
+    json!({
+        "inline_keyboard": [[
+            { "text": "More", "callback_data": format!("history:{}:{}", next_page, service.unwrap_or_default()) },
+        ]]
+    })
+}
+
+/// Decodes "history:<page>:<service>" (service empty for all) from a "More" button
+fn parse_history_callback(data: &str) -> Option<(usize, Option<String>)> {
+    let (page, service) = data.strip_prefix("history:")?.split_once(':')?;
+    Some((page.parse().ok()?, (!service.is_empty()).then(|| service.to_string())))
+}
+
+/// Why a message didn't go out
+#[derive(Debug, Error)]
+pub enum SendError {
+    #[error("{0}")]
+    Http(#[from] reqwest::Error), // Telegram unreachable, or its reply wasn't JSON
+    #[error("{description} (error_code {code})")]
+    Api {
+        code: i64, // error_code, e.g. 400 for "chat not found", 429 when rate limited
+        description: String,
+        retry_after: Option<u64>, // Seconds, sent with 429s
+    },
+    #[error("held back until Telegram's rate limit passes")]
+    RateLimited { until: Instant }, // Not sent: an earlier send got a 429
+}
+
+impl SendError {
+    /// Network trouble, rate limits and server errors are worth retrying; a 400 will fail again
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            SendError::Http(_) => true,
+            SendError::Api { code, .. } => *code == 429 || *code >= 500,
+            SendError::RateLimited { .. } => true,
+        }
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            SendError::Api { retry_after: Some(secs), .. } => Some(Duration::from_secs(*secs)),
+            SendError::RateLimited { until } => Some(until.saturating_duration_since(Instant::now())),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Telegram {
+    client: Client,
+    base_url: String,
+    token: String,
+    chat_id: String,
+    group_chats: HashMap<String, Vec<String>>, // Stop group -> chats that get its alerts instead
+    thread_id: Option<i64>,            // Forum topic to post into (TELEGRAM_THREAD_ID)
+    silent_hours: Option<TimeWindow>, // Sends are silent (no buzz) inside this window
+    style: Style,
+    min_backoff: Duration, // A 429 holds every send back at least this long (TELEGRAM_MIN_BACKOFF_SECS)
+    rate_limit: RateLimit,
+    sent_alerts: Arc<Mutex<VecDeque<SentAlert>>>, // Newest last; shared by clones, like the rate limit
+}
+
+impl Telegram {
+    /// Reads the bot settings from the environment, falling back to the config file
+    pub fn from_env(client: Client, style: Style, file: &ConfigFile) -> Result<Telegram, TrackerError> {
+        let group_chats = file
+            .groups
+            .iter()
+            .filter(|(_, group)| !group.telegram_chat_ids.is_empty())
+            .map(|(name, group)| (name.clone(), group.telegram_chat_ids.clone()))
+            .collect();
+        let silent_hours = match env_or_file("SILENT_HOURS")? {
+            Some(value) => Some(TimeWindow::parse(&value).ok_or_else(|| "SILENT_HOURS must look like 22:00-07:00.".to_string())?),
+            None => None,
+        };
+
+        Ok(Telegram {
+            client,
+            base_url: api_url()?,
+            token: secret("TELEGRAM_BOT_TOKEN")?
+                .or_else(|| file.telegram_bot_token.clone())
+                .ok_or_else(|| "Missing TELEGRAM_BOT_TOKEN in .env".to_string())?,
+            chat_id: secret("TELEGRAM_CHAT_ID")?
+                .or_else(|| file.telegram_chat_id.clone())
+                .ok_or_else(|| "Missing TELEGRAM_CHAT_ID in .env".to_string())?,
+            group_chats,
+            thread_id: env_parse_opt("TELEGRAM_THREAD_ID")?,
+            silent_hours,
+            style,
+            min_backoff: Duration::from_secs(env_parse("TELEGRAM_MIN_BACKOFF_SECS", 5)?),
+            rate_limit: RateLimit::new(Instant::now),
+            sent_alerts: Arc::default(),
+        })
+    }
+
+    /// POSTs one Bot API method and returns the raw reply. Only `request` calls this.
+    async fn call(&self, method: &str, body: &Value, timeout: Option<Duration>) -> Result<Value, reqwest::Error> {
+        let url = format!("{}/bot{}/{}", self.base_url, self.token, method);
+        let mut request = self.client.post(&url).json(body);
+        if let Some(timeout) = timeout {
+            request = request.timeout(timeout);
+        }
+        request.send().await?.json::<Value>().await
+    }
+
+    /// getUpdates, with a request timeout long enough for Telegram to hold the poll open
+    async fn get_updates(&self, body: &Value) -> Result<Value, SendError> {
+        self.request("getUpdates", body, Some(LONG_POLL_TIMEOUT)).await
+    }
+
+    /// Checks the token with getMe and every configured chat with getChat, logging the
+    /// bot's username and chat titles, or explaining what's wrong
+    pub async fn validate(&self) -> Result<(), TrackerError> {
+        let me = self.send("getMe", &json!({})).await.map_err(|e| match e {
+            SendError::Api { .. } => TrackerError::Notify(format!("Telegram rejected the bot token: {}", e)),
+            _ => TrackerError::Notify(format!("Could not reach Telegram to check the bot token: {}", e)),
+        })?;
+        info!("Telegram bot: @{}", me["username"].as_str().unwrap_or("?"));
+
+        // Each chat once: groups can share a chat, or use the main one
+        let group_chats: BTreeSet<&String> = self.group_chats.values().flatten().filter(|chat_id| **chat_id != self.chat_id).collect();
+        let chats = std::iter::once(&self.chat_id).chain(group_chats).cloned().collect::<Vec<_>>();
+        for chat_id in chats {
+            let result = self.send("getChat", &json!({ "chat_id": chat_id })).await.map_err(|e| match e {
+                SendError::Api { ref description, .. } if description.contains("chat not found") => {
+                    TrackerError::Notify(format!("Telegram chat {} not found — did you /start the bot?", chat_id))
+                }
+                SendError::Api { .. } => TrackerError::Notify(format!("Telegram chat {}: {}", chat_id, e)),
+                _ => TrackerError::Notify(format!("Could not reach Telegram to check chat {}: {}", chat_id, e)),
+            })?;
+            let title = result["title"].as_str().or(result["username"].as_str()).or(result["first_name"].as_str());
+            info!("Telegram chat {}: {}", chat_id, title.unwrap_or("(untitled)"));
+        }
+
+        Ok(())
+    }
+
+    /// Adds the forum topic and silent-hours options that apply to every message we send
+    fn with_send_options(&self, mut body: Value) -> Value {
+        if let Some(thread_id) = self.thread_id {
+            body["message_thread_id"] = json!(thread_id);
+        }
+
+        if self.in_silent_hours() {
+            body["disable_notification"] = json!(true);
+        }
+
+        body
+    }
+
+    /// Whether it's currently inside SILENT_HOURS
+    pub fn in_silent_hours(&self) -> bool {
+        let now = self.style.zone.local_time(Utc::now()).time();
+        self.silent_hours.is_some_and(|window| window.contains(now))
+    }
+
+    /// Chats allowed to control the tracker: the main chat plus any group-routed ones
+    fn is_authorised(&self, chat_id: &Value) -> bool {
+        let Some(chat_id) = json_string(chat_id) else {
+            return false;
+        };
+        chat_id == self.chat_id || self.group_chats.values().flatten().any(|chat| *chat == chat_id)
+    }
+
+    /// The chats that get alerts for `group`: its routed chats, or the main chat
+    pub fn alert_chats(&self, group: Option<&str>) -> Vec<String> {
+        match group.and_then(|g| self.group_chats.get(g)) {
+            Some(chats) => chats.clone(),
+            None => vec![self.chat_id.clone()],
+        }
+    }
+
+    /// Sends `text` to one chat, with the inline mute/stop keyboard for an alert about `bus` if
+    /// given, and returns the new message's id. `silent` overrides SILENT_HOURS when given.
+    pub async fn send_to(&self, chat_id: &str, text: &str, bus: Option<&AlertedBus>, silent: Option<bool>) -> Result<i64, SendError> {
+        let mut body = self.with_send_options(json!({
+            "chat_id": chat_id,
+            "text": text,
+        }));
+        if bus.is_some() {
+            body["reply_markup"] = alert_keyboard();
+        }
+        if let Some(silent) = silent {
+            body["disable_notification"] = json!(silent);
+        }
+        let result = self.send("sendMessage", &body).await?;
+        let message_id = result["message_id"].as_i64().unwrap_or_default();
+        if let Some(bus) = bus {
+            let mut sent = self.sent_alerts.lock().unwrap();
+            if sent.len() >= MAX_SENT_ALERTS {
+                sent.pop_front();
+            }
+            sent.push_back(SentAlert { chat_id: chat_id.to_string(), message_id, bus: bus.clone() });
+        }
+        Ok(message_id)
+    }
+
+    /// The bus an alert sent to `chat_id` as `message_id` was about, if it's still remembered
+    fn alerted_bus(&self, chat_id: &Value, message_id: i64) -> Option<AlertedBus> {
+        let chat_id = json_string(chat_id)?;
+        let sent = self.sent_alerts.lock().unwrap();
+        sent.iter().rev().find(|alert| alert.chat_id == chat_id && alert.message_id == message_id).map(|alert| alert.bus.clone())
+    }
+
+    /// Calls a Bot API method and returns its result, turning an `ok: false` reply into an error.
+    /// Every method goes through here, so nothing is called while a rate limit is in force and
+    /// any 429 starts one.
+    async fn send(&self, method: &str, body: &Value) -> Result<Value, SendError> {
+        self.request(method, body, None).await.map(|mut response| response["result"].take())
+    }
+
+    /// `send` with the whole reply, and a request timeout other than HTTP_TIMEOUT_SECS if given
+    async fn request(&self, method: &str, body: &Value, timeout: Option<Duration>) -> Result<Value, SendError> {
+        if let Some(until) = self.blocked_until() {
+            return Err(SendError::RateLimited { until });
+        }
+        let response = self.call(method, body, timeout).await?;
+        if response["ok"].as_bool() == Some(true) {
+            return Ok(response);
+        }
+        let code = response["error_code"].as_i64().unwrap_or_default();
+        let retry_after = response["parameters"]["retry_after"].as_u64();
+        if code == 429 {
+            self.hold_sends(retry_after);
+        }
+        Err(SendError::Api {
+            code,
+            description: response["description"].as_str().unwrap_or("unknown error").to_string(),
+            retry_after,
+        })
+    }
+
+    /// When sends may start again after a 429, if that's still to come
+    pub fn blocked_until(&self) -> Option<Instant> {
+        let now = self.rate_limit.now();
+        self.rate_limit.until.lock().unwrap().filter(|until| *until > now)
+    }
+
+    /// Holds back every send for Telegram's retry_after (at least TELEGRAM_MIN_BACKOFF_SECS)
+    fn hold_sends(&self, retry_after: Option<u64>) {
+        let wait = Duration::from_secs(retry_after.unwrap_or_default()).max(self.min_backoff);
+        let until = self.rate_limit.now() + wait;
+        let mut blocked = self.rate_limit.until.lock().unwrap();
+        if blocked.is_none_or(|current| current < until) {
+            *blocked = Some(until);
+            warn!("Telegram rate limit: holding all Telegram sends for {} s", wait.as_secs());
+        }
+    }
+
+    async fn answer_callback(&self, query_id: &str, text: &str, show_alert: bool) -> Result<(), SendError> {
+        let body = json!({
+            "callback_query_id": query_id,
+            "text": text,
+            "show_alert": show_alert,
+        });
+        self.send("answerCallbackQuery", &body).await?;
+        Ok(())
+    }
+
+    /// Handles a press of one of the alert buttons, or a /history "More" button
+    async fn handle_callback(&self, query: &Value, controls: &SharedControls, requests: &mpsc::Sender<LoopRequest>) -> Result<(), SendError> {
+        let query_id = query["id"].as_str().unwrap_or_default();
+        let message = &query["message"];
+
+        // Only the configured chats may control the tracker
+        if !self.is_authorised(&message["chat"]["id"]) {
+            warn!("Ignoring button press from unauthorised chat {}", message["chat"]["id"]);
+            return self.answer_callback(query_id, "This chat is not allowed to control the tracker.", true).await;
+        }
+
+        if let Some((page, service)) = query["data"].as_str().and_then(parse_history_callback) {
+            let request = LoopRequest::History { origin: CommandOrigin::of(message), service, page };
+            let toast = if requests.try_send(request).is_ok() { "" } else { "Busy, please try again in a moment." };
+            return self.answer_callback(query_id, toast, false).await;
+        }
+
+        let action = match query["data"].as_str().and_then(ButtonAction::from_callback_data) {
+            Some(action) => action,
+            None => return self.answer_callback(query_id, "Unknown action.", true).await,
+        };
+
+        let bus = self.alerted_bus(&message["chat"]["id"], message["message_id"].as_i64().unwrap_or_default());
+        let confirmation = action.apply(&mut controls.lock().unwrap(), bus, Utc::now(), &self.style);
+        info!("Telegram button pressed: {}", confirmation);
+        self.answer_callback(query_id, &confirmation, false).await?;
+
+        // Edit the original alert to show what was done, which also removes the buttons
+        let text = format!("{}\n\n{}", message["text"].as_str().unwrap_or_default(), confirmation);
+        let body = json!({
+            "chat_id": message["chat"]["id"],
+            "message_id": message["message_id"],
+            "text": text,
+        });
+        self.send("editMessageText", &body).await?;
+        Ok(())
+    }
+
+    /// Replies to a text command such as /status, or hands /now, /history, /extend and /follow to the main loop
+    async fn handle_command(&self, message: &Value, status: &SharedStatus, requests: &mpsc::Sender<LoopRequest>) -> Result<(), SendError> {
+        let Some(text) = message["text"].as_str().filter(|text| text.starts_with('/')) else {
+            return Ok(());
+        };
+        if !self.is_authorised(&message["chat"]["id"]) {
+            warn!("Ignoring command from unauthorised chat {}", message["chat"]["id"]);
+            return Ok(());
+        }
+
+        let origin = CommandOrigin::of(message);
+        let request = match commands::reply_to(text, status, &self.style) {
+            Some(Response::Text(reply)) => return self.reply(&origin, &reply, None).await,
+            Some(Response::NearestBuses) => LoopRequest::Now(origin.clone()),
+            Some(Response::History { service }) => LoopRequest::History { origin: origin.clone(), service, page: 0 },
+            Some(Response::Extend { minutes }) => LoopRequest::Extend { origin: origin.clone(), minutes },
+            Some(Response::Follow { fleet }) => LoopRequest::Follow { origin: origin.clone(), fleet },
+            None => return Ok(()),
+        };
+        if requests.try_send(request).is_err() {
+            return self.reply(&origin, "Busy, please try again in a moment.", None).await;
+        }
+        Ok(())
+    }
+
+    /// Sends `text` to the chat (and forum topic) a command came from, with optional inline buttons
+    pub async fn reply(&self, to: &CommandOrigin, text: &str, keyboard: Option<Value>) -> Result<(), SendError> {
+        let mut body = json!({
+            "chat_id": to.chat_id,
+            "text": text,
+        });
+        if let Some(thread_id) = to.thread_id {
+            body["message_thread_id"] = json!(thread_id);
+        }
+        if let Some(keyboard) = keyboard {
+            body["reply_markup"] = keyboard;
+        }
+        self.send("sendMessage", &body).await?;
+        Ok(())
+    }
+
+    /// Sends a plain message to the main chat and returns its id so it can be edited later
+    pub async fn send_message(&self, text: &str) -> Result<i64, SendError> {
+        self.send_to(&self.chat_id, text, None, None).await
+    }
+
+    /// Replaces the text of a message we sent earlier
+    async fn edit_message(&self, message_id: i64, text: &str) -> Result<(), SendError> {
+        let body = json!({
+            "chat_id": self.chat_id,
+            "message_id": message_id,
+            "text": text,
+        });
+        self.send("editMessageText", &body).await?;
+        Ok(())
+    }
+}
+
+/// A single "tracking" message that is edited in place each cycle (LIVE_MESSAGE mode).
+/// After too many consecutive failed edits it disables itself and normal alerts carry on alone.
+#[derive(Debug)]
+pub struct LiveMessage {
+    message_id: Option<i64>,
+    last_body: Option<String>, // Last successfully shown content, excluding the "updated" time
+    next_edit: Option<Instant>, // Earliest time we may edit again (rate limiting)
+    min_edit_interval: Duration,
+    failures: u32,
+    max_failures: u32,
+}
+
+impl LiveMessage {
+    pub fn from_env() -> Result<Option<LiveMessage>, String> {
+        if !env_flag("LIVE_MESSAGE", false)? {
+            return Ok(None);
+        }
+
+        Ok(Some(LiveMessage {
+            message_id: None,
+            last_body: None,
+            next_edit: None,
+            min_edit_interval: Duration::from_secs(env_parse("LIVE_EDIT_MIN_SECS", 5)?),
+            failures: 0,
+            max_failures: env_parse("LIVE_MAX_FAILURES", 3)?,
+        }))
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.failures < self.max_failures
+    }
+
+    /// Sends the initial message, or edits it if the content changed and we're not rate limited
+    pub async fn update(&mut self, telegram: &Telegram, updated_at: &str, body: &str) {
+        if !self.is_active() || self.last_body.as_deref() == Some(body) {
+            return;
+        }
+        if self.next_edit.is_some_and(|next| Instant::now() < next) || telegram.blocked_until().is_some() {
+            return; // Try again next cycle with whatever the text is then
+        }
+
+        let text = format!("Tracking buses (updated {})\n{}", updated_at, body);
+        let result = match self.message_id {
+            None => telegram.send_message(&text).await.map(|id| self.message_id = Some(id)),
+            Some(message_id) => telegram.edit_message(message_id, &text).await,
+        };
+
+        let mut wait = self.min_edit_interval;
+        let succeeded = match result {
+            Ok(()) => true,
+            // Telegram rejects edits that don't change anything; that's not a failure
+            Err(SendError::Api { description, .. }) if description.contains("message is not modified") => true,
+            Err(e) => {
+                if let Some(retry_after) = e.retry_after() {
+                    wait = wait.max(retry_after);
+                }
+                error!("Error updating live Telegram message: {}", e);
+                false
+            }
+        };
+
+        self.next_edit = Some(Instant::now() + wait);
+        if succeeded {
+            self.failures = 0;
+            self.last_body = Some(body.to_string());
+        } else {
+            self.failures += 1;
+            if !self.is_active() {
+                warn!("Live message failed {} times in a row; falling back to per-event messages only.", self.failures);
+            }
+        }
+    }
+}
+
+fn alert_keyboard() -> Value {
+    json!({
+        "inline_keyboard": [
+            [
+                { "text": "Mute 30 min", "callback_data": "mute:30" },
+                { "text": "Mute rest of run", "callback_data": "mute:run" },
+            ],
+            [
+                { "text": "Got it (stop after this bus)", "callback_data": "stop" },
+            ],
+        ]
+    })
+}
+
+fn api_url() -> Result<String, String> {
+    Ok(env_or_file("TELEGRAM_API_URL")?.unwrap_or_else(|| DEFAULT_API_URL.to_string()))
+}
+
+/// Waits up to `timeout` for someone to send /start to the bot and returns that chat's id.
+/// Used by `setup` so nobody has to dig the chat id out of the API by hand.
+pub async fn wait_for_start(client: &Client, token: &str, timeout: Duration) -> Result<String, String> {
+    let url = format!("{}/bot{}/getUpdates", api_url()?, token);
+    let deadline = Instant::now() + timeout;
+    let mut offset: i64 = 0;
+
+    while Instant::now() < deadline {
+        let body = json!({
+            "offset": offset,
+            "timeout": LONG_POLL_SECS.min(deadline.saturating_duration_since(Instant::now()).as_secs()),
+            "allowed_updates": ["message"],
+        });
+        let response = client
+            .post(&url)
+            .json(&body)
+            .timeout(LONG_POLL_TIMEOUT)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| format!("Could not reach Telegram: {}", e))?
+            .json::<Value>()
+            .await
+            .map_err(|e| format!("Unexpected reply from Telegram: {}", e))?;
+
+        for update in response["result"].as_array().into_iter().flatten() {
+            if let Some(id) = update["update_id"].as_i64() {
+                offset = offset.max(id + 1);
+            }
+            let message = &update["message"];
+            if message["text"].as_str().is_some_and(|text| text.starts_with("/start")) {
+                if let Some(chat_id) = json_string(&message["chat"]["id"]) {
+                    return Ok(chat_id);
+                }
+            }
+        }
+    }
+
+    Err("No /start message arrived in time.".to_string())
+}
+
+/// Long-polls getUpdates for the lifetime of the process, applying button presses to `controls`
+/// and answering commands from the latest `status`
+pub async fn poll_updates(telegram: Telegram, controls: SharedControls, status: SharedStatus, requests: mpsc::Sender<LoopRequest>) {
+    let mut offset: i64 = 0;
+
+    loop {
+        let body = json!({
+            "offset": offset,
+            "timeout": LONG_POLL_SECS,
+            "allowed_updates": ["callback_query", "message"],
+        });
+
+        let response = match telegram.get_updates(&body).await {
+            Ok(response) => response,
+            Err(SendError::RateLimited { until }) => {
+                time::sleep_until(until).await;
+                continue;
+            }
+            Err(e @ SendError::Api { .. }) => {
+                error!("Error polling Telegram updates: {}", e);
+                time::sleep(Duration::from_secs(LONG_POLL_SECS)).await;
+                continue;
+            }
+            Err(e) => {
+                error!("Error polling Telegram updates: {}", e);
+                time::sleep(Duration::from_secs(5)).await;
+                continue;
+            }
+        };
+
+        for update in response["result"].as_array().into_iter().flatten() {
+            if let Some(id) = update["update_id"].as_i64() {
+                offset = offset.max(id + 1);
+            }
+
+            if update["callback_query"].is_object() {
+                if let Err(e) = telegram.handle_callback(&update["callback_query"], &controls, &requests).await {
+                    error!("Error handling Telegram button press: {}", e);
+                }
+            }
+            if update["message"].is_object() {
+                if let Err(e) = telegram.handle_command(&update["message"], &status, &requests).await {
+                    error!("Error handling Telegram command: {}", e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, MockServer};
+
+    const RATE_LIMITED: &str = r#"{"ok":false,"error_code":429,"description":"Too Many Requests: retry after 30","parameters":{"retry_after":30}}"#;
+    const SENT: &str = r#"{"ok":true,"result":{"message_id":7}}"#;
+
+    /// A client for `server` whose rate limit reads the time from `clock`
+    fn telegram(server: &MockServer, clock: &Arc<Mutex<Instant>>) -> Telegram {
+        let clock = clock.clone();
+        Telegram {
+            client: Client::new(),
+            base_url: server.url.clone(),
+            token: "TOKEN".to_string(),
+            chat_id: "1".to_string(),
+            group_chats: HashMap::new(),
+            thread_id: None,
+            silent_hours: None,
+            style: test_support::with_env(&[], Style::from_env).unwrap(),
+            min_backoff: Duration::from_secs(5),
+            rate_limit: RateLimit::new(move || *clock.lock().unwrap()),
+            sent_alerts: Arc::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn calls_after_a_429_wait_for_retry_after() {
+        let server = MockServer::start(vec![(429, RATE_LIMITED.to_string()), (200, SENT.to_string())]);
+        let clock = Arc::new(Mutex::new(Instant::now()));
+        let telegram = telegram(&server, &clock);
+        let origin = CommandOrigin { chat_id: json!(1), thread_id: None };
+
+        let error = telegram.send_message("first").await.unwrap_err();
+        assert!(matches!(error, SendError::Api { code: 429, retry_after: Some(30), .. }), "{:?}", error);
+
+        // Every method is held back, not just sendMessage
+        *clock.lock().unwrap() += Duration::from_secs(29);
+        assert!(matches!(telegram.send_message("second").await, Err(SendError::RateLimited { .. })));
+        assert!(matches!(telegram.reply(&origin, "/status reply", None).await, Err(SendError::RateLimited { .. })));
+        assert!(matches!(telegram.answer_callback("query", "Muted", false).await, Err(SendError::RateLimited { .. })));
+        assert!(matches!(telegram.edit_message(7, "edited").await, Err(SendError::RateLimited { .. })));
+        assert_eq!(server.requests().len(), 1);
+
+        *clock.lock().unwrap() += Duration::from_secs(2);
+        assert_eq!(telegram.send_message("third").await.unwrap(), 7);
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!((requests[1].method.as_str(), requests[1].path.as_str()), ("POST", "/botTOKEN/sendMessage"));
+        assert_eq!(requests[1].header("content-type"), Some("application/json"));
+        assert!(requests[1].body.contains("third"));
+    }
+
+    #[tokio::test]
+    async fn clones_share_the_rate_limit() {
+        let server = MockServer::start(vec![(429, RATE_LIMITED.to_string())]);
+        let clock = Arc::new(Mutex::new(Instant::now()));
+        let telegram = telegram(&server, &clock);
+        let poller = telegram.clone();
+
+        let _ = telegram.send_message("alert").await;
+        assert!(matches!(poller.get_updates(&json!({})).await, Err(SendError::RateLimited { .. })));
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    /// A client from the environment, with TELEGRAM_API_URL pointed at `server`
+    fn from_env(server: &MockServer) -> Telegram {
+        let vars = [("TELEGRAM_API_URL", server.url.as_str()), ("TELEGRAM_BOT_TOKEN", "TOKEN"), ("TELEGRAM_CHAT_ID", "100")];
+        test_support::with_env(&vars, || Telegram::from_env(Client::new(), Style::from_env().unwrap(), &ConfigFile::default())).unwrap()
+    }
+
+    #[tokio::test]
+    async fn validation_checks_the_token_then_the_chat() {
+        let server = MockServer::start(vec![
+            (200, r#"{"ok":true,"result":{"id":1,"is_bot":true,"username":"bus_bot"}}"#.to_string()),
+            (200, r#"{"ok":true,"result":{"id":100,"type":"private","first_name":"Sam"}}"#.to_string()),
+        ]);
+        from_env(&server).validate().await.unwrap();
+
+        let requests = server.requests();
+        let calls: Vec<(&str, &str)> = requests.iter().map(|request| (request.path.as_str(), request.body.as_str())).collect();
+        assert_eq!(calls, [("/botTOKEN/getMe", "{}"), ("/botTOKEN/getChat", r#"{"chat_id":"100"}"#)]);
+    }
+
+    #[tokio::test]
+    async fn validation_checks_each_chat_once_however_many_groups_share_it() {
+        let server = MockServer::start(vec![
+            (200, r#"{"ok":true,"result":{"id":1,"is_bot":true,"username":"bus_bot"}}"#.to_string()),
+            (200, r#"{"ok":true,"result":{"id":100,"type":"group","title":"Buses"}}"#.to_string()),
+        ]);
+        let file: ConfigFile = toml::from_str(
+            r#"
+            [groups.home]
+            telegram_chat_ids = ["-300", "100", "-200"]
+            [groups.school]
+            telegram_chat_ids = ["-200"]
+            [groups.work]
+            telegram_chat_ids = ["-300"]
+            "#,
+        )
+        .unwrap();
+        let vars = [("TELEGRAM_API_URL", server.url.as_str()), ("TELEGRAM_BOT_TOKEN", "TOKEN"), ("TELEGRAM_CHAT_ID", "100")];
+        let telegram = test_support::with_env(&vars, || Telegram::from_env(Client::new(), Style::from_env().unwrap(), &file)).unwrap();
+        telegram.validate().await.unwrap();
+
+        let bodies: Vec<String> = server.requests().into_iter().skip(1).map(|request| request.body).collect();
+        assert_eq!(bodies, [r#"{"chat_id":"100"}"#, r#"{"chat_id":"-200"}"#, r#"{"chat_id":"-300"}"#]);
+    }
+
+    #[tokio::test]
+    async fn validation_explains_a_bad_token_or_an_unknown_chat() {
+        let bad_token = MockServer::start(vec![(401, r#"{"ok":false,"error_code":401,"description":"Unauthorized"}"#.to_string())]);
+        let error = from_env(&bad_token).validate().await.unwrap_err();
+        assert_eq!(error.to_string(), "Telegram rejected the bot token: Unauthorized (error_code 401)");
+        assert_eq!(bad_token.requests().len(), 1);
+
+        let unknown_chat = MockServer::start(vec![
+            (200, r#"{"ok":true,"result":{"id":1,"username":"bus_bot"}}"#.to_string()),
+            (400, r#"{"ok":false,"error_code":400,"description":"Bad Request: chat not found"}"#.to_string()),
+        ]);
+        let error = from_env(&unknown_chat).validate().await.unwrap_err();
+        assert_eq!(error.to_string(), "Telegram chat 100 not found — did you /start the bot?");
+    }
+
+    #[tokio::test]
+    async fn ok_false_replies_are_errors_whatever_the_http_status() {
+        let server = MockServer::start(vec![(200, r#"{"ok":false,"error_code":400,"description":"Bad Request: message text is empty"}"#.to_string())]);
+        let clock = Arc::new(Mutex::new(Instant::now()));
+        let error = telegram(&server, &clock).send_message("").await.unwrap_err();
+        assert!(matches!(&error, SendError::Api { code: 400, retry_after: None, .. }), "{:?}", error);
+        assert_eq!(error.to_string(), "Bad Request: message text is empty (error_code 400)");
+        assert!(!error.is_retryable());
+    }
+
+    /// A press of `data` on alert 7 in `chat_id`, whose text was "Bus 7 is near Home"
+    fn press(chat_id: i64, data: &str) -> Value {
+        json!({
+            "id": "query",
+            "data": data,
+            "message": { "message_id": 7, "chat": { "id": chat_id }, "text": "Bus 7 is near Home" },
+        })
+    }
+
+    /// The JSON body of each request `server` has had
+    fn bodies(server: &MockServer) -> Vec<Value> {
+        server.requests().iter().map(|request| serde_json::from_str(&request.body).unwrap()).collect()
+    }
+
+    #[tokio::test]
+    async fn mute_30_mutes_then_confirms_in_a_toast_and_the_alert() {
+        let server = MockServer::start(vec![(200, r#"{"ok":true,"result":true}"#.to_string())]);
+        let clock = Arc::new(Mutex::new(Instant::now()));
+        let telegram = telegram(&server, &clock);
+        let controls = SharedControls::default();
+        let (requests, _) = mpsc::channel(1);
+
+        let before = Utc::now();
+        telegram.handle_callback(&press(1, "mute:30"), &controls, &requests).await.unwrap();
+        let until = controls.lock().unwrap().muted_until.expect("muted");
+        assert!(until >= before + ChronoDuration::minutes(30) && until <= Utc::now() + ChronoDuration::minutes(30), "{}", until);
+
+        let confirmation = format!("Muted until {}", telegram.style.time(until));
+        let paths: Vec<String> = server.requests().into_iter().map(|request| request.path).collect();
+        assert_eq!(paths, ["/botTOKEN/answerCallbackQuery", "/botTOKEN/editMessageText"]);
+        assert_eq!(
+            bodies(&server),
+            [
+                json!({ "callback_query_id": "query", "text": confirmation, "show_alert": false }),
+                json!({ "chat_id": 1, "message_id": 7, "text": format!("Bus 7 is near Home\n\n{}", confirmation) }),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn presses_from_other_chats_change_nothing() {
+        let server = MockServer::start(vec![(200, r#"{"ok":true,"result":true}"#.to_string())]);
+        let clock = Arc::new(Mutex::new(Instant::now()));
+        let controls = SharedControls::default();
+        let (requests, _) = mpsc::channel(1);
+
+        for data in ["mute:30", "mute:run", "stop"] {
+            telegram(&server, &clock).handle_callback(&press(999, data), &controls, &requests).await.unwrap();
+        }
+        let controls = controls.lock().unwrap();
+        assert!(controls.muted_until.is_none() && !controls.muted_for_run && !controls.stop_requested && controls.stop_after.is_none(), "{:?}", controls);
+        // Only the refusals went out: no alert was edited
+        let refusal = json!({ "callback_query_id": "query", "text": "This chat is not allowed to control the tracker.", "show_alert": true });
+        assert_eq!(bodies(&server), [refusal.clone(), refusal.clone(), refusal]);
+        assert!(server.requests().iter().all(|request| request.path == "/botTOKEN/answerCallbackQuery"));
+    }
+
+    #[tokio::test]
+    async fn stop_waits_for_the_alerted_bus_unless_the_alert_is_forgotten() {
+        let server = MockServer::start(vec![(200, SENT.to_string())]);
+        let clock = Arc::new(Mutex::new(Instant::now()));
+        let telegram = telegram(&server, &clock);
+        let controls = SharedControls::default();
+        let (requests, _) = mpsc::channel(1);
+        let bus = AlertedBus { key: "fleet:10812".to_string(), service: "7".to_string(), vehicle: "fleet 10812".to_string(), stop: "Home".to_string() };
+
+        assert_eq!(telegram.send_to("1", "Bus 7 is near Home", Some(&bus), None).await.unwrap(), 7);
+        assert_eq!(bodies(&server)[0]["reply_markup"], alert_keyboard());
+        telegram.handle_callback(&press(1, "stop"), &controls, &requests).await.unwrap();
+        {
+            let controls = controls.lock().unwrap();
+            assert_eq!(controls.stop_after.as_ref(), Some(&bus));
+            assert!(!controls.stop_requested);
+        }
+        assert_eq!(bodies(&server)[1]["text"], "Got it, tracking stops once bus 7 (fleet 10812) reaches Home");
+
+        // After a restart the alert isn't remembered, so the same press stops now
+        let restarted = self::telegram(&server, &clock);
+        restarted.handle_callback(&press(1, "stop"), &controls, &requests).await.unwrap();
+        assert!(controls.lock().unwrap().stop_requested);
+        assert_eq!(bodies(&server)[3]["text"], "Got it, tracking stopped");
+    }
+
+    #[tokio::test]
+    async fn group_alerts_go_to_the_group_chats_which_may_press_their_buttons() {
+        let server = MockServer::start(vec![(200, SENT.to_string())]);
+        let clock = Arc::new(Mutex::new(Instant::now()));
+        let mut telegram = telegram(&server, &clock);
+        telegram.group_chats = HashMap::from([("home".to_string(), vec!["20".to_string(), "21".to_string()])]);
+        let controls = SharedControls::default();
+        let (requests, _) = mpsc::channel(1);
+        let bus = AlertedBus { key: "fleet:10812".to_string(), service: "7".to_string(), vehicle: "fleet 10812".to_string(), stop: "Home".to_string() };
+
+        assert_eq!(telegram.alert_chats(Some("home")), ["20", "21"]);
+        assert_eq!(telegram.alert_chats(Some("gym")), ["1"]); // Unrouted: the main chat
+        for chat_id in telegram.alert_chats(Some("home")) {
+            telegram.send_to(&chat_id, "Bus 7 is near Home", Some(&bus), None).await.unwrap();
+        }
+
+        telegram.handle_callback(&press(21, "stop"), &controls, &requests).await.unwrap();
+        assert_eq!(controls.lock().unwrap().stop_after.as_ref(), Some(&bus));
+    }
+
+    #[test]
+    fn rate_limits_and_server_errors_are_retryable_but_bad_requests_are_not() {
+        let api = |code| SendError::Api { code, description: String::new(), retry_after: None };
+        assert!(api(429).is_retryable());
+        assert!(api(500).is_retryable());
+        assert!(api(502).is_retryable());
+        assert!(!api(400).is_retryable());
+        assert!(!api(403).is_retryable());
+        assert!(SendError::RateLimited { until: Instant::now() }.is_retryable());
+    }
+}
@@ -0,0 +1,255 @@
+// Helpers shared by the unit tests. Settings come from the environment, so tests that touch it
+// take one lock and put every variable back afterwards. MockServer stands in for the HTTP APIs
+// the notifiers talk to, recording what they send.
+
+use chrono::{DateTime, TimeZone, Utc};
+use reqwest::Client;
+use std::env;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+
+use tokio::time::{Duration, Instant};
+
+use crate::absence::AbsenceWatch;
+use crate::alerts::AlertTracker;
+use crate::config_file::ConfigFile;
+use crate::health::FailureTracker;
+use crate::notify::Notifiers;
+use crate::session::{Session, SharedStatus};
+use crate::telegram::{SharedControls, Telegram};
+use crate::tracker::{Clock, Tracker};
+use crate::tracks::Tracks;
+use crate::{BusStop, Config, StopsReload};
+
+static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+/// Holds the environment lock; a test that panicked while holding it doesn't poison it for the rest
+pub fn env_lock() -> MutexGuard<'static, ()> {
+    ENV_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Runs `f` with `vars` set, restoring their previous values afterwards
+pub fn with_env<T>(vars: &[(&str, &str)], f: impl FnOnce() -> T) -> T {
+    let _lock = env_lock();
+    let saved: Vec<(String, Option<String>)> = vars.iter().map(|(name, _)| (name.to_string(), env::var(name).ok())).collect();
+    for (name, value) in vars {
+        env::set_var(name, value);
+    }
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f));
+    for (name, value) in saved {
+        match value {
+            Some(value) => env::set_var(&name, value),
+            None => env::remove_var(&name),
+        }
+    }
+    result.unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+}
+
+/// A Config as read from the environment, centred on 53.0,-1.5 with a 1 km radius unless `vars` say otherwise
+pub fn config(vars: &[(&str, &str)]) -> Config {
+    with_env(&config_vars(vars), || Config::from_env(&ConfigFile::default()).expect("test config is valid"))
+}
+
+/// config()'s defaults, overridden or added to by `vars`
+fn config_vars<'a>(vars: &[(&'a str, &'a str)]) -> Vec<(&'a str, &'a str)> {
+    let mut all = vec![("LAT", "53.0"), ("LNG", "-1.5"), ("RADIUS", "1000"), ("TIMEZONE", "Europe/London")];
+    all.retain(|(name, _)| !vars.iter().any(|(set, _)| set == name));
+    all.extend_from_slice(vars);
+    all
+}
+
+/// A session for `config` that started at `started_at`, watching no services for absence
+pub fn session(config: &Config, started_at: DateTime<Utc>) -> Session {
+    let tracks = Tracks::new(config.max_plausible_speed_kmh);
+    let mut session = Session::new(AlertTracker::new(config.alert_cooldown), tracks, None, None, AbsenceWatch::default());
+    session.started_at = started_at;
+    session
+}
+
+/// A stop with the default radius and no restrictions
+pub fn stop(name: &str, lat: f64, lng: f64) -> BusStop {
+    BusStop {
+        name: name.to_string(),
+        lat,
+        lng,
+        radius: crate::DEFAULT_STOP_RADIUS,
+        group: None,
+        services: Vec::new(),
+        days: Vec::new(),
+        quiet: None,
+    }
+}
+
+/// 2024-03-04 08:00:00 UTC (a Monday), a fixed "now" for tests and the one the fixtures' update times are relative to
+pub fn fixed_now() -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(2024, 3, 4, 8, 0, 0).unwrap()
+}
+
+/// fixed_now() when created, moving on with tokio's clock (so with a paused clock too)
+pub struct TestClock(Instant);
+
+impl Clock for TestClock {
+    fn now(&self) -> DateTime<Utc> {
+        fixed_now() + chrono::Duration::from_std(self.0.elapsed()).unwrap()
+    }
+}
+
+/// A tracker for `stops`, fetching buses from `api` and sending through Telegram at `telegram`,
+/// with config()'s settings plus `vars`, and an hour-old session so it isn't warming up.
+/// Like a real one, everything in it shares one client.
+/// Its run hasn't started: `deadline` is now.
+pub fn tracker(api: &MockServer, telegram: &MockServer, vars: &[(&str, &str)], stops: Vec<BusStop>) -> Tracker {
+    let mut all = vec![
+        ("API_URL", api.url.as_str()),
+        ("API_RETRIES", "0"),
+        ("TELEGRAM_API_URL", telegram.url.as_str()),
+        ("TELEGRAM_BOT_TOKEN", "T"),
+        ("TELEGRAM_CHAT_ID", "100"),
+    ];
+    all.retain(|(name, _)| !vars.iter().any(|(set, _)| set == name));
+    all.extend_from_slice(vars);
+    let (client, config, telegram, notifiers) = with_env(&config_vars(&all), || {
+        // http_client()'s user agent without its timeouts, which a paused clock would fire straight away
+        let client = Client::builder().user_agent(crate::USER_AGENT).build().unwrap();
+        let config = Config::from_env(&ConfigFile::default()).expect("test config is valid");
+        let telegram = Telegram::from_env(client.clone(), config.style, &ConfigFile::default()).unwrap();
+        let notifiers = Notifiers::from_env(client.clone(), telegram.clone(), &Default::default()).unwrap();
+        (client, config, telegram, notifiers)
+    });
+    Tracker {
+        client,
+        session: session(&config, fixed_now() - chrono::Duration::hours(1)),
+        config,
+        bus_stops: stops,
+        telegram,
+        notifiers,
+        controls: SharedControls::default(),
+        status: SharedStatus::default(),
+        failures: FailureTracker::new(u32::MAX, u32::MAX),
+        clock: Box::new(TestClock(Instant::now())),
+        latest: None,
+        last_cycle_at: None,
+        reload: StopsReload { config_path: None, profile: None, stops_path: None, input: None },
+        active_stops: Vec::new(),
+        active_date: None,
+        polling: None,
+        summary: false,
+        background: Vec::new(),
+        deadline: Instant::now(),
+        extended: Duration::ZERO,
+        end_warned: false,
+        until_first_match: None,
+        journey: None,
+        followed_first: false,
+        stop_after: None,
+        state_file: None,
+    }
+}
+
+/// Runs `f`, returning what it logged on this thread at debug level and up, one line per event
+/// without timestamps, e.g. ` WARN bus_notification_app: Read coordinate ...`
+pub fn logged<T>(f: impl FnOnce() -> T) -> (T, String) {
+    let buffer = Arc::new(Mutex::new(Vec::new()));
+    let writer = buffer.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::DEBUG)
+        .with_ansi(false)
+        .without_time()
+        .with_writer(move || LogWriter(writer.clone()))
+        .finish();
+    let result = tracing::subscriber::with_default(subscriber, f);
+    let logs = String::from_utf8_lossy(&buffer.lock().unwrap()).into_owned();
+    (result, logs)
+}
+
+struct LogWriter(Arc<Mutex<Vec<u8>>>);
+
+impl Write for LogWriter {
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// One request as the mock server saw it
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    pub path: String, // Including any query
+    pub headers: Vec<(String, String)>, // Names lowercased
+    pub body: String,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+}
+
+/// An HTTP server on a loopback port answering every request with the next canned reply
+/// (the last one repeats), recording each request
+pub struct MockServer {
+    pub url: String,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl MockServer {
+    /// Starts a server; each reply is (status, JSON body)
+    pub fn start(replies: Vec<(u16, String)>) -> MockServer {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        thread::spawn(move || {
+            for (served, stream) in listener.incoming().enumerate() {
+                let Ok(mut stream) = stream else { continue };
+                let Some(request) = read_request(&mut stream) else { continue };
+                recorded.lock().unwrap().push(request);
+                let (status, body) = &replies[served.min(replies.len() - 1)];
+                let response = format!(
+                    "HTTP/1.1 {} X\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+        MockServer { url, requests }
+    }
+
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+fn read_request(stream: &mut std::net::TcpStream) -> Option<Request> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let mut parts = line.split_whitespace();
+    let (method, path) = (parts.next()?.to_string(), parts.next()?.to_string());
+
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':')?;
+        headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+    }
+
+    let length = headers.iter().find(|(name, _)| name == "content-length").and_then(|(_, value)| value.parse().ok()).unwrap_or(0);
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).ok()?;
+    Some(Request { method, path, headers, body: String::from_utf8_lossy(&body).into_owned() })
+}
@@ -0,0 +1,143 @@
+// Timezone used for every human-readable timestamp (logs, alerts, confirmations)
+
+use chrono::{DateTime, Datelike, Local, NaiveDateTime, NaiveTime, TimeZone, Utc, Weekday};
+use chrono_tz::Tz;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Zone {
+    System,    // Whatever the host (or container) considers local time
+    Named(Tz), // IANA name from TIMEZONE, e.g. Europe/London
+}
+
+impl Zone {
+    /// Reads `TIMEZONE`, defaulting to the system's local time
+    pub fn from_env() -> Result<Zone, String> {
+        match crate::env_or_file("TIMEZONE")? {
+            Some(name) if !name.trim().is_empty() => name
+                .trim()
+                .parse()
+                .map(Zone::Named)
+                .map_err(|_| format!("TIMEZONE must be an IANA timezone name such as Europe/London, got '{}'.", name)),
+            _ => Ok(Zone::System),
+        }
+    }
+
+    /// Wall-clock date and time in this zone
+    pub fn local_time(&self, t: DateTime<Utc>) -> NaiveDateTime {
+        match self {
+            Zone::System => t.with_timezone(&Local).naive_local(),
+            Zone::Named(tz) => t.with_timezone(tz).naive_local(),
+        }
+    }
+
+    /// The most recent local midnight at or before `t`
+    pub fn start_of_day(&self, t: DateTime<Utc>) -> DateTime<Utc> {
+        self.instant_at(self.local_time(t).date().and_time(NaiveTime::MIN)).unwrap_or(t)
+    }
+
+    /// The instant a local date and time in this zone refers to (the earlier one if the clocks went back,
+    /// none if they skipped it)
+    pub fn instant_at(&self, local: NaiveDateTime) -> Option<DateTime<Utc>> {
+        match self {
+            Zone::System => Local.from_local_datetime(&local).earliest().map(|d| d.with_timezone(&Utc)),
+            Zone::Named(tz) => tz.from_local_datetime(&local).earliest().map(|d| d.with_timezone(&Utc)),
+        }
+    }
+
+    /// Formats a timestamp in this zone using a chrono format string
+    pub fn format(&self, t: DateTime<Utc>, fmt: &str) -> String {
+        match self {
+            Zone::System => t.with_timezone(&Local).format(fmt).to_string(),
+            Zone::Named(tz) => t.with_timezone(tz).format(fmt).to_string(),
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            Zone::System => "system local time".to_string(),
+            Zone::Named(tz) => tz.name().to_string(),
+        }
+    }
+}
+
+/// A daily time-of-day window such as `22:00-07:00`, which may wrap past midnight
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl TimeWindow {
+    pub fn parse(value: &str) -> Option<TimeWindow> {
+        let (start, end) = value.split_once('-')?;
+        Some(TimeWindow {
+            start: NaiveTime::parse_from_str(start.trim(), "%H:%M").ok()?,
+            end: NaiveTime::parse_from_str(end.trim(), "%H:%M").ok()?,
+        })
+    }
+
+    /// Whether `time` falls inside the window (start inclusive, end exclusive)
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+/// A stop's own quiet hours, which replace SILENT_HOURS for its alerts
+#[derive(Debug, Clone, PartialEq)]
+pub enum QuietHours {
+    Never, // Always alert out loud, even inside SILENT_HOURS
+    During { days: Vec<Weekday>, window: Option<TimeWindow> }, // Empty days: every day; no window: all day
+}
+
+impl QuietHours {
+    /// Whether alerts should be silent at this local time
+    pub fn contains(&self, local: NaiveDateTime) -> bool {
+        match self {
+            QuietHours::Never => false,
+            QuietHours::During { days, window } => {
+                (days.is_empty() || days.contains(&local.weekday())) && window.is_none_or(|window| window.contains(local.time()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::with_env;
+
+    #[test]
+    fn timestamps_render_in_the_configured_zone() {
+        let t = Utc.with_ymd_and_hms(2024, 7, 1, 6, 30, 0).unwrap();
+        let zone = with_env(&[("TIMEZONE", " America/New_York ")], Zone::from_env).unwrap();
+        assert_eq!(zone, Zone::Named(chrono_tz::America::New_York));
+        assert_eq!(zone.format(t, "%Y-%m-%d %H:%M %Z"), "2024-07-01 02:30 EDT");
+        assert_eq!(zone.name(), "America/New_York");
+
+        // Summer and winter time in the same zone
+        let london = Zone::Named(chrono_tz::Europe::London);
+        assert_eq!(london.format(t, "%H:%M"), "07:30");
+        assert_eq!(london.format(Utc.with_ymd_and_hms(2024, 1, 1, 6, 30, 0).unwrap(), "%H:%M"), "06:30");
+    }
+
+    #[test]
+    fn timezone_defaults_to_system_time_and_rejects_unknown_names() {
+        assert_eq!(with_env(&[("TIMEZONE", "")], Zone::from_env), Ok(Zone::System));
+        assert_eq!(
+            with_env(&[("TIMEZONE", "Mars/Olympus_Mons")], Zone::from_env),
+            Err("TIMEZONE must be an IANA timezone name such as Europe/London, got 'Mars/Olympus_Mons'.".to_string())
+        );
+    }
+
+    #[test]
+    fn the_day_starts_at_local_midnight() {
+        let zone = Zone::Named(chrono_tz::Asia::Tokyo);
+        // 2024-03-04 23:30 UTC is 08:30 on the 5th in Tokyo, whose day began at 15:00 UTC
+        let t = Utc.with_ymd_and_hms(2024, 3, 4, 23, 30, 0).unwrap();
+        assert_eq!(zone.start_of_day(t), Utc.with_ymd_and_hms(2024, 3, 4, 15, 0, 0).unwrap());
+    }
+}
@@ -0,0 +1,811 @@
+// The polling loop: fetch, check and notify on a fixed schedule until the run ends.
+// Cycles are driven by a tokio interval, so a slow cycle doesn't push the next one back,
+// and wall-clock time comes from a Clock so the loop can run under a paused tokio clock.
+
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
+use reqwest::Client;
+use serde_json::Value;
+use std::fs;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::mpsc;
+use tokio::task::AbortHandle;
+use tokio::time::{self, Duration, Instant, MissedTickBehavior};
+use tracing::{error, info, warn};
+
+use crate::health::{FailureEvent, FailureTracker};
+use crate::journey::{self, Journey, Progress};
+use crate::notify::Notifiers;
+use crate::session::{self, AlertEvent, Session, SharedStatus};
+use crate::state_file::{self, Health, StateFile, StopState};
+use crate::stop_index::StopIndex;
+use crate::stop_names;
+use crate::telegram::{LoopRequest, SharedControls, Telegram};
+use crate::format::format_duration;
+use crate::{
+    answer_history, answer_now, check_buses, debug_server, fetch_services, group_labels, history_page, usable_vehicles, BusStop, Config, ExitReason, StopsReload,
+    ACTIVATION_FORMAT, NEXT_ACTIVATIONS,
+};
+
+const SUSPEND_GAP_FACTOR: u32 = 5; // A gap this many poll intervals long (and at least a minute) means we were suspended
+const MIN_SUSPEND_GAP: Duration = Duration::from_secs(60);
+const END_WARNING: Duration = Duration::from_secs(5 * 60); // Notify this long before the run ends
+
+/// The signals a running tracker answers
+pub struct Signals {
+    snapshot: Signal,  // SIGUSR1
+    reload: Signal,    // SIGHUP
+    extend: Signal,    // SIGUSR2
+    terminate: Signal, // SIGTERM
+    interrupt: Signal, // SIGINT
+}
+
+impl Signals {
+    /// Starts listening; from here on SIGTERM and SIGINT end the run cleanly rather than the process
+    pub fn listen() -> Result<Signals, String> {
+        let listen = |kind, name| signal(kind).map_err(|e| format!("Could not listen for {}: {}", name, e));
+        Ok(Signals {
+            snapshot: listen(SignalKind::user_defined1(), "SIGUSR1")?,
+            reload: listen(SignalKind::hangup(), "SIGHUP")?,
+            extend: listen(SignalKind::user_defined2(), "SIGUSR2")?,
+            terminate: listen(SignalKind::terminate(), "SIGTERM")?,
+            interrupt: listen(SignalKind::interrupt(), "SIGINT")?,
+        })
+    }
+}
+
+/// Source of wall-clock time for timestamps, alerts and cooldowns
+pub trait Clock: Send {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+pub struct Tracker {
+    pub client: Client,
+    pub config: Config,
+    pub bus_stops: Vec<BusStop>,
+    pub telegram: Telegram,
+    pub notifiers: Notifiers,
+    pub controls: SharedControls,
+    pub status: SharedStatus,
+    pub session: Session,
+    pub failures: FailureTracker,
+    pub clock: Box<dyn Clock>,
+    pub latest: Option<(Instant, Value)>, // The last successful response, for /now
+    pub last_cycle_at: Option<DateTime<Utc>>,
+    pub reload: StopsReload, // Where SIGHUP re-reads the stops from
+    pub active_stops: Vec<BusStop>, // The stops watched today (see BusStop::days)
+    pub active_date: Option<NaiveDate>,
+    pub polling: Option<bool>, // Whether the [schedule] had polling on at the last cycle
+    pub summary: bool,         // Send a session summary at the end (--daemon)
+    pub background: Vec<AbortHandle>, // Tasks serving this run (Telegram updates, debug endpoint), stopped with it
+    pub deadline: Instant,  // When the run ends; set when it starts and pushed out by /extend and SIGUSR2
+    pub extended: Duration, // Added to RUN_MINUTES so far
+    pub end_warned: bool,   // Whether the "tracking ends at" notice went out for the current deadline
+    pub until_first_match: Option<FirstMatch>, // End the run at the first alert it matches (--until-first-match)
+    pub journey: Option<Journey>, // The bus being followed to its stop (/follow, FOLLOW_FIRST_BUS)
+    pub followed_first: bool,     // Whether FOLLOW_FIRST_BUS has adopted its bus this run
+    pub stop_after: Option<Journey>, // The bus whose arrival ends the run ("Got it (stop after this bus)")
+    pub state_file: Option<StateFile>, // Kept up to date after each cycle (--state-file)
+}
+
+/// Which arrival alert ends a --until-first-match run; unset fields match anything
+#[derive(Debug, Clone, Default)]
+pub struct FirstMatch {
+    pub service: Option<String>,
+    pub stop: Option<String>,
+}
+
+impl FirstMatch {
+    fn matches(&self, event: &AlertEvent) -> bool {
+        self.service.as_deref().is_none_or(|service| service.eq_ignore_ascii_case(&event.service_number))
+            && self.stop.as_deref().is_none_or(|stop| stop_names::same_stop(stop, &event.stop))
+    }
+}
+
+impl Tracker {
+    /// Polls every POLL_INTERVAL_SECS until RUN_MINUTES pass, a stop request, SIGTERM or SIGINT, or the
+    /// API giving up. Between cycles it logs a snapshot on SIGUSR1, reloads the stops on SIGHUP, extends
+    /// the run by EXTEND_MINUTES on SIGUSR2 and answers /now, /history, /extend and /follow.
+    /// With ALIGN_POLLS set, cycles land on round wall-clock times instead of counting from startup.
+    /// With REPORT_CLOSEST_APPROACH set, each service's closest approach to a stop is logged at the end,
+    /// and with EXPORT_GEOJSON the run's alerts are written out.
+    pub async fn run(mut self, mut signals: Signals, mut requests: mpsc::Receiver<LoopRequest>) -> ExitReason {
+        self.deadline = Instant::now() + self.config.run_length;
+        self.update_ends_at();
+        let period = Duration::from_secs(self.config.poll_interval_secs);
+        let mut ticker = if self.config.align_polls {
+            time::interval_at(Instant::now() + delay_to_boundary(self.clock.now(), period), period)
+        } else {
+            time::interval(period)
+        };
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        self.refresh_active_stops(self.clock.now());
+        if self.config.lifecycle_notifications {
+            let message = self.lifecycle_message("started");
+            self.notifiers.send_message(&message).await;
+        }
+
+        let reason = loop {
+            tokio::select! {
+                _ = time::sleep_until(self.deadline) => {
+                    info!("Script completed successfully after {} minutes!", self.run_length().as_secs() / 60);
+                    break self.expired();
+                }
+                _ = ticker.tick() => {
+                    if let Some(reason) = self.cycle().await {
+                        break reason;
+                    }
+                }
+                _ = signals.snapshot.recv() => {
+                    let now = self.clock.now();
+                    info!("{}", session::render_snapshot(&self.session, self.notifiers.failed_sends(), self.notifiers.suppressed_repeats(), &self.config.style, now));
+                }
+                _ = signals.reload.recv() => self.reload_stops(),
+                _ = signals.extend.recv() => match self.extend(self.config.default_extension) {
+                    Ok(message) | Err(message) => info!("SIGUSR2: {}", message),
+                },
+                // Stopping here rather than dying still sends the summary and the "stopped" notice
+                _ = signals.terminate.recv() => {
+                    info!("SIGTERM received; stopping.");
+                    break ExitReason::Completed;
+                }
+                _ = signals.interrupt.recv() => {
+                    info!("SIGINT received; stopping.");
+                    break ExitReason::Completed;
+                }
+                Some(request) = requests.recv() => self.answer(request).await,
+            }
+        };
+
+        if self.session.ignored_observations > 0 {
+            info!("Skipped {} sightings of ignored vehicles (IGNORE_VEHICLES)", self.session.ignored_observations);
+        }
+        if self.config.report_closest_approach {
+            info!("{}", session::render_closest_approach(&self.session, &self.config.style));
+        }
+        if let Some(path) = &self.config.export_geojson {
+            let geojson = session::alerts_geojson(&self.session);
+            match fs::write(path, format!("{:#}\n", geojson)) {
+                Ok(()) => info!("Wrote {} alerts to {}", self.session.alerts.len(), path),
+                Err(e) => error!("Could not write {}: {}", path, e),
+            }
+        }
+        if self.summary || self.until_first_match.is_some() {
+            let summary = session::render_session_summary(&self.session, &self.config.style, self.clock.now());
+            info!("{}", summary);
+            if self.summary {
+                self.notifiers.send_message(&summary).await;
+            }
+        } else if let Some(services) = session::unlabelled_services(&self.session) {
+            info!("{} (add them to [service_labels])", services);
+        }
+        if self.config.lifecycle_notifications && matches!(reason, ExitReason::Completed | ExitReason::NoMatch) {
+            let message = self.lifecycle_message("stopped");
+            self.notifiers.send_message(&message).await;
+        }
+        for task in &self.background {
+            task.abort();
+        }
+        reason
+    }
+
+    /// "Stagecoach tracker started", naming the profile when there is one
+    fn lifecycle_message(&self, what: &str) -> String {
+        match &self.reload.profile {
+            Some(profile) => format!("Stagecoach tracker {} (profile {})", what, profile),
+            None => format!("Stagecoach tracker {}", what),
+        }
+    }
+
+    /// One poll of the API. Returns why the run should end, if it should.
+    async fn cycle(&mut self) -> Option<ExitReason> {
+        if self.controls.lock().unwrap().stop_requested {
+            info!("Tracking stopped from Telegram.");
+            return Some(ExitReason::Completed);
+        }
+
+        let now = self.clock.now();
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        info!("Current time: {} ({} left)", self.config.style.time_with_seconds(now), format_duration(remaining.as_secs() as i64));
+        self.check_for_resume(now);
+        self.refresh_active_stops(now);
+
+        if self.config.wall_clock_budget && (now - self.session.started_at).to_std().is_ok_and(|elapsed| elapsed >= self.run_length()) {
+            info!("Script completed successfully after {} minutes!", self.run_length().as_secs() / 60);
+            return Some(self.expired());
+        }
+        if !self.end_warned && remaining <= END_WARNING && self.run_length() > END_WARNING {
+            self.end_warned = true;
+            let mut message = format!("Tracking ends at {}.", self.config.style.time(now + remaining));
+            if self.run_length() < self.config.max_run_length {
+                message.push_str(&format!(" Send /extend {} to keep going.", self.config.default_extension.as_secs() / 60));
+            }
+            self.notifiers.send_message(&message).await;
+        }
+        self.notifiers.retry_pending().await;
+        if !self.scheduled_on(now) {
+            return None;
+        }
+
+        let mut caught = None;
+        let mut bus_done = None;
+        let mut stop_states = None;
+        let mut fetched = false;
+        let event = match fetch_services(&self.client, &self.config, &self.active_stops).await {
+            Ok(response) => {
+                let event = self.failures.record_success();
+                let now = self.clock.now();
+                let alerts_before = self.session.alerts.len();
+                check_buses(&response, &self.config, &self.active_stops, &mut self.notifiers, &self.controls, &mut self.session, now).await;
+                self.follow(&response, now).await;
+                bus_done = self.stop_after_bus(&response, now);
+                if self.state_file.is_some() {
+                    stop_states = Some(state_file::stop_states(&usable_vehicles(&response, &self.config, now), &self.active_stops, &self.config));
+                }
+                let mut board = self.status.lock().unwrap();
+                board.checked_at = Some(now);
+                board.in_range = self.session.in_range.clone();
+                board.positions = self.session.positions.clone();
+                board.failed_sends = self.notifiers.failed_sends();
+                board.suppressed_repeats = self.notifiers.suppressed_repeats();
+                board.api_down_since = None;
+                drop(board);
+                self.latest = Some((Instant::now(), response));
+                fetched = true;
+                if let Some(first) = &self.until_first_match {
+                    caught = self.session.alerts[alerts_before..].iter().find(|alert| first.matches(alert)).cloned();
+                }
+                event
+            }
+            Err(e) => {
+                error!("Error fetching buses: {}", e);
+                // Keep showing the last good positions, marked stale; alerts only come from fresh responses
+                self.status.lock().unwrap().api_down_since.get_or_insert(now);
+                self.failures.record_failure(&e.to_string())
+            }
+        };
+
+        self.write_state(stop_states, now);
+        if fetched {
+            self.heartbeat().await;
+        }
+
+        if let Some(event) = event {
+            let message = event.message();
+            warn!("{}", message);
+            self.notifiers.send_message(&message).await;
+            if let FailureEvent::GiveUp { .. } = event {
+                return Some(ExitReason::ApiFailure);
+            }
+        }
+        if let Some(message) = bus_done {
+            info!("{}", message);
+            return Some(ExitReason::Completed);
+        }
+        if let Some(alert) = caught {
+            info!("Bus {} ({}) reached {}; ending the run (--until-first-match).", alert.service_number, alert.vehicle, alert.stop);
+            return Some(ExitReason::Completed);
+        }
+        None
+    }
+
+    /// Refreshes --state-file; `stops` is None when this cycle's fetch failed
+    fn write_state(&mut self, stops: Option<Vec<StopState>>, now: DateTime<Utc>) {
+        let Some(state_file) = &mut self.state_file else {
+            return;
+        };
+        let board = self.status.lock().unwrap();
+        let health = Health {
+            last_api_success: board.checked_at.map(|at| at.to_rfc3339()),
+            api_down_since: board.api_down_since.map(|at| at.to_rfc3339()),
+            consecutive_api_failures: self.failures.consecutive(),
+            failed_sends: self.notifiers.failed_sends(),
+            suppressed_repeats: self.notifiers.suppressed_repeats(),
+        };
+        drop(board);
+        if let Err(e) = state_file.update(stops, health, now) {
+            error!("Could not write the state file {}: {}", state_file.path().display(), e);
+        }
+    }
+
+    /// Pings HEARTBEAT_URL. Only cycles that got an answer from the API ping, so a tracker that's
+    /// wedged or can't reach the API goes quiet and the monitoring service raises the alarm.
+    async fn heartbeat(&mut self) {
+        let Some(url) = &self.config.heartbeat_url else {
+            return;
+        };
+        if let Err(e) = self.client.get(url).send().await.and_then(|response| response.error_for_status()) {
+            warn!("Heartbeat to HEARTBEAT_URL failed: {}", e);
+        }
+    }
+
+    /// Adopts a bus to follow with FOLLOW_FIRST_BUS, and sends the followed bus's progress when a line is due
+    async fn follow(&mut self, response: &Value, now: DateTime<Utc>) {
+        if self.journey.is_none() && !self.config.follow_first_bus {
+            return;
+        }
+        let vehicles = usable_vehicles(response, &self.config, now);
+        if self.journey.is_none() && !self.followed_first {
+            self.journey = Journey::adopt_first(&vehicles, &self.active_stops, &self.config, now);
+            if let Some(journey) = &self.journey {
+                self.followed_first = true;
+                info!("Following {} (FOLLOW_FIRST_BUS)", journey.describe(&self.config));
+            }
+        }
+        let Some(journey) = &mut self.journey else {
+            return;
+        };
+
+        let progress = journey.progress(&vehicles, &self.active_stops, &self.config, self.config.follow_update_every, now);
+        let message = match &progress {
+            Progress::Waiting => return,
+            Progress::Update(line) => line.clone(),
+            Progress::Arrived => {
+                info!("Stopped following {}: it has arrived", journey.describe(&self.config));
+                self.journey = None;
+                return;
+            }
+            Progress::Passed | Progress::Lost => {
+                let message = journey.ended(&progress, &self.config).unwrap_or_default();
+                self.journey = None;
+                message
+            }
+        };
+        info!("{}", message);
+        if !self.controls.lock().unwrap().is_muted(now) {
+            self.notifiers.send_message(&message).await;
+        }
+    }
+
+    /// Once "Got it (stop after this bus)" has been pressed, whether that bus has now reached its
+    /// stop, gone past it or out of sight, which ends the run; says which
+    fn stop_after_bus(&mut self, response: &Value, now: DateTime<Utc>) -> Option<String> {
+        if let Some(bus) = self.controls.lock().unwrap().stop_after.take() {
+            self.stop_after = Some(Journey::to_alerted_stop(&bus, now));
+        }
+        let journey = self.stop_after.as_mut()?;
+        let vehicles = usable_vehicles(response, &self.config, now);
+        let outcome = match journey.progress(&vehicles, &self.active_stops, &self.config, self.config.follow_update_every, now) {
+            Progress::Arrived => "has arrived",
+            Progress::Passed => "has gone past the stop",
+            Progress::Lost => "is no longer seen",
+            Progress::Update(_) | Progress::Waiting => return None,
+        };
+        Some(format!("Tracking stopped from Telegram: {} {}.", journey.describe(&self.config), outcome))
+    }
+
+    /// Starts (or with `fleet` None, stops) following a bus for /follow, and describes the outcome
+    fn follow_request(&mut self, fleet: Option<&str>, now: DateTime<Utc>) -> String {
+        let Some(fleet) = fleet else {
+            return match self.journey.take() {
+                Some(journey) => format!("Stopped following {}.", journey.describe(&self.config)),
+                None => "Not following a bus.".to_string(),
+            };
+        };
+        let Some((_, response)) = &self.latest else {
+            return "No buses seen yet; try again after the next update.".to_string();
+        };
+        let vehicles = usable_vehicles(response, &self.config, now);
+        let Some(vehicle) = journey::find_by_fleet(&vehicles, fleet) else {
+            return format!("No bus with fleet number {} in the latest update.", fleet);
+        };
+        match Journey::start(vehicle, &self.active_stops, &self.config, now) {
+            Some(journey) => {
+                let reply = format!(
+                    "Following {}; updates every {}.",
+                    journey.describe(&self.config),
+                    format_duration(self.config.follow_update_every.num_seconds())
+                );
+                self.journey = Some(journey);
+                reply
+            }
+            None => format!("Bus {} ({}) isn't heading for any watched stop.", self.config.service_labels.label(&vehicle.service_number), vehicle.identifier()),
+        }
+    }
+
+    /// Why the run ends when its time is up: with --until-first-match, that nothing matched
+    fn expired(&self) -> ExitReason {
+        if self.until_first_match.is_some() {
+            ExitReason::NoMatch
+        } else {
+            ExitReason::Completed
+        }
+    }
+
+    /// RUN_MINUTES plus any extensions
+    fn run_length(&self) -> Duration {
+        self.config.run_length + self.extended
+    }
+
+    /// Pushes the deadline out by `by`, as far as MAX_RUN_MINUTES allows, and describes the outcome
+    fn extend(&mut self, by: Duration) -> Result<String, String> {
+        let room = self.config.max_run_length.saturating_sub(self.run_length());
+        if room.is_zero() {
+            return Err(format!("Can't extend: the run is already at the {} maximum (MAX_RUN_MINUTES).", format_duration(self.config.max_run_length.as_secs() as i64)));
+        }
+        let by = by.min(room);
+        self.extended += by;
+        self.deadline += by;
+        if self.deadline.saturating_duration_since(Instant::now()) > END_WARNING {
+            self.end_warned = false;
+        }
+        let ends_at = self.update_ends_at();
+        Ok(format!("Extended by {}; tracking now ends at {}.", format_duration(by.as_secs() as i64), self.config.style.time(ends_at)))
+    }
+
+    /// Publishes the wall-clock time of the deadline for /status, and returns it
+    fn update_ends_at(&mut self) -> DateTime<Utc> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        let ends_at = self.clock.now() + chrono::Duration::from_std(remaining).unwrap_or_default();
+        self.status.lock().unwrap().ends_at = Some(ends_at);
+        ends_at
+    }
+
+    /// Whether [schedule] has polling on at `now`, logging each switch and refreshing the
+    /// upcoming start times shown in /status when it does
+    fn scheduled_on(&mut self, now: DateTime<Utc>) -> bool {
+        let Some(schedule) = &self.config.schedule else {
+            return true;
+        };
+        let local = self.config.style.zone.local_time(now);
+        let on = schedule.is_active(local);
+        if self.polling != Some(on) {
+            match (self.polling, on) {
+                (Some(_), true) => info!("Schedule started; polling"),
+                (_, false) => info!("Outside the schedule; not polling until the next start"),
+                (None, true) => {}
+            }
+            self.polling = Some(on);
+            let next = schedule.next_activations(local, NEXT_ACTIVATIONS);
+            self.status.lock().unwrap().next_activations = Some(next.iter().map(|at| at.format(ACTIVATION_FORMAT).to_string()).collect());
+        }
+        on
+    }
+
+    /// Swaps in freshly read stops. Cooldowns are keyed by stop name, so stops that didn't change
+    /// keep theirs; a failed or empty reload leaves the current stops in place.
+    fn reload_stops(&mut self) {
+        match self.reload.load() {
+            Ok(stops) if stops.is_empty() => warn!("Reloaded config has no stops; keeping the current {}.", self.bus_stops.len()),
+            Ok(stops) => {
+                info!("Reloaded stops: {} (was {}).", stops.len(), self.bus_stops.len());
+                let mut status = self.status.lock().unwrap();
+                status.groups = group_labels(&stops);
+                status.stops = debug_server::stops_json(&stops);
+                drop(status);
+                self.bus_stops = stops;
+                self.active_date = None;
+                self.refresh_active_stops(self.clock.now());
+            }
+            Err(e) => error!("Could not reload stops, keeping the current ones: {}", e),
+        }
+    }
+
+    /// Picks the stops watched today, once per local date, so day-specific stops switch at midnight
+    fn refresh_active_stops(&mut self, now: DateTime<Utc>) {
+        let today = self.config.style.zone.local_time(now).date();
+        if self.active_date == Some(today) {
+            return;
+        }
+        self.active_date = Some(today);
+        self.active_stops = self.bus_stops.iter().filter(|stop| stop.active_on(today.weekday())).cloned().collect();
+        self.session.stop_index = StopIndex::new(&self.active_stops);
+
+        if self.bus_stops.iter().any(|stop| !stop.days.is_empty()) {
+            let names: Vec<String> = self.active_stops.iter().map(|stop| stop.name.clone()).collect();
+            info!("Stops watched on {}: {}", today.weekday(), if names.is_empty() { "none".to_string() } else { names.join(", ") });
+            self.status.lock().unwrap().active_stops = Some(names);
+        }
+    }
+
+    /// Notices a wall-clock gap far longer than the poll interval (a laptop lid closed, say):
+    /// vehicle tracks from before it are dropped and the next cycle won't alert on stale fixes
+    fn check_for_resume(&mut self, now: DateTime<Utc>) {
+        let last = self.last_cycle_at.replace(now);
+        let threshold = (Duration::from_secs(self.config.poll_interval_secs) * SUSPEND_GAP_FACTOR).max(MIN_SUSPEND_GAP);
+        self.session.resumed = match last.and_then(|last| (now - last).to_std().ok()) {
+            Some(gap) if gap > threshold => {
+                info!("Resumed after {}", format_duration(gap.as_secs() as i64));
+                self.session.tracks.clear();
+                true
+            }
+            _ => false,
+        };
+    }
+
+    async fn answer(&mut self, request: LoopRequest) {
+        let now = self.clock.now();
+        match request {
+            LoopRequest::Now(origin) => {
+                answer_now(&origin, &self.client, &self.config, &self.active_stops, &mut self.latest, &self.telegram, now).await
+            }
+            LoopRequest::History { origin, service, page } => {
+                let reply = history_page(self.session.history.as_ref(), service.as_deref(), page, &self.config.style, now);
+                answer_history(&origin, service.as_deref(), page, reply, &self.telegram).await
+            }
+            LoopRequest::Extend { origin, minutes } => {
+                let by = minutes.map_or(self.config.default_extension, |minutes| Duration::from_secs(minutes * 60));
+                let (Ok(reply) | Err(reply)) = self.extend(by);
+                info!("/extend: {}", reply);
+                if let Err(e) = self.telegram.reply(&origin, &reply, None).await {
+                    error!("Error answering /extend: {}", e);
+                }
+            }
+            LoopRequest::Follow { origin, fleet } => {
+                let reply = self.follow_request(fleet.as_deref(), now);
+                info!("/follow: {}", reply);
+                if let Err(e) = self.telegram.reply(&origin, &reply, None).await {
+                    error!("Error answering /follow: {}", e);
+                }
+            }
+        }
+    }
+}
+
+/// Time from `now` until the next wall-clock multiple of `period` (counted from the Unix epoch)
+fn delay_to_boundary(now: DateTime<Utc>, period: Duration) -> Duration {
+    let period_ms = period.as_millis().max(1) as i64;
+    let past = now.timestamp_millis().rem_euclid(period_ms);
+    Duration::from_millis(((period_ms - past) % period_ms) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telegram::AlertedBus;
+    use crate::test_support::{self, MockServer};
+
+    const NO_BUSES: &str = r#"{"services": []}"#;
+    const TELEGRAM_OK: &str = r#"{"ok": true, "result": {"message_id": 1}}"#;
+
+    /// Bus 7 at Market Square, its fix half a minute old
+    fn at_market_square() -> String {
+        let updated = (test_support::fixed_now() - chrono::Duration::seconds(30)).timestamp_millis().to_string();
+        serde_json::json!({ "services": [
+            { "serviceNumber": "7", "fleetNumber": "10812", "latitude": "53.0001", "longitude": "-1.5", "speed": "20", "updateTime": updated }
+        ]})
+        .to_string()
+    }
+
+    #[test]
+    fn reloading_replaces_the_stops_unless_the_new_ones_are_unusable() {
+        let api = MockServer::start(vec![(200, NO_BUSES.to_string())]);
+        let telegram = MockServer::start(vec![(200, TELEGRAM_OK.to_string())]);
+        let mut tracker = test_support::tracker(&api, &telegram, &[], vec![test_support::stop("Market Square", 53.0, -1.5)]);
+        let path = std::env::temp_dir().join(format!("reload-stops-test-{}.csv", std::process::id()));
+        tracker.reload.stops_path = Some(path.clone());
+        let names = |tracker: &Tracker| {
+            let stops: Vec<String> = tracker.bus_stops.iter().map(|stop| stop.name.clone()).collect();
+            (stops, tracker.status.lock().unwrap().groups.clone())
+        };
+
+        std::fs::write(&path, "name,lat,lng,radius,group\nHospital,53.02,-1.5\nDepot,53.03,-1.49,100,work\n").unwrap();
+        tracker.reload_stops();
+        assert_eq!(names(&tracker), (vec!["Hospital".to_string(), "Depot".to_string()], vec![session::DEFAULT_GROUP.to_string(), "work".to_string()]));
+
+        // A file with no valid stops, or none at all, keeps what's there
+        std::fs::write(&path, "Hospital,north,west\n").unwrap();
+        tracker.reload_stops();
+        std::fs::remove_file(&path).unwrap();
+        tracker.reload_stops();
+        assert_eq!(names(&tracker).0, ["Hospital", "Depot"]);
+    }
+
+    #[tokio::test]
+    async fn every_request_goes_through_the_shared_client() {
+        let api = MockServer::start(vec![(200, at_market_square())]);
+        let telegram = MockServer::start(vec![(200, TELEGRAM_OK.to_string())]);
+        let ntfy = MockServer::start(vec![(200, "{}".to_string())]);
+        let webhook = MockServer::start(vec![(200, "{}".to_string())]);
+        let heartbeat = MockServer::start(vec![(200, "{}".to_string())]);
+        let vars = [("NTFY_URL", ntfy.url.as_str()), ("NTFY_TOPIC", "buses"), ("WEBHOOK_URL", webhook.url.as_str()), ("HEARTBEAT_URL", heartbeat.url.as_str())];
+        let mut tracker = test_support::tracker(&api, &telegram, &vars, vec![test_support::stop("Market Square", 53.0, -1.5)]);
+
+        tracker.cycle().await;
+        // The user agent is only set on the shared client, so a subsystem with a client of its own would show up here
+        for (name, server) in [("API", &api), ("Telegram", &telegram), ("ntfy", &ntfy), ("webhook", &webhook), ("heartbeat", &heartbeat)] {
+            let requests = server.requests();
+            assert!(!requests.is_empty(), "nothing sent to the {}", name);
+            for request in requests {
+                assert_eq!(request.header("user-agent"), Some(crate::USER_AGENT), "{} {}", name, request.path);
+            }
+        }
+    }
+
+    /// Held while a test's tracker is running: signals go to the whole process, so one test's SIGTERM
+    /// would stop every tracker listening at the time
+    static RUNNING: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+    /// Runs `tracker` to its end with no signals or Telegram requests arriving
+    async fn run(tracker: Tracker) -> ExitReason {
+        let _running = RUNNING.lock().await;
+        run_alone(tracker).await
+    }
+
+    /// `run` for a test that already holds RUNNING
+    async fn run_alone(tracker: Tracker) -> ExitReason {
+        let (_requests, receiver) = mpsc::channel(1);
+        tracker.run(Signals::listen().unwrap(), receiver).await
+    }
+
+    /// The text of each message sent through Telegram so far
+    fn sent(telegram: &MockServer) -> Vec<String> {
+        telegram
+            .requests()
+            .iter()
+            .filter_map(|request| serde_json::from_str::<Value>(&request.body).ok()?["text"].as_str().map(str::to_string))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn sigterm_stops_the_run_with_its_summary_and_one_stopped_notice() {
+        let api = MockServer::start(vec![(200, NO_BUSES.to_string())]);
+        let telegram = MockServer::start(vec![(200, TELEGRAM_OK.to_string())]);
+        let mut tracker = test_support::tracker(&api, &telegram, &[("LIFECYCLE_NOTIFICATIONS", "true")], vec![test_support::stop("Market Square", 53.0, -1.5)]);
+        tracker.summary = true;
+
+        let _running = RUNNING.lock().await;
+        let run = tokio::spawn(run_alone(tracker));
+        while api.requests().is_empty() {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        let kill = std::process::Command::new("kill").args(["-TERM", &std::process::id().to_string()]).status().unwrap();
+        assert!(kill.success());
+        assert_eq!(time::timeout(Duration::from_secs(10), run).await.unwrap().unwrap(), ExitReason::Completed);
+
+        let sent = sent(&telegram);
+        assert_eq!(sent.iter().filter(|text| *text == "Stagecoach tracker started").count(), 1, "{:?}", sent);
+        assert_eq!(sent.iter().filter(|text| *text == "Stagecoach tracker stopped").count(), 1, "{:?}", sent);
+        assert_eq!(sent.first().map(String::as_str), Some("Stagecoach tracker started"));
+        assert_eq!(sent.last().map(String::as_str), Some("Stagecoach tracker stopped"));
+        assert_eq!(sent.len(), 3, "{:?}", sent); // The summary in between
+        assert_eq!(api.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn a_stop_in_its_own_quiet_hours_alerts_silently_while_others_follow_the_default() {
+        use crate::timezone::QuietHours;
+        use chrono::Weekday;
+
+        // Bus 7 at Market Square and bus 9 at Station Road
+        let updated = (test_support::fixed_now() - chrono::Duration::seconds(30)).timestamp_millis().to_string();
+        let response = serde_json::json!({ "services": [
+            { "serviceNumber": "7", "fleetNumber": "10812", "latitude": "53.0001", "longitude": "-1.5", "speed": "20", "updateTime": updated },
+            { "serviceNumber": "9", "fleetNumber": "10901", "latitude": "53.0101", "longitude": "-1.5", "speed": "20", "updateTime": updated },
+        ]})
+        .to_string();
+        // (quiet hours for Market Square, how its alert goes out); Station Road has none of its own
+        let cases = [
+            (QuietHours::During { days: vec![Weekday::Mon], window: None }, Some(true)), // It's a Monday
+            (QuietHours::During { days: vec![Weekday::Sat, Weekday::Sun], window: None }, Some(false)),
+            (QuietHours::Never, Some(false)),
+        ];
+        for (quiet, silent) in cases {
+            let api = MockServer::start(vec![(200, response.clone())]);
+            let telegram = MockServer::start(vec![(200, TELEGRAM_OK.to_string())]);
+            let mut stops = vec![test_support::stop("Market Square", 53.0, -1.5), test_support::stop("Station Road", 53.01, -1.5)];
+            stops[0].quiet = Some(quiet.clone());
+            let mut tracker = test_support::tracker(&api, &telegram, &[], stops);
+            tracker.cycle().await;
+
+            let silent_at = |stop: &str| {
+                let requests = telegram.requests();
+                let body: Value = requests
+                    .iter()
+                    .map(|request| serde_json::from_str(&request.body).unwrap())
+                    .find(|body: &Value| body["text"].as_str().is_some_and(|text| text.contains(stop)))
+                    .unwrap_or_else(|| panic!("no alert for {}", stop));
+                body["disable_notification"].as_bool()
+            };
+            assert_eq!(silent_at("Market Square"), silent, "{:?}", quiet);
+            assert_eq!(silent_at("Station Road"), None, "{:?}", quiet); // SILENT_HOURS isn't set
+        }
+    }
+
+    #[tokio::test]
+    async fn stop_after_this_bus_waits_for_the_bus_to_reach_its_stop() {
+        // Bus 7 closing on Market Square a fix every 30 s: 445 m out, 335 m, then inside its 200 m radius
+        let at = |lat: f64, secs_ago: i64| {
+            let fixed = test_support::fixed_now() - chrono::Duration::seconds(secs_ago);
+            let vehicle = serde_json::json!({
+                "serviceNumber": "7", "serviceDescription": "City Centre - Hospital", "fleetNumber": "10812", "latitude": lat.to_string(),
+                "longitude": "-1.5", "speed": "20", "updateTime": fixed.timestamp_millis().to_string()
+            });
+            (200, serde_json::json!({ "services": [vehicle] }).to_string())
+        };
+        let api = MockServer::start(vec![at(53.004, 60), at(53.003, 30), at(53.0005, 0)]);
+        let telegram = MockServer::start(vec![(200, TELEGRAM_OK.to_string())]);
+        let mut tracker = test_support::tracker(&api, &telegram, &[], vec![test_support::stop("Market Square", 53.0, -1.5)]);
+        tracker.deadline = Instant::now() + Duration::from_secs(3600);
+        let bus = AlertedBus { key: "fleet:10812".to_string(), service: "7".to_string(), vehicle: "fleet 10812".to_string(), stop: "Market Square".to_string() };
+        tracker.controls.lock().unwrap().stop_after = Some(bus);
+
+        assert_eq!(tracker.cycle().await, None);
+        assert_eq!(tracker.cycle().await, None);
+        assert_eq!(tracker.cycle().await, Some(ExitReason::Completed));
+        // The arrival still went out before the run ended
+        let sent = sent(&telegram);
+        assert_eq!(sent.len(), 1, "{:?}", sent);
+        assert!(sent[0].contains("is near **Market Square**"), "{:?}", sent);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn polls_on_the_interval_until_the_run_ends() {
+        let api = MockServer::start(vec![(200, NO_BUSES.to_string())]);
+        let telegram = MockServer::start(vec![(200, TELEGRAM_OK.to_string())]);
+        let tracker = test_support::tracker(&api, &telegram, &[("RUN_MINUTES", "5"), ("POLL_INTERVAL_SECS", "70")], Vec::new());
+
+        let started = Instant::now();
+        assert_eq!(run(tracker).await, ExitReason::Completed);
+        assert_eq!(started.elapsed(), Duration::from_secs(300));
+        assert_eq!(api.requests().len(), 5); // At 0, 70, 140, 210 and 280 s
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retry_backoff_doesnt_push_later_polls_back() {
+        let api = MockServer::start(vec![(503, "{}".to_string())]);
+        let telegram = MockServer::start(vec![(200, TELEGRAM_OK.to_string())]);
+        let vars = [("RUN_MINUTES", "5"), ("POLL_INTERVAL_SECS", "70"), ("API_RETRIES", "2")];
+        let tracker = test_support::tracker(&api, &telegram, &vars, Vec::new());
+
+        let started = Instant::now();
+        assert_eq!(run(tracker).await, ExitReason::Completed);
+        assert_eq!(started.elapsed(), Duration::from_secs(300));
+        // Each poll tries three times over a few seconds of backoff, and the next still starts on time
+        assert_eq!(api.requests().len(), 15);
+    }
+
+    #[tokio::test]
+    async fn a_failed_fetch_keeps_the_last_buses_marked_stale_without_alerting() {
+        let normal = include_str!("../tests/fixtures/normal.json");
+        let api = MockServer::start(vec![(200, normal.to_string()), (503, "{}".to_string())]);
+        let telegram = MockServer::start(vec![(200, TELEGRAM_OK.to_string())]);
+        let stops = vec![test_support::stop("Market Square", 53.0, -1.5), test_support::stop("Station Road", 53.01, -1.5)];
+        let mut tracker = test_support::tracker(&api, &telegram, &[], stops);
+
+        assert!(tracker.cycle().await.is_none());
+        let alerts = tracker.session.alerts.len();
+        let sent = telegram.requests().len();
+        let (in_range, checked_at) = {
+            let status = tracker.status.lock().unwrap();
+            assert!(status.api_down_since.is_none());
+            (status.in_range.clone(), status.checked_at)
+        };
+        assert!(alerts > 0 && sent > 0 && !in_range.is_empty());
+
+        assert!(tracker.cycle().await.is_none());
+        assert_eq!(api.requests().len(), 2);
+        let status = tracker.status.lock().unwrap();
+        assert_eq!(status.in_range, in_range);
+        assert_eq!(status.checked_at, checked_at);
+        assert!(status.api_down_since.is_some());
+        assert!(session::render_status(&status, None, &tracker.config.style).contains("Stale: the bus API has been unreachable since"));
+        assert_eq!(tracker.session.alerts.len(), alerts);
+        assert_eq!(telegram.requests().len(), sent);
+    }
+
+    #[tokio::test]
+    async fn only_cycles_the_api_answered_ping_the_heartbeat() {
+        let api = MockServer::start(vec![(200, at_market_square()), (500, "{}".to_string()), (200, at_market_square())]);
+        let telegram = MockServer::start(vec![(200, TELEGRAM_OK.to_string())]);
+        let heartbeat = MockServer::start(vec![(200, "{}".to_string())]);
+        let heartbeat_url = format!("{}/ping/abc", heartbeat.url);
+        let mut tracker = test_support::tracker(&api, &telegram, &[("HEARTBEAT_URL", &heartbeat_url)], vec![test_support::stop("Market Square", 53.0, -1.5)]);
+
+        let mut pings = Vec::new();
+        for _ in 0..3 {
+            tracker.cycle().await;
+            pings.push(heartbeat.requests().len());
+        }
+        assert_eq!(pings, [1, 1, 2]);
+        assert!(heartbeat.requests().iter().all(|request| request.method == "GET" && request.path == "/ping/abc"));
+    }
+}
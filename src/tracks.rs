@@ -0,0 +1,155 @@
+// Per-vehicle position history, used to throw away GPS jumps: a fix implying a speed
+// above MAX_PLAUSIBLE_SPEED_KMH is ignored and the previous good position stands in for it.
+// It also remembers when each vehicle last moved, to spot buses parked for the night.
+
+use chrono::{DateTime, Duration, Utc};
+use std::collections::HashMap;
+use tracing::warn;
+
+use crate::geo::haversine_distance;
+
+const MAX_HELD_FIXES: u32 = 2; // Rejections in a row before the track restarts at the new position
+const TRACK_EXPIRY_MINS: i64 = 10; // Tracks of vehicles not seen for this long are forgotten
+
+#[derive(Debug, Clone, Copy)]
+struct Track {
+    lat: f64,
+    lng: f64,
+    at: DateTime<Utc>, // Time of the last good fix
+    seen_at: DateTime<Utc>,
+    rejected: u32, // Implausible fixes in a row since the last good one
+    speed_kmh: Option<f64>, // Implied by the last two good fixes
+    moving_at: DateTime<Utc>, // Last time the vehicle was seen moving (or first seen)
+}
+
+#[derive(Debug)]
+pub struct Tracks {
+    max_speed_kmh: f64,
+    tracks: HashMap<String, Track>,
+}
+
+impl Tracks {
+    pub fn new(max_speed_kmh: f64) -> Tracks {
+        Tracks { max_speed_kmh, tracks: HashMap::new() }
+    }
+
+    /// Forgets every track, e.g. after a suspend when speeds across the gap mean nothing
+    pub fn clear(&mut self) {
+        self.tracks.clear();
+    }
+
+    /// Checks a new fix against the vehicle's track and returns the position to use:
+    /// the fix itself, or the previous good one if the jump is implausibly fast
+    pub fn check(&mut self, vehicle_key: &str, lat: f64, lng: f64, at: DateTime<Utc>, now: DateTime<Utc>) -> (f64, f64) {
+        self.tracks.retain(|_, track| now - track.seen_at < Duration::minutes(TRACK_EXPIRY_MINS));
+
+        let fresh = Track { lat, lng, at, seen_at: now, rejected: 0, speed_kmh: None, moving_at: now };
+        let Some(track) = self.tracks.get_mut(vehicle_key) else {
+            self.tracks.insert(vehicle_key.to_string(), fresh);
+            return (lat, lng);
+        };
+        track.seen_at = now;
+
+        let distance = haversine_distance(track.lat, track.lng, lat, lng);
+        let secs = (at - track.at).num_milliseconds() as f64 / 1000.0;
+        let implied_kmh = if secs > 0.0 { distance / secs * 3.6 } else if distance > 0.0 { f64::INFINITY } else { 0.0 };
+
+        if implied_kmh <= self.max_speed_kmh {
+            // A repeat of the same fix says nothing new about the speed
+            let speed_kmh = if secs > 0.0 { Some(implied_kmh) } else { track.speed_kmh };
+            *track = Track { speed_kmh, moving_at: track.moving_at, ..fresh };
+            return (lat, lng);
+        }
+        if track.rejected >= MAX_HELD_FIXES {
+            *track = fresh;
+            return (lat, lng);
+        }
+
+        track.rejected += 1;
+        warn!(
+            "Ignoring GPS jump for {}: {:.0} m in {:.0} s ({:.0} km/h); keeping the previous position",
+            vehicle_key, distance, secs, implied_kmh
+        );
+        (track.lat, track.lng)
+    }
+
+    /// How long the vehicle has been below `threshold_kmh`, judged by its reported speed or, when it
+    /// doesn't report one, by how far it moved between fixes. Call after `check`.
+    pub fn stationary_for(&mut self, vehicle_key: &str, reported_kmh: Option<f64>, threshold_kmh: f64, now: DateTime<Utc>) -> Duration {
+        let Some(track) = self.tracks.get_mut(vehicle_key) else {
+            return Duration::zero();
+        };
+        if reported_kmh.or(track.speed_kmh).is_none_or(|speed| speed >= threshold_kmh) {
+            track.moving_at = now;
+        }
+        now - track.moving_at
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::fixed_now;
+
+    const HOME: (f64, f64) = (53.0, -1.5);
+    const FAR: (f64, f64) = (53.045, -1.5); // 5 km north
+
+    /// Feeds fixes `secs` apart (each seen as it's made) and returns the positions to use
+    fn run(tracks: &mut Tracks, secs: i64, fixes: &[(f64, f64)]) -> Vec<(f64, f64)> {
+        fixes
+            .iter()
+            .enumerate()
+            .map(|(i, &(lat, lng))| {
+                let at = fixed_now() + Duration::seconds(secs * i as i64);
+                tracks.check("fleet:1", lat, lng, at, at)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn a_single_spike_is_replaced_by_the_last_good_fix() {
+        let mut tracks = Tracks::new(130.0);
+        let next = (53.0015, -1.5); // 170 m on, 20 km/h over 30 s
+        assert_eq!(run(&mut tracks, 30, &[HOME, FAR, next]), [HOME, HOME, next]);
+    }
+
+    #[test]
+    fn a_genuine_jump_is_taken_after_being_held_twice() {
+        // The bus really is 5 km away (say it was re-assigned and the old fix was stale)
+        let mut tracks = Tracks::new(130.0);
+        assert_eq!(run(&mut tracks, 30, &[HOME, FAR, FAR, FAR, FAR]), [HOME, HOME, HOME, FAR, FAR]);
+    }
+
+    #[test]
+    fn fast_but_plausible_movement_is_kept() {
+        let mut tracks = Tracks::new(130.0);
+        let motorway = (53.009, -1.5); // 1 km in 30 s, 120 km/h
+        assert_eq!(run(&mut tracks, 30, &[HOME, motorway]), [HOME, motorway]);
+        // A repeated fix time with a different position can't be judged plausible
+        let at = fixed_now() + Duration::seconds(30);
+        assert_eq!(tracks.check("fleet:1", 53.01, -1.5, at, at + Duration::seconds(30)), motorway);
+    }
+
+    #[test]
+    fn tracks_unseen_for_a_while_start_over() {
+        let mut tracks = Tracks::new(130.0);
+        run(&mut tracks, 30, &[HOME]);
+        let later = fixed_now() + Duration::minutes(TRACK_EXPIRY_MINS);
+        assert_eq!(tracks.check("fleet:1", FAR.0, FAR.1, later, later), FAR);
+    }
+
+    #[test]
+    fn stationary_time_counts_from_the_last_movement() {
+        let mut tracks = Tracks::new(130.0);
+        for minute in 0..4 {
+            let at = fixed_now() + Duration::minutes(minute);
+            tracks.check("fleet:1", HOME.0, HOME.1, at, at);
+        }
+        let now = fixed_now() + Duration::minutes(3);
+        // No reported speed: it hasn't moved since it was first seen
+        assert_eq!(tracks.stationary_for("fleet:1", None, 3.0, now), Duration::minutes(3));
+        assert_eq!(tracks.stationary_for("fleet:1", Some(2.0), 3.0, now), Duration::minutes(3));
+        assert_eq!(tracks.stationary_for("fleet:1", Some(15.0), 3.0, now), Duration::zero());
+        assert_eq!(tracks.stationary_for("fleet:2", None, 3.0, now), Duration::zero());
+    }
+}
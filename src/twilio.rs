@@ -0,0 +1,101 @@
+// SMS alerts through Twilio's Messages API. Texts cost money, so only bus alerts go out
+// (not departures or notices) and at most TWILIO_MAX_MESSAGES per run.
+
+use reqwest::Client;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use tracing::info;
+
+use crate::config_file::{group_destinations, GroupEntry};
+use crate::error::TrackerError;
+use crate::{env_or_file, env_parse};
+
+const DEFAULT_API_URL: &str = "https://api.twilio.com";
+
+#[derive(Debug)]
+pub struct Twilio {
+    client: Client,
+    url: String, // .../Accounts/<sid>/Messages.json
+    account_sid: String,
+    auth_token: String,
+    from: String,
+    to: Vec<String>,
+    group_to: HashMap<String, Vec<String>>, // Stop group -> the numbers its alerts go to
+    max_messages: u32,
+    sent: u32, // Messages attempted this run; counts against max_messages even if Twilio refused them
+}
+
+impl Twilio {
+    /// Returns None when TWILIO_ACCOUNT_SID isn't set, leaving SMS disabled
+    pub fn from_env(client: Client, groups: &BTreeMap<String, GroupEntry>) -> Result<Option<Twilio>, TrackerError> {
+        let account_sid = match env_or_file("TWILIO_ACCOUNT_SID")? {
+            Some(sid) if !sid.trim().is_empty() => sid.trim().to_string(),
+            _ => return Ok(None),
+        };
+        let required = |name: &str| -> Result<String, TrackerError> {
+            env_or_file(name)?
+                .filter(|value| !value.trim().is_empty())
+                .map(|value| value.trim().to_string())
+                .ok_or_else(|| TrackerError::Config(format!("TWILIO_ACCOUNT_SID is set but {} isn't.", name)))
+        };
+        let auth_token = required("TWILIO_AUTH_TOKEN")?;
+        let from = required("TWILIO_FROM")?;
+        let to: Vec<String> = required("TWILIO_TO")?.split(',').map(|number| number.trim().to_string()).filter(|number| !number.is_empty()).collect();
+        let base_url = env_or_file("TWILIO_API_URL")?.unwrap_or_else(|| DEFAULT_API_URL.to_string());
+
+        Ok(Some(Twilio {
+            client,
+            url: format!("{}/2010-04-01/Accounts/{}/Messages.json", base_url.trim_end_matches('/'), account_sid),
+            account_sid,
+            auth_token,
+            from,
+            to,
+            group_to: group_destinations(groups, |group| {
+                let numbers: Vec<String> = group.sms_to.iter().map(|number| number.trim().to_string()).filter(|number| !number.is_empty()).collect();
+                (!numbers.is_empty()).then_some(numbers)
+            }),
+            max_messages: env_parse("TWILIO_MAX_MESSAGES", 5)?,
+            sent: 0,
+        }))
+    }
+
+    /// Texts `group`'s numbers, or every TWILIO_TO number for ungrouped alerts, until the run's cap is used up
+    pub async fn send(&mut self, group: Option<&str>, text: &str) -> Result<(), TrackerError> {
+        let text = text.replace("**", "");
+        let recipients = group.and_then(|group| self.group_to.get(group)).unwrap_or(&self.to).clone();
+        for to in recipients {
+            if self.sent >= self.max_messages {
+                info!("SMS cap of {} reached (TWILIO_MAX_MESSAGES), not texting {}: {}", self.max_messages, to, text);
+                continue;
+            }
+            self.sent += 1;
+            self.send_to(&to, &text).await?;
+        }
+        Ok(())
+    }
+
+    async fn send_to(&self, to: &str, text: &str) -> Result<(), TrackerError> {
+        let response = self
+            .client
+            .post(&self.url)
+            .basic_auth(&self.account_sid, Some(&self.auth_token))
+            .form(&[("From", self.from.as_str()), ("To", to), ("Body", text)])
+            .send()
+            .await?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+
+        // Errors come back as {"code": 21211, "message": "The 'To' number ... is not a valid phone number.", "more_info": "..."}
+        let body: Value = response.json().await.unwrap_or_default();
+        let mut error = match (body["code"].as_i64(), body["message"].as_str()) {
+            (Some(code), Some(message)) => format!("Twilio error {} ({}): {}", code, status, message),
+            _ => format!("Twilio returned {}", status),
+        };
+        if let Some(more_info) = body["more_info"].as_str() {
+            error.push_str(&format!(" See {}", more_info));
+        }
+        Err(TrackerError::Notify(error))
+    }
+}
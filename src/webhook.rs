@@ -0,0 +1,121 @@
+// Generic webhook: each alert and notice is POSTed to WEBHOOK_URL (or the alert's group's
+// webhook_url) as JSON, e.g.
+//
+//     {"kind": "arrival", "text": "Bus (7) ... is near **Home**!", "service": "7", "vehicle": "10812",
+//      "direction": "towards Hulme", "stop": "Home", "group": null}
+//
+// With WEBHOOK_SECRET set, the body is signed with HMAC-SHA256 and the lowercase hex digest
+// sent in an X-Signature header, so the receiver can check the request came from here.
+
+use hmac::{Hmac, KeyInit, Mac};
+use reqwest::Client;
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+use crate::config_file::{group_destinations, GroupEntry};
+use crate::env_or_file;
+use crate::error::TrackerError;
+use crate::notify::{Alert, AlertKind};
+
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    client: Client,
+    url: String,
+    group_urls: HashMap<String, String>, // Stop group -> where its alerts are posted
+    secret: Option<String>,
+}
+
+impl Webhook {
+    /// Returns None when WEBHOOK_URL isn't set, leaving the webhook disabled
+    pub fn from_env(client: Client, groups: &BTreeMap<String, GroupEntry>) -> Result<Option<Webhook>, TrackerError> {
+        let url = match env_or_file("WEBHOOK_URL")? {
+            Some(url) if !url.trim().is_empty() => url.trim().to_string(),
+            _ => return Ok(None),
+        };
+        let secret = env_or_file("WEBHOOK_SECRET")?.filter(|secret| !secret.is_empty());
+        let group_urls = group_destinations(groups, |group| group.webhook_url.as_deref().map(str::trim).filter(|url| !url.is_empty()).map(str::to_string));
+        Ok(Some(Webhook { client, url, group_urls, secret }))
+    }
+
+    pub fn alert_payload(alert: &Alert) -> Value {
+        let kind = match alert.kind {
+            AlertKind::Arrival => "arrival",
+            AlertKind::EarlyWarning => "early_warning",
+        };
+        json!({
+            "kind": kind,
+            "text": alert.text,
+            "service": alert.bus.service,
+            "vehicle": alert.bus.vehicle,
+            "direction": alert.direction.to_string(),
+            "stop": alert.bus.stop,
+            "group": alert.group,
+        })
+    }
+
+    pub fn notice_payload(text: &str) -> Value {
+        json!({ "kind": "notice", "text": text })
+    }
+
+    /// Posts to `group`'s URL, or WEBHOOK_URL for ungrouped alerts and notices
+    pub async fn send(&self, group: Option<&str>, payload: &Value) -> Result<(), TrackerError> {
+        let body = payload.to_string();
+        let url = group.and_then(|group| self.group_urls.get(group)).unwrap_or(&self.url);
+        let mut request = self.client.post(url).header("Content-Type", "application/json");
+        if let Some(secret) = &self.secret {
+            request = request.header("X-Signature", signature(secret, body.as_bytes()));
+        }
+        request.body(body).send().await?.error_for_status()?;
+        Ok(())
+    }
+}
+
+/// HMAC-SHA256 of `body` keyed with `secret`, as lowercase hex
+fn signature(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(body);
+    mac.finalize().into_bytes().iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{:02x}", byte);
+        hex
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::direction::Direction;
+    use crate::telegram::AlertedBus;
+
+    #[test]
+    fn signature_matches_rfc_4231_test_case_2() {
+        assert_eq!(signature("Jefe", b"what do ya want for nothing?"), "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+    }
+
+    #[test]
+    fn alert_payload_names_the_bus() {
+        let alert = Alert {
+            text: "Bus (7) towards Hulme [10812] is near **Home**!".to_string(),
+            dedup_key: String::new(),
+            bus: AlertedBus { key: "fleet:10812".to_string(), service: "7".to_string(), vehicle: "10812".to_string(), stop: "Home".to_string() },
+            direction: Direction::Towards("Hulme".to_string()),
+            group: None,
+            kind: AlertKind::Arrival,
+            quiet: None,
+            priority: false,
+        };
+        assert_eq!(
+            Webhook::alert_payload(&alert),
+            json!({
+                "kind": "arrival",
+                "text": "Bus (7) towards Hulme [10812] is near **Home**!",
+                "service": "7",
+                "vehicle": "10812",
+                "direction": "towards Hulme",
+                "stop": "Home",
+                "group": null,
+            })
+        );
+    }
+}